@@ -1,13 +1,19 @@
-use serde::Deserialize;
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
-use std::{borrow::BorrowMut, collections::HashSet, sync::Arc};
+use std::{
+    borrow::BorrowMut,
+    collections::{HashMap, HashSet},
+    io,
+    sync::Arc,
+};
 use tokio::sync::Mutex;
 
 use crate::{
-    db::db::DB,
-    events::{Body, BroadcastEvent, Event, Message, SharedEvent},
-    transport::Transport,
-    uid::unique_id::UID,
+    config::config::{BroadcastConfig, BroadcastMode},
+    db::db::{DbMetrics, DB},
+    events::{Body, BroadcastEvent, Event, GossipEvent, Message, SharedEvent},
+    transport::{MsgIds, Transport},
 };
 
 #[derive(Debug)]
@@ -17,17 +23,32 @@ pub struct Broadcast {
 
 #[derive(Debug, Default)]
 struct Store {
+    node_id: String,
     topology: HashSet<String>,
+    // Every node in the cluster, as received in the init message
+    membership: Vec<String>,
     db: DB<u64, BMessage>,
+    // Values seen by this node, expired after the seen ttl. Only used in epidemic mode
+    values: DB<String, Value>,
+    // Ids of the expired values, for another seen ttl. Peers that saw a value later still gossip it
+    forgotten: DB<String, ()>,
+    // Ids of the values every peer is known to have, from its gossip and its acknowledgements
+    known: HashMap<String, HashSet<String>>,
+    // Ids pushed in the current round waiting for an acknowledgement, by msg_id
+    in_flight: HashMap<u64, (String, Vec<String>)>,
+    // Rounds in which every value was pushed
+    pushes: HashMap<String, u32>,
+    msg_ids: Arc<MsgIds>,
     transport: Transport,
 }
 
 #[derive(Debug, Default)]
 struct Service {
+    config: BroadcastConfig,
     store: Store,
 }
 
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 struct BMessage {
     data: Value,
     dest: String,
@@ -47,27 +68,36 @@ pub struct BroadCastMessage {
 }
 
 impl Broadcast {
-    pub async fn new() -> Broadcast {
-        let service = Arc::new(Mutex::new(Service::new()));
+    pub async fn new(config: BroadcastConfig, msg_ids: Arc<MsgIds>) -> std::io::Result<Broadcast> {
+        let mode = config.mode;
+        let service = Arc::new(Mutex::new(Service::new(config, msg_ids)?));
+
+        match mode {
+            BroadcastMode::Flood => tokio::task::spawn(handle_broadworker(service.clone())),
+            BroadcastMode::Epidemic => tokio::task::spawn(handle_gossipworker(service.clone())),
+        };
 
-        tokio::task::spawn(handle_broadworker(service.clone()));
+        Ok(Broadcast { service })
+    }
+
+    pub async fn set_membership(&mut self, node_id: &str, node_ids: Vec<String>) {
+        let mut service = self.service.lock().await;
+        let service = service.borrow_mut();
 
-        Broadcast { service }
+        service.set_membership(node_id, node_ids);
     }
 
+    // Fails when the value or its retries could not be stored. Nothing is sent then
     pub async fn handle_broadcast(
         &mut self,
         parent_node_id: &str,
         src: &str,
-        uid: &mut UID,
         payload: BroadCastMessage,
-    ) {
+    ) -> io::Result<()> {
         let mut service = self.service.lock().await;
         let service = service.borrow_mut();
 
-        service
-            .handle_broadcast(parent_node_id, src, uid, payload)
-            .await;
+        service.handle_broadcast(parent_node_id, src, payload).await
     }
     pub async fn set_topology(&mut self, nodes: Vec<String>) {
         let mut service = self.service.lock().await;
@@ -76,18 +106,92 @@ impl Broadcast {
         service.set_topology(nodes).await;
     }
 
-    pub async fn handle_broadcast_ok(&mut self, message_id: u64) {
+    // Broadcasts still waiting for an acknowledgement
+    pub async fn retry_metrics(&self) -> DbMetrics {
+        self.service.lock().await.store.db.metrics()
+    }
+
+    // Values still gossiped
+    pub async fn seen_metrics(&self) -> DbMetrics {
+        self.service.lock().await.store.values.metrics()
+    }
+
+    pub async fn handle_broadcast_ok(&mut self, message_id: u64) -> io::Result<()> {
+        let mut service = self.service.lock().await;
+        let service = service.borrow_mut();
+
+        service.handle_broadcast_ok(message_id)
+    }
+
+    // Merges values received from a peer.
+    // Returns the values that were new to this node and the values the peer is missing.
+    pub async fn handle_gossip(
+        &mut self,
+        src: &str,
+        values: HashMap<String, Value>,
+    ) -> io::Result<(HashMap<String, Value>, HashMap<String, Value>)> {
         let mut service = self.service.lock().await;
         let service = service.borrow_mut();
 
-        service.handle_broadcast_ok(message_id);
+        let fresh = service.merge(src, values)?;
+        Ok((fresh, service.missing(src)))
+    }
+
+    // Merges the values a peer replied with, and marks the pushed ones as known to it.
+    // Returns the values that were new to this node.
+    pub async fn handle_gossip_ok(
+        &mut self,
+        src: &str,
+        in_reply_to: u64,
+        values: HashMap<String, Value>,
+    ) -> io::Result<HashMap<String, Value>> {
+        let mut service = self.service.lock().await;
+        let service = service.borrow_mut();
+
+        let store = &mut service.store;
+        if let Some((peer, ids)) = store.in_flight.remove(&in_reply_to) {
+            // Values that expired in the meantime are not remembered
+            let values = &store.values;
+            let ids = ids
+                .into_iter()
+                .filter(|id| values.get_message(id).is_some());
+            store.known.entry(peer).or_default().extend(ids);
+        }
+        service.merge(src, values)
+    }
+
+    // Adds values recovered from the data directory
+    pub async fn seed(&mut self, values: HashMap<String, Value>) -> io::Result<()> {
+        let mut service = self.service.lock().await;
+        for (id, value) in values {
+            service.see(id, value)?;
+        }
+        Ok(())
     }
 }
 
 impl Service {
-    fn new() -> Service {
-        let store = Store::default();
-        Service { store }
+    fn new(config: BroadcastConfig, msg_ids: Arc<MsgIds>) -> std::io::Result<Service> {
+        let mut db = DB::open(&config.store, "broadcast-messages")?;
+        db.on_evict(Box::new(|_, message: &BMessage| {
+            eprintln!(
+                "dropping broadcast {} to {} after its retry ttl",
+                message.dist_message_id, message.dest
+            );
+        }));
+
+        let store = Store {
+            db,
+            values: DB::open(&config.store, "broadcast-values")?,
+            msg_ids,
+            ..Store::default()
+        };
+        Ok(Service { config, store })
+    }
+
+    fn set_membership(&mut self, node_id: &str, node_ids: Vec<String>) {
+        node_id.clone_into(&mut self.store.node_id);
+        self.store.membership = node_ids;
     }
 
     async fn set_topology(&mut self, nodes: Vec<String>) {
@@ -100,9 +204,13 @@ impl Service {
         &mut self,
         parent_node_id: &str,
         src: &str,
-        uid: &mut UID,
         payload: BroadCastMessage,
-    ) {
+    ) -> io::Result<()> {
+        if self.config.mode == BroadcastMode::Epidemic {
+            // The value will be spread by the gossip worker
+            return self.see(payload.dist_message_id, payload.data);
+        }
+
         // To prevent the nodes from broadcasting the same message infinitely,
         // A node will not broadcast back to the sender.
         // The topology will guarantee that the message will be send to a node only once.
@@ -120,21 +228,22 @@ impl Service {
             let broadcast_message = BMessage {
                 data: payload.data.clone(),
                 dest: node_id.clone(),
-                broadcast_event_message_id: match uid.generate_int_unique_id().await {
-                    Ok(id) => id,
-                    Err(err) => {
-                        eprintln!("failed to generate unique int id: {:?}", err);
-                        continue;
-                    }
-                },
+                broadcast_event_message_id: self.store.msg_ids.next(),
                 dist_message_id: payload.dist_message_id.clone(),
                 src: parent_node_id.to_owned(),
             };
             // Store the message
-            db.add_message(
-                broadcast_message.broadcast_event_message_id,
-                broadcast_message.clone(),
-            );
+            match self.config.retry_ttl {
+                Some(ttl) => db.add_message_with_ttl(
+                    broadcast_message.broadcast_event_message_id,
+                    broadcast_message.clone(),
+                    ttl,
+                ),
+                None => db.add_message(
+                    broadcast_message.broadcast_event_message_id,
+                    broadcast_message.clone(),
+                ),
+            }?;
             messages.push(broadcast_message);
         }
 
@@ -144,6 +253,7 @@ impl Service {
         // for data in messages {
         //      self.broadcast(data)
         // }
+        Ok(())
     }
 
     fn broadcast(&mut self, data: BMessage) {
@@ -169,8 +279,128 @@ impl Service {
         self.store.transport.handleoutput(message);
     }
 
-    fn handle_broadcast_ok(&mut self, message_id: u64) {
-        self.store.db.delete_message(&message_id);
+    fn handle_broadcast_ok(&mut self, message_id: u64) -> io::Result<()> {
+        self.store.db.delete_message(&message_id)
+    }
+
+    // Values that could not be stored are not marked as known, so the peer sends them again.
+    // Forgotten values are neither new nor known, they stop circulating once every peer forgot them
+    fn merge(
+        &mut self,
+        src: &str,
+        values: HashMap<String, Value>,
+    ) -> io::Result<HashMap<String, Value>> {
+        // Expired values are forgotten before they could pass for new ones
+        self.evict_seen()?;
+
+        let mut fresh = HashMap::new();
+        for (id, value) in values {
+            if self.store.forgotten.get_message(&id).is_some() {
+                continue;
+            }
+            if self.store.values.get_message(&id).is_none() {
+                self.see(id.clone(), value.clone())?;
+                fresh.insert(id.clone(), value);
+            }
+            self.store
+                .known
+                .entry(src.to_owned())
+                .or_default()
+                .insert(id);
+        }
+        Ok(fresh)
+    }
+
+    fn see(&mut self, id: String, value: Value) -> io::Result<()> {
+        self.store
+            .values
+            .add_message_with_ttl(id, value, self.config.seen_ttl)
+    }
+
+    // Forgets the expired values, and what every peer is known to have of them
+    fn evict_seen(&mut self) -> io::Result<()> {
+        self.store.forgotten.evict_expired()?;
+        let expired = self.store.values.evict_expired_ids()?;
+        if expired.is_empty() {
+            return Ok(());
+        }
+        for id in expired {
+            self.store
+                .forgotten
+                .add_message_with_ttl(id, (), self.config.seen_ttl)?;
+        }
+        let values = &self.store.values;
+        for ids in self.store.known.values_mut() {
+            ids.retain(|id| values.get_message(id).is_some());
+        }
+        self.store
+            .pushes
+            .retain(|id, _| values.get_message(id).is_some());
+        Ok(())
+    }
+
+    // Values the peer is not known to have
+    fn missing(&self, peer: &str) -> HashMap<String, Value> {
+        let known = self.store.known.get(peer);
+        self.store
+            .values
+            .get_entries()
+            .into_iter()
+            .filter(|(id, _)| !known.is_some_and(|known| known.contains(id)))
+            .collect()
+    }
+
+    fn gossip(&mut self) {
+        // Push-pull round.
+        // Push the values a few random peers are missing. Each peer replies with the values
+        // this node is missing. Peers are picked from the whole cluster rather than the topology,
+        // so values still spread when the topology is disconnected or partially partitioned.
+        // Values pushed in `max_rounds` rounds already are only sent in replies.
+        // Pushes of earlier rounds are sent again unless acknowledged
+        self.store.in_flight.clear();
+
+        let peers = self
+            .store
+            .membership
+            .iter()
+            .filter(|node_id| **node_id != self.store.node_id)
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let mut pushed = HashSet::new();
+        for peer in peers.choose_multiple(&mut rand::thread_rng(), self.config.fanout) {
+            // An empty push still pulls what the peer has
+            let values = self
+                .missing(peer)
+                .into_iter()
+                .filter(|(id, _)| {
+                    let rounds = self.store.pushes.get(id).copied().unwrap_or_default();
+                    self.config.max_rounds.is_none_or(|max| rounds < max)
+                })
+                .collect::<HashMap<_, _>>();
+            pushed.extend(values.keys().cloned());
+
+            let msg_id = self.store.msg_ids.next();
+            self.store
+                .in_flight
+                .insert(msg_id, (peer.clone(), values.keys().cloned().collect()));
+            let message = Message {
+                src: self.store.node_id.clone(),
+                dest: peer.clone(),
+                body: Body {
+                    typ: Event::Gossip {
+                        gossip: GossipEvent { values },
+                        shared: SharedEvent { msg_id },
+                    },
+                },
+            };
+
+            self.store.transport.handleoutput(message);
+        }
+
+        for id in pushed {
+            *self.store.pushes.entry(id).or_default() += 1;
+        }
     }
 }
 
@@ -183,6 +413,9 @@ async fn handle_broadworker(service: Arc<Mutex<Service>>) {
         let mut service_lock = service.lock().await;
         let st = service_lock.borrow_mut();
 
+        if let Err(err) = st.store.db.evict_expired() {
+            eprintln!("failed to drop expired broadcasts: \n err: {:?}", err);
+        }
         let messages = st.store.db.get_messages_as_value();
 
         for message in messages {
@@ -190,3 +423,127 @@ async fn handle_broadworker(service: Arc<Mutex<Service>>) {
         }
     }
 }
+
+async fn handle_gossipworker(service: Arc<Mutex<Service>>) {
+    loop {
+        let round_interval = service.lock().await.config.round_interval;
+        tokio::time::sleep(round_interval).await;
+
+        let mut service_lock = service.lock().await;
+        let st = service_lock.borrow_mut();
+
+        if let Err(err) = st.evict_seen() {
+            eprintln!("failed to drop expired values: \n err: {:?}", err);
+        }
+        st.gossip();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    const TTL: Duration = Duration::from_millis(50);
+
+    fn service(seen_ttl: Duration) -> Service {
+        let config = BroadcastConfig {
+            mode: BroadcastMode::Epidemic,
+            seen_ttl,
+            ..BroadcastConfig::default()
+        };
+        Service::new(config, Arc::new(MsgIds::new())).unwrap()
+    }
+
+    fn values(ids: &[&str]) -> HashMap<String, Value> {
+        ids.iter().map(|id| (id.to_string(), json!(id))).collect()
+    }
+
+    fn known(service: &Service, peer: &str) -> Vec<String> {
+        let mut known = service
+            .store
+            .known
+            .get(peer)
+            .map(|ids| ids.iter().cloned().collect::<Vec<_>>())
+            .unwrap_or_default();
+        known.sort();
+        known
+    }
+
+    #[test]
+    fn expired_values_are_not_taken_for_new_ones() {
+        let mut service = service(TTL);
+        assert_eq!(service.merge("n2", values(&["a"])).unwrap().len(), 1);
+        assert_eq!(known(&service, "n2"), ["a"]);
+
+        std::thread::sleep(TTL + Duration::from_millis(10));
+        // n3 saw the value later and still gossips it
+        assert!(service
+            .merge("n3", values(&["a", "b"]))
+            .unwrap()
+            .contains_key("b"));
+        assert!(!service
+            .merge("n3", values(&["a"]))
+            .unwrap()
+            .contains_key("a"));
+        assert!(service.missing("n2").contains_key("b"));
+        assert!(!service.missing("n2").contains_key("a"));
+        assert_eq!(known(&service, "n2"), Vec::<String>::new());
+        assert_eq!(known(&service, "n3"), ["b"]);
+    }
+
+    #[tokio::test]
+    async fn values_of_a_gossip_reply_are_known_to_the_sender() {
+        let config = BroadcastConfig {
+            mode: BroadcastMode::Epidemic,
+            fanout: 2,
+            // The rounds stay idle, and the test runs one by hand
+            round_interval: Duration::from_secs(3600),
+            ..BroadcastConfig::default()
+        };
+        let mut broadcast = Broadcast::new(config, Arc::new(MsgIds::new()))
+            .await
+            .unwrap();
+        let node_ids = ["n1", "n2", "n3"].map(str::to_owned).to_vec();
+        broadcast.set_membership("n1", node_ids).await;
+        broadcast.seed(values(&["a"])).await.unwrap();
+
+        let msg_id = {
+            let mut service = broadcast.service.lock().await;
+            service.gossip();
+            let in_flight = service.store.in_flight.iter();
+            in_flight
+                .filter(|(_, (peer, _))| peer == "n2")
+                .map(|(msg_id, _)| *msg_id)
+                .next()
+                .unwrap()
+        };
+        let fresh = broadcast
+            .handle_gossip_ok("n2", msg_id, values(&["b"]))
+            .await
+            .unwrap();
+        assert_eq!(fresh, values(&["b"]));
+
+        // The next round pushes nothing back to n2
+        let service = broadcast.service.lock().await;
+        assert_eq!(known(&service, "n2"), ["a", "b"]);
+        assert!(service.missing("n2").is_empty());
+        assert_eq!(service.missing("n3"), values(&["a", "b"]));
+    }
+
+    #[test]
+    fn forgotten_ids_are_dropped_after_another_ttl() {
+        let mut service = service(TTL);
+        service.merge("n2", values(&["a"])).unwrap();
+
+        std::thread::sleep(TTL + Duration::from_millis(10));
+        service.evict_seen().unwrap();
+        assert_eq!(service.store.values.metrics().entries, 0);
+        assert_eq!(service.store.forgotten.metrics().entries, 1);
+
+        std::thread::sleep(TTL + Duration::from_millis(10));
+        service.evict_seen().unwrap();
+        assert_eq!(service.store.forgotten.metrics().entries, 0);
+    }
+}
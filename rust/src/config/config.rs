@@ -0,0 +1,738 @@
+use std::{collections::HashMap, path::PathBuf, str::FromStr, time::Duration};
+
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    pub broadcast: BroadcastConfig,
+    pub log: LogConfig,
+    pub ids: IdConfig,
+    pub workload: Workload,
+    pub replication: ReplicationConfig,
+    pub raft: RaftConfig,
+    // Leader election runs only when enabled
+    pub election: Option<ElectionConfig>,
+    // Storage of the broadcast messages seen by the node, by distributed message id
+    pub store: StoreKind,
+    // Node state is only kept in memory unless a directory is given. Every node uses a
+    // subdirectory named after it
+    pub data: Option<DataConfig>,
+}
+
+#[derive(Debug, Clone)]
+pub struct DataConfig {
+    pub dir: PathBuf,
+    pub fsync: FsyncPolicy,
+    // A snapshot is taken after this many operations, and the write-ahead log starts over
+    pub snapshot_every: u64,
+}
+
+impl DataConfig {
+    pub fn new(dir: PathBuf) -> DataConfig {
+        DataConfig {
+            dir,
+            fsync: FsyncPolicy::default(),
+            snapshot_every: 1000,
+        }
+    }
+}
+
+// Where a database keeps its entries
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum StoreKind {
+    // In memory, unordered
+    #[default]
+    Memory,
+    // In memory, ordered by key
+    Ordered,
+    // In memory and in a change log under the directory, reloaded on restart.
+    // Every node uses a subdirectory named after it. Changes are synced by the policy.
+    File(PathBuf, FsyncPolicy),
+}
+
+impl StoreKind {
+    // A file store moves to the node's own directory
+    pub fn for_node(&self, node_id: &str) -> StoreKind {
+        match self {
+            StoreKind::File(dir, fsync) => StoreKind::File(dir.join(node_id), *fsync),
+            kind => kind.clone(),
+        }
+    }
+}
+
+impl FromStr for StoreKind {
+    type Err = ();
+
+    // `memory`, `ordered` or `file:<dir>`
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        match spec.split_once(':') {
+            Some(("file", dir)) if !dir.is_empty() => {
+                Ok(StoreKind::File(PathBuf::from(dir), FsyncPolicy::default()))
+            }
+            None if spec == "memory" => Ok(StoreKind::Memory),
+            None if spec == "ordered" => Ok(StoreKind::Ordered),
+            _ => Err(()),
+        }
+    }
+}
+
+// What `read` and `add` operate on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Workload {
+    // `read` returns the broadcast values
+    #[default]
+    Broadcast,
+    // A set of integers that only grows
+    GSet,
+    // A counter that goes up and down
+    PnCounter,
+    // `read`, `write` and `cas` on keys, replicated with Raft
+    LinKv,
+}
+
+#[derive(Debug, Clone)]
+pub struct RaftConfig {
+    // A follower starts an election after hearing nothing from the leader for a random time
+    // between this and twice this
+    pub election_timeout: Duration,
+    pub heartbeat_interval: Duration,
+    // Storage of the log, the term and the vote. A node that forgets them after a restart
+    // could vote twice in a term or drop committed entries, so lin-kv warns unless it is a file store
+    pub store: StoreKind,
+}
+
+#[derive(Debug, Clone)]
+pub struct ElectionConfig {
+    // A node campaigns after hearing nothing from the leader for a random time between this and
+    // twice this. The leader steps down when a majority has not answered for this long
+    pub election_timeout: Duration,
+    pub heartbeat_interval: Duration,
+    // Storage of the term and the vote. A node voting again after a restart could elect
+    // two leaders in a term unless it is a file store. By default a file store under the
+    // temporary directory, which later runs may reuse: terms only grow, so that is harmless
+    pub store: StoreKind,
+}
+
+impl Default for ElectionConfig {
+    fn default() -> Self {
+        ElectionConfig {
+            election_timeout: Duration::from_millis(500),
+            heartbeat_interval: Duration::from_millis(100),
+            store: StoreKind::File(
+                std::env::temp_dir().join("gossip-glommers"),
+                FsyncPolicy::Always,
+            ),
+        }
+    }
+}
+
+impl Default for RaftConfig {
+    fn default() -> Self {
+        RaftConfig {
+            election_timeout: Duration::from_millis(300),
+            heartbeat_interval: Duration::from_millis(50),
+            store: StoreKind::Ordered,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReplicationMode {
+    // Send the whole state every round
+    State,
+    // Send what each peer has not acknowledged yet
+    #[default]
+    Delta,
+}
+
+// How the CRDTs of the g-set and pn-counter workloads reach the other nodes
+#[derive(Debug, Clone, Copy)]
+pub struct ReplicationConfig {
+    pub mode: ReplicationMode,
+    // Time between two rounds. Every round reaches every other node
+    pub interval: Duration,
+}
+
+impl Default for ReplicationConfig {
+    fn default() -> Self {
+        ReplicationConfig {
+            mode: ReplicationMode::default(),
+            interval: Duration::from_millis(200),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IdFormat {
+    // `{timestamp}-{node_id}-{counter}`
+    #[default]
+    String,
+    // 64 bit numbers ordered by time
+    Snowflake,
+    // The following are strings that sort lexicographically by time
+    UuidV7,
+    Ulid,
+    Ksuid,
+    // Dense integers leased in blocks from lin-kv
+    Dense,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct IdConfig {
+    // Format of the ids returned by `generate`
+    pub format: IdFormat,
+    pub clock_regression: ClockRegression,
+    pub lease: LeaseConfig,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LeaseConfig {
+    // Number of ids leased at once
+    pub block_size: u64,
+    // Lease the next block once fewer ids than this are left
+    pub low_water: u64,
+}
+
+impl Default for LeaseConfig {
+    fn default() -> Self {
+        LeaseConfig {
+            block_size: 1000,
+            low_water: 100,
+        }
+    }
+}
+
+// What to do when the clock reads earlier than the last generated id
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockRegression {
+    // Wait up to the given time for the clock to catch up, then fail
+    Wait(Duration),
+    // Keep using the last timestamp and its sequence numbers, then the following milliseconds
+    Borrow,
+    Fail,
+}
+
+impl Default for ClockRegression {
+    fn default() -> Self {
+        ClockRegression::Wait(Duration::from_millis(1000))
+    }
+}
+
+impl FromStr for ClockRegression {
+    type Err = ();
+
+    // `wait`, `wait:MS`, `borrow` or `fail`
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        match spec.split_once(':') {
+            Some(("wait", ms)) => Ok(ClockRegression::Wait(Duration::from_millis(
+                ms.parse::<u64>().map_err(|_| ())?,
+            ))),
+            None if spec == "wait" => Ok(ClockRegression::default()),
+            None if spec == "borrow" => Ok(ClockRegression::Borrow),
+            None if spec == "fail" => Ok(ClockRegression::Fail),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BroadcastMode {
+    // Send every new message to the neighbours from the topology and retry until acked
+    #[default]
+    Flood,
+    // Every round, exchange values with randomly chosen peers from the whole cluster
+    Epidemic,
+}
+
+#[derive(Debug, Clone)]
+pub struct BroadcastConfig {
+    pub mode: BroadcastMode,
+    // Number of peers contacted per gossip round in epidemic mode
+    pub fanout: usize,
+    // Time between two gossip rounds in epidemic mode
+    pub round_interval: Duration,
+    // Rounds in which a value is pushed at most. Peers still pull it afterwards. Unbounded when missing
+    pub max_rounds: Option<u32>,
+    pub store: StoreKind,
+    // Unacknowledged broadcasts stop being retried after this long, e.g. to a dead neighbour
+    pub retry_ttl: Option<Duration>,
+    // Values stop being gossiped this long after the node saw them. `read` still returns them.
+    // Their ids are remembered as long again, so that copies peers still gossip are not new values
+    pub seen_ttl: Duration,
+}
+
+impl Default for BroadcastConfig {
+    fn default() -> Self {
+        BroadcastConfig {
+            mode: BroadcastMode::Flood,
+            fanout: 3,
+            round_interval: Duration::from_millis(100),
+            max_rounds: None,
+            store: StoreKind::default(),
+            retry_ttl: None,
+            seen_ttl: Duration::from_secs(60),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LogConfig {
+    // Server side upper bounds for a single poll. Requests can only lower them.
+    pub poll_limits: PollLimits,
+    // Policy of every key without an override
+    pub retention: RetentionPolicy,
+    pub key_retention: HashMap<String, RetentionPolicy>,
+    // Logs are kept in memory unless a directory is given. Every node uses a subdirectory named
+    // after it
+    pub storage: Option<DiskConfig>,
+    // Number of sequence numbers remembered per producer to detect retried sends
+    pub producer_window: usize,
+    pub strategy: LogStrategy,
+    // Storage of the messages of every key. Polls scan it in offset order
+    pub store: StoreKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogStrategy {
+    // Every key is owned by one node. The other nodes forward requests to it
+    #[default]
+    Leader,
+    // Every node allocates offsets with a compare-and-set in lin-kv and stores the messages in seq-kv
+    LinKv,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            poll_limits: PollLimits::default(),
+            retention: RetentionPolicy::default(),
+            key_retention: HashMap::new(),
+            storage: None,
+            producer_window: 100,
+            strategy: LogStrategy::default(),
+            store: StoreKind::Ordered,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DiskConfig {
+    pub dir: PathBuf,
+    pub fsync: FsyncPolicy,
+    // A new segment file is started once the active one grows past this size
+    pub segment_bytes: u64,
+}
+
+impl DiskConfig {
+    pub fn new(dir: PathBuf) -> DiskConfig {
+        DiskConfig {
+            dir,
+            fsync: FsyncPolicy::default(),
+            segment_bytes: 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FsyncPolicy {
+    // Sync after every write
+    #[default]
+    Always,
+    // Sync after every N appends
+    Every(u64),
+    // Leave it to the operating system
+    Never,
+}
+
+impl FromStr for FsyncPolicy {
+    type Err = ();
+
+    // `always`, `never` or `every:N`
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        match spec.split_once(':') {
+            Some(("every", n)) => match n.parse::<u64>() {
+                Ok(n) if n > 0 => Ok(FsyncPolicy::Every(n)),
+                _ => Err(()),
+            },
+            None if spec == "always" => Ok(FsyncPolicy::Always),
+            None if spec == "never" => Ok(FsyncPolicy::Never),
+            _ => Err(()),
+        }
+    }
+}
+
+impl LogConfig {
+    pub fn retention_policy(&self, key: &str) -> RetentionPolicy {
+        match self.key_retention.get(key) {
+            Some(policy) => policy.clone(),
+            None => self.retention.clone(),
+        }
+    }
+
+    // Where the transactions of batches spanning several owners are kept: next to the messages
+    // when they are on disk. In memory otherwise, and the write-ahead log records them
+    pub fn txn_store(&self) -> StoreKind {
+        match (&self.store, &self.storage) {
+            (StoreKind::File(..), _) => self.store.clone(),
+            (_, Some(storage)) => StoreKind::File(storage.dir.clone(), storage.fsync),
+            _ => StoreKind::Memory,
+        }
+    }
+}
+
+// Messages are kept forever by default
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    pub max_messages: Option<usize>,
+    pub max_age: Option<Duration>,
+    // Delete every message at or below the committed offset
+    pub delete_committed: bool,
+    // Only keep the latest message of every message key
+    pub compact: bool,
+}
+
+impl FromStr for RetentionPolicy {
+    type Err = ();
+
+    // A comma separated list of rules, e.g. `messages:100,age-ms:5000,committed,compact`
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let mut policy = RetentionPolicy::default();
+
+        for rule in spec.split(',').filter(|rule| !rule.is_empty()) {
+            match rule.split_once(':') {
+                Some(("messages", max)) => {
+                    policy.max_messages = Some(max.parse::<usize>().map_err(|_| ())?)
+                }
+                Some(("age-ms", max)) => {
+                    policy.max_age =
+                        Some(Duration::from_millis(max.parse::<u64>().map_err(|_| ())?))
+                }
+                None if rule == "committed" => policy.delete_committed = true,
+                None if rule == "compact" => policy.compact = true,
+                _ => return Err(()),
+            }
+        }
+
+        Ok(policy)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PollLimits {
+    pub max_messages_per_key: usize,
+    pub max_messages: Option<usize>,
+    // Approximate size of the returned messages once serialized
+    pub max_bytes: Option<usize>,
+    // Longest a poll is held waiting for messages, whatever its timeout
+    pub max_wait: Duration,
+}
+
+impl Default for PollLimits {
+    fn default() -> Self {
+        PollLimits {
+            max_messages_per_key: 10,
+            max_messages: None,
+            max_bytes: None,
+            max_wait: Duration::from_secs(30),
+        }
+    }
+}
+
+impl PollLimits {
+    // Returns the tightest of the two limits
+    pub fn restrict(
+        &self,
+        max_messages_per_key: Option<usize>,
+        max_messages: Option<usize>,
+        max_bytes: Option<usize>,
+    ) -> PollLimits {
+        PollLimits {
+            max_messages_per_key: match max_messages_per_key {
+                Some(max) => max.min(self.max_messages_per_key),
+                None => self.max_messages_per_key,
+            },
+            max_messages: min_limit(self.max_messages, max_messages),
+            max_bytes: min_limit(self.max_bytes, max_bytes),
+            max_wait: self.max_wait,
+        }
+    }
+
+    // The wait of a poll asking for `timeout_ms`
+    pub fn wait(&self, timeout_ms: u64) -> Duration {
+        Duration::from_millis(timeout_ms).min(self.max_wait)
+    }
+}
+
+fn min_limit(a: Option<usize>, b: Option<usize>) -> Option<usize> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    UnknownFlag(String),
+    MissingValue(String),
+    InvalidValue { flag: String, value: String },
+    // The flag only makes sense after another one, e.g. `--log-fsync` after `--log-dir`
+    MissingFlag { flag: String, requires: String },
+}
+
+impl Config {
+    pub fn new() -> Config {
+        Config::default()
+    }
+
+    // Flags are passed as `--flag value`.
+    pub fn from_args<I: IntoIterator<Item = String>>(args: I) -> Result<Config, ConfigError> {
+        let mut config = Config::new();
+        let mut args = args.into_iter();
+
+        while let Some(flag) = args.next() {
+            let value = match args.next() {
+                Some(value) => value,
+                None => return Err(ConfigError::MissingValue(flag)),
+            };
+
+            match flag.as_str() {
+                "--broadcast-mode" => {
+                    config.broadcast.mode = match value.as_str() {
+                        "flood" => BroadcastMode::Flood,
+                        "epidemic" => BroadcastMode::Epidemic,
+                        _ => return Err(ConfigError::InvalidValue { flag, value }),
+                    }
+                }
+                "--id-format" => {
+                    config.ids.format = match value.as_str() {
+                        "string" => IdFormat::String,
+                        "snowflake" => IdFormat::Snowflake,
+                        "uuidv7" => IdFormat::UuidV7,
+                        "ulid" => IdFormat::Ulid,
+                        "ksuid" => IdFormat::Ksuid,
+                        "dense" => IdFormat::Dense,
+                        _ => return Err(ConfigError::InvalidValue { flag, value }),
+                    }
+                }
+                "--workload" => {
+                    config.workload = match value.as_str() {
+                        "broadcast" => Workload::Broadcast,
+                        "g-set" => Workload::GSet,
+                        "pn-counter" => Workload::PnCounter,
+                        "lin-kv" => Workload::LinKv,
+                        _ => return Err(ConfigError::InvalidValue { flag, value }),
+                    }
+                }
+                "--replication" => {
+                    config.replication.mode = match value.as_str() {
+                        "state" => ReplicationMode::State,
+                        "delta" => ReplicationMode::Delta,
+                        _ => return Err(ConfigError::InvalidValue { flag, value }),
+                    }
+                }
+                "--raft-election-ms" => {
+                    config.raft.election_timeout = Duration::from_millis(parse(&flag, &value)?)
+                }
+                "--raft-heartbeat-ms" => {
+                    config.raft.heartbeat_interval = Duration::from_millis(parse(&flag, &value)?)
+                }
+                "--election" => {
+                    config.election = match value.as_str() {
+                        "on" => Some(ElectionConfig::default()),
+                        "off" => None,
+                        _ => return Err(ConfigError::InvalidValue { flag, value }),
+                    }
+                }
+                "--election-timeout-ms" => {
+                    let timeout = Duration::from_millis(parse(&flag, &value)?);
+                    election_config(&mut config, &flag)?.election_timeout = timeout;
+                }
+                "--election-heartbeat-ms" => {
+                    let interval = Duration::from_millis(parse(&flag, &value)?);
+                    election_config(&mut config, &flag)?.heartbeat_interval = interval;
+                }
+                "--replication-ms" => {
+                    config.replication.interval = Duration::from_millis(parse(&flag, &value)?)
+                }
+                "--id-block-size" => config.ids.lease.block_size = parse(&flag, &value)?,
+                "--id-low-water" => config.ids.lease.low_water = parse(&flag, &value)?,
+                "--clock-regression" => config.ids.clock_regression = parse(&flag, &value)?,
+                "--gossip-fanout" => config.broadcast.fanout = parse(&flag, &value)?,
+                "--gossip-rounds" => config.broadcast.max_rounds = Some(parse(&flag, &value)?),
+                "--gossip-round-ms" => {
+                    config.broadcast.round_interval = Duration::from_millis(parse(&flag, &value)?)
+                }
+                "--broadcast-retry-ttl-ms" => {
+                    config.broadcast.retry_ttl = Some(Duration::from_millis(parse(&flag, &value)?))
+                }
+                "--broadcast-seen-ttl-ms" => {
+                    config.broadcast.seen_ttl = Duration::from_millis(parse(&flag, &value)?)
+                }
+                "--poll-max-per-key" => {
+                    config.log.poll_limits.max_messages_per_key = parse(&flag, &value)?
+                }
+                "--poll-max-messages" => {
+                    config.log.poll_limits.max_messages = Some(parse(&flag, &value)?)
+                }
+                "--poll-max-bytes" => {
+                    config.log.poll_limits.max_bytes = Some(parse(&flag, &value)?)
+                }
+                "--poll-max-wait-ms" => {
+                    config.log.poll_limits.max_wait = Duration::from_millis(parse(&flag, &value)?)
+                }
+                // Either `<spec>` for every key or `<key>=<spec>` for a single key
+                "--log-retention" => match value.split_once('=') {
+                    Some((key, spec)) => {
+                        let policy = parse(&flag, spec)?;
+                        config.log.key_retention.insert(key.to_owned(), policy);
+                    }
+                    None => config.log.retention = parse(&flag, &value)?,
+                },
+                "--producer-window" => config.log.producer_window = parse(&flag, &value)?,
+                "--log-strategy" => {
+                    config.log.strategy = match value.as_str() {
+                        "leader" => LogStrategy::Leader,
+                        "lin-kv" => LogStrategy::LinKv,
+                        _ => return Err(ConfigError::InvalidValue { flag, value }),
+                    }
+                }
+                // Either `<kind>` for every database or `node|broadcast|log|raft|election=<kind>`
+                // for one of them. The election store needs `--election on` first
+                "--store" => match value.split_once('=') {
+                    Some(("node", kind)) => config.store = parse(&flag, kind)?,
+                    Some(("broadcast", kind)) => config.broadcast.store = parse(&flag, kind)?,
+                    // Polls scan the log, which the unordered store sorts on every scan
+                    Some(("log", "memory")) => {
+                        return Err(ConfigError::InvalidValue { flag, value })
+                    }
+                    Some(("log", kind)) => config.log.store = parse(&flag, kind)?,
+                    Some(("raft", kind)) => config.raft.store = parse(&flag, kind)?,
+                    Some(("election", kind)) => {
+                        let kind = parse(&flag, kind)?;
+                        election_config(&mut config, &flag)?.store = kind;
+                    }
+                    Some(_) => return Err(ConfigError::InvalidValue { flag, value }),
+                    None => {
+                        let kind: StoreKind = parse(&flag, &value)?;
+                        config.store = kind.clone();
+                        config.broadcast.store = kind.clone();
+                        config.raft.store = kind.clone();
+                        if let Some(election) = config.election.as_mut() {
+                            election.store = kind.clone();
+                        }
+                        if kind != StoreKind::Memory {
+                            config.log.store = kind;
+                        }
+                    }
+                },
+                "--store-fsync" => {
+                    let policy: FsyncPolicy = parse(&flag, &value)?;
+                    let stores = [
+                        Some(&mut config.store),
+                        Some(&mut config.broadcast.store),
+                        Some(&mut config.log.store),
+                        Some(&mut config.raft.store),
+                        config.election.as_mut().map(|election| &mut election.store),
+                    ];
+                    let mut found = false;
+                    for store in stores.into_iter().flatten() {
+                        if let StoreKind::File(_, fsync) = store {
+                            *fsync = policy;
+                            found = true;
+                        }
+                    }
+                    if !found {
+                        return Err(ConfigError::MissingFlag {
+                            flag,
+                            requires: "--store file:<dir>".to_owned(),
+                        });
+                    }
+                }
+                "--data-dir" => config.data = Some(DataConfig::new(PathBuf::from(value))),
+                "--data-fsync" => {
+                    let fsync = parse(&flag, &value)?;
+                    data_config(&mut config, &flag)?.fsync = fsync;
+                }
+                "--snapshot-every" => {
+                    let snapshot_every = match parse(&flag, &value)? {
+                        0 => return Err(ConfigError::InvalidValue { flag, value }),
+                        n => n,
+                    };
+                    data_config(&mut config, &flag)?.snapshot_every = snapshot_every;
+                }
+                "--log-dir" => {
+                    config.log.storage = Some(DiskConfig::new(PathBuf::from(value)));
+                }
+                "--log-fsync" => {
+                    let fsync = parse(&flag, &value)?;
+                    disk_config(&mut config, &flag)?.fsync = fsync;
+                }
+                "--log-segment-bytes" => {
+                    let segment_bytes = parse(&flag, &value)?;
+                    disk_config(&mut config, &flag)?.segment_bytes = segment_bytes;
+                }
+                _ => return Err(ConfigError::UnknownFlag(flag)),
+            }
+        }
+
+        Ok(config)
+    }
+
+    // Every node of a run gets the same arguments, so each one keeps its files in a directory
+    // named after it. Only known once the init message arrives
+    pub fn for_node(&self, node_id: &str) -> Config {
+        let mut config = self.clone();
+        config.store = config.store.for_node(node_id);
+        config.broadcast.store = config.broadcast.store.for_node(node_id);
+        config.log.store = config.log.store.for_node(node_id);
+        config.raft.store = config.raft.store.for_node(node_id);
+        if let Some(election) = config.election.as_mut() {
+            election.store = election.store.for_node(node_id);
+        }
+        if let Some(data) = config.data.as_mut() {
+            data.dir = data.dir.join(node_id);
+        }
+        if let Some(storage) = config.log.storage.as_mut() {
+            storage.dir = storage.dir.join(node_id);
+        }
+        config
+    }
+}
+
+fn parse<T: FromStr>(flag: &str, value: &str) -> Result<T, ConfigError> {
+    value.parse::<T>().map_err(|_| ConfigError::InvalidValue {
+        flag: flag.to_owned(),
+        value: value.to_owned(),
+    })
+}
+
+fn disk_config<'a>(config: &'a mut Config, flag: &str) -> Result<&'a mut DiskConfig, ConfigError> {
+    match config.log.storage.as_mut() {
+        Some(disk) => Ok(disk),
+        None => Err(ConfigError::MissingFlag {
+            flag: flag.to_owned(),
+            requires: "--log-dir".to_owned(),
+        }),
+    }
+}
+
+fn data_config<'a>(config: &'a mut Config, flag: &str) -> Result<&'a mut DataConfig, ConfigError> {
+    match config.data.as_mut() {
+        Some(data) => Ok(data),
+        None => Err(ConfigError::MissingFlag {
+            flag: flag.to_owned(),
+            requires: "--data-dir".to_owned(),
+        }),
+    }
+}
+
+fn election_config<'a>(
+    config: &'a mut Config,
+    flag: &str,
+) -> Result<&'a mut ElectionConfig, ConfigError> {
+    match config.election.as_mut() {
+        Some(election) => Ok(election),
+        None => Err(ConfigError::MissingFlag {
+            flag: flag.to_owned(),
+            requires: "--election".to_owned(),
+        }),
+    }
+}
@@ -0,0 +1,84 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::crdt::crdt::Crdt;
+
+// Grow-only counter. Every node only increments its own count
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GCounter {
+    counts: BTreeMap<String, u64>,
+}
+
+impl GCounter {
+    pub fn new() -> GCounter {
+        GCounter::default()
+    }
+
+    pub fn increment(&mut self, node_id: &str, amount: u64) {
+        *self.counts.entry(node_id.to_owned()).or_default() += amount;
+    }
+
+    pub fn value(&self) -> u64 {
+        self.counts.values().sum()
+    }
+}
+
+impl Crdt for GCounter {
+    fn merge(&mut self, other: &Self) {
+        for (node_id, count) in other.counts.iter() {
+            let current = self.counts.entry(node_id.clone()).or_default();
+            *current = (*current).max(*count);
+        }
+    }
+
+    fn delta(&self, since: &Self) -> Self {
+        GCounter {
+            counts: self
+                .counts
+                .iter()
+                .filter(|(node_id, count)| since.counts.get(*node_id) < Some(count))
+                .map(|(node_id, count)| (node_id.clone(), *count))
+                .collect(),
+        }
+    }
+}
+
+// Counter that can go down. Increments and decrements are counted separately
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PNCounter {
+    increments: GCounter,
+    decrements: GCounter,
+}
+
+impl PNCounter {
+    pub fn new() -> PNCounter {
+        PNCounter::default()
+    }
+
+    pub fn add(&mut self, node_id: &str, delta: i64) {
+        if delta >= 0 {
+            self.increments.increment(node_id, delta as u64);
+        } else {
+            self.decrements.increment(node_id, delta.unsigned_abs());
+        }
+    }
+
+    pub fn value(&self) -> i64 {
+        self.increments.value() as i64 - self.decrements.value() as i64
+    }
+}
+
+impl Crdt for PNCounter {
+    fn merge(&mut self, other: &Self) {
+        self.increments.merge(&other.increments);
+        self.decrements.merge(&other.decrements);
+    }
+
+    fn delta(&self, since: &Self) -> Self {
+        PNCounter {
+            increments: self.increments.delta(&since.increments),
+            decrements: self.decrements.delta(&since.decrements),
+        }
+    }
+}
@@ -0,0 +1,114 @@
+use serde::{de::DeserializeOwned, Serialize};
+
+// A state-based conflict-free replicated data type.
+// Replicas update their own state and exchange it, or deltas of it, in any order and any number
+// of times. Every replica that has seen the same updates ends with the same state.
+pub trait Crdt: Clone + Default + Serialize + DeserializeOwned {
+    // Joins the other state into this one. Commutative, associative and idempotent
+    fn merge(&mut self, other: &Self);
+
+    // The part of this state `since` has not seen.
+    // Merging the delta into `since` gives the same result as merging the whole state.
+    fn delta(&self, since: &Self) -> Self;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt::Debug;
+
+    use super::*;
+    use crate::crdt::{
+        counter::{GCounter, PNCounter},
+        register::LWWRegister,
+        set::{GSet, ORSet},
+    };
+
+    fn merged<C: Crdt>(a: &C, b: &C) -> C {
+        let mut merged = a.clone();
+        merged.merge(b);
+        merged
+    }
+
+    // Checks the merge laws and the delta law on every combination of the states
+    fn assert_laws<C: Crdt + PartialEq + Debug>(states: &[C]) {
+        for a in states {
+            assert_eq!(&merged(a, a), a, "idempotent");
+            for b in states {
+                assert_eq!(merged(a, b), merged(b, a), "commutative");
+                assert_eq!(
+                    merged(b, &a.delta(b)),
+                    merged(b, a),
+                    "delta of {:?} since {:?}",
+                    a,
+                    b
+                );
+                for c in states {
+                    assert_eq!(
+                        merged(&merged(a, b), c),
+                        merged(a, &merged(b, c)),
+                        "associative"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn g_counter_follows_the_laws() {
+        let mut a = GCounter::new();
+        a.increment("n1", 2);
+        let mut b = a.clone();
+        b.increment("n2", 3);
+        let mut c = a.clone();
+        c.increment("n1", 1);
+        assert_laws(&[GCounter::new(), a, b, c]);
+    }
+
+    #[test]
+    fn pn_counter_follows_the_laws() {
+        let mut a = PNCounter::new();
+        a.add("n1", 5);
+        let mut b = a.clone();
+        b.add("n2", -3);
+        let mut c = a.clone();
+        c.add("n1", -1);
+        c.add("n3", 4);
+        assert_laws(&[PNCounter::new(), a, b, c]);
+    }
+
+    #[test]
+    fn lww_register_follows_the_laws() {
+        let mut a = LWWRegister::new();
+        a.set("n1", 1, 10);
+        let mut b = LWWRegister::new();
+        b.set("n2", 2, 10);
+        let mut c = LWWRegister::new();
+        c.set("n1", 3, 11);
+        assert_laws(&[LWWRegister::new(), a, b, c]);
+    }
+
+    #[test]
+    fn g_set_follows_the_laws() {
+        let mut a = GSet::new();
+        a.insert(1);
+        let mut b = a.clone();
+        b.insert(2);
+        let mut c = GSet::new();
+        c.insert(3);
+        assert_laws(&[GSet::new(), a, b, c]);
+    }
+
+    #[test]
+    fn or_set_follows_the_laws() {
+        let mut a = ORSet::new();
+        a.insert("n1", 1);
+        a.insert("n1", 2);
+        let mut b = a.clone();
+        b.remove(&1);
+        b.insert("n2", 3);
+        let mut c = a.clone();
+        c.insert("n3", 1);
+        c.remove(&2);
+        assert_laws(&[ORSet::new(), a, b, c]);
+    }
+}
@@ -0,0 +1,5 @@
+pub mod counter;
+pub mod crdt;
+pub mod register;
+pub mod replica;
+pub mod set;
@@ -0,0 +1,62 @@
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::crdt::crdt::Crdt;
+
+// Last-writer-wins register. The write with the highest timestamp wins and
+// the node id breaks ties, so every replica picks the same value.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(bound = "T: Serialize + DeserializeOwned")]
+pub struct LWWRegister<T> {
+    value: Option<T>,
+    timestamp: u64,
+    node_id: String,
+}
+
+impl<T> Default for LWWRegister<T> {
+    fn default() -> Self {
+        LWWRegister {
+            value: None,
+            timestamp: 0,
+            node_id: String::new(),
+        }
+    }
+}
+
+impl<T> LWWRegister<T> {
+    pub fn new() -> LWWRegister<T> {
+        LWWRegister::default()
+    }
+
+    // Ignored when a later write is already known
+    pub fn set(&mut self, node_id: &str, value: T, timestamp: u64) {
+        if (timestamp, node_id) > (self.timestamp, self.node_id.as_str()) {
+            self.value = Some(value);
+            self.timestamp = timestamp;
+            node_id.clone_into(&mut self.node_id);
+        }
+    }
+
+    pub fn get(&self) -> Option<&T> {
+        self.value.as_ref()
+    }
+
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+}
+
+impl<T: Clone + Serialize + DeserializeOwned> Crdt for LWWRegister<T> {
+    fn merge(&mut self, other: &Self) {
+        if let Some(value) = other.value.as_ref() {
+            self.set(&other.node_id, value.clone(), other.timestamp);
+        }
+    }
+
+    fn delta(&self, since: &Self) -> Self {
+        if (self.timestamp, &self.node_id) > (since.timestamp, &since.node_id) {
+            self.clone()
+        } else {
+            LWWRegister::default()
+        }
+    }
+}
@@ -0,0 +1,289 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Debug,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    config::config::{ReplicationConfig, ReplicationMode},
+    crdt::crdt::Crdt,
+    events::{Body, Event, Message, ReplicateEvent, SharedEvent},
+    transport::{MsgIds, Transport},
+};
+
+// A CRDT replicated to every other node of the cluster, without any key value store.
+// Updates are applied locally and reach the other nodes in the next round, so they are
+// still accepted during a partition and spread once it heals. Updates kept only in memory are
+// acknowledged once a peer has them, which takes up to a round.
+#[derive(Debug, Clone)]
+pub struct Replica<C> {
+    state: Arc<Mutex<ReplicaState<C>>>,
+}
+
+#[derive(Debug)]
+struct ReplicaState<C> {
+    config: ReplicationConfig,
+    crdt: C,
+    node_id: String,
+    // Every other node, as received in the init message
+    peers: Vec<String>,
+    // What every peer is known to have, from its own rounds and its acknowledgements
+    known: HashMap<String, C>,
+    // Peers that acknowledged the whole state of this node since it started
+    synced: HashSet<String>,
+    // Deltas of the current round waiting for an acknowledgement, by msg_id,
+    // and whether they are the whole state
+    in_flight: HashMap<u64, (String, C, bool)>,
+    // Replies to local updates waiting until a peer has the update
+    held: Vec<(C, Message)>,
+    msg_ids: Arc<MsgIds>,
+}
+
+impl<C: Crdt + PartialEq + Debug + Send + 'static> Replica<C> {
+    // Starts the rounds. They do nothing until the membership is known
+    pub fn new(config: ReplicationConfig, msg_ids: Arc<MsgIds>) -> Replica<C> {
+        let replica = Replica {
+            state: Arc::new(Mutex::new(ReplicaState {
+                config,
+                crdt: C::default(),
+                node_id: String::new(),
+                peers: vec![],
+                known: HashMap::new(),
+                synced: HashSet::new(),
+                in_flight: HashMap::new(),
+                held: vec![],
+                msg_ids,
+            })),
+        };
+
+        let worker = replica.clone();
+        tokio::spawn(async move {
+            let transport = Transport {};
+            loop {
+                tokio::time::sleep(config.interval).await;
+                for message in worker.round() {
+                    transport.handleoutput(message);
+                }
+            }
+        });
+
+        replica
+    }
+
+    pub fn set_membership(&self, node_id: &str, node_ids: &[String]) {
+        let mut state = self.state.lock().unwrap();
+        node_id.clone_into(&mut state.node_id);
+        state.peers = node_ids
+            .iter()
+            .filter(|peer| *peer != node_id)
+            .cloned()
+            .collect();
+    }
+
+    // Applies a local update. `update` is given the id of this node
+    pub fn update<R>(&self, update: impl FnOnce(&mut C, &str) -> R) -> R {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        update(&mut state.crdt, &state.node_id)
+    }
+
+    pub fn read<R>(&self, read: impl FnOnce(&C) -> R) -> R {
+        read(&self.state.lock().unwrap().crdt)
+    }
+
+    // Merges the state or delta received from a peer. Returns the part that was new to this node.
+    // A full state replaces what the peer is known to have, since it may have lost
+    // what it had when it restarted.
+    pub fn merge(&self, src: &str, crdt: C, full: bool) -> Option<C> {
+        let mut state = self.state.lock().unwrap();
+        let fresh = crdt.delta(&state.crdt);
+        state.crdt.merge(&crdt);
+        if full {
+            state.known.insert(src.to_owned(), crdt);
+        } else {
+            state.known.entry(src.to_owned()).or_default().merge(&crdt);
+        }
+        (fresh != C::default()).then_some(fresh)
+    }
+
+    // Merges a state recovered from the data directory
+    pub fn seed(&self, crdt: &C) {
+        self.state.lock().unwrap().crdt.merge(crdt);
+    }
+
+    // Holds the reply to a local update until a peer is known to have the update, for updates
+    // that would not survive a restart of this node otherwise.
+    // Returns the reply when there is no peer to wait for
+    pub fn hold(&self, update: C, reply: Message) -> Option<Message> {
+        let mut state = self.state.lock().unwrap();
+        if state.peers.is_empty() {
+            return Some(reply);
+        }
+        state.held.push((update, reply));
+        None
+    }
+
+    // The held replies whose update a peer now has, from its acknowledgements or its own rounds
+    pub fn released(&self) -> Vec<Message> {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let known = &state.known;
+        let (released, held): (Vec<_>, Vec<_>) = state.held.drain(..).partition(|(update, _)| {
+            known
+                .values()
+                .any(|known| update.delta(known) == C::default())
+        });
+        state.held = held;
+        released.into_iter().map(|(_, reply)| reply).collect()
+    }
+
+    pub fn acknowledge(&self, msg_id: u64) {
+        let mut state = self.state.lock().unwrap();
+        if let Some((peer, delta, full)) = state.in_flight.remove(&msg_id) {
+            if full {
+                state.synced.insert(peer.clone());
+            }
+            state.known.entry(peer).or_default().merge(&delta);
+        }
+    }
+
+    // The messages of one round, one per peer
+    fn round(&self) -> Vec<Message> {
+        let mut state = self.state.lock().unwrap();
+        // Deltas of earlier rounds are sent again unless acknowledged
+        state.in_flight.clear();
+
+        let empty = serde_json::to_value(C::default()).unwrap_or_default();
+        let mut messages = vec![];
+        for peer in state.peers.clone() {
+            // Peers are sent the whole state until they acknowledge one, so that they stop
+            // relying on what this node had before it restarted
+            let (payload, full) = match state.config.mode {
+                ReplicationMode::State => (state.crdt.clone(), true),
+                ReplicationMode::Delta => match state.known.get(&peer) {
+                    Some(known) if state.synced.contains(&peer) => (state.crdt.delta(known), false),
+                    _ => (state.crdt.clone(), true),
+                },
+            };
+
+            let value = match serde_json::to_value(&payload) {
+                Ok(value) => value,
+                Err(err) => {
+                    eprintln!(
+                        "failed to serialize the replicated state: \n err: {:?}",
+                        err
+                    );
+                    continue;
+                }
+            };
+            // The peer has everything already
+            if !full && value == empty {
+                continue;
+            }
+
+            let msg_id = state.msg_ids.next();
+            state
+                .in_flight
+                .insert(msg_id, (peer.clone(), payload, full));
+            messages.push(Message {
+                src: state.node_id.clone(),
+                dest: peer,
+                body: Body {
+                    typ: Event::Replicate {
+                        replicate: ReplicateEvent { state: value, full },
+                        shared: SharedEvent { msg_id },
+                    },
+                },
+            });
+        }
+        messages
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{crdt::counter::PNCounter, events::EventResponse};
+
+    fn replica(mode: ReplicationMode, node_ids: &[&str]) -> Replica<PNCounter> {
+        let config = ReplicationConfig {
+            mode,
+            // The rounds stay idle, and the tests run them by hand
+            interval: Duration::from_secs(3600),
+        };
+        let replica = Replica::new(config, Arc::new(MsgIds::new()));
+        replica.set_membership(
+            "n1",
+            &node_ids.iter().map(|id| id.to_string()).collect::<Vec<_>>(),
+        );
+        replica
+    }
+
+    fn add(replica: &Replica<PNCounter>, delta: i64, msg_id: u64) -> Option<Message> {
+        let update = replica.update(|counter, node_id| {
+            let before = counter.clone();
+            counter.add(node_id, delta);
+            counter.delta(&before)
+        });
+        let reply = Message {
+            src: "n1".to_owned(),
+            dest: "c1".to_owned(),
+            body: Body {
+                typ: Event::AddOk {
+                    event_response: EventResponse {
+                        in_reply_to: msg_id,
+                    },
+                },
+            },
+        };
+        replica.hold(update, reply)
+    }
+
+    fn in_reply_to(replies: Vec<Message>) -> Vec<u64> {
+        let mut ids = replies
+            .iter()
+            .filter_map(|reply| reply.body.in_reply_to())
+            .collect::<Vec<_>>();
+        ids.sort();
+        ids
+    }
+
+    fn msg_id(message: &Message) -> u64 {
+        match &message.body.typ {
+            Event::Replicate { shared, .. } => shared.msg_id,
+            event => panic!("not a replicate message: {:?}", event),
+        }
+    }
+
+    #[tokio::test]
+    async fn adds_are_acknowledged_once_a_peer_has_them() {
+        for mode in [ReplicationMode::State, ReplicationMode::Delta] {
+            let n1 = replica(mode, &["n1", "n2", "n3"]);
+            assert!(add(&n1, 5, 1).is_none());
+            assert!(add(&n1, -2, 2).is_none());
+            assert!(n1.released().is_empty());
+
+            let round = n1.round();
+            assert_eq!(round.len(), 2);
+            assert!(add(&n1, 1, 3).is_none());
+            n1.acknowledge(msg_id(&round[1]));
+            assert_eq!(in_reply_to(n1.released()), [1, 2]);
+
+            // A peer sending its state with the add counts too
+            let mut state = PNCounter::new();
+            state.add("n1", 5);
+            state.add("n1", -2);
+            state.add("n1", 1);
+            n1.merge("n2", state, true);
+            assert_eq!(in_reply_to(n1.released()), [3]);
+        }
+    }
+
+    #[tokio::test]
+    async fn adds_of_a_single_node_are_acknowledged_at_once() {
+        let n1 = replica(ReplicationMode::Delta, &["n1"]);
+        assert!(add(&n1, 5, 1).is_some());
+    }
+}
@@ -0,0 +1,212 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::crdt::crdt::Crdt;
+
+// Grow-only set
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(bound = "T: Ord + Serialize + DeserializeOwned")]
+pub struct GSet<T> {
+    elements: BTreeSet<T>,
+}
+
+impl<T> Default for GSet<T> {
+    fn default() -> Self {
+        GSet {
+            elements: BTreeSet::new(),
+        }
+    }
+}
+
+impl<T: Ord + Clone> GSet<T> {
+    pub fn new() -> GSet<T> {
+        GSet::default()
+    }
+
+    // Returns whether the element is new
+    pub fn insert(&mut self, element: T) -> bool {
+        self.elements.insert(element)
+    }
+
+    pub fn contains(&self, element: &T) -> bool {
+        self.elements.contains(element)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.elements.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.elements.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.elements.is_empty()
+    }
+}
+
+impl<T: Ord + Clone + Serialize + DeserializeOwned> Crdt for GSet<T> {
+    fn merge(&mut self, other: &Self) {
+        self.elements.extend(other.elements.iter().cloned());
+    }
+
+    fn delta(&self, since: &Self) -> Self {
+        GSet {
+            elements: self.elements.difference(&since.elements).cloned().collect(),
+        }
+    }
+}
+
+// Identifies a single add: the node and its count of adds so far
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Dot {
+    pub node_id: String,
+    pub counter: u64,
+}
+
+// Observed-remove set. An element is in the set while one of its adds has not been removed.
+// A remove only cancels the adds its replica has seen, so a concurrent add wins.
+// Removed adds are kept as tombstones.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(bound = "T: Ord + Serialize + DeserializeOwned")]
+pub struct ORSet<T> {
+    // Live adds
+    adds: BTreeSet<(T, Dot)>,
+    removed: BTreeSet<Dot>,
+    // Adds made by every node
+    counters: BTreeMap<String, u64>,
+}
+
+impl<T> Default for ORSet<T> {
+    fn default() -> Self {
+        ORSet {
+            adds: BTreeSet::new(),
+            removed: BTreeSet::new(),
+            counters: BTreeMap::new(),
+        }
+    }
+}
+
+impl<T: Ord + Clone> ORSet<T> {
+    pub fn new() -> ORSet<T> {
+        ORSet::default()
+    }
+
+    pub fn insert(&mut self, node_id: &str, element: T) {
+        let counter = self.counters.entry(node_id.to_owned()).or_default();
+        *counter += 1;
+        let dot = Dot {
+            node_id: node_id.to_owned(),
+            counter: *counter,
+        };
+        self.adds.insert((element, dot));
+    }
+
+    // Removes the adds of the element this replica has seen
+    pub fn remove(&mut self, element: &T) {
+        let dots = self.dots(element).cloned().collect::<Vec<_>>();
+        for dot in dots {
+            self.adds.remove(&(element.clone(), dot.clone()));
+            self.removed.insert(dot);
+        }
+    }
+
+    pub fn contains(&self, element: &T) -> bool {
+        self.dots(element).next().is_some()
+    }
+
+    // Every element in the set, in order
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        let mut last = None;
+        self.adds.iter().filter_map(move |(element, _)| {
+            if last == Some(element) {
+                return None;
+            }
+            last = Some(element);
+            Some(element)
+        })
+    }
+
+    fn dots<'a>(&'a self, element: &'a T) -> impl Iterator<Item = &'a Dot> + 'a {
+        // The smallest dot
+        let first = Dot {
+            node_id: String::new(),
+            counter: 0,
+        };
+        self.adds
+            .range((element.clone(), first)..)
+            .take_while(move |(other, _)| other == element)
+            .map(|(_, dot)| dot)
+    }
+}
+
+impl<T: Ord + Clone + Serialize + DeserializeOwned> Crdt for ORSet<T> {
+    fn merge(&mut self, other: &Self) {
+        self.removed.extend(other.removed.iter().cloned());
+        self.adds.extend(other.adds.iter().cloned());
+        let removed = &self.removed;
+        self.adds.retain(|(_, dot)| !removed.contains(dot));
+
+        for (node_id, counter) in other.counters.iter() {
+            let current = self.counters.entry(node_id.clone()).or_default();
+            *current = (*current).max(*counter);
+        }
+    }
+
+    fn delta(&self, since: &Self) -> Self {
+        ORSet {
+            adds: self.adds.difference(&since.adds).cloned().collect(),
+            removed: self.removed.difference(&since.removed).cloned().collect(),
+            counters: self
+                .counters
+                .iter()
+                .filter(|(node_id, counter)| since.counters.get(*node_id) < Some(counter))
+                .map(|(node_id, counter)| (node_id.clone(), *counter))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn concurrent_add_wins_over_remove() {
+        let mut a = ORSet::new();
+        a.insert("n1", 1);
+        let mut b = a.clone();
+
+        // n1 removes the add it has seen while n2 adds the element again
+        a.remove(&1);
+        b.insert("n2", 1);
+        assert!(!a.contains(&1));
+
+        let mut merged = a.clone();
+        merged.merge(&b);
+        assert!(merged.contains(&1));
+        b.merge(&a);
+        assert_eq!(merged, b);
+
+        // A remove after seeing both adds cancels both
+        merged.remove(&1);
+        assert!(!merged.contains(&1));
+        b.merge(&merged);
+        assert!(!b.contains(&1));
+    }
+
+    #[test]
+    fn dots_are_looked_up_by_element() {
+        let mut set = ORSet::new();
+        set.insert("n1", 2);
+        set.insert("n1", 1);
+        set.insert("n2", 2);
+        set.insert("n1", 3);
+
+        assert_eq!(set.dots(&2).count(), 2);
+        assert_eq!(set.dots(&4).count(), 0);
+        set.remove(&2);
+        assert_eq!(set.iter().copied().collect::<Vec<_>>(), [1, 3]);
+    }
+}
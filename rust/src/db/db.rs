@@ -1,33 +1,227 @@
-use std::{
-    collections::HashMap,
-    fmt::{Debug, Display},
-    hash::Hash,
+use std::{collections::HashMap, fmt, io, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+use crate::{
+    config::config::StoreKind,
+    db::store::{open_store, Entry, HashStore, Key, Store},
 };
 
-#[derive(Debug, Default)]
+// Called with every entry removed because its time to live elapsed
+pub type EvictFn<K, V> = Box<dyn FnMut(&K, &V) + Send>;
+
 pub struct DB<K, V> {
-    messages: HashMap<K, V>,
+    messages: Box<dyn Store<K, V>>,
+    // Deadline of every entry added with a time to live. Only kept in memory,
+    // so entries reloaded from a file store never expire.
+    deadlines: HashMap<K, Instant>,
+    on_evict: Option<EvictFn<K, V>>,
+    expired: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct DbMetrics {
+    pub entries: usize,
+    // Entries removed because their time to live elapsed
+    pub expired: u64,
+}
+
+impl<K: fmt::Debug, V: fmt::Debug> fmt::Debug for DB<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DB")
+            .field("messages", &self.messages)
+            .field("deadlines", &self.deadlines)
+            .field("expired", &self.expired)
+            .finish()
+    }
+}
+
+impl<K: Key, V: Entry> Default for DB<K, V> {
+    fn default() -> Self {
+        DB::new()
+    }
 }
 
-impl<K: Clone + Eq + PartialEq + Hash + Display, V: Clone> DB<K, V> {
+impl<K: Key, V: Entry> DB<K, V> {
+    // Kept in memory
     pub fn new() -> DB<K, V> {
+        DB::with_store(Box::new(HashStore::new()))
+    }
+
+    // `name` identifies the database within a file store directory
+    pub fn open(kind: &StoreKind, name: &str) -> io::Result<DB<K, V>> {
+        Ok(DB::with_store(open_store(kind, name)?))
+    }
+
+    fn with_store(messages: Box<dyn Store<K, V>>) -> DB<K, V> {
         DB {
-            messages: HashMap::new(),
+            messages,
+            deadlines: HashMap::new(),
+            on_evict: None,
+            expired: 0,
         }
     }
 
-    pub fn add_message(&mut self, id: K, message: V) {
-        self.messages.insert(id, message);
+    pub fn on_evict(&mut self, on_evict: EvictFn<K, V>) {
+        self.on_evict = Some(on_evict);
+    }
+
+    pub fn add_message(&mut self, id: K, message: V) -> io::Result<()> {
+        self.messages.put(id.clone(), message)?;
+        self.deadlines.remove(&id);
+        Ok(())
+    }
+
+    // The entry is no longer returned once `ttl` has elapsed
+    pub fn add_message_with_ttl(&mut self, id: K, message: V, ttl: Duration) -> io::Result<()> {
+        self.messages.put(id.clone(), message)?;
+        self.deadlines.insert(id, Instant::now() + ttl);
+        Ok(())
     }
 
     pub fn get_messages_as_value(&self) -> Vec<V> {
-        self.messages.values().cloned().collect::<Vec<_>>()
+        self.live()
+            .map(|(_, message)| message.clone())
+            .collect::<Vec<_>>()
+    }
+
+    pub fn get_entries(&self) -> Vec<(K, V)> {
+        self.live()
+            .map(|(id, message)| (id.clone(), message.clone()))
+            .collect::<Vec<_>>()
     }
 
-    pub fn get_message(&mut self, id: &K) -> Option<V> {
+    // An expired entry is no longer returned, even before `evict_expired` removes it
+    pub fn get_message(&self, id: &K) -> Option<V> {
+        if self.is_expired(id, Instant::now()) {
+            return None;
+        }
         self.messages.get(id).cloned()
     }
-    pub fn delete_message(&mut self, id: &K) {
-        self.messages.remove(id);
+
+    pub fn delete_message(&mut self, id: &K) -> io::Result<()> {
+        self.messages.delete(id)?;
+        self.deadlines.remove(id);
+        Ok(())
+    }
+
+    // Removes every expired entry. Returns how many were removed
+    pub fn evict_expired(&mut self) -> io::Result<usize> {
+        Ok(self.evict_expired_ids()?.len())
+    }
+
+    // Removes every expired entry. Returns their ids
+    pub fn evict_expired_ids(&mut self) -> io::Result<Vec<K>> {
+        let now = Instant::now();
+        let expired = self
+            .deadlines
+            .iter()
+            .filter(|(_, deadline)| **deadline <= now)
+            .map(|(id, _)| id.clone())
+            .collect::<Vec<_>>();
+
+        for id in expired.iter() {
+            self.evict(id)?;
+        }
+        Ok(expired)
+    }
+
+    // The earliest time at which `evict_expired` has work to do
+    pub fn next_expiry(&self) -> Option<Instant> {
+        self.deadlines.values().min().copied()
+    }
+
+    pub fn metrics(&self) -> DbMetrics {
+        DbMetrics {
+            entries: self.messages.len(),
+            expired: self.expired,
+        }
+    }
+
+    fn live(&self) -> impl Iterator<Item = (&K, &V)> {
+        let now = Instant::now();
+        self.messages
+            .iter()
+            .filter(move |(id, _)| !self.is_expired(id, now))
+    }
+
+    fn is_expired(&self, id: &K, now: Instant) -> bool {
+        self.deadlines
+            .get(id)
+            .is_some_and(|deadline| *deadline <= now)
+    }
+
+    fn evict(&mut self, id: &K) -> io::Result<()> {
+        let message = self.messages.delete(id)?;
+        self.deadlines.remove(id);
+        if let Some(message) = message {
+            self.expired += 1;
+            if let Some(on_evict) = self.on_evict.as_mut() {
+                on_evict(id, &message);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    const HOUR: Duration = Duration::from_secs(3600);
+
+    #[test]
+    fn expired_entries_are_hidden_until_evicted() {
+        let mut db = DB::<String, u64>::new();
+        let evicted = Arc::new(Mutex::new(vec![]));
+        let on_evict = evicted.clone();
+        db.on_evict(Box::new(move |id: &String, value: &u64| {
+            on_evict.lock().unwrap().push((id.clone(), *value));
+        }));
+
+        db.add_message_with_ttl("a".to_owned(), 1, Duration::ZERO)
+            .unwrap();
+        db.add_message_with_ttl("b".to_owned(), 2, HOUR).unwrap();
+        db.add_message("c".to_owned(), 3).unwrap();
+
+        assert_eq!(db.get_message(&"a".to_owned()), None);
+        assert_eq!(db.get_message(&"b".to_owned()), Some(2));
+        let mut entries = db.get_entries();
+        entries.sort();
+        assert_eq!(entries, [("b".to_owned(), 2), ("c".to_owned(), 3)]);
+        assert_eq!(db.metrics().entries, 3);
+        assert_eq!(db.metrics().expired, 0);
+
+        assert_eq!(db.evict_expired_ids().unwrap(), ["a".to_owned()]);
+        assert_eq!(*evicted.lock().unwrap(), [("a".to_owned(), 1)]);
+        assert_eq!(db.metrics().entries, 2);
+        assert_eq!(db.metrics().expired, 1);
+        assert_eq!(db.evict_expired().unwrap(), 0);
+    }
+
+    #[test]
+    fn adding_without_a_ttl_clears_the_deadline() {
+        let mut db = DB::<String, u64>::new();
+        db.add_message_with_ttl("a".to_owned(), 1, Duration::ZERO)
+            .unwrap();
+        assert!(db.next_expiry().is_some());
+
+        db.add_message("a".to_owned(), 2).unwrap();
+        assert_eq!(db.next_expiry(), None);
+        assert_eq!(db.evict_expired().unwrap(), 0);
+        assert_eq!(db.get_message(&"a".to_owned()), Some(2));
+    }
+
+    #[test]
+    fn deleted_entries_are_not_counted_as_expired() {
+        let mut db = DB::<String, u64>::new();
+        db.add_message_with_ttl("a".to_owned(), 1, HOUR).unwrap();
+        db.delete_message(&"a".to_owned()).unwrap();
+
+        assert_eq!(db.next_expiry(), None);
+        assert_eq!(db.metrics().entries, 0);
+        assert_eq!(db.metrics().expired, 0);
     }
 }
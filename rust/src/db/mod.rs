@@ -1 +1,3 @@
 pub mod db;
+pub mod mvcc;
+pub mod store;
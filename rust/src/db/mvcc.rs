@@ -0,0 +1,410 @@
+use std::{collections::BTreeMap, io, ops::Bound};
+
+use crate::{
+    config::config::StoreKind,
+    db::store::{open_store, Entry, Key, OrderedStore, Store},
+};
+
+#[derive(Debug)]
+pub enum MvccError {
+    // The versions the write would hide were already garbage collected
+    StaleTimestamp { ts: u64, watermark: u64 },
+    // The write would change what an open snapshot reads
+    SnapshotConflict { ts: u64, snapshot: u64 },
+    // The key already has a version at this timestamp. Versions are never overwritten
+    VersionExists { ts: u64 },
+    // Writing to the store failed. The write was not applied
+    Storage(io::Error),
+}
+
+impl MvccError {
+    // Maelstrom error code
+    pub fn code(&self) -> u64 {
+        match self {
+            // txn-conflict
+            MvccError::StaleTimestamp { .. }
+            | MvccError::SnapshotConflict { .. }
+            | MvccError::VersionExists { .. } => 30,
+            // crash
+            MvccError::Storage(_) => 13,
+        }
+    }
+
+    pub fn text(&self) -> String {
+        match self {
+            MvccError::StaleTimestamp { ts, watermark } => format!(
+                "write at {} is older than the collected versions at {}",
+                ts, watermark
+            ),
+            MvccError::SnapshotConflict { ts, snapshot } => format!(
+                "write at {} is not after the open snapshot at {}",
+                ts, snapshot
+            ),
+            MvccError::VersionExists { ts } => {
+                format!("the key already has a version at {}", ts)
+            }
+            MvccError::Storage(err) => format!("storage error: {}", err),
+        }
+    }
+}
+
+// Keys with every version still visible to a snapshot.
+// A read at snapshot `ts` sees the latest version written at or before `ts`.
+// Snapshots in use are registered with `open_snapshot` so that `gc` keeps what they can see.
+#[derive(Debug)]
+pub struct MvccDB<K, V> {
+    // One entry per version, by key then timestamp. None when the key was deleted.
+    // Reads scan the versions of a key, so the store should be ordered
+    versions: Box<dyn Store<(K, u64), Option<V>>>,
+    // Number of readers of every open snapshot
+    snapshots: BTreeMap<u64, usize>,
+    // Timestamp of the latest write
+    latest: u64,
+    // Versions older than this were collected. Later writes must not go below it
+    watermark: u64,
+}
+
+impl<K: Key, V: Entry> Default for MvccDB<K, V> {
+    fn default() -> Self {
+        MvccDB::new()
+    }
+}
+
+impl<K: Key, V: Entry> MvccDB<K, V> {
+    // Kept in memory
+    pub fn new() -> MvccDB<K, V> {
+        MvccDB::with_store(Box::new(OrderedStore::new()))
+    }
+
+    // `name` identifies the database within a file store directory
+    pub fn open(kind: &StoreKind, name: &str) -> io::Result<MvccDB<K, V>> {
+        Ok(MvccDB::with_store(open_store(kind, name)?))
+    }
+
+    fn with_store(versions: Box<dyn Store<(K, u64), Option<V>>>) -> MvccDB<K, V> {
+        let latest = versions
+            .iter()
+            .map(|((_, ts), _)| *ts)
+            .max()
+            .unwrap_or_default();
+        MvccDB {
+            versions,
+            snapshots: BTreeMap::new(),
+            latest,
+            watermark: 0,
+        }
+    }
+
+    // Timestamp of the latest write. A snapshot at this timestamp sees every write so far
+    pub fn latest_ts(&self) -> u64 {
+        self.latest
+    }
+
+    pub fn write(&mut self, key: K, value: V, ts: u64) -> Result<(), MvccError> {
+        self.push(key, Some(value), ts)
+    }
+
+    pub fn delete(&mut self, key: K, ts: u64) -> Result<(), MvccError> {
+        self.push(key, None, ts)
+    }
+
+    // Latest value at or before the snapshot
+    pub fn read(&self, key: &K, snapshot: u64) -> Option<&V> {
+        let start = (key.clone(), 0);
+        let end = (key.clone(), snapshot);
+        self.versions
+            .range(Bound::Included(&start), Bound::Included(&end))
+            .last()
+            .and_then(|(_, value)| value.as_ref())
+    }
+
+    // Latest value, e.g. for read committed
+    pub fn read_latest(&self, key: &K) -> Option<&V> {
+        self.read(key, u64::MAX)
+    }
+
+    // Timestamp of the latest write to the key, deletes included.
+    // A snapshot isolation transaction conflicts when it is after the transaction's snapshot.
+    pub fn last_write(&self, key: &K) -> Option<u64> {
+        let start = (key.clone(), 0);
+        let end = (key.clone(), u64::MAX);
+        self.versions
+            .range(Bound::Included(&start), Bound::Included(&end))
+            .last()
+            .map(|((_, ts), _)| *ts)
+    }
+
+    // Keys within the bounds and their values at the snapshot, ordered by key
+    pub fn scan<'a>(
+        &'a self,
+        start: Bound<&'a K>,
+        end: Bound<&'a K>,
+        snapshot: u64,
+    ) -> impl Iterator<Item = (&'a K, &'a V)> + 'a {
+        // Every version of the keys within the bounds
+        let start = match start {
+            Bound::Included(key) => Bound::Included((key.clone(), 0)),
+            Bound::Excluded(key) => Bound::Excluded((key.clone(), u64::MAX)),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let end = match end {
+            Bound::Included(key) => Bound::Included((key.clone(), u64::MAX)),
+            Bound::Excluded(key) => Bound::Excluded((key.clone(), 0)),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+
+        let mut visible: Vec<(&K, Option<&V>)> = vec![];
+        for ((key, ts), value) in self.versions.range(start.as_ref(), end.as_ref()) {
+            if *ts > snapshot {
+                continue;
+            }
+            match visible.last_mut() {
+                Some(last) if last.0 == key => last.1 = value.as_ref(),
+                _ => visible.push((key, value.as_ref())),
+            }
+        }
+        visible
+            .into_iter()
+            .filter_map(|(key, value)| Some((key, value?)))
+    }
+
+    // Registers a reader at the latest timestamp. Must be closed once the reader is done
+    pub fn open_snapshot(&mut self) -> u64 {
+        let snapshot = self.latest;
+        *self.snapshots.entry(snapshot).or_default() += 1;
+        snapshot
+    }
+
+    pub fn close_snapshot(&mut self, snapshot: u64) {
+        if let Some(readers) = self.snapshots.get_mut(&snapshot) {
+            *readers -= 1;
+            if *readers == 0 {
+                self.snapshots.remove(&snapshot);
+            }
+        }
+    }
+
+    // Removes the versions no open snapshot nor a new one can see. Returns how many were removed
+    pub fn gc(&mut self) -> io::Result<usize> {
+        let horizon = self.snapshots.keys().next().copied().unwrap_or(self.latest);
+
+        // Of the versions at or before the horizon, every reader sees the latest one at most.
+        // A deleted key reads the same without its tombstone
+        let mut collectable = vec![];
+        // Latest version so far at or before the horizon, and whether it is a tombstone
+        let mut kept: Option<(&(K, u64), bool)> = None;
+        for (version, value) in self.versions.range(Bound::Unbounded, Bound::Unbounded) {
+            if version.1 > horizon {
+                continue;
+            }
+            if let Some((previous, deleted)) = kept {
+                if previous.0 == version.0 || deleted {
+                    collectable.push(previous.clone());
+                }
+            }
+            kept = Some((version, value.is_none()));
+        }
+        if let Some((previous, true)) = kept {
+            collectable.push(previous.clone());
+        }
+
+        // Later writes must not hide what may already be collected
+        self.watermark = self.watermark.max(horizon);
+
+        // Oldest first, so a failure leaves every key readable
+        for version in collectable.iter() {
+            self.versions.delete(version)?;
+        }
+        Ok(collectable.len())
+    }
+
+    fn push(&mut self, key: K, value: Option<V>, ts: u64) -> Result<(), MvccError> {
+        if ts < self.watermark {
+            return Err(MvccError::StaleTimestamp {
+                ts,
+                watermark: self.watermark,
+            });
+        }
+        if let Some(snapshot) = self.snapshots.keys().next_back().copied() {
+            if ts <= snapshot {
+                return Err(MvccError::SnapshotConflict { ts, snapshot });
+            }
+        }
+
+        let version = (key, ts);
+        if self.versions.get(&version).is_some() {
+            return Err(MvccError::VersionExists { ts });
+        }
+        self.versions
+            .put(version, value)
+            .map_err(MvccError::Storage)?;
+
+        self.latest = self.latest.max(ts);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn db() -> MvccDB<String, u64> {
+        MvccDB::new()
+    }
+
+    fn key(key: &str) -> String {
+        key.to_owned()
+    }
+
+    fn scan(
+        db: &MvccDB<String, u64>,
+        start: Bound<&String>,
+        end: Bound<&String>,
+        snapshot: u64,
+    ) -> Vec<(String, u64)> {
+        db.scan(start, end, snapshot)
+            .map(|(key, value)| (key.clone(), *value))
+            .collect()
+    }
+
+    #[test]
+    fn reads_see_the_latest_version_at_or_before_the_snapshot() {
+        let mut db = db();
+        db.write(key("a"), 1, 1).unwrap();
+        db.write(key("a"), 2, 3).unwrap();
+        db.write(key("b"), 5, 2).unwrap();
+
+        assert_eq!(db.read(&key("a"), 0), None);
+        assert_eq!(db.read(&key("a"), 1), Some(&1));
+        assert_eq!(db.read(&key("a"), 2), Some(&1));
+        assert_eq!(db.read(&key("a"), 3), Some(&2));
+        assert_eq!(db.read_latest(&key("a")), Some(&2));
+        assert_eq!(db.last_write(&key("a")), Some(3));
+        assert_eq!(db.latest_ts(), 3);
+
+        assert_eq!(
+            scan(&db, Bound::Unbounded, Bound::Unbounded, 1),
+            [(key("a"), 1)]
+        );
+        assert_eq!(
+            scan(&db, Bound::Unbounded, Bound::Unbounded, 2),
+            [(key("a"), 1), (key("b"), 5)]
+        );
+        assert_eq!(
+            scan(
+                &db,
+                Bound::Excluded(&key("a")),
+                Bound::Included(&key("b")),
+                3
+            ),
+            [(key("b"), 5)]
+        );
+        assert_eq!(
+            scan(
+                &db,
+                Bound::Included(&key("a")),
+                Bound::Excluded(&key("b")),
+                3
+            ),
+            [(key("a"), 2)]
+        );
+    }
+
+    #[test]
+    fn tombstones_hide_the_key_from_later_snapshots() {
+        let mut db = db();
+        db.write(key("a"), 1, 1).unwrap();
+        db.delete(key("a"), 2).unwrap();
+
+        assert_eq!(db.read(&key("a"), 1), Some(&1));
+        assert_eq!(db.read(&key("a"), 2), None);
+        assert_eq!(db.read_latest(&key("a")), None);
+        assert_eq!(db.last_write(&key("a")), Some(2));
+        assert_eq!(
+            scan(&db, Bound::Unbounded, Bound::Unbounded, 1),
+            [(key("a"), 1)]
+        );
+        assert!(scan(&db, Bound::Unbounded, Bound::Unbounded, 2).is_empty());
+    }
+
+    #[test]
+    fn writes_visible_to_an_open_snapshot_are_rejected() {
+        let mut db = db();
+        db.write(key("a"), 1, 1).unwrap();
+        let snapshot = db.open_snapshot();
+        assert_eq!(snapshot, 1);
+
+        let err = db.write(key("b"), 2, 1).unwrap_err();
+        assert!(matches!(
+            err,
+            MvccError::SnapshotConflict { ts: 1, snapshot: 1 }
+        ));
+        assert_eq!(err.code(), 30);
+        assert_eq!(db.read(&key("b"), snapshot), None);
+
+        db.write(key("b"), 2, 2).unwrap();
+        assert_eq!(db.read(&key("b"), snapshot), None);
+        db.close_snapshot(snapshot);
+    }
+
+    #[test]
+    fn versions_are_never_overwritten() {
+        let mut db = db();
+        db.write(key("a"), 1, 1).unwrap();
+
+        let err = db.delete(key("a"), 1).unwrap_err();
+        assert!(matches!(err, MvccError::VersionExists { ts: 1 }));
+        assert_eq!(db.read_latest(&key("a")), Some(&1));
+    }
+
+    #[test]
+    fn gc_keeps_what_open_snapshots_see() {
+        let mut db = db();
+        db.write(key("a"), 1, 1).unwrap();
+        db.write(key("a"), 2, 2).unwrap();
+        let snapshot = db.open_snapshot();
+        db.write(key("a"), 3, 3).unwrap();
+        db.write(key("a"), 4, 4).unwrap();
+
+        // Only the version before the snapshot's is hidden from it
+        assert_eq!(db.gc().unwrap(), 1);
+        assert_eq!(db.read(&key("a"), snapshot), Some(&2));
+        assert_eq!(db.read(&key("a"), 1), None);
+
+        db.close_snapshot(snapshot);
+        assert_eq!(db.gc().unwrap(), 2);
+        assert_eq!(db.read_latest(&key("a")), Some(&4));
+        assert_eq!(db.gc().unwrap(), 0);
+    }
+
+    #[test]
+    fn gc_removes_keys_deleted_before_the_horizon() {
+        let mut db = db();
+        db.write(key("a"), 1, 1).unwrap();
+        db.delete(key("a"), 2).unwrap();
+        db.write(key("b"), 1, 3).unwrap();
+
+        assert_eq!(db.gc().unwrap(), 2);
+        assert_eq!(db.last_write(&key("a")), None);
+        assert_eq!(db.read_latest(&key("b")), Some(&1));
+    }
+
+    #[test]
+    fn writes_below_the_watermark_are_rejected() {
+        let mut db = db();
+        db.write(key("a"), 1, 1).unwrap();
+        db.write(key("a"), 2, 5).unwrap();
+        db.gc().unwrap();
+
+        let err = db.write(key("b"), 1, 3).unwrap_err();
+        assert!(matches!(
+            err,
+            MvccError::StaleTimestamp {
+                ts: 3,
+                watermark: 5
+            }
+        ));
+        assert_eq!(db.read_latest(&key("b")), None);
+        db.write(key("b"), 1, 6).unwrap();
+    }
+}
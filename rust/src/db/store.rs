@@ -0,0 +1,477 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Debug,
+    fs::{self, File, OpenOptions},
+    hash::Hash,
+    io::{self, BufRead, BufReader, Write},
+    ops::{Bound, RangeBounds},
+    path::PathBuf,
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::config::config::{FsyncPolicy, StoreKind};
+
+// Requirements on the keys and values of every store.
+// Keys and values are serialized by the file store.
+pub trait Key: Clone + Ord + Hash + Debug + Send + Serialize + DeserializeOwned + 'static {}
+impl<T: Clone + Ord + Hash + Debug + Send + Serialize + DeserializeOwned + 'static> Key for T {}
+
+pub trait Entry: Clone + Debug + Send + Serialize + DeserializeOwned + 'static {}
+impl<T: Clone + Debug + Send + Serialize + DeserializeOwned + 'static> Entry for T {}
+
+pub type Entries<'a, K, V> = Box<dyn Iterator<Item = (&'a K, &'a V)> + 'a>;
+
+// Key value storage behind `DB` and the kafka log.
+// A failed put or delete leaves the store unchanged.
+pub trait Store<K, V>: Debug + Send {
+    fn get(&self, key: &K) -> Option<&V>;
+    fn put(&mut self, key: K, value: V) -> io::Result<()>;
+    fn delete(&mut self, key: &K) -> io::Result<Option<V>>;
+    // Entries within the bounds, ordered by key
+    fn range(&self, start: Bound<&K>, end: Bound<&K>) -> Entries<'_, K, V>;
+    // Every entry, in no particular order
+    fn iter(&self) -> Entries<'_, K, V>;
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Makes every change so far durable, whatever the sync policy
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Opens the store of the given kind. `name` identifies the file of a file store.
+pub fn open_store<K: Key, V: Entry>(
+    kind: &StoreKind,
+    name: &str,
+) -> io::Result<Box<dyn Store<K, V>>> {
+    Ok(match kind {
+        StoreKind::Memory => Box::new(HashStore::new()),
+        StoreKind::Ordered => Box::new(OrderedStore::new()),
+        StoreKind::File(dir, fsync) => Box::new(FileStore::open(
+            dir.join(format!("{}.store", name)),
+            *fsync,
+        )?),
+    })
+}
+
+// In memory. Range scans sort the matching entries, so every scan costs O(n log n).
+// Not meant for the kafka log, which scans on every poll.
+#[derive(Debug)]
+pub struct HashStore<K, V> {
+    entries: HashMap<K, V>,
+}
+
+impl<K, V> HashStore<K, V> {
+    pub fn new() -> HashStore<K, V> {
+        HashStore {
+            entries: HashMap::new(),
+        }
+    }
+}
+
+impl<K, V> Default for HashStore<K, V> {
+    fn default() -> Self {
+        HashStore::new()
+    }
+}
+
+impl<K: Key, V: Entry> Store<K, V> for HashStore<K, V> {
+    fn get(&self, key: &K) -> Option<&V> {
+        self.entries.get(key)
+    }
+
+    fn put(&mut self, key: K, value: V) -> io::Result<()> {
+        self.entries.insert(key, value);
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &K) -> io::Result<Option<V>> {
+        Ok(self.entries.remove(key))
+    }
+
+    fn range(&self, start: Bound<&K>, end: Bound<&K>) -> Entries<'_, K, V> {
+        let mut entries = self
+            .entries
+            .iter()
+            .filter(|(key, _)| (start, end).contains(*key))
+            .collect::<Vec<_>>();
+        entries.sort_by(|a, b| a.0.cmp(b.0));
+        Box::new(entries.into_iter())
+    }
+
+    fn iter(&self) -> Entries<'_, K, V> {
+        Box::new(self.entries.iter())
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+// In memory, ordered by key
+#[derive(Debug)]
+pub struct OrderedStore<K, V> {
+    entries: BTreeMap<K, V>,
+}
+
+impl<K, V> OrderedStore<K, V> {
+    pub fn new() -> OrderedStore<K, V> {
+        OrderedStore {
+            entries: BTreeMap::new(),
+        }
+    }
+}
+
+impl<K, V> Default for OrderedStore<K, V> {
+    fn default() -> Self {
+        OrderedStore::new()
+    }
+}
+
+impl<K: Key, V: Entry> Store<K, V> for OrderedStore<K, V> {
+    fn get(&self, key: &K) -> Option<&V> {
+        self.entries.get(key)
+    }
+
+    fn put(&mut self, key: K, value: V) -> io::Result<()> {
+        self.entries.insert(key, value);
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &K) -> io::Result<Option<V>> {
+        Ok(self.entries.remove(key))
+    }
+
+    fn range(&self, start: Bound<&K>, end: Bound<&K>) -> Entries<'_, K, V> {
+        Box::new(self.entries.range((start, end)))
+    }
+
+    fn iter(&self) -> Entries<'_, K, V> {
+        Box::new(self.entries.iter())
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+// Survives restarts. Every change is appended to a file as a JSON line and replayed on open.
+// Entries are also kept in memory, ordered by key.
+// The file is rewritten once it holds many more changes than entries.
+// A change is synced according to the policy before the put or delete returns. When writing or
+// syncing fails, the file is cut back to the previous change and the entries are left unchanged.
+#[derive(Debug)]
+pub struct FileStore<K, V> {
+    entries: BTreeMap<K, V>,
+    path: PathBuf,
+    file: File,
+    fsync: FsyncPolicy,
+    // Bytes of complete changes in the file
+    len: u64,
+    // Changes in the file
+    changes: usize,
+    // Changes written since the last sync
+    unsynced: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Change<K, V> {
+    Put { key: K, value: V },
+    Delete { key: K },
+}
+
+impl<K: Key, V: Entry> FileStore<K, V> {
+    pub fn open(path: PathBuf, fsync: FsyncPolicy) -> io::Result<FileStore<K, V>> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let mut entries = BTreeMap::new();
+        let mut changes = 0;
+        let mut valid_bytes = 0;
+
+        if path.exists() {
+            let reader = BufReader::new(File::open(&path)?);
+            for line in reader.split(b'\n') {
+                let line = line?;
+                // A torn last line is dropped
+                let change = match serde_json::from_slice::<Change<K, V>>(&line) {
+                    Ok(change) => change,
+                    Err(_) => break,
+                };
+                match change {
+                    Change::Put { key, value } => entries.insert(key, value),
+                    Change::Delete { key } => entries.remove(&key),
+                };
+                changes += 1;
+                valid_bytes += line.len() as u64 + 1;
+            }
+        }
+
+        let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+        if valid_bytes > file.metadata()?.len() {
+            // The last change is complete but misses its newline
+            file.write_all(b"\n")?;
+        } else {
+            file.set_len(valid_bytes)?;
+        }
+        if fsync != FsyncPolicy::Never {
+            file.sync_data()?;
+        }
+
+        Ok(FileStore {
+            entries,
+            path,
+            file,
+            fsync,
+            len: valid_bytes,
+            changes,
+            unsynced: 0,
+        })
+    }
+
+    // Called before the entries are changed
+    fn write(&mut self, change: Change<&K, &V>) -> io::Result<()> {
+        if self.changes > 2 * self.entries.len() + 64 {
+            self.compact()?;
+        }
+
+        let mut line = serde_json::to_vec(&change).map_err(io::Error::other)?;
+        line.push(b'\n');
+        if let Err(err) = self.append(&line) {
+            // A torn or unsynced change must not be replayed on open
+            let _ = self.file.set_len(self.len);
+            return Err(err);
+        }
+        self.len += line.len() as u64;
+        self.changes += 1;
+        Ok(())
+    }
+
+    fn append(&mut self, line: &[u8]) -> io::Result<()> {
+        self.file.write_all(line)?;
+        self.unsynced += 1;
+
+        let sync = match self.fsync {
+            FsyncPolicy::Always => true,
+            FsyncPolicy::Every(n) => self.unsynced >= n,
+            FsyncPolicy::Never => false,
+        };
+        if sync {
+            self.file.sync_data()?;
+            self.unsynced = 0;
+        }
+        Ok(())
+    }
+
+    // Rewrites the file with one change per entry
+    fn compact(&mut self) -> io::Result<()> {
+        let tmp = self.path.with_extension("tmp");
+
+        let mut file = File::create(&tmp)?;
+        let mut len = 0;
+        for (key, value) in self.entries.iter() {
+            let mut line =
+                serde_json::to_vec(&Change::Put { key, value }).map_err(io::Error::other)?;
+            line.push(b'\n');
+            file.write_all(&line)?;
+            len += line.len() as u64;
+        }
+        file.sync_data()?;
+        // Opened before the rename, so the store never appends to the replaced file
+        let file = OpenOptions::new().append(true).open(&tmp)?;
+        fs::rename(&tmp, &self.path)?;
+
+        self.file = file;
+        self.len = len;
+        self.changes = self.entries.len();
+        self.unsynced = 0;
+
+        if self.fsync != FsyncPolicy::Never {
+            if let Some(dir) = self.path.parent() {
+                File::open(dir)?.sync_all()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<K: Key, V: Entry> Store<K, V> for FileStore<K, V> {
+    fn get(&self, key: &K) -> Option<&V> {
+        self.entries.get(key)
+    }
+
+    fn put(&mut self, key: K, value: V) -> io::Result<()> {
+        self.write(Change::Put {
+            key: &key,
+            value: &value,
+        })?;
+        self.entries.insert(key, value);
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &K) -> io::Result<Option<V>> {
+        if !self.entries.contains_key(key) {
+            return Ok(None);
+        }
+        self.write(Change::Delete { key })?;
+        Ok(self.entries.remove(key))
+    }
+
+    fn range(&self, start: Bound<&K>, end: Bound<&K>) -> Entries<'_, K, V> {
+        Box::new(self.entries.range((start, end)))
+    }
+
+    fn iter(&self) -> Entries<'_, K, V> {
+        Box::new(self.entries.iter())
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        if self.unsynced > 0 {
+            self.file.sync_data()?;
+            self.unsynced = 0;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    fn path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("store-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir.join("test.store")
+    }
+
+    fn open(path: &Path) -> FileStore<String, u64> {
+        FileStore::open(path.to_path_buf(), FsyncPolicy::Always).unwrap()
+    }
+
+    fn entries(store: &FileStore<String, u64>) -> Vec<(String, u64)> {
+        store
+            .iter()
+            .map(|(key, value)| (key.clone(), *value))
+            .collect()
+    }
+
+    fn lines(path: &Path) -> usize {
+        fs::read(path)
+            .unwrap()
+            .iter()
+            .filter(|byte| **byte == b'\n')
+            .count()
+    }
+
+    #[test]
+    fn changes_survive_reopening() {
+        let path = path("reopen");
+        let mut store = open(&path);
+        store.put("a".to_owned(), 1).unwrap();
+        store.put("b".to_owned(), 2).unwrap();
+        store.put("c".to_owned(), 3).unwrap();
+        store.put("a".to_owned(), 4).unwrap();
+        assert_eq!(store.delete(&"b".to_owned()).unwrap(), Some(2));
+        assert_eq!(store.delete(&"b".to_owned()).unwrap(), None);
+        drop(store);
+
+        let store = open(&path);
+        assert_eq!(
+            entries(&store),
+            vec![("a".to_owned(), 4), ("c".to_owned(), 3)]
+        );
+        let range = store
+            .range(Bound::Excluded(&"a".to_owned()), Bound::Unbounded)
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(range, vec!["c".to_owned()]);
+    }
+
+    #[test]
+    fn file_is_compacted_once_it_holds_many_more_changes_than_entries() {
+        let path = path("compact");
+        let mut store = open(&path);
+        for value in 0..200 {
+            store.put(format!("k{}", value % 2), value).unwrap();
+        }
+        assert!(lines(&path) < 100);
+        assert!(!path.with_extension("tmp").exists());
+        drop(store);
+
+        let store = open(&path);
+        assert_eq!(
+            entries(&store),
+            vec![("k0".to_owned(), 198), ("k1".to_owned(), 199)]
+        );
+    }
+
+    #[test]
+    fn torn_last_change_is_dropped() {
+        let path = path("torn");
+        let mut store = open(&path);
+        store.put("a".to_owned(), 1).unwrap();
+        store.put("b".to_owned(), 2).unwrap();
+        drop(store);
+
+        // A crash in the middle of writing the last change
+        let len = fs::metadata(&path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 5)
+            .unwrap();
+
+        let mut store = open(&path);
+        assert_eq!(entries(&store), vec![("a".to_owned(), 1)]);
+        // Later changes are not appended to the torn one
+        store.put("c".to_owned(), 3).unwrap();
+        drop(store);
+
+        let store = open(&path);
+        assert_eq!(
+            entries(&store),
+            vec![("a".to_owned(), 1), ("c".to_owned(), 3)]
+        );
+    }
+
+    #[test]
+    fn last_change_without_its_newline_is_kept() {
+        let path = path("newline");
+        let mut store = open(&path);
+        store.put("a".to_owned(), 1).unwrap();
+        drop(store);
+
+        let len = fs::metadata(&path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 1)
+            .unwrap();
+
+        let mut store = open(&path);
+        assert_eq!(entries(&store), vec![("a".to_owned(), 1)]);
+        store.put("b".to_owned(), 2).unwrap();
+        drop(store);
+
+        let store = open(&path);
+        assert_eq!(
+            entries(&store),
+            vec![("a".to_owned(), 1), ("b".to_owned(), 2)]
+        );
+    }
+}
@@ -0,0 +1,230 @@
+use std::{collections::HashSet, fmt::Debug, io, sync::Arc};
+
+use rand::Rng;
+use serde_json::Value;
+use tokio::time::{Duration, Instant};
+
+use crate::{
+    config::config::StoreKind,
+    db::store::{open_store, Store},
+};
+
+// Key of the term and the vote in the meta store. Written together, so a vote never moves to another term
+const VOTE: &str = "vote";
+
+// Source of the time for deadlines. Tests move it by hand
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+#[derive(Debug)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+// The term, the vote and the role of a node taking part in elections, for Raft and `Election`.
+// A node votes once per term. The term and the vote are stored before they are acted on,
+// so that holds across restarts too.
+#[derive(Debug)]
+pub struct Ballot {
+    node_id: String,
+    // Every other node of the cluster
+    peers: Vec<String>,
+    role: Role,
+    term: u64,
+    voted_for: Option<String>,
+    // Votes of the current election. Candidate only
+    votes: HashSet<String>,
+    election_timeout: Duration,
+    // Not set before the membership is known, nor while leading
+    election_deadline: Option<Instant>,
+    meta: Box<dyn Store<String, Value>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Ballot {
+    // `name` identifies the meta store within a file store directory.
+    // A follower starts an election after hearing nothing for between `election_timeout`
+    // and twice that
+    pub fn open(kind: &StoreKind, name: &str, election_timeout: Duration) -> io::Result<Ballot> {
+        Ballot::with_clock(kind, name, election_timeout, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(
+        kind: &StoreKind,
+        name: &str,
+        election_timeout: Duration,
+        clock: Arc<dyn Clock>,
+    ) -> io::Result<Ballot> {
+        let meta: Box<dyn Store<String, Value>> = open_store(kind, name)?;
+        let vote = meta.get(&VOTE.to_string());
+        let term = vote
+            .and_then(|vote| vote["term"].as_u64())
+            .unwrap_or_default();
+        let voted_for = vote
+            .and_then(|vote| vote["voted_for"].as_str())
+            .map(str::to_owned);
+
+        Ok(Ballot {
+            node_id: String::new(),
+            peers: vec![],
+            role: Role::Follower,
+            term,
+            voted_for,
+            votes: HashSet::new(),
+            election_timeout,
+            election_deadline: None,
+            meta,
+            clock,
+        })
+    }
+
+    pub fn set_membership(&mut self, node_id: &str, node_ids: &[String]) {
+        node_id.clone_into(&mut self.node_id);
+        self.peers = node_ids
+            .iter()
+            .filter(|id| **id != node_id)
+            .cloned()
+            .collect();
+        self.reset_election_deadline();
+    }
+
+    pub fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    pub fn peers(&self) -> &[String] {
+        &self.peers
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    pub fn term(&self) -> u64 {
+        self.term
+    }
+
+    pub fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    pub fn election_deadline(&self) -> Option<Instant> {
+        self.election_deadline
+    }
+
+    // Majority of the whole cluster, this node included
+    pub fn quorum(&self) -> usize {
+        let cluster = self.peers.len() + 1;
+        cluster / 2 + 1
+    }
+
+    pub fn reset_election_deadline(&mut self) {
+        let timeout = self.election_timeout;
+        let jitter = rand::thread_rng().gen_range(0..=timeout.as_millis() as u64);
+        self.election_deadline = Some(self.clock.now() + timeout + Duration::from_millis(jitter));
+    }
+
+    // Votes for the candidate unless this node voted for another one in the term.
+    // `eligible` holds the caller's own conditions, e.g. an up-to-date log.
+    // A term above the current one must have been stepped down to first.
+    pub fn grant_vote(&mut self, candidate: &str, term: u64, eligible: bool) -> bool {
+        let grant = term == self.term
+            && eligible
+            && self
+                .voted_for
+                .as_deref()
+                .is_none_or(|voted| voted == candidate);
+        if !grant {
+            return false;
+        }
+
+        // A vote that is not durable could be given again after a restart
+        let stored = self.persist(self.term, Some(candidate));
+        if stored.is_ok() {
+            // Even when the sync fails, the vote may have reached the disk
+            self.voted_for = Some(candidate.to_owned());
+        }
+        match stored.and_then(|()| self.sync()) {
+            Ok(()) => {
+                self.reset_election_deadline();
+                true
+            }
+            Err(err) => {
+                eprintln!("failed to store a vote: \n err: {:?}", err);
+                false
+            }
+        }
+    }
+
+    // Moves to the next term and votes for this node. Fails when the vote could not be stored,
+    // and the election is tried again at the next deadline
+    pub fn start_election(&mut self) -> io::Result<()> {
+        let node_id = self.node_id.clone();
+        self.reset_election_deadline();
+        self.persist(self.term + 1, Some(&node_id))
+            .and_then(|()| self.sync())?;
+
+        self.term += 1;
+        self.role = Role::Candidate;
+        self.voted_for = Some(node_id.clone());
+        self.votes = HashSet::from([node_id]);
+        Ok(())
+    }
+
+    // Counts the vote of a peer. Returns whether this node now has a majority
+    pub fn add_vote(&mut self, voter: &str, term: u64, granted: bool) -> bool {
+        if self.role != Role::Candidate || term != self.term || !granted {
+            return false;
+        }
+        self.votes.insert(voter.to_owned());
+        self.has_majority()
+    }
+
+    // A single node cluster needs no other vote
+    pub fn has_majority(&self) -> bool {
+        self.role == Role::Candidate && self.votes.len() >= self.quorum()
+    }
+
+    pub fn become_leader(&mut self) {
+        self.role = Role::Leader;
+        self.election_deadline = None;
+    }
+
+    // Follows the leader of `term`, which is at least the current term
+    pub fn step_down(&mut self, term: u64) {
+        if term > self.term {
+            // Until the new term is stored, a restart goes back to the old term and vote
+            if let Err(err) = self.persist(term, None) {
+                eprintln!("failed to store a term: \n err: {:?}", err);
+            }
+            self.term = term;
+            self.voted_for = None;
+        }
+        self.role = Role::Follower;
+        self.reset_election_deadline();
+    }
+
+    // Makes the term and the vote durable, whatever the sync policy of the store
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.meta.sync()
+    }
+
+    fn persist(&mut self, term: u64, voted_for: Option<&str>) -> io::Result<()> {
+        self.meta.put(
+            VOTE.to_string(),
+            serde_json::json!({ "term": term, "voted_for": voted_for }),
+        )
+    }
+}
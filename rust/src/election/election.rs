@@ -0,0 +1,404 @@
+use std::{
+    collections::HashMap,
+    io,
+    sync::{Arc, Mutex},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{sync::watch, time::Instant};
+
+use crate::{
+    config::config::ElectionConfig,
+    election::ballot::{Ballot, Clock, Role, SystemClock},
+    events::{
+        Body, CampaignEvent, CampaignOkEvent, Event, HeartbeatEvent, HeartbeatOkEvent, Message,
+        SharedEvent,
+    },
+    transport::{MsgIds, Transport},
+};
+
+// The leader as last known to this node
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Leadership {
+    pub term: u64,
+    // None while an election is running
+    pub leader: Option<String>,
+}
+
+// Elects one node of the cluster as the leader, for whatever needs one.
+// A node votes once per term, and the node with the votes of a majority leads for the term.
+// The term and the vote survive restarts when the config has a file store.
+// The leader sends heartbeats, and steps down when it stops hearing from a majority, so a
+// partitioned leader does not keep believing it leads.
+#[derive(Debug, Clone)]
+pub struct Election {
+    state: Arc<Mutex<ElectionState>>,
+    leadership: watch::Receiver<Leadership>,
+}
+
+#[derive(Debug)]
+struct ElectionState {
+    config: ElectionConfig,
+    ballot: Ballot,
+    // When every peer last acknowledged a heartbeat of the current term. Leader only
+    heard: HashMap<String, Instant>,
+    leadership: watch::Sender<Leadership>,
+    msg_ids: Arc<MsgIds>,
+}
+
+impl Election {
+    // Starts the timer. It does nothing until the membership is known
+    pub fn new(config: ElectionConfig, msg_ids: Arc<MsgIds>) -> io::Result<Election> {
+        Election::with_clock(config, msg_ids, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(
+        config: ElectionConfig,
+        msg_ids: Arc<MsgIds>,
+        clock: Arc<dyn Clock>,
+    ) -> io::Result<Election> {
+        let ballot = Ballot::with_clock(
+            &config.store,
+            "election-meta",
+            config.election_timeout,
+            clock,
+        )?;
+        let (sender, leadership) = watch::channel(Leadership {
+            term: ballot.term(),
+            leader: None,
+        });
+        let election = Election {
+            state: Arc::new(Mutex::new(ElectionState {
+                config,
+                ballot,
+                heard: HashMap::new(),
+                leadership: sender,
+                msg_ids,
+            })),
+            leadership,
+        };
+
+        tokio::spawn(handle_electionworker(election.clone()));
+
+        Ok(election)
+    }
+
+    pub fn set_membership(&self, node_id: &str, node_ids: &[String]) {
+        let mut state = self.state.lock().unwrap();
+        state.ballot.set_membership(node_id, node_ids);
+    }
+
+    pub fn leadership(&self) -> Leadership {
+        self.leadership.borrow().clone()
+    }
+
+    pub fn leader(&self) -> Option<String> {
+        self.leadership.borrow().leader.clone()
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.state.lock().unwrap().ballot.role() == Role::Leader
+    }
+
+    // Notified on every change of term or leader
+    pub fn subscribe(&self) -> watch::Receiver<Leadership> {
+        self.leadership.clone()
+    }
+
+    pub fn handle_campaign(&self, src: &str, data: CampaignEvent) -> CampaignOkEvent {
+        let mut state = self.state.lock().unwrap();
+        if data.term > state.ballot.term() {
+            state.step_down(data.term, None);
+        }
+
+        let vote_granted = state.ballot.grant_vote(src, data.term, true);
+        CampaignOkEvent {
+            term: state.ballot.term(),
+            vote_granted,
+        }
+    }
+
+    pub fn handle_campaign_ok(&self, src: &str, data: CampaignOkEvent) {
+        let mut state = self.state.lock().unwrap();
+        if data.term > state.ballot.term() {
+            state.step_down(data.term, None);
+            return;
+        }
+        if state.ballot.add_vote(src, data.term, data.vote_granted) {
+            state.become_leader();
+        }
+    }
+
+    pub fn handle_heartbeat(&self, src: &str, data: HeartbeatEvent) -> HeartbeatOkEvent {
+        let mut state = self.state.lock().unwrap();
+        // Heartbeats of an older term tell their sender to step down
+        if data.term >= state.ballot.term() {
+            state.step_down(data.term, Some(src.to_owned()));
+        }
+
+        HeartbeatOkEvent {
+            term: state.ballot.term(),
+        }
+    }
+
+    pub fn handle_heartbeat_ok(&self, src: &str, data: HeartbeatOkEvent) {
+        let mut state = self.state.lock().unwrap();
+        if data.term > state.ballot.term() {
+            state.step_down(data.term, None);
+            return;
+        }
+        if state.ballot.role() == Role::Leader && data.term == state.ballot.term() {
+            let now = state.ballot.now();
+            state.heard.insert(src.to_owned(), now);
+        }
+    }
+
+    // The messages due now: heartbeats from the leader, votes requested by a new candidate
+    fn tick(&self) -> Vec<Message> {
+        let mut state = self.state.lock().unwrap();
+        let now = state.ballot.now();
+
+        match state.ballot.role() {
+            Role::Leader => {
+                let timeout = state.config.election_timeout;
+                let heard = state
+                    .heard
+                    .values()
+                    .filter(|at| now.duration_since(**at) < timeout)
+                    .count();
+                if heard + 1 < state.ballot.quorum() {
+                    let term = state.ballot.term();
+                    state.step_down(term, None);
+                    return vec![];
+                }
+                let heartbeat = HeartbeatEvent {
+                    term: state.ballot.term(),
+                };
+                state.to_peers(|shared| Event::Heartbeat {
+                    heartbeat: heartbeat.clone(),
+                    shared,
+                })
+            }
+            Role::Follower | Role::Candidate
+                if state.ballot.election_deadline().is_some_and(|at| at <= now) =>
+            {
+                state.start_election()
+            }
+            _ => vec![],
+        }
+    }
+}
+
+impl ElectionState {
+    fn start_election(&mut self) -> Vec<Message> {
+        if let Err(err) = self.ballot.start_election() {
+            eprintln!("failed to store a vote: \n err: {:?}", err);
+            return vec![];
+        }
+        self.publish(None);
+
+        if self.ballot.has_majority() {
+            self.become_leader();
+            return vec![];
+        }
+
+        let campaign = CampaignEvent {
+            term: self.ballot.term(),
+        };
+        self.to_peers(|shared| Event::Campaign {
+            campaign: campaign.clone(),
+            shared,
+        })
+    }
+
+    fn become_leader(&mut self) {
+        self.ballot.become_leader();
+        // Every peer gets a full election timeout to acknowledge the first heartbeats
+        let now = self.ballot.now();
+        self.heard = self
+            .ballot
+            .peers()
+            .iter()
+            .map(|peer| (peer.clone(), now))
+            .collect();
+        self.publish(Some(self.ballot.node_id().to_owned()));
+    }
+
+    // Follows `leader`, or waits for the next one when it is not known yet
+    fn step_down(&mut self, term: u64, leader: Option<String>) {
+        self.ballot.step_down(term);
+        self.publish(leader);
+    }
+
+    fn publish(&self, leader: Option<String>) {
+        let leadership = Leadership {
+            term: self.ballot.term(),
+            leader,
+        };
+        self.leadership.send_if_modified(|current| {
+            let changed = *current != leadership;
+            *current = leadership;
+            changed
+        });
+    }
+
+    // One message per peer, each with its own msg_id
+    fn to_peers(&self, event: impl Fn(SharedEvent) -> Event) -> Vec<Message> {
+        self.ballot
+            .peers()
+            .iter()
+            .map(|peer| Message {
+                src: self.ballot.node_id().to_owned(),
+                dest: peer.clone(),
+                body: Body {
+                    typ: event(SharedEvent {
+                        msg_id: self.msg_ids.next(),
+                    }),
+                },
+            })
+            .collect()
+    }
+}
+
+async fn handle_electionworker(election: Election) {
+    let transport = Transport {};
+    loop {
+        let interval = election.state.lock().unwrap().config.heartbeat_interval;
+        tokio::time::sleep(interval).await;
+
+        for message in election.tick() {
+            transport.handleoutput(message);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::config::{FsyncPolicy, StoreKind};
+    use tokio::time::Duration;
+
+    const TIMEOUT: Duration = Duration::from_millis(500);
+
+    #[derive(Debug)]
+    struct FakeClock(Mutex<Instant>);
+
+    impl FakeClock {
+        fn new() -> Arc<FakeClock> {
+            Arc::new(FakeClock(Mutex::new(Instant::now())))
+        }
+
+        fn advance(&self, by: Duration) {
+            *self.0.lock().unwrap() += by;
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> Instant {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    fn election(node_id: &str, store: StoreKind, clock: Arc<FakeClock>) -> Election {
+        let config = ElectionConfig {
+            election_timeout: TIMEOUT,
+            // The timer stays idle, and the tests tick by hand
+            heartbeat_interval: Duration::from_secs(3600),
+            store,
+        };
+        let election = Election::with_clock(config, Arc::new(MsgIds::new()), clock).unwrap();
+        election.set_membership(node_id, &["n1", "n2", "n3"].map(str::to_owned));
+        election
+    }
+
+    fn campaign(term: u64) -> CampaignEvent {
+        CampaignEvent { term }
+    }
+
+    #[tokio::test]
+    async fn votes_once_per_term_across_restarts() {
+        let dir = std::env::temp_dir().join(format!("election-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let store = StoreKind::File(dir, FsyncPolicy::Always);
+
+        let n2 = election("n2", store.clone(), FakeClock::new());
+        assert!(n2.handle_campaign("n1", campaign(1)).vote_granted);
+        assert!(!n2.handle_campaign("n3", campaign(1)).vote_granted);
+        // A retried request
+        assert!(n2.handle_campaign("n1", campaign(1)).vote_granted);
+
+        let restarted = election("n2", store, FakeClock::new());
+        assert_eq!(restarted.leadership().term, 1);
+        assert!(!restarted.handle_campaign("n3", campaign(1)).vote_granted);
+
+        let stale = restarted.handle_campaign("n1", campaign(0));
+        assert!(!stale.vote_granted);
+        assert_eq!(stale.term, 1);
+        assert!(restarted.handle_campaign("n3", campaign(2)).vote_granted);
+    }
+
+    #[tokio::test]
+    async fn campaigns_only_after_the_election_timeout() {
+        let clock = FakeClock::new();
+        let n1 = election("n1", StoreKind::Memory, clock.clone());
+        assert!(n1.tick().is_empty());
+
+        // The deadline is between one and two timeouts away
+        clock.advance(TIMEOUT - Duration::from_millis(1));
+        assert!(n1.tick().is_empty());
+        clock.advance(TIMEOUT + Duration::from_millis(1));
+        assert_eq!(n1.tick().len(), 2);
+        assert_eq!(
+            n1.leadership(),
+            Leadership {
+                term: 1,
+                leader: None
+            }
+        );
+
+        // A heartbeat from the leader of the term holds the next election back
+        n1.handle_heartbeat("n2", HeartbeatEvent { term: 1 });
+        assert_eq!(n1.leader().as_deref(), Some("n2"));
+        clock.advance(TIMEOUT - Duration::from_millis(1));
+        assert!(n1.tick().is_empty());
+    }
+
+    #[tokio::test]
+    async fn leader_steps_down_without_a_majority() {
+        let clock = FakeClock::new();
+        let n1 = election("n1", StoreKind::Memory, clock.clone());
+        let mut leadership = n1.subscribe();
+        // Past the election deadline
+        clock.advance(TIMEOUT * 2);
+        assert_eq!(n1.tick().len(), 2);
+        let granted = CampaignOkEvent {
+            term: 1,
+            vote_granted: true,
+        };
+        n1.handle_campaign_ok("n2", granted);
+        assert!(n1.is_leader());
+        assert_eq!(n1.leader().as_deref(), Some("n1"));
+        assert!(leadership.has_changed().unwrap());
+        assert_eq!(leadership.borrow_and_update().leader.as_deref(), Some("n1"));
+
+        // n2 still answers, which makes a majority with the leader
+        clock.advance(TIMEOUT - Duration::from_millis(100));
+        n1.handle_heartbeat_ok("n2", HeartbeatOkEvent { term: 1 });
+        clock.advance(Duration::from_millis(200));
+        assert_eq!(n1.tick().len(), 2);
+        assert!(n1.is_leader());
+
+        // Nobody answered for an election timeout
+        clock.advance(TIMEOUT);
+        assert!(n1.tick().is_empty());
+        assert!(!n1.is_leader());
+        assert!(leadership.has_changed().unwrap());
+        assert_eq!(
+            *leadership.borrow_and_update(),
+            Leadership {
+                term: 1,
+                leader: None
+            }
+        );
+    }
+}
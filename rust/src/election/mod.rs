@@ -0,0 +1,2 @@
+pub mod ballot;
+pub mod election;
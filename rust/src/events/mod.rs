@@ -2,6 +2,11 @@ use std::collections::HashMap;
 
 use serde::{Deserialize, Serialize};
 
+use crate::{
+    db::db::DbMetrics, election::election::Leadership, raft::raft::LogEntry,
+    transport::MsgIdMetrics, uid::unique_id::IdMetrics,
+};
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Message {
     pub src: String,
@@ -15,6 +20,84 @@ pub struct Body {
     pub typ: Event,
 }
 
+impl Body {
+    // Replies carry the msg_id of the request they answer
+    pub fn in_reply_to(&self) -> Option<u64> {
+        self.event_response()
+            .map(|event_response| event_response.in_reply_to)
+    }
+
+    pub fn event_response(&self) -> Option<&EventResponse> {
+        match &self.typ {
+            Event::InitOk { event_response, .. }
+            | Event::EchoOk { event_response, .. }
+            | Event::Error { event_response, .. }
+            | Event::TopologyOk { event_response, .. }
+            | Event::BroadcastOk { event_response, .. }
+            | Event::GossipOk { event_response, .. }
+            | Event::ReadOk { event_response, .. }
+            | Event::GenerateOk { event_response, .. }
+            | Event::SendOk { event_response, .. }
+            | Event::SendBatchOk { event_response, .. }
+            | Event::PollOk { event_response, .. }
+            | Event::CommitOffsetsOk { event_response, .. }
+            | Event::ListCommittedOffsetsOk { event_response, .. }
+            | Event::JoinGroupOk { event_response, .. }
+            | Event::LeaveGroupOk { event_response, .. }
+            | Event::GroupLagOk { event_response, .. }
+            | Event::ListKeysOk { event_response, .. }
+            | Event::TxnPrepareOk { event_response, .. }
+            | Event::TxnDecideOk { event_response, .. }
+            | Event::TxnStatusOk { event_response, .. }
+            | Event::WriteOk { event_response, .. }
+            | Event::CasOk { event_response, .. }
+            | Event::AddOk { event_response, .. }
+            | Event::ReplicateOk { event_response, .. }
+            | Event::RequestVoteOk { event_response, .. }
+            | Event::AppendEntriesOk { event_response, .. }
+            | Event::CampaignOk { event_response, .. }
+            | Event::HeartbeatOk { event_response, .. }
+            | Event::MetricsOk { event_response, .. } => Some(event_response),
+            _ => None,
+        }
+    }
+
+    pub fn event_response_mut(&mut self) -> Option<&mut EventResponse> {
+        match &mut self.typ {
+            Event::InitOk { event_response, .. }
+            | Event::EchoOk { event_response, .. }
+            | Event::Error { event_response, .. }
+            | Event::TopologyOk { event_response, .. }
+            | Event::BroadcastOk { event_response, .. }
+            | Event::GossipOk { event_response, .. }
+            | Event::ReadOk { event_response, .. }
+            | Event::GenerateOk { event_response, .. }
+            | Event::SendOk { event_response, .. }
+            | Event::SendBatchOk { event_response, .. }
+            | Event::PollOk { event_response, .. }
+            | Event::CommitOffsetsOk { event_response, .. }
+            | Event::ListCommittedOffsetsOk { event_response, .. }
+            | Event::JoinGroupOk { event_response, .. }
+            | Event::LeaveGroupOk { event_response, .. }
+            | Event::GroupLagOk { event_response, .. }
+            | Event::ListKeysOk { event_response, .. }
+            | Event::TxnPrepareOk { event_response, .. }
+            | Event::TxnDecideOk { event_response, .. }
+            | Event::TxnStatusOk { event_response, .. }
+            | Event::WriteOk { event_response, .. }
+            | Event::CasOk { event_response, .. }
+            | Event::AddOk { event_response, .. }
+            | Event::ReplicateOk { event_response, .. }
+            | Event::RequestVoteOk { event_response, .. }
+            | Event::AppendEntriesOk { event_response, .. }
+            | Event::CampaignOk { event_response, .. }
+            | Event::HeartbeatOk { event_response, .. }
+            | Event::MetricsOk { event_response, .. } => Some(event_response),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
@@ -69,6 +152,18 @@ pub enum Event {
         #[serde(flatten)]
         event_response: EventResponse,
     },
+    Gossip {
+        #[serde(flatten)]
+        gossip: GossipEvent,
+        #[serde(flatten)]
+        shared: SharedEvent,
+    },
+    GossipOk {
+        #[serde(flatten)]
+        event_response: EventResponse,
+        #[serde(flatten)]
+        gossip_ok: GossipEvent,
+    },
     Read {
         #[serde(flatten)]
         read: ReadEvent,
@@ -105,6 +200,18 @@ pub enum Event {
         #[serde(flatten)]
         send_ok: SendOkEvent,
     },
+    SendBatch {
+        #[serde(flatten)]
+        send_batch: SendBatchEvent,
+        #[serde(flatten)]
+        shared: SharedEvent,
+    },
+    SendBatchOk {
+        #[serde(flatten)]
+        event_response: EventResponse,
+        #[serde(flatten)]
+        send_batch_ok: SendBatchOk,
+    },
     Poll {
         #[serde(flatten)]
         poll: PollEvent,
@@ -139,6 +246,193 @@ pub enum Event {
         #[serde(flatten)]
         list_committed_offsets_ok: ListCommittedOffsetsOk,
     },
+    JoinGroup {
+        #[serde(flatten)]
+        join_group: GroupMemberEvent,
+        #[serde(flatten)]
+        shared: SharedEvent,
+    },
+    JoinGroupOk {
+        #[serde(flatten)]
+        event_response: EventResponse,
+    },
+    LeaveGroup {
+        #[serde(flatten)]
+        leave_group: GroupMemberEvent,
+        #[serde(flatten)]
+        shared: SharedEvent,
+    },
+    LeaveGroupOk {
+        #[serde(flatten)]
+        event_response: EventResponse,
+    },
+    GroupLag {
+        #[serde(flatten)]
+        group_lag: GroupLagEvent,
+        #[serde(flatten)]
+        shared: SharedEvent,
+    },
+    GroupLagOk {
+        #[serde(flatten)]
+        event_response: EventResponse,
+        #[serde(flatten)]
+        group_lag_ok: GroupLagOk,
+    },
+    ListKeys {
+        #[serde(flatten)]
+        shared: SharedEvent,
+    },
+    ListKeysOk {
+        #[serde(flatten)]
+        event_response: EventResponse,
+        #[serde(flatten)]
+        list_keys_ok: ListKeysOk,
+    },
+    // Between nodes, for the batches spanning several owners
+    TxnPrepare {
+        #[serde(flatten)]
+        txn_prepare: TxnPrepareEvent,
+        #[serde(flatten)]
+        shared: SharedEvent,
+    },
+    TxnPrepareOk {
+        #[serde(flatten)]
+        event_response: EventResponse,
+        #[serde(flatten)]
+        txn_prepare_ok: TxnPrepareOkEvent,
+    },
+    TxnDecide {
+        #[serde(flatten)]
+        txn_decide: TxnDecideEvent,
+        #[serde(flatten)]
+        shared: SharedEvent,
+    },
+    TxnDecideOk {
+        #[serde(flatten)]
+        event_response: EventResponse,
+        #[serde(flatten)]
+        txn_decide_ok: TxnEvent,
+    },
+    TxnStatus {
+        #[serde(flatten)]
+        txn_status: TxnEvent,
+        #[serde(flatten)]
+        shared: SharedEvent,
+    },
+    TxnStatusOk {
+        #[serde(flatten)]
+        event_response: EventResponse,
+        #[serde(flatten)]
+        txn_status_ok: TxnStatusOkEvent,
+    },
+
+    // Key value stores
+    Write {
+        #[serde(flatten)]
+        write: WriteEvent,
+        #[serde(flatten)]
+        shared: SharedEvent,
+    },
+    WriteOk {
+        #[serde(flatten)]
+        event_response: EventResponse,
+    },
+    Cas {
+        #[serde(flatten)]
+        cas: CasEvent,
+        #[serde(flatten)]
+        shared: SharedEvent,
+    },
+    CasOk {
+        #[serde(flatten)]
+        event_response: EventResponse,
+    },
+
+    // g-set and pn-counter
+    Add {
+        #[serde(flatten)]
+        add: AddEvent,
+        #[serde(flatten)]
+        shared: SharedEvent,
+    },
+    AddOk {
+        #[serde(flatten)]
+        event_response: EventResponse,
+    },
+    // Between nodes, carrying the state of a CRDT or a delta of it
+    Replicate {
+        #[serde(flatten)]
+        replicate: ReplicateEvent,
+        #[serde(flatten)]
+        shared: SharedEvent,
+    },
+    ReplicateOk {
+        #[serde(flatten)]
+        event_response: EventResponse,
+    },
+
+    // Raft, between the nodes of the lin-kv workload
+    RequestVote {
+        #[serde(flatten)]
+        request_vote: RequestVoteEvent,
+        #[serde(flatten)]
+        shared: SharedEvent,
+    },
+    RequestVoteOk {
+        #[serde(flatten)]
+        event_response: EventResponse,
+        #[serde(flatten)]
+        request_vote_ok: RequestVoteOkEvent,
+    },
+    AppendEntries {
+        #[serde(flatten)]
+        append_entries: AppendEntriesEvent,
+        #[serde(flatten)]
+        shared: SharedEvent,
+    },
+    AppendEntriesOk {
+        #[serde(flatten)]
+        event_response: EventResponse,
+        #[serde(flatten)]
+        append_entries_ok: AppendEntriesOkEvent,
+    },
+
+    // Leader election
+    Campaign {
+        #[serde(flatten)]
+        campaign: CampaignEvent,
+        #[serde(flatten)]
+        shared: SharedEvent,
+    },
+    CampaignOk {
+        #[serde(flatten)]
+        event_response: EventResponse,
+        #[serde(flatten)]
+        campaign_ok: CampaignOkEvent,
+    },
+    Heartbeat {
+        #[serde(flatten)]
+        heartbeat: HeartbeatEvent,
+        #[serde(flatten)]
+        shared: SharedEvent,
+    },
+    HeartbeatOk {
+        #[serde(flatten)]
+        event_response: EventResponse,
+        #[serde(flatten)]
+        heartbeat_ok: HeartbeatOkEvent,
+    },
+
+    Metrics {
+        #[serde(flatten)]
+        shared: SharedEvent,
+    },
+    MetricsOk {
+        #[serde(flatten)]
+        event_response: EventResponse,
+        #[serde(flatten)]
+        metrics_ok: MetricsOkEvent,
+    },
 }
 
 // Shared
@@ -162,7 +456,8 @@ pub struct ErrorEvent {
 // Generate Event
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GenerateOk {
-    pub id: String,
+    // A string or a number, depending on the id format
+    pub id: serde_json::Value,
 }
 
 // Init
@@ -192,15 +487,48 @@ pub struct BroadcastEvent {
     pub message: serde_json::Value,
 }
 
+// Gossip
+// Values are keyed by their dist_message_id
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GossipEvent {
+    pub values: HashMap<String, serde_json::Value>,
+}
+
 // Read
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ReadEvent {
     pub msg_id: u64,
+    // Only set for reads of a key value store. Keys are strings or integers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<serde_json::Value>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ReadOkEvent {
-    pub messages: Vec<serde_json::Value>,
+    // Broadcast values
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub messages: Option<Vec<serde_json::Value>>,
+    // Value of the key when read from a key value store, or of the counter or set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<serde_json::Value>,
+}
+
+// CRDT workloads
+// `element` is added to a g-set, `delta` to a pn-counter
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AddEvent {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub element: Option<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delta: Option<i64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplicateEvent {
+    pub state: serde_json::Value,
+    // The whole state of the sender rather than a delta, e.g. after it restarted
+    #[serde(default)]
+    pub full: bool,
 }
 
 // Log
@@ -209,6 +537,15 @@ pub struct ReadOkEvent {
 pub struct SendEvent {
     pub key: String,
     pub msg: serde_json::Value,
+    // Message key used by compacted logs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub msg_key: Option<String>,
+    // Idempotent producers send a sequence number with every message.
+    // A retried send with the same sequence returns the original offset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub producer_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -216,9 +553,45 @@ pub struct SendOkEvent {
     pub offset: u64,
 }
 
+// Appends to several keys and optionally commits offsets atomically
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SendBatchEvent {
+    pub msgs: Vec<BatchSendMessage>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub offsets: HashMap<String, u64>,
+    // Consumer group of the committed offsets
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatchSendMessage {
+    pub key: String,
+    pub msg: serde_json::Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub msg_key: Option<String>,
+}
+
+// Offsets of the messages, in the order they were sent
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SendBatchOk {
+    pub offsets: Vec<u64>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PollEvent {
     pub offsets: HashMap<String, u64>,
+    // Optional limits. The server side limits still apply when these are higher.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_messages_per_key: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_messages: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_bytes: Option<usize>,
+    // When nothing is available, wait up to this long for new messages before replying.
+    // Rejected when the keys have several owners
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_ms: Option<u64>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -229,14 +602,178 @@ pub struct PollOkEvent {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CommitOffsetsEvent {
     pub offsets: HashMap<String, u64>,
+    // Consumer group. The default group is used when missing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    // Required once the group has members
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub member: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ListCommittedOffsets {
     pub keys: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ListCommittedOffsetsOk {
     pub offsets: HashMap<String, u64>,
 }
+
+// Consumer groups
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GroupMemberEvent {
+    pub group: String,
+    pub member: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GroupLagEvent {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    // Every existing key is reported when missing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keys: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GroupLagOk {
+    pub lag: HashMap<String, u64>,
+    pub members: Vec<String>,
+}
+
+// Existing keys with their high-water marks
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListKeysOk {
+    pub keys: HashMap<String, u64>,
+}
+
+// Transactions of the batches spanning several owners
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TxnEvent {
+    pub txn: String,
+}
+
+// The part of the batch of one owner
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TxnPrepareEvent {
+    pub txn: String,
+    pub msgs: Vec<BatchSendMessage>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub offsets: HashMap<String, u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+}
+
+// The vote of an owner: the offsets of its messages, or why it could not prepare them
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TxnPrepareOkEvent {
+    pub txn: String,
+    pub offsets: Vec<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorEvent>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TxnDecideEvent {
+    pub txn: String,
+    pub commit: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TxnStatusOkEvent {
+    pub txn: String,
+    // Missing while the coordinator still waits for votes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub commit: Option<bool>,
+}
+
+// Key value stores
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WriteEvent {
+    pub key: serde_json::Value,
+    pub value: serde_json::Value,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CasEvent {
+    pub key: serde_json::Value,
+    pub from: serde_json::Value,
+    pub to: serde_json::Value,
+    // Create the key with `to` when it does not exist yet
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub create_if_not_exists: bool,
+}
+
+// Raft
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RequestVoteEvent {
+    pub term: u64,
+    pub last_log_index: u64,
+    pub last_log_term: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RequestVoteOkEvent {
+    pub term: u64,
+    pub vote_granted: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AppendEntriesEvent {
+    pub term: u64,
+    pub prev_log_index: u64,
+    pub prev_log_term: u64,
+    pub entries: Vec<LogEntry>,
+    pub leader_commit: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AppendEntriesOkEvent {
+    pub term: u64,
+    pub success: bool,
+    // Last index known to match the leader's log. On failure, where the leader should retry from
+    pub match_index: u64,
+}
+
+// Leader election
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CampaignEvent {
+    pub term: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CampaignOkEvent {
+    pub term: u64,
+    pub vote_granted: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HeartbeatEvent {
+    pub term: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HeartbeatOkEvent {
+    pub term: u64,
+}
+
+// Metrics
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MetricsOkEvent {
+    // msg_ids of the requests sent by the node
+    pub msg_ids: MsgIdMetrics,
+    // Ids returned by `generate`
+    pub ids: IdMetrics,
+    // Broadcast values known to the node
+    pub values: DbMetrics,
+    // Broadcasts waiting to be acknowledged by a neighbour
+    pub broadcast_retries: DbMetrics,
+    // Values gossiped in epidemic mode, until their seen ttl
+    pub broadcast_seen: DbMetrics,
+    // Only with leader election enabled
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub leadership: Option<Leadership>,
+}
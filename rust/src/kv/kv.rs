@@ -0,0 +1,249 @@
+use std::time::Duration;
+#[cfg(test)]
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use serde_json::Value;
+
+use crate::{
+    events::{CasEvent, Event, ReadEvent, SharedEvent, WriteEvent},
+    rpc::rpc::{Rpc, RpcError},
+};
+
+// Services provided by Maelstrom
+pub const LIN_KV: &str = "lin-kv";
+pub const SEQ_KV: &str = "seq-kv";
+
+const KV_TIMEOUT: Duration = Duration::from_millis(1000);
+
+#[derive(Debug)]
+pub enum KvError {
+    // key-does-not-exist
+    KeyDoesNotExist,
+    // precondition-failed. A `cas` found another value
+    PreconditionFailed,
+    // No reply in time. The operation may or may not have been applied
+    Timeout,
+    Other { code: u64, text: String },
+}
+
+// Client of a Maelstrom key value store
+#[derive(Debug, Clone)]
+pub struct Kv {
+    service: &'static str,
+    node_id: String,
+    rpc: Rpc,
+    // Answers in place of the service
+    #[cfg(test)]
+    fake: Option<Arc<Mutex<FakeKv>>>,
+}
+
+impl Kv {
+    pub fn new(service: &'static str, rpc: Rpc) -> Kv {
+        Kv {
+            service,
+            node_id: String::new(),
+            rpc,
+            #[cfg(test)]
+            fake: None,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn fake(service: &'static str, fake: Arc<Mutex<FakeKv>>) -> Kv {
+        let mut kv = Kv::new(service, Rpc::new(Arc::new(crate::transport::MsgIds::new())));
+        kv.fake = Some(fake);
+        kv
+    }
+
+    // Requests are sent from this node. Must be called once the node knows its id
+    pub fn set_node_id(&mut self, node_id: &str) {
+        node_id.clone_into(&mut self.node_id);
+    }
+
+    pub async fn read(&self, key: &str) -> Result<Value, KvError> {
+        let key = Value::from(key);
+        let reply = self
+            .call(|shared| Event::Read {
+                read: ReadEvent {
+                    msg_id: shared.msg_id,
+                    key: Some(key),
+                },
+            })
+            .await?;
+
+        match reply {
+            Event::ReadOk { read_ok, .. } => Ok(read_ok.value.unwrap_or(Value::Null)),
+            event => Err(unexpected(event)),
+        }
+    }
+
+    pub async fn write(&self, key: &str, value: Value) -> Result<(), KvError> {
+        let key = Value::from(key);
+        let reply = self
+            .call(|shared| Event::Write {
+                write: WriteEvent { key, value },
+                shared,
+            })
+            .await?;
+
+        match reply {
+            Event::WriteOk { .. } => Ok(()),
+            event => Err(unexpected(event)),
+        }
+    }
+
+    pub async fn cas(
+        &self,
+        key: &str,
+        from: Value,
+        to: Value,
+        create_if_not_exists: bool,
+    ) -> Result<(), KvError> {
+        let key = Value::from(key);
+        let reply = self
+            .call(|shared| Event::Cas {
+                cas: CasEvent {
+                    key,
+                    from,
+                    to,
+                    create_if_not_exists,
+                },
+                shared,
+            })
+            .await?;
+
+        match reply {
+            Event::CasOk { .. } => Ok(()),
+            event => Err(unexpected(event)),
+        }
+    }
+
+    // Sets the key unless it already exists
+    pub async fn create(&self, key: &str, value: Value) -> Result<(), KvError> {
+        // Stored values are never null, so the comparison fails for every existing key
+        self.cas(key, Value::Null, value, true).await
+    }
+
+    async fn call<F>(&self, event: F) -> Result<Event, KvError>
+    where
+        F: FnOnce(SharedEvent) -> Event,
+    {
+        #[cfg(test)]
+        if let Some(fake) = &self.fake {
+            // Lets concurrent requests interleave like they would over the network
+            tokio::task::yield_now().await;
+            let reply = fake
+                .lock()
+                .unwrap()
+                .handle(event(SharedEvent { msg_id: 0 }));
+            tokio::task::yield_now().await;
+            return reply;
+        }
+
+        let reply = match self
+            .rpc
+            .call(&self.node_id, self.service, event, KV_TIMEOUT)
+            .await
+        {
+            Ok(reply) => reply,
+            Err(RpcError::Timeout) => return Err(KvError::Timeout),
+        };
+
+        match reply.body.typ {
+            Event::Error { error, .. } => Err(match error.code {
+                20 => KvError::KeyDoesNotExist,
+                22 => KvError::PreconditionFailed,
+                code => KvError::Other {
+                    code,
+                    text: error.text,
+                },
+            }),
+            event => Ok(event),
+        }
+    }
+}
+
+fn unexpected(event: Event) -> KvError {
+    KvError::Other {
+        code: 13,
+        text: format!("unexpected reply: {:?}", event),
+    }
+}
+
+// In-memory key value store for tests. Behaves like the Maelstrom services
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct FakeKv {
+    pub values: HashMap<String, Value>,
+    // The next writes of these keys time out. Applied or not
+    pub timeouts: Vec<(String, bool)>,
+}
+
+#[cfg(test)]
+impl FakeKv {
+    fn handle(&mut self, event: Event) -> Result<Event, KvError> {
+        let event_response = crate::events::EventResponse { in_reply_to: 0 };
+        match event {
+            Event::Read { read, .. } => {
+                let key = read
+                    .key
+                    .as_ref()
+                    .and_then(Value::as_str)
+                    .unwrap_or_default();
+                match self.values.get(key) {
+                    Some(value) => Ok(Event::ReadOk {
+                        event_response,
+                        read_ok: crate::events::ReadOkEvent {
+                            messages: None,
+                            value: Some(value.clone()),
+                        },
+                    }),
+                    None => Err(KvError::KeyDoesNotExist),
+                }
+            }
+            Event::Write { write, .. } => {
+                let key = write.key.as_str().unwrap_or_default().to_owned();
+                let timeout = self.timeout(&key);
+                if timeout != Some(false) {
+                    self.values.insert(key, write.value);
+                }
+                match timeout {
+                    Some(_) => Err(KvError::Timeout),
+                    None => Ok(Event::WriteOk { event_response }),
+                }
+            }
+            Event::Cas { cas, .. } => {
+                let key = cas.key.as_str().unwrap_or_default().to_owned();
+                let timeout = self.timeout(&key);
+                if timeout == Some(false) {
+                    return Err(KvError::Timeout);
+                }
+                match self.values.get(&key) {
+                    None if !cas.create_if_not_exists => return Err(KvError::KeyDoesNotExist),
+                    Some(current) if *current != cas.from => {
+                        return Err(KvError::PreconditionFailed)
+                    }
+                    _ => {}
+                }
+                self.values.insert(key, cas.to);
+                match timeout {
+                    Some(_) => Err(KvError::Timeout),
+                    None => Ok(Event::CasOk { event_response }),
+                }
+            }
+            event => Err(unexpected(event)),
+        }
+    }
+
+    // Whether the write times out, and whether it is applied anyway
+    fn timeout(&mut self, key: &str) -> Option<bool> {
+        let index = self
+            .timeouts
+            .iter()
+            .position(|(timeout, _)| timeout == key)?;
+        Some(self.timeouts.remove(index).1)
+    }
+}
@@ -1,7 +1,16 @@
+#![allow(clippy::module_inception)]
+
 pub mod broadcast;
+pub mod config;
+pub mod crdt;
 pub mod db;
+pub mod election;
 pub mod events;
+pub mod kv;
 pub mod log;
 pub mod node;
+pub mod raft;
+pub mod rpc;
 pub mod transport;
 pub mod uid;
+pub mod wal;
@@ -1,113 +1,1435 @@
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fs,
+    ops::Bound::{Excluded, Included, Unbounded},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
+use crate::{
+    config::config::{LogConfig, PollLimits, RetentionPolicy, StoreKind},
+    db::store::{open_store, Store},
+    events::ErrorEvent,
+    log::segment::{decode_key, encode_key, DiskLog, Meta, Record, Recovered, TxnLog, TxnMarker},
+};
+
+#[derive(Debug)]
+pub enum LogError {
+    // The offset is past the last message of the log
+    OffsetBeyondEnd {
+        key: String,
+        offset: u64,
+        latest: Option<u64>,
+    },
+    // The offset was removed by the retention policy
+    OffsetOutOfRange {
+        key: String,
+        offset: u64,
+        start_offset: u64,
+    },
+    // The sequence number is older than every sequence remembered for the producer.
+    // It may be a retry of a message that was already appended.
+    StaleSequence {
+        producer_id: String,
+        seq: u64,
+        oldest: u64,
+    },
+    // Reading or writing the on-disk log failed
+    Storage {
+        key: String,
+        err: std::io::Error,
+    },
+    // The key value stores kept rejecting or timing out. The operation may or may not have been applied
+    Unavailable {
+        key: String,
+    },
+    // Only members may commit for a group that has any
+    NotAMember {
+        group: String,
+        member: Option<String>,
+    },
+    // Only polls of keys with a single owner can wait for messages
+    WaitSpansOwners {
+        owners: Vec<String>,
+    },
+}
+
+impl From<LogError> for ErrorEvent {
+    fn from(err: LogError) -> Self {
+        ErrorEvent {
+            code: err.code(),
+            text: err.text(),
+        }
+    }
+}
+
+impl LogError {
+    // Maelstrom error code
+    pub fn code(&self) -> u64 {
+        match self {
+            // precondition-failed
+            LogError::OffsetBeyondEnd { .. } => 22,
+            LogError::OffsetOutOfRange { .. } => 1004,
+            // precondition-failed
+            LogError::StaleSequence { .. } => 22,
+            // crash. The write may or may not have been persisted
+            LogError::Storage { .. } => 13,
+            // timeout
+            LogError::Unavailable { .. } => 0,
+            // precondition-failed
+            LogError::NotAMember { .. } => 22,
+            // not-supported
+            LogError::WaitSpansOwners { .. } => 10,
+        }
+    }
+
+    pub fn text(&self) -> String {
+        match self {
+            LogError::OffsetBeyondEnd {
+                key,
+                offset,
+                latest: Some(latest),
+            } => format!(
+                "cannot commit offset {} for key {}: latest offset is {}",
+                offset, key, latest
+            ),
+            LogError::OffsetBeyondEnd {
+                key,
+                offset,
+                latest: None,
+            } => format!(
+                "cannot commit offset {} for key {}: the log is empty",
+                offset, key
+            ),
+            LogError::OffsetOutOfRange {
+                key,
+                offset,
+                start_offset,
+            } => format!(
+                "offset {} for key {} is out of range: the log starts at offset {}",
+                offset, key, start_offset
+            ),
+            LogError::StaleSequence {
+                producer_id,
+                seq,
+                oldest,
+            } => format!(
+                "sequence {} of producer {} is older than the oldest remembered sequence {}",
+                seq, producer_id, oldest
+            ),
+            LogError::Storage { key, err } => format!("storage error for key {}: {}", key, err),
+            LogError::Unavailable { key } => {
+                format!(
+                    "gave up on key {} after repeated conflicts or timeouts",
+                    key
+                )
+            }
+            LogError::NotAMember {
+                group,
+                member: Some(member),
+            } => format!("{} is not a member of group {:?}", member, group),
+            LogError::NotAMember {
+                group,
+                member: None,
+            } => format!("commits for group {:?} must name a member", group),
+            LogError::WaitSpansOwners { owners } => format!(
+                "the poll spans keys owned by {}: only polls of a single owner can wait, \
+                 poll without timeout_ms or by owner",
+                owners.join(", ")
+            ),
+        }
+    }
+}
+
+// Commits and listings without a group id use this group
+pub const DEFAULT_GROUP: &str = "";
+
 #[derive(Debug, Default)]
 pub struct KLog {
+    config: LogConfig,
     logs: HashMap<String, Log>,
+    // Members of every consumer group
+    groups: HashMap<String, HashSet<String>>,
+    // Latest sequences of every idempotent producer, by producer id and key.
+    // Like Kafka's per-partition sequences, a producer numbers the sends of every key on its own
+    producers: HashMap<(String, String), ProducerWindow>,
+    // Markers of committed transactions. Only used with on-disk storage
+    txn_log: Option<TxnLog>,
+    // Batches being applied, by transaction. Only used when file stores are the only copy
+    batches: Option<Box<dyn Store<u64, StagedBatch>>>,
+    next_txn: u64,
+    // Parts of batches spanning several owners, waiting for the decision of their coordinator.
+    // By transaction of the coordinator
+    prepared: HashMap<String, PreparedTxn>,
+    // Copy of `prepared` when the logs are on disk
+    prepared_store: Option<Box<dyn Store<String, PreparedTxn>>>,
+}
+
+// A batch is written here before any of its messages. Whatever is found on startup was
+// interrupted and is applied again
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StagedBatch {
+    // (key, message), in order
+    messages: Vec<(String, Message)>,
+    offsets: HashMap<String, u64>,
+    group: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PreparedTxn {
+    coordinator: String,
+    // Transaction of the records on disk, which only commits with its marker
+    local: Option<u64>,
+    // (key, message), in order
+    messages: Vec<(String, Message)>,
+    offsets: HashMap<String, u64>,
+    group: Option<String>,
+    // Set once the coordinator decided to commit, so that a restart rolls the part forward.
+    // With segments, the marker of `local` does that
+    #[serde(default)]
+    committed: bool,
+}
+
+// A message of a batch
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatchMessage {
+    pub key: String,
+    pub msg: Value,
+    pub msg_key: Option<String>,
+}
+
+// The state of every log, for snapshots of the node
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct KLogSnapshot {
+    logs: Vec<LogSnapshot>,
+    groups: HashMap<String, HashSet<String>>,
+    // (producer id, key, window)
+    producers: Vec<(String, String, ProducerWindow)>,
+    // (transaction, part)
+    #[serde(default)]
+    prepared: Vec<(String, PreparedTxn)>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct LogSnapshot {
+    key: String,
+    offset: u64,
+    start_offset: u64,
+    committed_offsets: HashMap<String, u64>,
+    messages: Vec<Message>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct ProducerWindow {
+    // (sequence, offset), oldest first
+    appends: VecDeque<(u64, u64)>,
+}
+
+impl ProducerWindow {
+    fn get(&self, seq: u64) -> Option<u64> {
+        self.appends
+            .iter()
+            .find(|(s, _)| *s == seq)
+            .map(|(_, offset)| *offset)
+    }
+
+    fn insert(&mut self, seq: u64, offset: u64, size: usize) {
+        self.appends.push_back((seq, offset));
+        while self.appends.len() > size {
+            self.appends.pop_front();
+        }
+    }
 }
 
 impl KLog {
-    pub fn new() -> KLog {
-        KLog::default()
+    // With on-disk storage, the logs found in the directory are recovered
+    pub fn new(config: LogConfig) -> Result<KLog, LogError> {
+        let mut klog = KLog {
+            config,
+            logs: HashMap::new(),
+            groups: HashMap::new(),
+            producers: HashMap::new(),
+            txn_log: None,
+            batches: None,
+            next_txn: 0,
+            prepared: HashMap::new(),
+            prepared_store: None,
+        };
+
+        // (timestamp, producer id, key, sequence, offset) of every recovered idempotent append
+        let mut appends = vec![];
+        let mut committed_txns = HashSet::new();
+        if let Some(disk_config) = &klog.config.storage {
+            let storage_error = |err| LogError::Storage {
+                key: String::new(),
+                err,
+            };
+            let recovered = DiskLog::recover(disk_config).map_err(storage_error)?;
+            let (txn_log, markers) = TxnLog::recover(disk_config).map_err(storage_error)?;
+
+            committed_txns = markers
+                .iter()
+                .map(|marker| marker.txn)
+                .collect::<HashSet<_>>();
+            klog.next_txn = markers
+                .iter()
+                .map(|marker| marker.txn + 1)
+                .max()
+                .unwrap_or(0);
+
+            for mut recovered in recovered {
+                // Drop the records of transactions that did not commit
+                for record in recovered.records.iter() {
+                    if let Some(txn) = record.txn {
+                        klog.next_txn = klog.next_txn.max(txn + 1);
+                    }
+                }
+                recovered
+                    .records
+                    .retain(|record| record.txn.is_none_or(|txn| committed_txns.contains(&txn)));
+
+                for record in recovered.records.iter() {
+                    if let Some((producer_id, seq)) = &record.producer {
+                        appends.push((
+                            record.timestamp,
+                            producer_id.clone(),
+                            recovered.key.clone(),
+                            *seq,
+                            record.offset,
+                        ));
+                    }
+                }
+
+                let policy = klog.config.retention_policy(&recovered.key);
+                let key = recovered.key.clone();
+                let messages = klog.open_messages(&key)?;
+                let mut log =
+                    Log::recover(policy, recovered, messages).map_err(|err| LogError::Storage {
+                        key: key.clone(),
+                        err,
+                    })?;
+                log.apply_retention(now());
+                klog.logs.insert(key, log);
+            }
+
+            // Offsets committed by transactions may not have made it to the meta files
+            for marker in markers {
+                let group = marker.group.unwrap_or_else(|| DEFAULT_GROUP.to_owned());
+                for (log_key, offset) in marker.offsets {
+                    if let Some(log) = klog.logs.get_mut(&log_key) {
+                        log.committ_offsets(&group, offset)
+                            .map_err(|err| LogError::Storage { key: log_key, err })?;
+                    }
+                }
+            }
+            klog.txn_log = Some(txn_log);
+        } else if let StoreKind::File(dir, _) = &klog.config.store {
+            // Without segments the file stores are the only copy of the messages. Committed
+            // offsets are kept in a meta store next to them, and every message names its producer
+            let entries = match fs::read_dir(dir) {
+                Ok(entries) => entries.collect::<Result<Vec<_>, _>>(),
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(vec![]),
+                Err(err) => Err(err),
+            }
+            .map_err(|err| LogError::Storage {
+                key: String::new(),
+                err,
+            })?;
+
+            for entry in entries {
+                let key = match entry
+                    .file_name()
+                    .to_str()
+                    .and_then(|name| name.strip_prefix("log-")?.strip_suffix(".store"))
+                    .and_then(decode_key)
+                {
+                    Some(key) => key,
+                    None => continue,
+                };
+
+                let policy = klog.config.retention_policy(&key);
+                let mut log = Log::new(policy, klog.open_messages(&key)?);
+                log.meta_store = klog.open_meta(&key)?;
+                if let Some(meta) = log.meta_store.as_ref().and_then(|store| store.get(&0)) {
+                    let meta = meta.clone();
+                    log.apply_meta(meta);
+                    log.offset = log.offset.max(log.start_offset);
+                }
+                for (offset, message) in log.messages.range(Unbounded, Unbounded) {
+                    if let Some((producer_id, seq)) = &message.producer {
+                        appends.push((
+                            message.timestamp,
+                            producer_id.clone(),
+                            key.clone(),
+                            *seq,
+                            *offset,
+                        ));
+                    }
+                }
+                log.apply_retention(now());
+                klog.logs.insert(key, log);
+            }
+
+            if let Some(mut batches) = klog.open_batches()? {
+                let mut staged = batches
+                    .iter()
+                    .map(|(txn, batch)| (*txn, batch.clone()))
+                    .collect::<Vec<_>>();
+                staged.sort_by_key(|(txn, _)| *txn);
+                for (txn, batch) in staged {
+                    klog.roll_forward(batch)?;
+                    batches.delete(&txn).map_err(|err| LogError::Storage {
+                        key: String::new(),
+                        err,
+                    })?;
+                    klog.next_txn = klog.next_txn.max(txn + 1);
+                }
+                klog.batches = Some(batches);
+            }
+        }
+
+        if let Some(mut store) = klog.open_prepared()? {
+            let found = store
+                .iter()
+                .map(|(txn, prepared)| (txn.clone(), prepared.clone()))
+                .collect::<Vec<_>>();
+            for (txn, prepared) in found {
+                let applied = prepared
+                    .local
+                    .is_some_and(|local| committed_txns.contains(&local));
+                if applied || prepared.committed {
+                    if !applied {
+                        klog.apply_prepared(prepared)?;
+                    }
+                    store.delete(&txn).map_err(|err| LogError::Storage {
+                        key: String::new(),
+                        err,
+                    })?;
+                    continue;
+                }
+                klog.hold(txn, prepared)?;
+            }
+            klog.prepared_store = Some(store);
+        }
+
+        // Rebuild the producer windows in the order the messages were appended
+        appends.sort();
+        for (_, producer_id, key, seq, offset) in appends {
+            klog.producers
+                .entry((producer_id, key))
+                .or_default()
+                .insert(seq, offset, klog.config.producer_window);
+        }
+
+        Ok(klog)
+    }
+
+    // Whether the logs survive a restart without snapshots
+    pub fn is_durable(&self) -> bool {
+        self.config.storage.is_some() || matches!(self.config.store, StoreKind::File(..))
     }
-    pub fn handle_append(&mut self, key: String, message: Value) -> u64 {
+
+    pub fn snapshot(&self) -> KLogSnapshot {
+        KLogSnapshot {
+            logs: self
+                .logs
+                .iter()
+                .map(|(key, log)| LogSnapshot {
+                    key: key.clone(),
+                    offset: log.offset,
+                    start_offset: log.start_offset,
+                    committed_offsets: log.committed_offsets.clone(),
+                    messages: log
+                        .messages
+                        .range(Unbounded, Unbounded)
+                        .map(|(_, message)| message.clone())
+                        .collect(),
+                })
+                .collect(),
+            groups: self.groups.clone(),
+            producers: self
+                .producers
+                .iter()
+                .map(|((producer_id, key), window)| {
+                    (producer_id.clone(), key.clone(), window.clone())
+                })
+                .collect(),
+            prepared: self
+                .prepared
+                .iter()
+                .map(|(txn, prepared)| (txn.clone(), prepared.clone()))
+                .collect(),
+        }
+    }
+
+    // Replaces the logs with the ones of the snapshot
+    pub fn restore(&mut self, snapshot: KLogSnapshot) -> Result<(), LogError> {
+        self.logs.clear();
+        for saved in snapshot.logs {
+            let policy = self.config.retention_policy(&saved.key);
+            let mut log = Log::new(policy, self.open_messages(&saved.key)?);
+            for message in saved.messages {
+                log.push(message).map_err(|err| LogError::Storage {
+                    key: saved.key.clone(),
+                    err,
+                })?;
+            }
+            log.offset = saved.offset;
+            log.start_offset = saved.start_offset;
+            log.committed_offsets = saved.committed_offsets;
+            self.logs.insert(saved.key, log);
+        }
+
+        self.groups = snapshot.groups;
+        self.producers = snapshot
+            .producers
+            .into_iter()
+            .map(|(producer_id, key, window)| ((producer_id, key), window))
+            .collect();
+        self.prepared = snapshot.prepared.into_iter().collect();
+        Ok(())
+    }
+
+    fn open_messages(&self, key: &str) -> Result<Box<dyn Store<u64, Message>>, LogError> {
+        open_store(&self.config.store, &format!("log-{}", encode_key(key))).map_err(|err| {
+            LogError::Storage {
+                key: key.to_owned(),
+                err,
+            }
+        })
+    }
+
+    // Only needed when a file store is the only copy of the log
+    fn open_meta(&self, key: &str) -> Result<Option<Box<dyn Store<u64, Meta>>>, LogError> {
+        if self.config.storage.is_some() || !matches!(self.config.store, StoreKind::File(..)) {
+            return Ok(None);
+        }
+        open_store(&self.config.store, &format!("meta-{}", encode_key(key)))
+            .map(Some)
+            .map_err(|err| LogError::Storage {
+                key: key.to_owned(),
+                err,
+            })
+    }
+
+    fn open_batches(&self) -> Result<Option<Box<dyn Store<u64, StagedBatch>>>, LogError> {
+        if self.config.storage.is_some() || !matches!(self.config.store, StoreKind::File(..)) {
+            return Ok(None);
+        }
+        open_store(&self.config.store, "batches")
+            .map(Some)
+            .map_err(|err| LogError::Storage {
+                key: String::new(),
+                err,
+            })
+    }
+
+    // Parts of batches spanning several owners are kept with the logs when those are on disk
+    fn open_prepared(&self) -> Result<Option<Box<dyn Store<String, PreparedTxn>>>, LogError> {
+        let kind = self.config.txn_store();
+        if matches!(kind, StoreKind::Memory) {
+            return Ok(None);
+        }
+        open_store(&kind, "txn-prepared")
+            .map(Some)
+            .map_err(|err| LogError::Storage {
+                key: String::new(),
+                err,
+            })
+    }
+
+    // Pushes the messages of an interrupted batch that are not in their log yet. Messages are
+    // pushed in offset order, so those below the next offset of their log were pushed or
+    // compacted away, and nothing was appended after the batch
+    fn roll_forward(&mut self, batch: StagedBatch) -> Result<(), LogError> {
+        for (key, message) in batch.messages {
+            if !self.logs.contains_key(&key) {
+                self.create_log(&key)?;
+            }
+            let log = self.logs.get_mut(&key).unwrap();
+            if message.offset >= log.offset {
+                log.push(message)
+                    .map_err(|err| LogError::Storage { key, err })?;
+            }
+        }
+        if !batch.offsets.is_empty() {
+            self.commit_offsets(batch.offsets, batch.group)?;
+        }
+        Ok(())
+    }
+
+    // `msg_key` is the message key used for compaction.
+    // Sends of idempotent producers carry their producer id and sequence number.
+    // `timestamp` is the time of the original append, which retention goes by, also on replay.
+    pub fn handle_append(
+        &mut self,
+        key: String,
+        message: Value,
+        msg_key: Option<String>,
+        producer_id: Option<String>,
+        seq: Option<u64>,
+        timestamp: u64,
+    ) -> Result<u64, LogError> {
+        let producer = match (producer_id, seq) {
+            (Some(producer_id), Some(seq)) => Some((producer_id, seq)),
+            _ => None,
+        };
+
+        if let Some((producer_id, seq)) = &producer {
+            if let Some(window) = self.producers.get(&(producer_id.clone(), key.clone())) {
+                // A retry. Return the offset of the original append
+                if let Some(offset) = window.get(*seq) {
+                    return Ok(offset);
+                }
+
+                let oldest = window.appends.front().map(|(seq, _)| *seq);
+                if window.appends.len() >= self.config.producer_window
+                    && oldest.is_some_and(|oldest| *seq < oldest)
+                {
+                    return Err(LogError::StaleSequence {
+                        producer_id: producer_id.clone(),
+                        seq: *seq,
+                        oldest: oldest.unwrap_or_default(),
+                    });
+                }
+            }
+        }
+
         // Check if the log exists
         if !self.logs.contains_key(&key) {
             // Create log
-            self.create_log(&key);
+            self.create_log(&key)?;
         }
 
         // The log should exist. So the unwrap is just to make the compiler happy
         let log = self.logs.get_mut(&key).unwrap();
-        log.append(message)
+        let offset = match log.append(message, msg_key, producer.clone(), timestamp) {
+            Ok(offset) => offset,
+            Err(err) => return Err(LogError::Storage { key, err }),
+        };
+        log.apply_retention(now());
+
+        if let Some((producer_id, seq)) = producer {
+            self.producers
+                .entry((producer_id, key))
+                .or_default()
+                .insert(seq, offset, self.config.producer_window);
+        }
+        Ok(offset)
     }
 
-    fn create_log(&mut self, key: &str) {
-        self.logs.insert(key.to_owned(), Log::new());
+    fn create_log(&mut self, key: &str) -> Result<(), LogError> {
+        let policy = self.config.retention_policy(key);
+        let mut log = Log::new(policy, self.open_messages(key)?);
+        log.meta_store = self.open_meta(key)?;
+
+        if let Some(disk_config) = &self.config.storage {
+            let disk = DiskLog::create(disk_config, key).map_err(|err| LogError::Storage {
+                key: key.to_owned(),
+                err,
+            })?;
+            log.disk = Some(disk);
+        }
+
+        self.logs.insert(key.to_owned(), log);
+        Ok(())
     }
 
-    pub fn handle_poll(
+    // Appends every message and commits the offsets in one step.
+    // Either every message becomes visible to polls or none does.
+    // Returns the offset of every message, in order.
+    pub fn handle_send_batch(
         &mut self,
+        messages: Vec<BatchMessage>,
         offsets: HashMap<String, u64>,
-    ) -> HashMap<String, Vec<Vec<Value>>> {
-        let mut output = HashMap::new();
+        group: Option<String>,
+        timestamp: u64,
+    ) -> Result<Vec<u64>, LogError> {
+        self.validate_commit_offsets(&offsets)?;
 
-        for (log_key, offset) in offsets {
-            let log = self.get_log(log_key.clone());
+        let txn = self.local_txn();
+        let prepared = self.prepare_batch(messages, txn, timestamp)?;
+
+        if let (Some(txn_log), Some(txn)) = (self.txn_log.as_mut(), txn) {
+            let marker = TxnMarker {
+                txn,
+                group: group.clone(),
+                offsets: offsets.clone(),
+            };
+            txn_log.commit(&marker).map_err(|err| LogError::Storage {
+                key: String::new(),
+                err,
+            })?;
+        }
+
+        // Without segments the batch is staged, so that a crash halfway is rolled forward
+        let staged = match self.batches.as_mut() {
+            Some(batches) => {
+                let txn = self.next_txn;
+                let batch = StagedBatch {
+                    messages: prepared.clone(),
+                    offsets: offsets.clone(),
+                    group: group.clone(),
+                };
+                batches
+                    .put(txn, batch)
+                    .and_then(|_| batches.sync())
+                    .map_err(|err| LogError::Storage {
+                        key: String::new(),
+                        err,
+                    })?;
+                self.next_txn += 1;
+                Some(txn)
+            }
+            None => None,
+        };
+
+        let mut appended = Vec::with_capacity(prepared.len());
+        let mut pushed = Vec::with_capacity(prepared.len());
+        for (key, message) in prepared {
+            let offset = message.offset;
+            // Every log was created above
+            match self.logs.get_mut(&key).unwrap().push(message) {
+                Ok(replaced) => pushed.push((key, offset, replaced)),
+                Err(err) => {
+                    self.roll_back(pushed, staged);
+                    return Err(LogError::Storage { key, err });
+                }
+            }
+            appended.push(offset);
+        }
+
+        // With a staged batch, a failure here is rolled forward on restart
+        if !offsets.is_empty() {
+            self.commit_offsets(offsets, group)?;
+        }
+
+        let now = now();
+        for (key, _, _) in pushed.iter() {
+            self.logs.get_mut(key).unwrap().apply_retention(now);
+        }
 
-            output.insert(log_key, log.poll(offset));
+        if let (Some(batches), Some(txn)) = (self.batches.as_mut(), staged) {
+            if let Err(err) = batches.delete(&txn) {
+                // Rolling it forward again skips the messages that are already in their log
+                eprintln!("failed to remove a staged batch: \n err: {:?}", err);
+            }
         }
 
-        output
+        Ok(appended)
+    }
+
+    // With on-disk storage, the records of a batch are tagged with a transaction and only
+    // recovered once its marker is written
+    fn local_txn(&mut self) -> Option<u64> {
+        self.txn_log.as_ref()?;
+        self.next_txn += 1;
+        Some(self.next_txn - 1)
     }
 
-    fn get_log(&mut self, log_key: String) -> &Log {
-        if !self.logs.contains_key(&log_key) {
-            self.create_log(&log_key);
+    // Writes every message of a batch, none of them visible yet. Returns them in order
+    fn prepare_batch(
+        &mut self,
+        messages: Vec<BatchMessage>,
+        txn: Option<u64>,
+        timestamp: u64,
+    ) -> Result<Vec<(String, Message)>, LogError> {
+        for message in messages.iter() {
+            if !self.logs.contains_key(&message.key) {
+                self.create_log(&message.key)?;
+            }
+        }
+
+        let mut prepared = Vec::with_capacity(messages.len());
+        for message in messages {
+            // Every log was created above
+            let log = self.logs.get_mut(&message.key).unwrap();
+            match log.prepare(message.msg, message.msg_key, None, txn, timestamp) {
+                Ok(prepared_message) => prepared.push((message.key, prepared_message)),
+                // The offsets written so far are skipped
+                Err(err) => {
+                    return Err(LogError::Storage {
+                        key: message.key,
+                        err,
+                    })
+                }
+            }
         }
-        self.logs.get(&log_key).unwrap()
+
+        let mut touched = prepared.iter().map(|(key, _)| key).collect::<Vec<_>>();
+        touched.sort();
+        touched.dedup();
+        for key in touched {
+            self.logs
+                .get_mut(key)
+                .unwrap()
+                .sync()
+                .map_err(|err| LogError::Storage {
+                    key: key.clone(),
+                    err,
+                })?;
+        }
+        Ok(prepared)
     }
-    fn get_log_mut(&mut self, log_key: String) -> &mut Log {
-        if !self.logs.contains_key(&log_key) {
-            self.create_log(&log_key);
+
+    // The part of a batch spanning several owners that this node owns. Its messages get their
+    // offsets and are written, but polls stop before them until the coordinator decides.
+    // Preparing the same transaction again returns the same offsets
+    pub fn handle_txn_prepare(
+        &mut self,
+        txn: String,
+        coordinator: String,
+        messages: Vec<BatchMessage>,
+        offsets: HashMap<String, u64>,
+        group: Option<String>,
+        timestamp: u64,
+    ) -> Result<Vec<u64>, LogError> {
+        if let Some(prepared) = self.prepared.get(&txn) {
+            return Ok(prepared
+                .messages
+                .iter()
+                .map(|(_, message)| message.offset)
+                .collect());
         }
-        self.logs.get_mut(&log_key).unwrap()
+        self.validate_commit_offsets(&offsets)?;
+
+        let local = self.local_txn();
+        let messages = self.prepare_batch(messages, local, timestamp)?;
+        let prepared = PreparedTxn {
+            coordinator,
+            local,
+            messages,
+            offsets,
+            group,
+            committed: false,
+        };
+        if let Some(store) = self.prepared_store.as_mut() {
+            store
+                .put(txn.clone(), prepared.clone())
+                .and_then(|_| store.sync())
+                .map_err(|err| LogError::Storage {
+                    key: String::new(),
+                    err,
+                })?;
+        }
+
+        let appended = prepared
+            .messages
+            .iter()
+            .map(|(_, message)| message.offset)
+            .collect();
+        self.prepared.insert(txn, prepared);
+        Ok(appended)
+    }
+
+    // Applies the decision of the coordinator. Returns the keys that got messages.
+    // Unknown transactions were decided already
+    pub fn handle_txn_decide(&mut self, txn: &str, commit: bool) -> Result<Vec<String>, LogError> {
+        let mut prepared = match self.prepared.get(txn) {
+            Some(prepared) => prepared.clone(),
+            None => return Ok(vec![]),
+        };
+
+        let mut keys = vec![];
+        if commit {
+            // Once the decision is on disk, a restart rolls the part forward
+            let result = match (self.txn_log.as_mut(), prepared.local) {
+                (Some(txn_log), Some(local)) => txn_log.commit(&TxnMarker {
+                    txn: local,
+                    group: prepared.group.clone(),
+                    offsets: prepared.offsets.clone(),
+                }),
+                _ => match self.prepared_store.as_mut() {
+                    Some(store) => {
+                        prepared.committed = true;
+                        store
+                            .put(txn.to_owned(), prepared.clone())
+                            .and_then(|_| store.sync())
+                    }
+                    None => Ok(()),
+                },
+            };
+            result.map_err(|err| LogError::Storage {
+                key: String::new(),
+                err,
+            })?;
+            keys = self.apply_prepared(prepared)?;
+        }
+
+        self.prepared.remove(txn);
+        if let Some(store) = self.prepared_store.as_mut() {
+            if let Err(err) = store.delete(&txn.to_owned()) {
+                // A restart applies the decision again, or asks the coordinator
+                eprintln!("failed to remove a prepared transaction: \n err: {:?}", err);
+            }
+        }
+        Ok(keys)
     }
 
-    pub fn handle_commit_offsets(&mut self, offsets: HashMap<String, u64>) {
+    pub fn is_prepared(&self, txn: &str) -> bool {
+        self.prepared.contains_key(txn)
+    }
+
+    pub fn has_prepared(&self) -> bool {
+        !self.prepared.is_empty()
+    }
+
+    // Transactions waiting for a decision, with their coordinator
+    pub fn prepared_txns(&self) -> Vec<(String, String)> {
+        self.prepared
+            .iter()
+            .map(|(txn, prepared)| (txn.clone(), prepared.coordinator.clone()))
+            .collect()
+    }
+
+    // Makes a committed part visible. Pushing is idempotent, so this also rolls it forward
+    fn apply_prepared(&mut self, prepared: PreparedTxn) -> Result<Vec<String>, LogError> {
+        let mut keys = vec![];
+        for (key, message) in prepared.messages {
+            if !self.logs.contains_key(&key) {
+                self.create_log(&key)?;
+            }
+            let log = self.logs.get_mut(&key).unwrap();
+            if let Err(err) = log.push(message) {
+                return Err(LogError::Storage { key, err });
+            }
+            keys.push(key);
+        }
+        if !prepared.offsets.is_empty() {
+            self.commit_offsets(prepared.offsets, prepared.group)?;
+        }
+
+        keys.sort();
+        keys.dedup();
+        let now = now();
+        for key in keys.iter() {
+            self.logs.get_mut(key).unwrap().apply_retention(now);
+        }
+        Ok(keys)
+    }
+
+    // Holds a part found on startup until its coordinator decides
+    fn hold(&mut self, txn: String, prepared: PreparedTxn) -> Result<(), LogError> {
+        for (key, message) in prepared.messages.iter() {
+            if !self.logs.contains_key(key) {
+                self.create_log(key)?;
+            }
+            let log = self.logs.get_mut(key).unwrap();
+            log.offset = log.offset.max(message.offset + 1);
+        }
+        if let Some(local) = prepared.local {
+            self.next_txn = self.next_txn.max(local + 1);
+        }
+        self.prepared.insert(txn, prepared);
+        Ok(())
+    }
+
+    // Polls stop before the first message of a batch that is still undecided
+    fn last_stable_offset(&self, key: &str) -> Option<u64> {
+        self.prepared
+            .values()
+            .flat_map(|prepared| prepared.messages.iter())
+            .filter(|(message_key, _)| message_key == key)
+            .map(|(_, message)| message.offset)
+            .min()
+    }
+
+    // Removes the pushed messages of a failed batch, latest first, and its staged copy.
+    // Their offsets are skipped. What cannot be removed is rolled forward on restart
+    fn roll_back(&mut self, pushed: Vec<(String, u64, Option<Message>)>, staged: Option<u64>) {
+        for (key, offset, replaced) in pushed.into_iter().rev() {
+            let log = self.logs.get_mut(&key).unwrap();
+            if let Err(err) = log.unpush(offset, replaced) {
+                eprintln!("failed to roll back a batch: \n err: {:?}", err);
+                return;
+            }
+        }
+        if let (Some(batches), Some(txn)) = (self.batches.as_mut(), staged) {
+            if let Err(err) = batches.delete(&txn) {
+                eprintln!("failed to remove a staged batch: \n err: {:?}", err);
+            }
+        }
+    }
+
+    // Removes the messages that fall out of their log's retention policy
+    pub fn handle_retention(&mut self) {
+        let now = now();
+        for log in self.logs.values_mut() {
+            log.apply_retention(now);
+        }
+    }
+
+    // The limits are the ones requested by the client. They can only tighten the server limits.
+    // Unknown keys are reported with no messages. Polling never creates a log.
+    pub fn handle_poll(
+        &self,
+        offsets: HashMap<String, u64>,
+        max_messages_per_key: Option<usize>,
+        max_messages: Option<usize>,
+        max_bytes: Option<usize>,
+    ) -> Result<HashMap<String, Vec<Vec<Value>>>, LogError> {
+        let limits =
+            self.config
+                .poll_limits
+                .restrict(max_messages_per_key, max_messages, max_bytes);
+
+        // Sort the keys so that responses are deterministic
+        let mut log_keys = offsets.into_iter().collect::<Vec<_>>();
+        log_keys.sort();
+
+        let mut candidates = Vec::with_capacity(log_keys.len());
+        for (log_key, offset) in log_keys {
+            let messages = match self.get_log(&log_key) {
+                Some(log) if offset < log.start_offset => {
+                    return Err(LogError::OffsetOutOfRange {
+                        key: log_key,
+                        offset,
+                        start_offset: log.start_offset,
+                    })
+                }
+                Some(log) => log.poll(
+                    offset,
+                    limits.max_messages_per_key,
+                    self.last_stable_offset(&log_key),
+                ),
+                None => vec![],
+            };
+            candidates.push((log_key, messages));
+        }
+
+        Ok(interleave(candidates, &limits))
+    }
+
+    // Only appends create logs. Reads must leave the key space untouched.
+    fn get_log(&self, log_key: &str) -> Option<&Log> {
+        self.logs.get(log_key)
+    }
+    fn get_log_mut(&mut self, log_key: &str) -> Option<&mut Log> {
+        self.logs.get_mut(log_key)
+    }
+
+    pub fn handle_commit_offsets(
+        &mut self,
+        offsets: HashMap<String, u64>,
+        group: Option<String>,
+    ) -> Result<(), LogError> {
+        self.validate_commit_offsets(&offsets)?;
+        self.commit_offsets(offsets, group)
+    }
+
+    // Validate every offset before committing any of them so that a rejected request has no effect
+    pub fn validate_commit_offsets(&self, offsets: &HashMap<String, u64>) -> Result<(), LogError> {
+        for (log_key, offset) in offsets.iter() {
+            let latest = self.get_log(log_key).and_then(|log| log.latest_offset());
+
+            if latest.is_none_or(|latest| *offset > latest) {
+                return Err(LogError::OffsetBeyondEnd {
+                    key: log_key.clone(),
+                    offset: *offset,
+                    latest,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn commit_offsets(
+        &mut self,
+        offsets: HashMap<String, u64>,
+        group: Option<String>,
+    ) -> Result<(), LogError> {
+        let group = group.unwrap_or_else(|| DEFAULT_GROUP.to_owned());
+
+        let now = now();
         for (log_key, offset) in offsets {
-            let log = self.get_log_mut(log_key.clone());
-            log.committ_offsets(offset);
+            // Every key was validated above, so the log exists
+            if let Some(log) = self.get_log_mut(&log_key) {
+                log.committ_offsets(&group, offset)
+                    .map_err(|err| LogError::Storage { key: log_key, err })?;
+                log.apply_retention(now);
+            }
         }
+        Ok(())
     }
 
-    pub fn handle_list_committed_offsets(&mut self, logs: Vec<String>) -> HashMap<String, u64> {
+    // Keys that were never committed by the group are left out
+    pub fn handle_list_committed_offsets(
+        &self,
+        logs: Vec<String>,
+        group: Option<String>,
+    ) -> HashMap<String, u64> {
+        let group = group.unwrap_or_else(|| DEFAULT_GROUP.to_owned());
+
         let mut hash = HashMap::with_capacity(logs.len());
         for log_key in logs {
-            let log = self.get_log(log_key.clone());
-
-            let offset = log.list_committed_offset();
+            let offset = match self.get_log(&log_key) {
+                Some(log) => log.list_committed_offset(&group),
+                None => None,
+            };
 
-            hash.insert(log_key, offset);
+            if let Some(offset) = offset {
+                hash.insert(log_key, offset);
+            }
         }
         hash
     }
+
+    // Groups without members accept commits from anyone
+    pub fn check_member(&self, group: &str, member: Option<&str>) -> Result<(), LogError> {
+        let members = match self.groups.get(group) {
+            Some(members) if !members.is_empty() => members,
+            _ => return Ok(()),
+        };
+        match member {
+            Some(member) if members.contains(member) => Ok(()),
+            _ => Err(LogError::NotAMember {
+                group: group.to_owned(),
+                member: member.map(str::to_owned),
+            }),
+        }
+    }
+
+    pub fn groups(&self) -> HashMap<String, Vec<String>> {
+        self.groups
+            .iter()
+            .map(|(group, members)| (group.clone(), members.iter().cloned().collect()))
+            .collect()
+    }
+
+    pub fn handle_join_group(&mut self, group: String, member: String) {
+        self.groups.entry(group).or_default().insert(member);
+    }
+
+    // The committed offsets of the group are kept when its last member leaves
+    pub fn handle_leave_group(&mut self, group: &str, member: &str) {
+        if let Some(members) = self.groups.get_mut(group) {
+            members.remove(member);
+            if members.is_empty() {
+                self.groups.remove(group);
+            }
+        }
+    }
+
+    // Returns the number of messages the group has not committed yet for every key,
+    // along with the members of the group. Without keys, every existing key is reported.
+    pub fn handle_group_lag(
+        &self,
+        group: Option<String>,
+        keys: Option<Vec<String>>,
+    ) -> (HashMap<String, u64>, Vec<String>) {
+        let group = group.unwrap_or_else(|| DEFAULT_GROUP.to_owned());
+        let keys = match keys {
+            Some(keys) => keys,
+            None => self.logs.keys().cloned().collect::<Vec<_>>(),
+        };
+
+        let mut lag = HashMap::with_capacity(keys.len());
+        for log_key in keys {
+            if let Some(log) = self.get_log(&log_key) {
+                lag.insert(log_key, log.lag(&group));
+            }
+        }
+
+        let mut members = self
+            .groups
+            .get(&group)
+            .map(|members| members.iter().cloned().collect::<Vec<_>>())
+            .unwrap_or_default();
+        members.sort();
+
+        (lag, members)
+    }
+
+    // Lists every existing key with its high-water mark, the offset the next message will get
+    pub fn handle_list_keys(&self) -> HashMap<String, u64> {
+        self.logs
+            .iter()
+            .map(|(log_key, log)| (log_key.clone(), log.high_water_mark()))
+            .collect::<HashMap<_, _>>()
+    }
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug)]
 struct Log {
-    messages: Vec<Message>,
+    // Keyed by offset. Retention and compaction can leave gaps.
+    messages: Box<dyn Store<u64, Message>>,
     offset: u64,
-    committed_offset: u64,
+    // The lowest offset that has not been removed by the retention policy
+    start_offset: u64,
+    // Committed offset of every consumer group
+    committed_offsets: HashMap<String, u64>,
+    policy: RetentionPolicy,
+    // Latest offset of every message key. Only maintained for compacted logs.
+    latest_by_key: HashMap<String, u64>,
+    // Every message is also written here when the log is persisted
+    disk: Option<DiskLog>,
+    // Holds the meta under offset 0 when the file store of the messages is the only copy
+    meta_store: Option<Box<dyn Store<u64, Meta>>>,
 }
 
 impl Log {
-    fn new() -> Log {
-        Log::default()
+    // The store may already hold messages, e.g. a file store after a restart
+    fn new(policy: RetentionPolicy, messages: Box<dyn Store<u64, Message>>) -> Log {
+        let mut log = Log {
+            messages,
+            offset: 0,
+            start_offset: 0,
+            committed_offsets: HashMap::new(),
+            policy,
+            latest_by_key: HashMap::new(),
+            disk: None,
+            meta_store: None,
+        };
+
+        for (offset, message) in log.messages.range(Unbounded, Unbounded) {
+            if log.offset == 0 {
+                log.start_offset = *offset;
+            }
+            log.offset = offset + 1;
+            if let Some(msg_key) = message.msg_key.as_ref().filter(|_| log.policy.compact) {
+                log.latest_by_key.insert(msg_key.clone(), *offset);
+            }
+        }
+        log
+    }
+
+    fn recover(
+        policy: RetentionPolicy,
+        recovered: Recovered,
+        messages: Box<dyn Store<u64, Message>>,
+    ) -> std::io::Result<Log> {
+        let mut log = Log::new(policy, messages);
+        log.apply_meta(recovered.meta);
+
+        for record in recovered.records {
+            // Compaction is applied again while replaying
+            log.push(Message::new(
+                record.offset,
+                record.msg,
+                record.msg_key,
+                record.producer,
+                record.timestamp,
+            ))?;
+        }
+
+        log.offset = recovered.next_offset.max(log.start_offset);
+        log.disk = Some(recovered.disk);
+        Ok(log)
     }
 
-    fn append(&mut self, message: Value) -> u64 {
+    fn append(
+        &mut self,
+        message: Value,
+        msg_key: Option<String>,
+        producer: Option<(String, u64)>,
+        timestamp: u64,
+    ) -> std::io::Result<u64> {
+        let message = self.prepare(message, msg_key, producer, None, timestamp)?;
+        let offset = message.offset;
+        self.push(message)?;
+        Ok(offset)
+    }
+
+    // Assigns an offset to the message and persists it. The message is not visible until pushed.
+    fn prepare(
+        &mut self,
+        message: Value,
+        msg_key: Option<String>,
+        producer: Option<(String, u64)>,
+        txn: Option<u64>,
+        timestamp: u64,
+    ) -> std::io::Result<Message> {
         // Create a new message
-        let message = Message::new(self.offset, Some(message));
+        let message = Message::new(self.offset, Some(message), msg_key, producer, timestamp);
+
+        // Persist the message before it becomes visible
+        if let Some(disk) = self.disk.as_mut() {
+            disk.append(&Record {
+                offset: message.offset,
+                msg: message.message.clone(),
+                msg_key: message.msg_key.clone(),
+                timestamp: message.timestamp,
+                producer: message.producer.clone(),
+                txn,
+            })?;
+        }
+
         self.offset += 1;
-        self.messages.push(message);
-        self.offset - 1
+        Ok(message)
     }
 
-    fn poll(&self, offset: u64) -> Vec<Vec<Value>> {
-        // Return a max of 10 after the offset
-        let max = 10;
+    fn sync(&mut self) -> std::io::Result<()> {
+        match self.disk.as_mut() {
+            Some(disk) => disk.sync(),
+            None => Ok(()),
+        }
+    }
 
-        let mut messages: Vec<Vec<Value>> = Vec::with_capacity(max);
+    // Returns the message that compaction removed, if any.
+    // Messages of batches spanning several owners are pushed after later offsets, and may be
+    // pushed twice
+    fn push(&mut self, message: Message) -> std::io::Result<Option<Message>> {
+        let offset = message.offset;
+        self.offset = self.offset.max(offset + 1);
+        // Removed by the retention policy, or already replaced by a later value of its key
+        let msg_key = message.msg_key.clone().filter(|_| self.policy.compact);
+        let superseded = msg_key
+            .as_ref()
+            .and_then(|msg_key| self.latest_by_key.get(msg_key))
+            .is_some_and(|latest| *latest > offset);
+        if offset < self.start_offset || superseded {
+            return Ok(None);
+        }
+        self.messages.put(offset, message)?;
 
-        //NOTE:  offset.try_into.unwrap will never unwrap in a 64 bit architecture
-        for message in self
+        if let Some(msg_key) = msg_key {
+            // The latest value per message key wins
+            match self.latest_by_key.insert(msg_key, offset) {
+                Some(previous) if previous != offset => return self.messages.delete(&previous),
+                _ => {}
+            }
+        }
+        Ok(None)
+    }
+
+    // Undoes `push`. The next offset stays where it is
+    fn unpush(&mut self, offset: u64, replaced: Option<Message>) -> std::io::Result<()> {
+        let message = match self.messages.delete(&offset)? {
+            Some(message) => message,
+            None => return Ok(()),
+        };
+        if let Some(msg_key) = message.msg_key.filter(|_| self.policy.compact) {
+            match replaced {
+                Some(replaced) => {
+                    self.latest_by_key.insert(msg_key, replaced.offset);
+                    self.messages.put(replaced.offset, replaced)?;
+                }
+                None => {
+                    self.latest_by_key.remove(&msg_key);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn meta(&self) -> Meta {
+        let mut groups = self.committed_offsets.clone();
+        Meta {
+            committed_offset: groups.remove(DEFAULT_GROUP),
+            groups,
+            start_offset: self.start_offset,
+        }
+    }
+
+    // The meta found on disk. Messages before the start offset may still be in the store
+    fn apply_meta(&mut self, meta: Meta) {
+        self.start_offset = self.start_offset.max(meta.start_offset);
+        self.committed_offsets = meta.groups;
+        if let Some(offset) = meta.committed_offset {
+            self.committed_offsets
+                .insert(DEFAULT_GROUP.to_owned(), offset);
+        }
+    }
+
+    fn write_meta(&mut self) -> std::io::Result<()> {
+        let meta = self.meta();
+        if let Some(disk) = self.disk.as_ref() {
+            disk.write_meta(&meta)?;
+        }
+        if let Some(store) = self.meta_store.as_mut() {
+            store.put(0, meta)?;
+        }
+        Ok(())
+    }
+
+    fn apply_retention(&mut self, now: u64) {
+        let mut retained = self.messages.len();
+        if let Some(max) = self.policy.max_messages {
+            retained = retained.min(max);
+        }
+
+        // Messages are appended in order, so the expired and committed ones are at the front
+        let expired = match self.policy.max_age {
+            Some(max_age) => {
+                let deadline = now.saturating_sub(max_age.as_millis() as u64);
+                self.messages
+                    .range(Unbounded, Unbounded)
+                    .take_while(|(_, m)| m.timestamp < deadline)
+                    .count()
+            }
+            None => 0,
+        };
+        // Only delete what every group has committed
+        let committed = match (
+            self.policy.delete_committed,
+            self.committed_offsets.values().min(),
+        ) {
+            (true, Some(committed)) => self
+                .messages
+                .range(Unbounded, Unbounded)
+                .take_while(|(offset, _)| *offset <= committed)
+                .count(),
+            _ => 0,
+        };
+
+        let removed = (self.messages.len() - retained).max(expired).max(committed);
+        if removed == 0 {
+            return;
+        }
+
+        let offsets = self
             .messages
-            .iter()
-            .skip(offset.try_into().unwrap())
-            .take(max)
-        {
+            .range(Unbounded, Unbounded)
+            .take(removed)
+            .map(|(offset, _)| *offset)
+            .collect::<Vec<_>>();
+        for offset in offsets {
+            let message = match self.messages.delete(&offset) {
+                Ok(Some(message)) => message,
+                Ok(None) => continue,
+                // The rest is removed on the next pass
+                Err(err) => {
+                    eprintln!(
+                        "failed to remove offset {} from a log: \n err: {:?}",
+                        offset, err
+                    );
+                    break;
+                }
+            };
+            self.start_offset = message.offset + 1;
+
+            if let Some(msg_key) = message.msg_key {
+                if self.latest_by_key.get(&msg_key) == Some(&message.offset) {
+                    self.latest_by_key.remove(&msg_key);
+                }
+            }
+        }
+
+        // The messages are already gone from memory. A failure here only delays freeing the disk space.
+        let start_offset = self.start_offset;
+        let result = self.write_meta().and_then(|_| match self.disk.as_mut() {
+            Some(disk) => disk.truncate_before(start_offset),
+            None => Ok(()),
+        });
+        if let Err(err) = result {
+            eprintln!("failed to apply retention on disk: \n err: {:?}", err);
+        }
+    }
+
+    // Messages from `offset`, up to `before` when given
+    fn poll(&self, offset: u64, max: usize, before: Option<u64>) -> Vec<Vec<Value>> {
+        let mut messages: Vec<Vec<Value>> = Vec::with_capacity(max);
+
+        let end = match before.as_ref() {
+            Some(before) => Excluded(before),
+            None => Unbounded,
+        };
+        if before.is_some_and(|before| before <= offset) {
+            return messages;
+        }
+        for (_, message) in self.messages.range(Included(&offset), end).take(max) {
             let m_message = vec![
                 serde_json::Value::from(message.offset),
                 message.message.to_owned().into(),
@@ -118,23 +1440,511 @@ impl Log {
         messages
     }
 
-    fn committ_offsets(&mut self, offset: u64) {
-        self.committed_offset = offset;
+    fn high_water_mark(&self) -> u64 {
+        self.offset
     }
 
-    fn list_committed_offset(&self) -> u64 {
-        self.committed_offset
+    // Offset of the last appended message
+    fn latest_offset(&self) -> Option<u64> {
+        self.offset.checked_sub(1)
+    }
+
+    fn committ_offsets(&mut self, group: &str, offset: u64) -> std::io::Result<()> {
+        // Commits never move backwards. A stale commit is ignored.
+        if self
+            .committed_offsets
+            .get(group)
+            .is_some_and(|committed| offset <= *committed)
+        {
+            return Ok(());
+        }
+
+        let previous = self.committed_offsets.insert(group.to_owned(), offset);
+        if let Err(err) = self.write_meta() {
+            match previous {
+                Some(previous) => self.committed_offsets.insert(group.to_owned(), previous),
+                None => self.committed_offsets.remove(group),
+            };
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    fn list_committed_offset(&self, group: &str) -> Option<u64> {
+        self.committed_offsets.get(group).copied()
+    }
+
+    // Number of retained messages after the committed offset of the group
+    fn lag(&self, group: &str) -> u64 {
+        let consumed = match self.committed_offsets.get(group) {
+            Some(committed) => committed + 1,
+            None => self.start_offset,
+        };
+        let consumed = consumed.max(self.start_offset);
+        self.messages.range(Included(&consumed), Unbounded).count() as u64
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct Message {
     offset: u64,
     message: Option<Value>,
+    msg_key: Option<String>,
+    // Producer id and sequence of an idempotent append, to rebuild the producer windows
+    #[serde(default)]
+    producer: Option<(String, u64)>,
+    // Milliseconds since the unix epoch
+    timestamp: u64,
 }
 
 impl Message {
-    fn new(offset: u64, message: Option<Value>) -> Self {
-        Message { offset, message }
+    fn new(
+        offset: u64,
+        message: Option<Value>,
+        msg_key: Option<String>,
+        producer: Option<(String, u64)>,
+        timestamp: u64,
+    ) -> Self {
+        Message {
+            offset,
+            message,
+            msg_key,
+            producer,
+            timestamp,
+        }
+    }
+}
+
+// Picks the messages of every key within the total limits of a poll.
+// `candidates` holds the messages of every key, already capped per key.
+pub fn interleave(
+    candidates: Vec<(String, Vec<Vec<Value>>)>,
+    limits: &PollLimits,
+) -> HashMap<String, Vec<Vec<Value>>> {
+    let mut candidates = candidates
+        .into_iter()
+        .map(|(log_key, messages)| (log_key, messages.into_iter()))
+        .collect::<Vec<_>>();
+
+    // Take one message per key in turns so that a busy key does not starve the others
+    // once the total limits are reached.
+    let mut output = candidates
+        .iter()
+        .map(|(log_key, _)| (log_key.clone(), vec![]))
+        .collect::<HashMap<_, Vec<Vec<Value>>>>();
+    let mut total_messages = 0;
+    let mut total_bytes = 0;
+
+    'outer: loop {
+        let mut progressed = false;
+
+        for (log_key, messages) in candidates.iter_mut() {
+            let message = match messages.next() {
+                Some(message) => message,
+                None => continue,
+            };
+
+            if limits.max_messages.is_some_and(|max| total_messages >= max) {
+                break 'outer;
+            }
+
+            let size = serde_json::to_string(&message).map_or(0, |m| m.len());
+            // Always return at least one message, even when it is bigger than the limit.
+            // Otherwise the client would never make progress.
+            if total_messages > 0 && limits.max_bytes.is_some_and(|max| total_bytes + size > max) {
+                break 'outer;
+            }
+
+            total_messages += 1;
+            total_bytes += size;
+            progressed = true;
+
+            // The key is always present in the output
+            output.get_mut(log_key).unwrap().push(message);
+        }
+
+        if !progressed {
+            break;
+        }
+    }
+
+    output
+}
+
+// Every key is owned by exactly one node of the cluster.
+// `node_ids` must be sorted so that every node agrees on the owner.
+pub fn key_owner<'a>(key: &str, node_ids: &'a [String]) -> Option<&'a String> {
+    if node_ids.is_empty() {
+        return None;
+    }
+
+    // FNV-1a. Stable across processes, unlike the std hasher
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in key.bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+
+    node_ids.get((hash % node_ids.len() as u64) as usize)
+}
+
+// Milliseconds since the unix epoch. Appended messages are stamped with it
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::config::{DiskConfig, FsyncPolicy};
+
+    fn file_config(name: &str) -> LogConfig {
+        let dir = std::env::temp_dir().join(format!("klog-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        LogConfig {
+            store: StoreKind::File(dir, FsyncPolicy::Never),
+            ..LogConfig::default()
+        }
+    }
+
+    fn append(klog: &mut KLog, key: &str, producer: Option<(&str, u64)>) -> Result<u64, LogError> {
+        klog.handle_append(
+            key.to_owned(),
+            Value::from(1),
+            None,
+            producer.map(|(producer_id, _)| producer_id.to_owned()),
+            producer.map(|(_, seq)| seq),
+            now(),
+        )
+    }
+
+    #[test]
+    fn file_store_keeps_commits_and_producers() {
+        let config = file_config("restart");
+        let mut klog = KLog::new(config.clone()).unwrap();
+        append(&mut klog, "k1", Some(("p1", 0))).unwrap();
+        append(&mut klog, "k1", Some(("p1", 1))).unwrap();
+        klog.handle_commit_offsets(HashMap::from([("k1".to_owned(), 1)]), None)
+            .unwrap();
+        drop(klog);
+
+        let mut klog = KLog::new(config).unwrap();
+        let committed = klog.handle_list_committed_offsets(vec!["k1".to_owned()], None);
+        assert_eq!(committed, HashMap::from([("k1".to_owned(), 1)]));
+        // A retry after the restart gets the offset of the original append
+        assert_eq!(append(&mut klog, "k1", Some(("p1", 1))).unwrap(), 1);
+        assert_eq!(append(&mut klog, "k1", None).unwrap(), 2);
+    }
+
+    fn window_config(size: usize) -> LogConfig {
+        LogConfig {
+            producer_window: size,
+            ..LogConfig::default()
+        }
+    }
+
+    #[test]
+    fn producer_retries_get_the_original_offset() {
+        let mut klog = KLog::new(window_config(3)).unwrap();
+        assert_eq!(append(&mut klog, "k1", Some(("p1", 0))).unwrap(), 0);
+        assert_eq!(append(&mut klog, "k1", None).unwrap(), 1);
+        assert_eq!(append(&mut klog, "k1", Some(("p1", 1))).unwrap(), 2);
+
+        assert_eq!(append(&mut klog, "k1", Some(("p1", 0))).unwrap(), 0);
+        assert_eq!(append(&mut klog, "k1", Some(("p1", 1))).unwrap(), 2);
+        assert_eq!(poll(&klog, "k1"), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn producer_sequences_may_arrive_out_of_order() {
+        let mut klog = KLog::new(window_config(3)).unwrap();
+        assert_eq!(append(&mut klog, "k1", Some(("p1", 2))).unwrap(), 0);
+        assert_eq!(append(&mut klog, "k1", Some(("p1", 0))).unwrap(), 1);
+        assert_eq!(append(&mut klog, "k1", Some(("p1", 1))).unwrap(), 2);
+
+        assert_eq!(append(&mut klog, "k1", Some(("p1", 2))).unwrap(), 0);
+        assert_eq!(append(&mut klog, "k1", Some(("p1", 0))).unwrap(), 1);
+        assert_eq!(append(&mut klog, "k1", Some(("p1", 1))).unwrap(), 2);
+    }
+
+    #[test]
+    fn sequences_older_than_a_full_window_are_rejected() {
+        let mut klog = KLog::new(window_config(3)).unwrap();
+        for seq in 0..5 {
+            assert_eq!(append(&mut klog, "k1", Some(("p1", seq))).unwrap(), seq);
+        }
+
+        // The window holds 2, 3 and 4
+        assert!(matches!(
+            append(&mut klog, "k1", Some(("p1", 1))),
+            Err(LogError::StaleSequence {
+                seq: 1,
+                oldest: 2,
+                ..
+            })
+        ));
+        assert_eq!(append(&mut klog, "k1", Some(("p1", 2))).unwrap(), 2);
+        assert_eq!(append(&mut klog, "k1", Some(("p1", 6))).unwrap(), 5);
+    }
+
+    #[test]
+    fn producer_windows_are_kept_per_producer_and_key() {
+        let mut klog = KLog::new(window_config(3)).unwrap();
+        assert_eq!(append(&mut klog, "k1", Some(("p1", 0))).unwrap(), 0);
+        assert_eq!(append(&mut klog, "k2", Some(("p1", 0))).unwrap(), 0);
+        assert_eq!(append(&mut klog, "k1", Some(("p2", 0))).unwrap(), 1);
+
+        // Filling the window of one key leaves the others alone
+        for seq in 1..5 {
+            append(&mut klog, "k1", Some(("p1", seq))).unwrap();
+        }
+        assert_eq!(append(&mut klog, "k2", Some(("p1", 0))).unwrap(), 0);
+        assert_eq!(append(&mut klog, "k1", Some(("p2", 0))).unwrap(), 1);
+    }
+
+    fn batch(keys: &[&str]) -> Vec<BatchMessage> {
+        keys.iter()
+            .map(|key| BatchMessage {
+                key: key.to_string(),
+                msg: Value::from(1),
+                msg_key: None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn interrupted_batch_is_rolled_forward() {
+        let config = file_config("batch");
+        let mut klog = KLog::new(config.clone()).unwrap();
+        append(&mut klog, "k1", None).unwrap();
+        klog.handle_send_batch(batch(&["k1", "k2"]), HashMap::new(), None, now())
+            .unwrap();
+
+        // A crash after staging the next batch and pushing only its first message
+        let k1 = klog.logs.get_mut("k1").unwrap();
+        let first = Message::new(2, Some(Value::from(2)), None, None, now());
+        k1.push(first.clone()).unwrap();
+        let second = Message::new(1, Some(Value::from(2)), None, None, now());
+        let staged = StagedBatch {
+            messages: vec![("k1".to_owned(), first), ("k2".to_owned(), second)],
+            offsets: HashMap::from([("k1".to_owned(), 2)]),
+            group: None,
+        };
+        klog.batches.as_mut().unwrap().put(7, staged).unwrap();
+        drop(klog);
+
+        let mut klog = KLog::new(config.clone()).unwrap();
+        let polled = klog
+            .handle_poll(
+                HashMap::from([("k1".to_owned(), 0), ("k2".to_owned(), 0)]),
+                None,
+                None,
+                None,
+            )
+            .unwrap();
+        assert_eq!(polled["k1"].len(), 3);
+        assert_eq!(polled["k2"].len(), 2);
+        let committed = klog.handle_list_committed_offsets(vec!["k1".to_owned()], None);
+        assert_eq!(committed, HashMap::from([("k1".to_owned(), 2)]));
+        assert!(klog.batches.as_ref().unwrap().is_empty());
+
+        // Batches go on after the recovered one
+        klog.handle_send_batch(batch(&["k2"]), HashMap::new(), None, now())
+            .unwrap();
+        drop(klog);
+        let klog = KLog::new(config).unwrap();
+        assert_eq!(klog.handle_list_keys()["k2"], 3);
+    }
+
+    #[test]
+    fn unpush_restores_the_compacted_message() {
+        let policy = RetentionPolicy {
+            compact: true,
+            ..RetentionPolicy::default()
+        };
+        let mut log = Log::new(policy, open_store(&StoreKind::Ordered, "").unwrap());
+        let message = |offset| Message::new(offset, None, Some("a".to_owned()), None, 0);
+        log.push(message(0)).unwrap();
+        let replaced = log.push(message(1)).unwrap();
+        assert_eq!(replaced.as_ref().map(|message| message.offset), Some(0));
+
+        log.unpush(1, replaced).unwrap();
+        assert_eq!(
+            log.poll(0, 10, None),
+            vec![vec![Value::from(0), Value::Null]]
+        );
+        // A later message of the key compacts the restored one
+        log.push(message(2)).unwrap();
+        assert_eq!(
+            log.poll(0, 10, None),
+            vec![vec![Value::from(2), Value::Null]]
+        );
+    }
+
+    #[test]
+    fn file_store_keeps_offsets_past_removed_messages() {
+        let mut config = file_config("retention");
+        config.retention.delete_committed = true;
+        let mut klog = KLog::new(config.clone()).unwrap();
+        append(&mut klog, "k1", None).unwrap();
+        append(&mut klog, "k1", None).unwrap();
+        klog.handle_commit_offsets(HashMap::from([("k1".to_owned(), 1)]), None)
+            .unwrap();
+        drop(klog);
+
+        // Every message is gone, but offsets are not reused
+        let mut klog = KLog::new(config).unwrap();
+        assert_eq!(append(&mut klog, "k1", None).unwrap(), 2);
+    }
+
+    fn segment_config(name: &str) -> LogConfig {
+        let dir = std::env::temp_dir().join(format!("klog-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        LogConfig {
+            storage: Some(DiskConfig::new(dir)),
+            ..LogConfig::default()
+        }
+    }
+
+    fn poll(klog: &KLog, key: &str) -> Vec<u64> {
+        let polled = klog
+            .handle_poll(HashMap::from([(key.to_owned(), 0)]), None, None, None)
+            .unwrap();
+        polled[key]
+            .iter()
+            .map(|message| message[0].as_u64().unwrap())
+            .collect()
+    }
+
+    fn prepare(klog: &mut KLog, txn: &str, keys: &[&str]) -> Vec<u64> {
+        klog.handle_txn_prepare(
+            txn.to_owned(),
+            "n1".to_owned(),
+            batch(keys),
+            HashMap::new(),
+            None,
+            now(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn polls_stop_before_undecided_batches() {
+        let mut klog = KLog::new(LogConfig::default()).unwrap();
+        assert_eq!(prepare(&mut klog, "t1", &["k1"]), vec![0]);
+        // A retried prepare gets the same offsets
+        assert_eq!(prepare(&mut klog, "t1", &["k1"]), vec![0]);
+        assert_eq!(append(&mut klog, "k1", None).unwrap(), 1);
+        assert!(poll(&klog, "k1").is_empty());
+
+        assert_eq!(klog.handle_txn_decide("t1", true).unwrap(), vec!["k1"]);
+        assert_eq!(poll(&klog, "k1"), vec![0, 1]);
+        // Decided already
+        assert!(klog.handle_txn_decide("t1", false).unwrap().is_empty());
+
+        assert_eq!(prepare(&mut klog, "t2", &["k1", "k1"]), vec![2, 3]);
+        klog.handle_txn_decide("t2", false).unwrap();
+        assert_eq!(append(&mut klog, "k1", None).unwrap(), 4);
+        assert_eq!(poll(&klog, "k1"), vec![0, 1, 4]);
+    }
+
+    #[test]
+    fn late_commits_keep_the_latest_value_of_a_compacted_key() {
+        let mut config = LogConfig::default();
+        config.retention.compact = true;
+        let mut klog = KLog::new(config).unwrap();
+        let messages = vec![BatchMessage {
+            key: "k1".to_owned(),
+            msg: Value::from(1),
+            msg_key: Some("a".to_owned()),
+        }];
+        klog.handle_txn_prepare(
+            "t1".to_owned(),
+            "n1".to_owned(),
+            messages,
+            HashMap::new(),
+            None,
+            now(),
+        )
+        .unwrap();
+        klog.handle_append(
+            "k1".to_owned(),
+            Value::from(2),
+            Some("a".to_owned()),
+            None,
+            None,
+            now(),
+        )
+        .unwrap();
+
+        klog.handle_txn_decide("t1", true).unwrap();
+        assert_eq!(poll(&klog, "k1"), vec![1]);
+    }
+
+    #[test]
+    fn prepared_batches_survive_restarts() {
+        for config in [file_config("prepared"), segment_config("prepared-segments")] {
+            let mut klog = KLog::new(config.clone()).unwrap();
+            append(&mut klog, "k1", None).unwrap();
+            let offsets = HashMap::from([("k1".to_owned(), 0)]);
+            klog.handle_txn_prepare(
+                "t1".to_owned(),
+                "n1".to_owned(),
+                batch(&["k1", "k2"]),
+                offsets,
+                None,
+                now(),
+            )
+            .unwrap();
+            prepare(&mut klog, "t2", &["k2"]);
+            drop(klog);
+
+            let mut klog = KLog::new(config.clone()).unwrap();
+            let mut prepared = klog.prepared_txns();
+            prepared.sort();
+            assert_eq!(
+                prepared,
+                vec![
+                    ("t1".to_owned(), "n1".to_owned()),
+                    ("t2".to_owned(), "n1".to_owned())
+                ]
+            );
+            assert_eq!(poll(&klog, "k1"), vec![0]);
+            // Offsets of prepared messages are not reused
+            assert_eq!(append(&mut klog, "k2", None).unwrap(), 2);
+
+            klog.handle_txn_decide("t1", true).unwrap();
+            klog.handle_txn_decide("t2", false).unwrap();
+            drop(klog);
+
+            let klog = KLog::new(config).unwrap();
+            assert!(!klog.has_prepared());
+            assert_eq!(poll(&klog, "k1"), vec![0, 1]);
+            assert_eq!(poll(&klog, "k2"), vec![0, 2]);
+            let committed = klog.handle_list_committed_offsets(vec!["k1".to_owned()], None);
+            assert_eq!(committed, HashMap::from([("k1".to_owned(), 0)]));
+        }
+    }
+
+    #[test]
+    fn committed_part_is_rolled_forward() {
+        let config = file_config("decided");
+        let mut klog = KLog::new(config.clone()).unwrap();
+        prepare(&mut klog, "t1", &["k1"]);
+
+        // A crash after storing the decision, before pushing the messages
+        let mut prepared = klog.prepared["t1"].clone();
+        prepared.committed = true;
+        let store = klog.prepared_store.as_mut().unwrap();
+        store.put("t1".to_owned(), prepared).unwrap();
+        drop(klog);
+
+        let klog = KLog::new(config).unwrap();
+        assert!(!klog.is_prepared("t1"));
+        assert_eq!(poll(&klog, "k1"), vec![0]);
+        assert!(klog.prepared_store.as_ref().unwrap().is_empty());
     }
 }
@@ -1 +1,4 @@
 pub mod log;
+pub mod segment;
+pub mod shared;
+pub mod txn;
@@ -0,0 +1,694 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::config::config::{DiskConfig, FsyncPolicy};
+
+// On disk layout:
+//
+//  <dir>/<hex encoded key>/meta                    committed offsets of every group and start offset
+//  <dir>/<hex encoded key>/<base offset>.log       one json record per line
+//  <dir>/<hex encoded key>/<base offset>.index     (offset, byte position) pairs for the records above
+//  <dir>/transactions                              one marker per committed transaction
+//
+// Records appended by a transaction are only recovered when the transaction's marker exists.
+//
+// Segments are append only. Only the meta file is rewritten, through a rename so that it is never torn.
+
+const INDEX_ENTRY_BYTES: u64 = 16;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Record {
+    pub offset: u64,
+    pub msg: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub msg_key: Option<String>,
+    pub timestamp: u64,
+    // Kept so that the producer windows can be rebuilt on recovery
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub producer: Option<(String, u64)>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub txn: Option<u64>,
+}
+
+// Written once every record of a transaction is on disk
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TxnMarker {
+    pub txn: u64,
+    // Offsets committed by the transaction
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub offsets: HashMap<String, u64>,
+}
+
+#[derive(Debug)]
+pub struct TxnLog {
+    file: File,
+    fsync: FsyncPolicy,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Meta {
+    // Committed offset of the default consumer group
+    pub committed_offset: Option<u64>,
+    // Committed offsets of the other consumer groups
+    #[serde(default)]
+    pub groups: HashMap<String, u64>,
+    pub start_offset: u64,
+}
+
+#[derive(Debug)]
+pub struct DiskLog {
+    dir: PathBuf,
+    config: DiskConfig,
+    // Sorted by base offset. The last one is the active segment.
+    segments: Vec<Segment>,
+    // Appends since the last sync
+    unsynced: u64,
+}
+
+#[derive(Debug)]
+struct Segment {
+    base_offset: u64,
+    log: File,
+    index: File,
+    size: u64,
+    // Offset the next record appended to this segment will get
+    next_offset: u64,
+}
+
+// A log read back from disk
+#[derive(Debug)]
+pub struct Recovered {
+    pub key: String,
+    pub disk: DiskLog,
+    pub meta: Meta,
+    // Records at or after the start offset
+    pub records: Vec<Record>,
+    pub next_offset: u64,
+}
+
+impl DiskLog {
+    pub fn create(config: &DiskConfig, key: &str) -> io::Result<DiskLog> {
+        let dir = config.dir.join(encode_key(key));
+        fs::create_dir_all(&dir)?;
+
+        let mut disk = DiskLog {
+            dir,
+            config: config.clone(),
+            segments: vec![],
+            unsynced: 0,
+        };
+        disk.roll(0)?;
+        disk.write_meta(&Meta::default())?;
+        Ok(disk)
+    }
+
+    // Reads every log found in the configured directory
+    pub fn recover(config: &DiskConfig) -> io::Result<Vec<Recovered>> {
+        fs::create_dir_all(&config.dir)?;
+
+        let mut logs = vec![];
+        for entry in fs::read_dir(&config.dir)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+
+            let key = match entry.file_name().to_str().and_then(decode_key) {
+                Some(key) => key,
+                None => {
+                    eprintln!(
+                        "skipping unknown directory in the log dir: {:?}",
+                        entry.path()
+                    );
+                    continue;
+                }
+            };
+
+            logs.push(DiskLog::recover_log(config, key, entry.path())?);
+        }
+        Ok(logs)
+    }
+
+    fn recover_log(config: &DiskConfig, key: String, dir: PathBuf) -> io::Result<Recovered> {
+        let meta = match fs::read(dir.join("meta")) {
+            Ok(bytes) => serde_json::from_slice::<Meta>(&bytes).map_err(io::Error::other)?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Meta::default(),
+            Err(err) => return Err(err),
+        };
+
+        let mut base_offsets = vec![];
+        for entry in fs::read_dir(&dir)? {
+            let name = entry?.file_name();
+            let name = name.to_string_lossy();
+            if let Some(base_offset) = name.strip_suffix(".log") {
+                if let Ok(base_offset) = base_offset.parse::<u64>() {
+                    base_offsets.push(base_offset);
+                }
+            }
+        }
+        base_offsets.sort();
+
+        let mut disk = DiskLog {
+            dir,
+            config: config.clone(),
+            segments: vec![],
+            unsynced: 0,
+        };
+        let mut records = vec![];
+
+        for (i, base_offset) in base_offsets.iter().enumerate() {
+            // Segments entirely below the start offset were being deleted when the node stopped
+            let is_last = i + 1 == base_offsets.len();
+            if !is_last && base_offsets[i + 1] <= meta.start_offset {
+                disk.remove_segment_files(*base_offset)?;
+                continue;
+            }
+
+            let (segment, segment_records) =
+                disk.recover_segment(*base_offset, meta.start_offset)?;
+            records.extend(segment_records);
+            disk.segments.push(segment);
+        }
+
+        if disk.segments.is_empty() {
+            disk.roll(meta.start_offset)?;
+        }
+
+        let next_offset = disk.next_offset();
+        Ok(Recovered {
+            key,
+            disk,
+            meta,
+            records,
+            next_offset,
+        })
+    }
+
+    fn recover_segment(
+        &self,
+        base_offset: u64,
+        start_offset: u64,
+    ) -> io::Result<(Segment, Vec<Record>)> {
+        let (mut log, mut index) = self.open_segment_files(base_offset)?;
+
+        // Use the index to skip the records below the start offset
+        let mut entries = vec![];
+        index.read_to_end(&mut entries)?;
+        let entries = entries
+            .chunks_exact(INDEX_ENTRY_BYTES as usize)
+            .map(|entry| {
+                let offset = u64::from_le_bytes(entry[..8].try_into().unwrap());
+                let position = u64::from_le_bytes(entry[8..].try_into().unwrap());
+                (offset, position)
+            })
+            .collect::<Vec<_>>();
+        let skipped = entries.partition_point(|(offset, _)| *offset < start_offset);
+        // When every indexed record is below the start offset, continue from the last one.
+        // The records written after it may be missing from the index.
+        let mut indexed = if skipped < entries.len() {
+            skipped
+        } else {
+            skipped.saturating_sub(1)
+        };
+        let mut position = entries.get(indexed).map_or(0, |(_, position)| *position);
+
+        log.seek(SeekFrom::Start(position))?;
+        let mut reader = BufReader::new(&log);
+        let mut records = vec![];
+        let mut rebuilding = false;
+        let mut next_offset = base_offset.max(start_offset);
+
+        loop {
+            let mut line = String::new();
+            let read = reader.read_line(&mut line)?;
+            // A line without a newline is a write that was interrupted
+            if read == 0 || !line.ends_with('\n') {
+                break;
+            }
+            let record = match serde_json::from_str::<Record>(&line) {
+                Ok(record) => record,
+                Err(_) => break,
+            };
+
+            let record_position = position;
+            position += read as u64;
+            next_offset = next_offset.max(record.offset + 1);
+
+            // The index may be missing the records written right before a crash
+            if !rebuilding
+                && entries
+                    .get(indexed)
+                    .is_some_and(|(offset, _)| *offset == record.offset)
+            {
+                indexed += 1;
+            } else {
+                if !rebuilding {
+                    truncate_index(&index, indexed)?;
+                    rebuilding = true;
+                }
+                write_index_entry(&mut index, record.offset, record_position)?;
+            }
+
+            if record.offset >= start_offset {
+                records.push(record);
+            }
+        }
+
+        // Drop whatever follows the last complete record
+        drop(reader);
+        log.set_len(position)?;
+        if !rebuilding && indexed < entries.len() {
+            truncate_index(&index, indexed)?;
+        }
+
+        let segment = Segment {
+            base_offset,
+            log,
+            index,
+            size: position,
+            next_offset,
+        };
+        Ok((segment, records))
+    }
+
+    // Offset the next appended record will get
+    pub fn next_offset(&self) -> u64 {
+        self.segments
+            .last()
+            .map_or(0, |segment| segment.next_offset)
+    }
+
+    pub fn append(&mut self, record: &Record) -> io::Result<()> {
+        let needs_roll = self
+            .segments
+            .last()
+            .is_none_or(|segment| segment.size >= self.config.segment_bytes);
+        if needs_roll {
+            self.roll(record.offset)?;
+        }
+
+        let mut line = serde_json::to_vec(record).map_err(io::Error::other)?;
+        line.push(b'\n');
+
+        // A segment always exists after the roll above
+        let segment = self.segments.last_mut().unwrap();
+        segment.log.write_all(&line)?;
+        write_index_entry(&mut segment.index, record.offset, segment.size)?;
+        segment.size += line.len() as u64;
+        segment.next_offset = record.offset + 1;
+
+        self.unsynced += 1;
+        let sync = match self.config.fsync {
+            FsyncPolicy::Always => true,
+            FsyncPolicy::Every(n) => self.unsynced >= n,
+            FsyncPolicy::Never => false,
+        };
+        if sync {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    pub fn sync(&mut self) -> io::Result<()> {
+        if let Some(segment) = self.segments.last() {
+            segment.log.sync_data()?;
+            segment.index.sync_data()?;
+        }
+        self.unsynced = 0;
+        Ok(())
+    }
+
+    pub fn write_meta(&self, meta: &Meta) -> io::Result<()> {
+        let tmp = self.dir.join("meta.tmp");
+        let bytes = serde_json::to_vec(meta).map_err(io::Error::other)?;
+
+        let mut file = File::create(&tmp)?;
+        file.write_all(&bytes)?;
+        if self.config.fsync != FsyncPolicy::Never {
+            file.sync_data()?;
+        }
+        fs::rename(tmp, self.dir.join("meta"))?;
+        if self.config.fsync != FsyncPolicy::Never {
+            self.sync_dir()?;
+        }
+        Ok(())
+    }
+
+    // Makes renames and new files in the directory durable
+    fn sync_dir(&self) -> io::Result<()> {
+        File::open(&self.dir)?.sync_all()
+    }
+
+    // Deletes the segments that only hold records below the start offset.
+    // The active segment is always kept.
+    pub fn truncate_before(&mut self, start_offset: u64) -> io::Result<()> {
+        while self.segments.len() > 1 && self.segments[1].base_offset <= start_offset {
+            let segment = self.segments.remove(0);
+            self.remove_segment_files(segment.base_offset)?;
+        }
+        Ok(())
+    }
+
+    fn roll(&mut self, base_offset: u64) -> io::Result<()> {
+        // `sync` only reaches the active segment, so the records of this one are synced now
+        if self.config.fsync != FsyncPolicy::Never && self.unsynced > 0 {
+            self.sync()?;
+        }
+
+        let (log, index) = self.open_segment_files(base_offset)?;
+        if self.config.fsync != FsyncPolicy::Never {
+            self.sync_dir()?;
+        }
+        self.segments.push(Segment {
+            base_offset,
+            log,
+            index,
+            size: 0,
+            next_offset: base_offset,
+        });
+        Ok(())
+    }
+
+    fn open_segment_files(&self, base_offset: u64) -> io::Result<(File, File)> {
+        let (log_path, index_path) = segment_paths(&self.dir, base_offset);
+        let open = |path: &Path| {
+            OpenOptions::new()
+                .read(true)
+                .append(true)
+                .create(true)
+                .open(path)
+        };
+        Ok((open(&log_path)?, open(&index_path)?))
+    }
+
+    fn remove_segment_files(&self, base_offset: u64) -> io::Result<()> {
+        let (log_path, index_path) = segment_paths(&self.dir, base_offset);
+        fs::remove_file(log_path)?;
+        match fs::remove_file(index_path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl TxnLog {
+    // Returns the markers of every committed transaction
+    pub fn recover(config: &DiskConfig) -> io::Result<(TxnLog, Vec<TxnMarker>)> {
+        fs::create_dir_all(&config.dir)?;
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(config.dir.join("transactions"))?;
+
+        let mut markers = vec![];
+        let mut position = 0;
+        let mut reader = BufReader::new(&file);
+        loop {
+            let mut line = String::new();
+            let read = reader.read_line(&mut line)?;
+            if read == 0 || !line.ends_with('\n') {
+                break;
+            }
+            match serde_json::from_str::<TxnMarker>(&line) {
+                Ok(marker) => markers.push(marker),
+                Err(_) => break,
+            }
+            position += read as u64;
+        }
+        drop(reader);
+
+        // A torn marker means the transaction never committed
+        file.set_len(position)?;
+
+        let txn_log = TxnLog {
+            file,
+            fsync: config.fsync,
+        };
+        Ok((txn_log, markers))
+    }
+
+    pub fn commit(&mut self, marker: &TxnMarker) -> io::Result<()> {
+        let mut line = serde_json::to_vec(marker).map_err(io::Error::other)?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+
+        // The marker is what makes the transaction durable, so it is synced unless syncing is disabled
+        if self.fsync != FsyncPolicy::Never {
+            self.file.sync_data()?;
+        }
+        Ok(())
+    }
+}
+
+fn segment_paths(dir: &Path, base_offset: u64) -> (PathBuf, PathBuf) {
+    (
+        dir.join(format!("{:020}.log", base_offset)),
+        dir.join(format!("{:020}.index", base_offset)),
+    )
+}
+
+fn write_index_entry(index: &mut File, offset: u64, position: u64) -> io::Result<()> {
+    let mut entry = [0u8; INDEX_ENTRY_BYTES as usize];
+    entry[..8].copy_from_slice(&offset.to_le_bytes());
+    entry[8..].copy_from_slice(&position.to_le_bytes());
+    index.write_all(&entry)
+}
+
+fn truncate_index(index: &File, entries: usize) -> io::Result<()> {
+    index.set_len(entries as u64 * INDEX_ENTRY_BYTES)
+}
+
+// Keys are hex encoded so that any key is a valid directory name
+pub fn encode_key(key: &str) -> String {
+    key.bytes().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn decode_key(name: &str) -> Option<String> {
+    if !name.len().is_multiple_of(2) {
+        return None;
+    }
+    let bytes = (0..name.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(name.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<_>>>()?;
+    String::from_utf8(bytes).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(name: &str, segment_bytes: u64) -> DiskConfig {
+        let dir = std::env::temp_dir().join(format!("segment-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        DiskConfig {
+            segment_bytes,
+            ..DiskConfig::new(dir)
+        }
+    }
+
+    fn record(offset: u64) -> Record {
+        Record {
+            offset,
+            msg: Some(Value::from(offset)),
+            msg_key: None,
+            timestamp: 0,
+            producer: None,
+            txn: None,
+        }
+    }
+
+    fn create(config: &DiskConfig, offsets: std::ops::Range<u64>) -> DiskLog {
+        let mut disk = DiskLog::create(config, "k1").unwrap();
+        for offset in offsets {
+            disk.append(&record(offset)).unwrap();
+        }
+        disk
+    }
+
+    fn recover(config: &DiskConfig) -> Recovered {
+        let mut recovered = DiskLog::recover(config).unwrap();
+        assert_eq!(recovered.len(), 1);
+        recovered.remove(0)
+    }
+
+    fn offsets(recovered: &Recovered) -> Vec<u64> {
+        recovered
+            .records
+            .iter()
+            .map(|record| record.offset)
+            .collect()
+    }
+
+    fn key_dir(config: &DiskConfig) -> PathBuf {
+        config.dir.join(encode_key("k1"))
+    }
+
+    fn base_offsets(config: &DiskConfig) -> Vec<u64> {
+        let mut base_offsets = fs::read_dir(key_dir(config))
+            .unwrap()
+            .filter_map(|entry| {
+                let name = entry.unwrap().file_name().into_string().unwrap();
+                name.strip_suffix(".log")?.parse().ok()
+            })
+            .collect::<Vec<u64>>();
+        base_offsets.sort();
+        base_offsets
+    }
+
+    #[test]
+    fn torn_tail_record_is_dropped() {
+        let config = config("torn", 1024 * 1024);
+        drop(create(&config, 0..3));
+
+        let (log_path, _) = segment_paths(&key_dir(&config), 0);
+        let valid = fs::metadata(&log_path).unwrap().len();
+        let mut file = OpenOptions::new().append(true).open(&log_path).unwrap();
+        file.write_all(br#"{"offset":3,"msg":"#).unwrap();
+        drop(file);
+
+        let mut recovered = recover(&config);
+        assert_eq!(offsets(&recovered), [0, 1, 2]);
+        assert_eq!(recovered.next_offset, 3);
+        assert_eq!(fs::metadata(&log_path).unwrap().len(), valid);
+
+        // Records appended after the truncation are read back
+        recovered.disk.append(&record(3)).unwrap();
+        drop(recovered);
+        assert_eq!(offsets(&recover(&config)), [0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn missing_or_stale_index_is_rebuilt() {
+        let config = config("index", 1024 * 1024);
+        drop(create(&config, 0..4));
+        let (_, index_path) = segment_paths(&key_dir(&config), 0);
+        let complete = fs::read(&index_path).unwrap();
+
+        // Records written right before a crash can be missing from the index
+        let index = OpenOptions::new().write(true).open(&index_path).unwrap();
+        truncate_index(&index, 1).unwrap();
+        drop(index);
+        assert_eq!(offsets(&recover(&config)), [0, 1, 2, 3]);
+        assert_eq!(fs::read(&index_path).unwrap(), complete);
+
+        fs::remove_file(&index_path).unwrap();
+        let disk = recover(&config).disk;
+        disk.write_meta(&Meta {
+            start_offset: 2,
+            ..Meta::default()
+        })
+        .unwrap();
+        drop(disk);
+        assert_eq!(fs::read(&index_path).unwrap(), complete);
+
+        // The rebuilt index finds the start offset
+        let recovered = recover(&config);
+        assert_eq!(offsets(&recovered), [2, 3]);
+        assert_eq!(recovered.meta.start_offset, 2);
+    }
+
+    #[test]
+    fn segments_roll_over_and_are_truncated() {
+        // Every record fills a segment
+        let config = config("roll", 1);
+        let mut disk = create(&config, 0..4);
+        assert_eq!(base_offsets(&config), [0, 1, 2, 3]);
+
+        disk.truncate_before(2).unwrap();
+        assert_eq!(base_offsets(&config), [2, 3]);
+        // The active segment stays
+        disk.truncate_before(10).unwrap();
+        assert_eq!(base_offsets(&config), [3]);
+        drop(disk);
+
+        let recovered = recover(&config);
+        assert_eq!(offsets(&recovered), [3]);
+        assert_eq!(recovered.next_offset, 4);
+    }
+
+    #[test]
+    fn recovery_removes_segments_below_the_start_offset() {
+        let config = config("start", 1);
+        let disk = create(&config, 0..4);
+        // A crash after moving the start offset, before deleting the segments
+        disk.write_meta(&Meta {
+            start_offset: 2,
+            ..Meta::default()
+        })
+        .unwrap();
+        drop(disk);
+
+        let recovered = recover(&config);
+        assert_eq!(offsets(&recovered), [2, 3]);
+        assert_eq!(base_offsets(&config), [2, 3]);
+    }
+
+    #[test]
+    fn meta_is_replaced_by_a_rename() {
+        let config = config("meta", 1024 * 1024);
+        let disk = create(&config, 0..2);
+        let meta = Meta {
+            committed_offset: Some(1),
+            groups: HashMap::from([("g1".to_owned(), 0)]),
+            start_offset: 1,
+        };
+        disk.write_meta(&meta).unwrap();
+        assert!(!key_dir(&config).join("meta.tmp").exists());
+
+        // A crash while writing the next meta leaves the previous one in place
+        fs::write(
+            key_dir(&config).join("meta.tmp"),
+            br#"{"committed_offset":"#,
+        )
+        .unwrap();
+        drop(disk);
+
+        let recovered = recover(&config);
+        assert_eq!(recovered.meta.committed_offset, Some(1));
+        assert_eq!(recovered.meta.groups, meta.groups);
+        assert_eq!(offsets(&recovered), [1]);
+    }
+
+    #[test]
+    fn torn_txn_marker_is_dropped() {
+        let config = config("txn", 1024 * 1024);
+        let (mut txn_log, markers) = TxnLog::recover(&config).unwrap();
+        assert!(markers.is_empty());
+        let marker = TxnMarker {
+            txn: 1,
+            group: None,
+            offsets: HashMap::from([("k1".to_owned(), 4)]),
+        };
+        txn_log.commit(&marker).unwrap();
+        drop(txn_log);
+
+        let path = config.dir.join("transactions");
+        let valid = fs::metadata(&path).unwrap().len();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(br#"{"txn":2,"offs"#).unwrap();
+        drop(file);
+
+        let (mut txn_log, markers) = TxnLog::recover(&config).unwrap();
+        assert_eq!(markers.len(), 1);
+        assert_eq!(markers[0].txn, 1);
+        assert_eq!(markers[0].offsets, marker.offsets);
+        assert_eq!(fs::metadata(&path).unwrap().len(), valid);
+
+        txn_log.commit(&TxnMarker { txn: 3, ..marker }).unwrap();
+        drop(txn_log);
+        let (_, markers) = TxnLog::recover(&config).unwrap();
+        let txns = markers.iter().map(|marker| marker.txn).collect::<Vec<_>>();
+        assert_eq!(txns, [1, 3]);
+    }
+}
@@ -0,0 +1,850 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
+use serde_json::{json, Value};
+
+use crate::{
+    config::config::PollLimits,
+    election::election::Election,
+    kv::kv::{Kv, KvError, LIN_KV, SEQ_KV},
+    log::log::{interleave, BatchMessage, LogError, DEFAULT_GROUP},
+    rpc::rpc::Rpc,
+};
+
+// Give up on a request after this many conflicts or timeouts
+const MAX_ATTEMPTS: usize = 50;
+
+// Retries wait a random delay up to a bound that doubles with every attempt
+const BACKOFF_BASE: Duration = Duration::from_millis(2);
+const BACKOFF_MAX: Duration = Duration::from_millis(100);
+
+// An allocated offset still without an entry after this long is filled with a tombstone by the readers
+const ABANDONED_AFTER: Duration = Duration::from_secs(2);
+
+// A waiting poll reads the keys again this often. Nobody tells the node about new messages
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// Decisions of batches are final, so they are kept. Up to this many, then forgotten at once
+const MAX_DECISIONS: usize = 10_000;
+
+// A log shared by every node through the Maelstrom key value stores.
+// Offsets are allocated with a compare-and-set on a per-key counter in lin-kv. The messages are
+// stored in seq-kv under their offset, and each offset can only be created once.
+// When the allocation times out the offset may or may not be ours, so it is filled with a tombstone.
+// Whoever creates the entry first wins and the loser allocates another offset.
+//
+// Polls stop at the first offset without an entry. When a writer crashes between allocating an
+// offset and storing its message, the readers give up on it after a while and fill the offset
+// with a tombstone. A writer that was only slow then finds its offset taken and allocates another.
+// With an election, the other nodes leave that to the leader for twice as long.
+//
+// The stores cannot update several keys atomically, so a batch is a transaction with a status in
+// lin-kv. Its offsets are allocated with one compare-and-set per key, and its entries name the
+// transaction. Readers only return them once the status says committed, and skip them once it
+// says aborted. Polls stop at an entry without a decision, and give up on its writer like on a
+// missing entry: the status is then created as aborted, and the writer tries again.
+// The offsets committed by a batch are committed after its decision, so a crash in between loses
+// them while the messages are there.
+#[derive(Debug, Clone)]
+pub struct SharedLog {
+    lin_kv: Kv,
+    seq_kv: Kv,
+    poll_limits: PollLimits,
+    // ABANDONED_AFTER, shorter in tests
+    abandoned_after: Duration,
+    election: Option<Election>,
+    // The first missing entry of every key and when a poll first found it missing
+    gaps: Arc<Mutex<HashMap<String, (u64, Instant)>>>,
+    // Whether every batch read so far committed
+    decisions: Arc<Mutex<HashMap<String, bool>>>,
+}
+
+impl SharedLog {
+    pub fn new(rpc: Rpc, poll_limits: PollLimits, election: Option<Election>) -> SharedLog {
+        SharedLog {
+            lin_kv: Kv::new(LIN_KV, rpc.clone()),
+            seq_kv: Kv::new(SEQ_KV, rpc),
+            poll_limits,
+            abandoned_after: ABANDONED_AFTER,
+            election,
+            gaps: Arc::new(Mutex::new(HashMap::new())),
+            decisions: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn set_node_id(&mut self, node_id: &str) {
+        self.lin_kv.set_node_id(node_id);
+        self.seq_kv.set_node_id(node_id);
+    }
+
+    pub async fn append(&self, key: &str, msg: Value) -> Result<u64, LogError> {
+        // Wrapped so that a message is never mistaken for a tombstone
+        let entry = json!({ "msg": msg });
+
+        for attempt in 0..MAX_ATTEMPTS {
+            backoff(attempt).await;
+
+            let offset = match self.allocate(key).await? {
+                Some(offset) => offset,
+                None => continue,
+            };
+
+            if self.claim(&entry_key(key, offset), &entry).await? {
+                return Ok(offset);
+            }
+            // Somebody else filled the offset with a tombstone first
+        }
+
+        Err(unavailable(key))
+    }
+
+    // Appends once per producer sequence, retries return the offset of the first append.
+    // A record in lin-kv points at the offset of the message. It only moves on to a new offset
+    // when the old one was filled with a tombstone, so the message is stored at one offset at most.
+    // Records are never removed.
+    pub async fn append_idempotent(
+        &self,
+        key: &str,
+        msg: Value,
+        producer_id: &str,
+        seq: u64,
+    ) -> Result<u64, LogError> {
+        let entry = json!({ "msg": msg });
+        let record = producer_key(key, producer_id, seq);
+
+        for attempt in 0..MAX_ATTEMPTS {
+            backoff(attempt).await;
+
+            let recorded = match self.lin_kv.read(&record).await {
+                Ok(offset) => offset.as_u64(),
+                Err(KvError::KeyDoesNotExist) => None,
+                Err(_) => continue,
+            };
+
+            if let Some(offset) = recorded {
+                if self.claim(&entry_key(key, offset), &entry).await? {
+                    return Ok(offset);
+                }
+            }
+
+            // No offset yet, or it was filled with a tombstone
+            let offset = match self.allocate(key).await? {
+                Some(offset) => offset,
+                None => continue,
+            };
+            let from = recorded.map_or(Value::Null, Value::from);
+            if self
+                .lin_kv
+                .cas(&record, from, json!(offset), true)
+                .await
+                .is_err()
+            {
+                // Another attempt moved the record first, or the outcome is unknown
+                self.claim(&entry_key(key, offset), &tombstone()).await?;
+            }
+        }
+
+        Err(unavailable(key))
+    }
+
+    // Appends every message and commits the offsets. Either every message becomes visible to
+    // polls or none does. `txn` must be unique in the cluster.
+    // Returns the offset of every message, in order
+    pub async fn send_batch(
+        &self,
+        txn: &str,
+        messages: Vec<BatchMessage>,
+        offsets: HashMap<String, u64>,
+        group: Option<String>,
+    ) -> Result<Vec<u64>, LogError> {
+        self.validate_commit_offsets(&offsets).await?;
+
+        // Messages of every key, in order
+        let mut keys: Vec<(String, Vec<usize>)> = vec![];
+        for (index, message) in messages.iter().enumerate() {
+            match keys.iter_mut().find(|(key, _)| *key == message.key) {
+                Some((_, indexes)) => indexes.push(index),
+                None => keys.push((message.key.clone(), vec![index])),
+            }
+        }
+
+        'attempts: for attempt in 0..MAX_ATTEMPTS {
+            backoff(attempt).await;
+            // Every attempt is a transaction of its own. An aborted one is never reused
+            let txn = format!("{}-{}", txn, attempt);
+
+            let mut appended = vec![0; messages.len()];
+            for (position, (key, indexes)) in keys.iter().enumerate() {
+                let first = match self.allocate_many(key, indexes.len() as u64).await? {
+                    Some(first) => first,
+                    None => {
+                        // Readers must not wait for the entries claimed so far
+                        if position > 0 {
+                            self.decide(&txn, false).await?;
+                        }
+                        continue 'attempts;
+                    }
+                };
+                for (offset, index) in (first..).zip(indexes.iter()) {
+                    let entry = json!({ "txn": txn, "msg": messages[*index].msg });
+                    // A reader gave up on the entry, so the batch cannot commit any more
+                    if !self.claim(&entry_key(key, offset), &entry).await? {
+                        self.decide(&txn, false).await?;
+                        continue 'attempts;
+                    }
+                    appended[*index] = offset;
+                }
+            }
+
+            // The batch becomes visible at once
+            if !self.decide(&txn, true).await? {
+                continue;
+            }
+            for (key, offset) in offsets {
+                self.commit_offset(&key, group.as_deref().unwrap_or(DEFAULT_GROUP), offset)
+                    .await?;
+            }
+            return Ok(appended);
+        }
+
+        let keys = keys.into_iter().map(|(key, _)| key).collect::<Vec<_>>();
+        Err(unavailable(&keys.join(", ")))
+    }
+
+    // With `timeout_ms`, the keys are read again until any has messages or the wait is over
+    pub async fn poll(
+        &self,
+        offsets: HashMap<String, u64>,
+        max_messages_per_key: Option<usize>,
+        max_messages: Option<usize>,
+        max_bytes: Option<usize>,
+        timeout_ms: Option<u64>,
+    ) -> HashMap<String, Vec<Vec<Value>>> {
+        let limits = self
+            .poll_limits
+            .restrict(max_messages_per_key, max_messages, max_bytes);
+        let deadline = Instant::now() + self.poll_limits.wait(timeout_ms.unwrap_or(0));
+
+        let mut keys = offsets.into_iter().collect::<Vec<_>>();
+        keys.sort();
+
+        loop {
+            let mut candidates = Vec::with_capacity(keys.len());
+            for (key, offset) in keys.iter() {
+                let messages = self
+                    .read_entries(key, *offset, limits.max_messages_per_key)
+                    .await;
+                candidates.push((key.clone(), messages));
+            }
+
+            let now = Instant::now();
+            if now >= deadline || candidates.iter().any(|(_, messages)| !messages.is_empty()) {
+                return interleave(candidates, &limits);
+            }
+            tokio::time::sleep(POLL_INTERVAL.min(deadline - now)).await;
+        }
+    }
+
+    pub async fn commit_offsets(
+        &self,
+        offsets: HashMap<String, u64>,
+        group: Option<String>,
+    ) -> Result<(), LogError> {
+        let group = group.unwrap_or_else(|| DEFAULT_GROUP.to_owned());
+
+        self.validate_commit_offsets(&offsets).await?;
+        for (key, offset) in offsets {
+            self.commit_offset(&key, &group, offset).await?;
+        }
+        Ok(())
+    }
+
+    // Validate every offset before committing any of them
+    async fn validate_commit_offsets(
+        &self,
+        offsets: &HashMap<String, u64>,
+    ) -> Result<(), LogError> {
+        for (key, offset) in offsets.iter() {
+            let high_water_mark = self.retry(key, || self.high_water_mark(key)).await?;
+            if *offset >= high_water_mark {
+                return Err(LogError::OffsetBeyondEnd {
+                    key: key.clone(),
+                    offset: *offset,
+                    latest: high_water_mark.checked_sub(1),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn list_committed_offsets(
+        &self,
+        keys: Vec<String>,
+        group: Option<String>,
+    ) -> Result<HashMap<String, u64>, LogError> {
+        let group = group.unwrap_or_else(|| DEFAULT_GROUP.to_owned());
+
+        let mut offsets = HashMap::new();
+        for key in keys {
+            if let Some(offset) = self
+                .retry(&key, || self.committed_offset(&key, &group))
+                .await?
+            {
+                offsets.insert(key, offset);
+            }
+        }
+        Ok(offsets)
+    }
+
+    // Reserves the next offset of the key.
+    // None when another node got it first, or when the outcome is unknown
+    async fn allocate(&self, key: &str) -> Result<Option<u64>, LogError> {
+        self.allocate_many(key, 1).await
+    }
+
+    // Reserves the next `count` offsets of the key at once. Returns the first one
+    async fn allocate_many(&self, key: &str, count: u64) -> Result<Option<u64>, LogError> {
+        let offset = match self.high_water_mark(key).await {
+            Ok(offset) => offset,
+            Err(_) => return Ok(None),
+        };
+
+        match self
+            .lin_kv
+            .cas(
+                &counter_key(key),
+                json!(offset),
+                json!(offset + count),
+                true,
+            )
+            .await
+        {
+            Ok(()) => Ok(Some(offset)),
+            Err(KvError::Timeout) => {
+                // Nobody must wait for these offsets forever
+                for offset in offset..offset + count {
+                    self.claim(&entry_key(key, offset), &tombstone()).await?;
+                }
+                Ok(None)
+            }
+            Err(_) => Ok(None),
+        }
+    }
+
+    // Creates the status of the batch unless it exists. Returns whether the batch committed
+    async fn decide(&self, txn: &str, commit: bool) -> Result<bool, LogError> {
+        let status = match commit {
+            true => "committed",
+            false => "aborted",
+        };
+        for attempt in 0..MAX_ATTEMPTS {
+            backoff(attempt).await;
+
+            let decided = match self.lin_kv.create(&txn_key(txn), json!(status)).await {
+                Ok(()) => commit,
+                // Decided already. Possibly by an earlier attempt that timed out after all
+                Err(KvError::PreconditionFailed) => match self.lin_kv.read(&txn_key(txn)).await {
+                    Ok(current) => current == json!("committed"),
+                    Err(_) => continue,
+                },
+                Err(_) => continue,
+            };
+            self.remember(txn, decided);
+            return Ok(decided);
+        }
+
+        Err(unavailable(txn))
+    }
+
+    // The decision of the batch, None while there is none
+    async fn decision(&self, txn: &str) -> Result<Option<bool>, KvError> {
+        if let Some(committed) = self.decisions.lock().unwrap().get(txn) {
+            return Ok(Some(*committed));
+        }
+        let committed = match self.lin_kv.read(&txn_key(txn)).await {
+            Ok(status) => status == json!("committed"),
+            Err(KvError::KeyDoesNotExist) => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        self.remember(txn, committed);
+        Ok(Some(committed))
+    }
+
+    fn remember(&self, txn: &str, committed: bool) {
+        let mut decisions = self.decisions.lock().unwrap();
+        if decisions.len() >= MAX_DECISIONS {
+            decisions.clear();
+        }
+        decisions.insert(txn.to_owned(), committed);
+    }
+
+    // Offset of the next message
+    async fn high_water_mark(&self, key: &str) -> Result<u64, KvError> {
+        match self.lin_kv.read(&counter_key(key)).await {
+            Ok(offset) => Ok(offset.as_u64().unwrap_or_default()),
+            Err(KvError::KeyDoesNotExist) => Ok(0),
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn committed_offset(&self, key: &str, group: &str) -> Result<Option<u64>, KvError> {
+        match self.lin_kv.read(&commit_key(key, group)).await {
+            Ok(offset) => Ok(offset.as_u64()),
+            Err(KvError::KeyDoesNotExist) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn commit_offset(&self, key: &str, group: &str, offset: u64) -> Result<(), LogError> {
+        for attempt in 0..MAX_ATTEMPTS {
+            backoff(attempt).await;
+
+            let committed = match self.committed_offset(key, group).await {
+                Ok(committed) => committed,
+                Err(_) => continue,
+            };
+
+            // Commits never move backwards
+            if committed.is_some_and(|committed| committed >= offset) {
+                return Ok(());
+            }
+
+            let from = committed.map_or(Value::Null, Value::from);
+            match self
+                .lin_kv
+                .cas(&commit_key(key, group), from, json!(offset), true)
+                .await
+            {
+                Ok(()) => return Ok(()),
+                // Retrying is safe, the next read tells whether the commit was applied
+                Err(_) => continue,
+            }
+        }
+
+        Err(unavailable(key))
+    }
+
+    // Creates the entry unless it exists. Returns whether the entry now holds `value`.
+    async fn claim(&self, entry_key: &str, value: &Value) -> Result<bool, LogError> {
+        for attempt in 0..MAX_ATTEMPTS {
+            backoff(attempt).await;
+
+            match self.seq_kv.create(entry_key, value.clone()).await {
+                Ok(()) => return Ok(true),
+                // The entry exists. It is ours when an earlier attempt timed out after all
+                Err(KvError::PreconditionFailed) => match self.seq_kv.read(entry_key).await {
+                    Ok(current) => return Ok(current == *value),
+                    Err(_) => continue,
+                },
+                Err(_) => continue,
+            }
+        }
+
+        Err(unavailable(entry_key))
+    }
+
+    // Messages from `offset` up to the first offset without an entry
+    async fn read_entries(&self, key: &str, offset: u64, max: usize) -> Vec<Vec<Value>> {
+        let mut messages = vec![];
+
+        let mut offset = offset;
+        while messages.len() < max {
+            let entry = match self.seq_kv.read(&entry_key(key, offset)).await {
+                Ok(entry) => entry,
+                // Not written yet. Later messages must not be returned before this one
+                Err(KvError::KeyDoesNotExist) => {
+                    if self.fill_abandoned(key, offset).await {
+                        continue;
+                    }
+                    break;
+                }
+                Err(_) => break,
+            };
+
+            // Entries of batches wait for the decision of their batch
+            if let Some(txn) = entry.get("txn").and_then(Value::as_str) {
+                match self.decision(txn).await {
+                    Ok(Some(true)) => {}
+                    Ok(Some(false)) => {
+                        offset += 1;
+                        continue;
+                    }
+                    Ok(None) => {
+                        if self.abort_abandoned(key, offset, txn).await {
+                            continue;
+                        }
+                        break;
+                    }
+                    Err(_) => break,
+                }
+            }
+
+            if let Some(msg) = entry.get("msg") {
+                messages.push(vec![Value::from(offset), msg.clone()]);
+            }
+            offset += 1;
+        }
+        messages
+    }
+
+    // Whether the missing entry at `offset` was allocated long enough ago to give up on its writer.
+    // The entry then holds a tombstone, or whatever the writer stored in the meantime
+    async fn fill_abandoned(&self, key: &str, offset: u64) -> bool {
+        match self.high_water_mark(key).await {
+            Ok(high_water_mark) if offset < high_water_mark => {}
+            // Past the end of the log, or unknown
+            _ => return false,
+        }
+
+        if !self.stuck(key, offset) {
+            return false;
+        }
+
+        self.claim(&entry_key(key, offset), &tombstone())
+            .await
+            .is_ok()
+    }
+
+    // Whether the batch of the entry at `offset` went without a decision long enough to give up on
+    // its writer. The batch is then decided, most likely aborted
+    async fn abort_abandoned(&self, key: &str, offset: u64, txn: &str) -> bool {
+        if !self.stuck(key, offset) {
+            return false;
+        }
+        self.decide(txn, false).await.is_ok()
+    }
+
+    // Whether polls have been stopping at `offset` for long enough
+    fn stuck(&self, key: &str, offset: u64) -> bool {
+        let since = {
+            let mut gaps = self.gaps.lock().unwrap();
+            let gap = gaps
+                .entry(key.to_owned())
+                .or_insert((offset, Instant::now()));
+            if gap.0 != offset {
+                *gap = (offset, Instant::now());
+            }
+            gap.1
+        };
+        since.elapsed() >= self.abandoned_after()
+    }
+
+    // Readers racing to fill the same gaps would mostly lose their claims
+    fn abandoned_after(&self) -> Duration {
+        match self.election.as_ref() {
+            Some(election) if election.leader().is_some() && !election.is_leader() => {
+                self.abandoned_after * 2
+            }
+            _ => self.abandoned_after,
+        }
+    }
+
+    async fn retry<T, F, R>(&self, key: &str, operation: F) -> Result<T, LogError>
+    where
+        F: Fn() -> R,
+        R: std::future::Future<Output = Result<T, KvError>>,
+    {
+        for attempt in 0..MAX_ATTEMPTS {
+            backoff(attempt).await;
+
+            if let Ok(value) = operation().await {
+                return Ok(value);
+            }
+        }
+        Err(unavailable(key))
+    }
+}
+
+// Keys are free-form, so the variable parts come last or are prefixed with their length
+fn counter_key(key: &str) -> String {
+    format!("offset-{}", key)
+}
+
+fn entry_key(key: &str, offset: u64) -> String {
+    format!("msg-{}-{}", offset, key)
+}
+
+fn commit_key(key: &str, group: &str) -> String {
+    format!("commit-{}-{}-{}", group.len(), group, key)
+}
+
+fn producer_key(key: &str, producer_id: &str, seq: u64) -> String {
+    format!(
+        "producer-{}-{}-{}-{}",
+        producer_id.len(),
+        producer_id,
+        seq,
+        key
+    )
+}
+
+fn txn_key(txn: &str) -> String {
+    format!("txn-{}", txn)
+}
+
+fn tombstone() -> Value {
+    json!({ "tombstone": true })
+}
+
+// Nothing before the first attempt. Random delays keep the nodes racing for a key from colliding again
+async fn backoff(attempt: usize) {
+    if attempt == 0 {
+        return;
+    }
+    let bound = BACKOFF_BASE
+        .saturating_mul(1 << (attempt - 1).min(16))
+        .min(BACKOFF_MAX);
+    let delay = rand::thread_rng().gen_range(Duration::ZERO..=bound);
+    tokio::time::sleep(delay).await;
+}
+
+fn unavailable(key: &str) -> LogError {
+    LogError::Unavailable {
+        key: key.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        config::config::{ElectionConfig, StoreKind},
+        events::HeartbeatEvent,
+        kv::kv::FakeKv,
+        transport::MsgIds,
+    };
+
+    use super::*;
+
+    struct Fakes {
+        log: SharedLog,
+        lin_kv: Arc<Mutex<FakeKv>>,
+        seq_kv: Arc<Mutex<FakeKv>>,
+    }
+
+    fn shared_log(abandoned_after: Duration) -> Fakes {
+        let lin_kv = Arc::new(Mutex::new(FakeKv::default()));
+        let seq_kv = Arc::new(Mutex::new(FakeKv::default()));
+        let log = SharedLog {
+            lin_kv: Kv::fake(LIN_KV, lin_kv.clone()),
+            seq_kv: Kv::fake(SEQ_KV, seq_kv.clone()),
+            poll_limits: PollLimits::default(),
+            abandoned_after,
+            election: None,
+            gaps: Arc::new(Mutex::new(HashMap::new())),
+            decisions: Arc::new(Mutex::new(HashMap::new())),
+        };
+        Fakes {
+            log,
+            lin_kv,
+            seq_kv,
+        }
+    }
+
+    fn set(kv: &Mutex<FakeKv>, key: &str, value: Value) {
+        kv.lock().unwrap().values.insert(key.to_owned(), value);
+    }
+
+    fn get(kv: &Mutex<FakeKv>, key: &str) -> Option<Value> {
+        kv.lock().unwrap().values.get(key).cloned()
+    }
+
+    // Offsets returned by a poll of the key from 0
+    async fn poll(log: &SharedLog, key: &str) -> Vec<u64> {
+        let polled = log
+            .poll(HashMap::from([(key.to_owned(), 0)]), None, None, None, None)
+            .await;
+        polled
+            .get(key)
+            .map(|messages| messages.iter().map(|m| m[0].as_u64().unwrap()).collect())
+            .unwrap_or_default()
+    }
+
+    fn batch(keys: &[&str]) -> Vec<BatchMessage> {
+        keys.iter()
+            .enumerate()
+            .map(|(msg, key)| BatchMessage {
+                key: key.to_string(),
+                msg: json!(msg),
+                msg_key: None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn concurrent_appends_get_distinct_offsets() {
+        let fakes = shared_log(ABANDONED_AFTER);
+
+        let appends = (0..10)
+            .map(|msg| {
+                let log = fakes.log.clone();
+                tokio::spawn(async move { log.append("k", json!(msg)).await.unwrap() })
+            })
+            .collect::<Vec<_>>();
+        let mut offsets = vec![];
+        for append in appends {
+            offsets.push(append.await.unwrap());
+        }
+        offsets.sort();
+
+        assert_eq!(offsets, (0..10).collect::<Vec<_>>());
+        assert_eq!(poll(&fakes.log, "k").await, offsets);
+    }
+
+    #[tokio::test]
+    async fn timed_out_allocations_are_tombstoned() {
+        let fakes = shared_log(ABANDONED_AFTER);
+
+        // Applied or not, nobody knows whether the offset was taken
+        for applied in [true, false] {
+            fakes
+                .lin_kv
+                .lock()
+                .unwrap()
+                .timeouts
+                .push(("offset-k".to_owned(), applied));
+            fakes.log.append("k", json!(applied)).await.unwrap();
+        }
+
+        assert_eq!(get(&fakes.seq_kv, "msg-0-k"), Some(tombstone()));
+        assert_eq!(get(&fakes.seq_kv, "msg-2-k"), Some(tombstone()));
+        assert_eq!(poll(&fakes.log, "k").await, vec![1, 3]);
+    }
+
+    #[tokio::test]
+    async fn idempotent_appends_are_stored_once() {
+        let fakes = shared_log(ABANDONED_AFTER);
+
+        let first = fakes.log.append_idempotent("k", json!(1), "p", 0).await;
+        let retry = fakes.log.append_idempotent("k", json!(1), "p", 0).await;
+        let next = fakes.log.append_idempotent("k", json!(2), "p", 1).await;
+
+        assert_eq!(first.unwrap(), 0);
+        assert_eq!(retry.unwrap(), 0);
+        assert_eq!(next.unwrap(), 1);
+        assert_eq!(poll(&fakes.log, "k").await, vec![0, 1]);
+    }
+
+    #[tokio::test]
+    async fn idempotent_appends_move_past_a_tombstone() {
+        let fakes = shared_log(ABANDONED_AFTER);
+        // A reader gave up on the recorded offset before the writer stored its message
+        set(&fakes.lin_kv, "offset-k", json!(1));
+        set(&fakes.lin_kv, &producer_key("k", "p", 0), json!(0));
+        set(&fakes.seq_kv, "msg-0-k", tombstone());
+
+        let offset = fakes.log.append_idempotent("k", json!(1), "p", 0).await;
+
+        assert_eq!(offset.unwrap(), 1);
+        assert_eq!(
+            get(&fakes.lin_kv, &producer_key("k", "p", 0)),
+            Some(json!(1))
+        );
+        assert_eq!(poll(&fakes.log, "k").await, vec![1]);
+    }
+
+    #[tokio::test]
+    async fn abandoned_offsets_are_tombstoned_by_readers() {
+        // A writer allocated offset 0 and never stored its message
+        let waiting = shared_log(ABANDONED_AFTER);
+        set(&waiting.lin_kv, "offset-k", json!(1));
+        assert!(poll(&waiting.log, "k").await.is_empty());
+        assert!(poll(&waiting.log, "k").await.is_empty());
+        assert_eq!(get(&waiting.seq_kv, "msg-0-k"), None);
+
+        let fakes = shared_log(Duration::from_millis(20));
+        set(&fakes.lin_kv, "offset-k", json!(1));
+        assert!(poll(&fakes.log, "k").await.is_empty());
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert!(poll(&fakes.log, "k").await.is_empty());
+        assert_eq!(get(&fakes.seq_kv, "msg-0-k"), Some(tombstone()));
+
+        fakes.log.append("k", json!(1)).await.unwrap();
+        assert_eq!(poll(&fakes.log, "k").await, vec![1]);
+    }
+
+    #[tokio::test]
+    async fn followers_leave_abandoned_offsets_to_the_leader() {
+        let mut fakes = shared_log(Duration::from_millis(20));
+        let config = ElectionConfig {
+            store: StoreKind::Memory,
+            ..ElectionConfig::default()
+        };
+        let election = Election::new(config, Arc::new(MsgIds::new())).unwrap();
+        election.set_membership("n1", &["n1", "n2", "n3"].map(str::to_owned));
+        election.handle_heartbeat("n2", HeartbeatEvent { term: 1 });
+        fakes.log.election = Some(election);
+        set(&fakes.lin_kv, "offset-k", json!(1));
+
+        assert!(poll(&fakes.log, "k").await.is_empty());
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert!(poll(&fakes.log, "k").await.is_empty());
+        assert_eq!(get(&fakes.seq_kv, "msg-0-k"), None);
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(poll(&fakes.log, "k").await.is_empty());
+        assert_eq!(get(&fakes.seq_kv, "msg-0-k"), Some(tombstone()));
+    }
+
+    #[tokio::test]
+    async fn batches_are_visible_once_committed() {
+        let fakes = shared_log(ABANDONED_AFTER);
+        fakes.log.append("a", json!(0)).await.unwrap();
+
+        let offsets = fakes
+            .log
+            .send_batch(
+                "t",
+                batch(&["a", "b", "a"]),
+                HashMap::from([("a".to_owned(), 0)]),
+                None,
+            )
+            .await;
+
+        assert_eq!(offsets.unwrap(), vec![1, 0, 2]);
+        assert_eq!(get(&fakes.lin_kv, "txn-t-0"), Some(json!("committed")));
+        assert_eq!(poll(&fakes.log, "a").await, vec![0, 1, 2]);
+        assert_eq!(poll(&fakes.log, "b").await, vec![0]);
+        let committed = fakes
+            .log
+            .list_committed_offsets(vec!["a".to_owned()], None)
+            .await;
+        assert_eq!(committed.unwrap(), HashMap::from([("a".to_owned(), 0)]));
+    }
+
+    #[tokio::test]
+    async fn batches_losing_a_claim_abort_and_retry() {
+        let fakes = shared_log(ABANDONED_AFTER);
+        // A reader already gave up on the first offset of b
+        set(&fakes.seq_kv, "msg-0-b", tombstone());
+
+        let offsets = fakes
+            .log
+            .send_batch("t", batch(&["a", "b"]), HashMap::new(), None)
+            .await;
+
+        assert_eq!(offsets.unwrap(), vec![1, 1]);
+        assert_eq!(get(&fakes.lin_kv, "txn-t-0"), Some(json!("aborted")));
+        assert_eq!(get(&fakes.lin_kv, "txn-t-1"), Some(json!("committed")));
+        assert_eq!(poll(&fakes.log, "a").await, vec![1]);
+        assert_eq!(poll(&fakes.log, "b").await, vec![1]);
+    }
+
+    #[tokio::test]
+    async fn undecided_batches_are_aborted_by_readers() {
+        let fakes = shared_log(Duration::from_millis(20));
+        // A writer crashed before deciding its batch
+        set(&fakes.lin_kv, "offset-k", json!(1));
+        set(&fakes.seq_kv, "msg-0-k", json!({ "txn": "t", "msg": 0 }));
+        fakes.log.append("k", json!(1)).await.unwrap();
+
+        assert!(poll(&fakes.log, "k").await.is_empty());
+        tokio::time::sleep(Duration::from_millis(30)).await;
+
+        assert_eq!(poll(&fakes.log, "k").await, vec![1]);
+        assert_eq!(get(&fakes.lin_kv, "txn-t"), Some(json!("aborted")));
+    }
+}
@@ -0,0 +1,481 @@
+use std::{collections::HashMap, io, sync::Arc};
+
+use tokio::time::{Duration, Instant};
+
+use crate::{
+    config::config::StoreKind,
+    db::store::{open_store, Store},
+    events::*,
+    transport::MsgIds,
+};
+
+// A batch spanning several owners, waiting for its owners
+#[derive(Debug)]
+struct Coordinated {
+    client: String,
+    msg_id: u64,
+    // The part of every owner
+    parts: HashMap<String, TxnPrepareEvent>,
+    // Owner and position in its part of every message, in batch order
+    order: Vec<(String, usize)>,
+    // Offsets of the owners that voted to commit
+    votes: HashMap<String, Vec<u64>>,
+    // Owners that did not apply the commit yet. Empty until every owner voted
+    unacked: Vec<String>,
+    // Undecided transactions abort when the owners do not all vote by then
+    deadline: Instant,
+}
+
+// Commits the batches whose keys have several owners with a two-phase commit.
+// The node receiving the batch coordinates it: every owner prepares its part and votes, and the
+// batch commits once every owner voted to. The decision to commit is stored before any owner hears
+// of it, and sent again until every owner applied it. An abort is not stored: an owner asking about
+// a transaction its coordinator does not know aborts it.
+// Does no io itself, like the raft module: every call returns the messages to send, and the node
+// calls `tick` when `next_deadline` passes.
+#[derive(Debug)]
+pub struct Coordinator {
+    node_id: String,
+    // How long the owners get to vote, and to apply the decision before it is sent again
+    timeout: Duration,
+    txns: HashMap<String, Coordinated>,
+    // Owners of every committed transaction that did not apply the decision yet
+    decisions: Box<dyn Store<String, Vec<String>>>,
+    resend_deadline: Option<Instant>,
+    msg_ids: Arc<MsgIds>,
+}
+
+impl Coordinator {
+    // The decisions found in the store are sent again on the first tick
+    pub fn open(store: &StoreKind, timeout: Duration, msg_ids: Arc<MsgIds>) -> io::Result<Self> {
+        let decisions: Box<dyn Store<String, Vec<String>>> = open_store(store, "txn-decisions")?;
+        let resend_deadline = (!decisions.is_empty()).then(Instant::now);
+        Ok(Coordinator {
+            node_id: String::new(),
+            timeout,
+            txns: HashMap::new(),
+            decisions,
+            resend_deadline,
+            msg_ids,
+        })
+    }
+
+    pub fn set_node_id(&mut self, node_id: &str) {
+        node_id.clone_into(&mut self.node_id);
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.txns
+            .values()
+            .filter(|coordinated| coordinated.unacked.is_empty())
+            .map(|coordinated| coordinated.deadline)
+            .chain(self.resend_deadline)
+            .min()
+    }
+
+    // Asks every owner to prepare its part. `owner` gives the owner of every key
+    pub fn begin(
+        &mut self,
+        txn: String,
+        client: &str,
+        msg_id: u64,
+        batch: SendBatchEvent,
+        owner: impl Fn(&str) -> String,
+        now: Instant,
+    ) -> Vec<Message> {
+        let new_part = || TxnPrepareEvent {
+            txn: txn.clone(),
+            msgs: vec![],
+            offsets: HashMap::new(),
+            group: batch.group.clone(),
+        };
+        let mut parts: HashMap<String, TxnPrepareEvent> = HashMap::new();
+        let mut order = Vec::with_capacity(batch.msgs.len());
+        for message in batch.msgs {
+            let owner = owner(&message.key);
+            let part = parts.entry(owner.clone()).or_insert_with(new_part);
+            order.push((owner, part.msgs.len()));
+            part.msgs.push(message);
+        }
+        // Owners of committed offsets without messages take part too
+        for (key, offset) in batch.offsets {
+            let part = parts.entry(owner(&key)).or_insert_with(new_part);
+            part.offsets.insert(key, offset);
+        }
+
+        let messages = parts
+            .iter()
+            .map(|(owner, part)| {
+                self.to_owner(
+                    owner,
+                    Event::TxnPrepare {
+                        txn_prepare: part.clone(),
+                        shared: SharedEvent {
+                            msg_id: self.msg_ids.next(),
+                        },
+                    },
+                )
+            })
+            .collect();
+
+        self.txns.insert(
+            txn,
+            Coordinated {
+                client: client.to_owned(),
+                msg_id,
+                parts,
+                order,
+                votes: HashMap::new(),
+                unacked: vec![],
+                deadline: now + self.timeout,
+            },
+        );
+        messages
+    }
+
+    // A vote. The batch commits once every owner voted to, and aborts on the first refusal
+    pub fn handle_prepare_ok(&mut self, src: &str, data: TxnPrepareOkEvent) -> Vec<Message> {
+        let coordinated = match self.txns.get_mut(&data.txn) {
+            Some(coordinated) if coordinated.unacked.is_empty() => coordinated,
+            // Decided already
+            _ => return vec![],
+        };
+        if let Some(error) = data.error {
+            return self.abort(&data.txn, error);
+        }
+        if !coordinated.parts.contains_key(src) {
+            return vec![];
+        }
+        coordinated.votes.insert(src.to_owned(), data.offsets);
+        if coordinated.votes.len() < coordinated.parts.len() {
+            return vec![];
+        }
+
+        let mut owners = coordinated.parts.keys().cloned().collect::<Vec<_>>();
+        owners.sort();
+        let stored = self
+            .decisions
+            .put(data.txn.clone(), owners.clone())
+            .and_then(|_| self.decisions.sync());
+        if let Err(err) = stored {
+            eprintln!("failed to store a commit decision: \n err: {:?}", err);
+            // Owners must not be told to commit after a restart
+            let _ = self.decisions.delete(&data.txn);
+            let error = ErrorEvent {
+                // crash
+                code: 13,
+                text: format!("storage error: {}", err),
+            };
+            return self.abort(&data.txn, error);
+        }
+
+        if let Some(coordinated) = self.txns.get_mut(&data.txn) {
+            coordinated.unacked.clone_from(&owners);
+        }
+        self.resend_deadline
+            .get_or_insert_with(|| Instant::now() + self.timeout);
+        self.decide(&data.txn, &owners, true)
+    }
+
+    // An owner applied the commit. The client is answered once every owner did
+    pub fn handle_decide_ok(&mut self, src: &str, data: TxnEvent) -> Vec<Message> {
+        if let Some(mut owners) = self.decisions.get(&data.txn).cloned() {
+            owners.retain(|owner| owner != src);
+            let stored = match owners.is_empty() {
+                true => self.decisions.delete(&data.txn).map(|_| ()),
+                false => self.decisions.put(data.txn.clone(), owners),
+            };
+            // The owner applies the decision again when it is sent again
+            if let Err(err) = stored {
+                eprintln!("failed to update a commit decision: \n err: {:?}", err);
+            }
+        }
+
+        let coordinated = match self.txns.get_mut(&data.txn) {
+            Some(coordinated) if !coordinated.unacked.is_empty() => coordinated,
+            _ => return vec![],
+        };
+        coordinated.unacked.retain(|owner| owner != src);
+        if !coordinated.unacked.is_empty() {
+            return vec![];
+        }
+
+        let coordinated = self.txns.remove(&data.txn).unwrap();
+        let offsets = coordinated
+            .order
+            .iter()
+            .map(|(owner, index)| coordinated.votes[owner][*index])
+            .collect();
+        vec![Message {
+            src: self.node_id.clone(),
+            dest: coordinated.client,
+            body: Body {
+                typ: Event::SendBatchOk {
+                    event_response: EventResponse {
+                        in_reply_to: coordinated.msg_id,
+                    },
+                    send_batch_ok: SendBatchOk { offsets },
+                },
+            },
+        }]
+    }
+
+    // The decision for an owner that asks. None while the owners are still voting
+    pub fn status(&self, txn: &str) -> Option<bool> {
+        if self.decisions.get(&txn.to_owned()).is_some() {
+            return Some(true);
+        }
+        match self.txns.get(txn) {
+            Some(coordinated) if coordinated.unacked.is_empty() => None,
+            Some(_) => Some(true),
+            // Aborted, or never heard of
+            None => Some(false),
+        }
+    }
+
+    // Aborts the batches whose owners did not vote in time, and sends the commits again
+    pub fn tick(&mut self, now: Instant) -> Vec<Message> {
+        let expired = self
+            .txns
+            .iter()
+            .filter(|(_, coordinated)| {
+                coordinated.unacked.is_empty() && coordinated.deadline <= now
+            })
+            .map(|(txn, _)| txn.clone())
+            .collect::<Vec<_>>();
+
+        let mut messages = vec![];
+        for txn in expired {
+            let error = ErrorEvent {
+                // temporarily-unavailable. Nothing was applied
+                code: 11,
+                text: "timed out waiting for the owners of the batch to prepare it".to_string(),
+            };
+            messages.extend(self.abort(&txn, error));
+        }
+
+        if self.resend_deadline.is_some_and(|at| at <= now) {
+            let decisions = self
+                .decisions
+                .iter()
+                .map(|(txn, owners)| (txn.clone(), owners.clone()))
+                .collect::<Vec<_>>();
+            for (txn, owners) in decisions.iter() {
+                messages.extend(self.decide(txn, owners, true));
+            }
+            self.resend_deadline = (!decisions.is_empty()).then(|| now + self.timeout);
+        }
+        messages
+    }
+
+    // Tells every owner to drop its part, and the client why
+    fn abort(&mut self, txn: &str, error: ErrorEvent) -> Vec<Message> {
+        let coordinated = match self.txns.remove(txn) {
+            Some(coordinated) => coordinated,
+            None => return vec![],
+        };
+        let owners = coordinated.parts.into_keys().collect::<Vec<_>>();
+        let mut messages = self.decide(txn, &owners, false);
+        messages.push(Message {
+            src: self.node_id.clone(),
+            dest: coordinated.client,
+            body: Body {
+                typ: Event::Error {
+                    event_response: EventResponse {
+                        in_reply_to: coordinated.msg_id,
+                    },
+                    error,
+                },
+            },
+        });
+        messages
+    }
+
+    fn decide(&self, txn: &str, owners: &[String], commit: bool) -> Vec<Message> {
+        owners
+            .iter()
+            .map(|owner| {
+                self.to_owner(
+                    owner,
+                    Event::TxnDecide {
+                        txn_decide: TxnDecideEvent {
+                            txn: txn.to_owned(),
+                            commit,
+                        },
+                        shared: SharedEvent {
+                            msg_id: self.msg_ids.next(),
+                        },
+                    },
+                )
+            })
+            .collect()
+    }
+
+    fn to_owner(&self, owner: &str, typ: Event) -> Message {
+        Message {
+            src: self.node_id.clone(),
+            dest: owner.to_owned(),
+            body: Body { typ },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::config::FsyncPolicy;
+
+    fn coordinator(store: &StoreKind) -> Coordinator {
+        let mut coordinator =
+            Coordinator::open(store, Duration::from_secs(1), Arc::new(MsgIds::new())).unwrap();
+        coordinator.set_node_id("n1");
+        coordinator
+    }
+
+    // Keys are owned by the node named after their first letter
+    fn begin(coordinator: &mut Coordinator, txn: &str, keys: &[&str]) -> Vec<Message> {
+        let batch = SendBatchEvent {
+            msgs: keys
+                .iter()
+                .map(|key| BatchSendMessage {
+                    key: key.to_string(),
+                    msg: serde_json::Value::from(1),
+                    msg_key: None,
+                })
+                .collect(),
+            offsets: HashMap::new(),
+            group: None,
+        };
+        let owner = |key: &str| format!("n{}", &key[..1]);
+        coordinator.begin(txn.to_owned(), "c1", 7, batch, owner, Instant::now())
+    }
+
+    fn vote(txn: &str, offsets: Vec<u64>) -> TxnPrepareOkEvent {
+        TxnPrepareOkEvent {
+            txn: txn.to_owned(),
+            offsets,
+            error: None,
+        }
+    }
+
+    fn applied(txn: &str) -> TxnEvent {
+        TxnEvent {
+            txn: txn.to_owned(),
+        }
+    }
+
+    // (destination, commit) of every decision
+    fn decisions(messages: &[Message]) -> Vec<(String, bool)> {
+        let mut decisions = messages
+            .iter()
+            .filter_map(|message| match &message.body.typ {
+                Event::TxnDecide { txn_decide, .. } => {
+                    Some((message.dest.clone(), txn_decide.commit))
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        decisions.sort();
+        decisions
+    }
+
+    #[test]
+    fn commits_once_every_owner_voted() {
+        let mut coordinator = coordinator(&StoreKind::Memory);
+        let prepares = begin(&mut coordinator, "t1", &["1a", "2a", "1b"]);
+        assert_eq!(prepares.len(), 2);
+
+        assert!(coordinator
+            .handle_prepare_ok("n2", vote("t1", vec![5]))
+            .is_empty());
+        assert_eq!(coordinator.status("t1"), None);
+        let decided = coordinator.handle_prepare_ok("n1", vote("t1", vec![3, 4]));
+        let expected = vec![("n1".to_owned(), true), ("n2".to_owned(), true)];
+        assert_eq!(decisions(&decided), expected);
+        assert_eq!(coordinator.status("t1"), Some(true));
+
+        assert!(coordinator.handle_decide_ok("n1", applied("t1")).is_empty());
+        // A repeated answer
+        assert!(coordinator.handle_decide_ok("n1", applied("t1")).is_empty());
+        let replies = coordinator.handle_decide_ok("n2", applied("t1"));
+        match &replies[..] {
+            [Message {
+                dest,
+                body:
+                    Body {
+                        typ: Event::SendBatchOk { send_batch_ok, .. },
+                    },
+                ..
+            }] => {
+                assert_eq!(dest, "c1");
+                // In batch order
+                assert_eq!(send_batch_ok.offsets, vec![3, 5, 4]);
+            }
+            replies => panic!("unexpected replies {:?}", replies),
+        }
+        assert!(coordinator.decisions.is_empty());
+    }
+
+    #[test]
+    fn aborts_on_a_refusal_or_a_timeout() {
+        let mut coordinator = coordinator(&StoreKind::Memory);
+        begin(&mut coordinator, "t1", &["1a", "2a"]);
+        let refusal = TxnPrepareOkEvent {
+            error: Some(ErrorEvent {
+                code: 13,
+                text: "storage error".to_string(),
+            }),
+            ..vote("t1", vec![])
+        };
+        let aborted = coordinator.handle_prepare_ok("n2", refusal);
+        let expected = vec![("n1".to_owned(), false), ("n2".to_owned(), false)];
+        assert_eq!(decisions(&aborted), expected);
+        assert!(aborted.iter().any(|message| matches!(
+            &message.body.typ,
+            Event::Error { error, .. } if error.code == 13
+        )));
+        // Late votes change nothing
+        assert!(coordinator
+            .handle_prepare_ok("n1", vote("t1", vec![0]))
+            .is_empty());
+        assert_eq!(coordinator.status("t1"), Some(false));
+
+        begin(&mut coordinator, "t2", &["1a", "2a"]);
+        coordinator.handle_prepare_ok("n1", vote("t2", vec![1]));
+        assert!(coordinator.tick(Instant::now()).is_empty());
+        let aborted = coordinator.tick(Instant::now() + Duration::from_secs(2));
+        assert_eq!(decisions(&aborted), expected);
+        assert!(aborted.iter().any(|message| matches!(
+            &message.body.typ,
+            Event::Error { error, .. } if error.code == 11
+        )));
+        assert_eq!(coordinator.next_deadline(), None);
+    }
+
+    #[test]
+    fn sends_stored_commits_again_after_a_restart() {
+        let dir = std::env::temp_dir().join(format!("txn-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let store = StoreKind::File(dir, FsyncPolicy::Never);
+
+        let mut first = coordinator(&store);
+        begin(&mut first, "t1", &["1a", "2a"]);
+        first.handle_prepare_ok("n1", vote("t1", vec![0]));
+        first.handle_prepare_ok("n2", vote("t1", vec![0]));
+        first.handle_decide_ok("n1", applied("t1"));
+        drop(first);
+
+        let mut restarted = coordinator(&store);
+        assert_eq!(restarted.status("t1"), Some(true));
+        let resent = restarted.tick(Instant::now());
+        assert_eq!(decisions(&resent), vec![("n2".to_owned(), true)]);
+        assert!(restarted.next_deadline().is_some());
+
+        // Nobody waits for the reply anymore
+        assert!(restarted.handle_decide_ok("n2", applied("t1")).is_empty());
+        assert_eq!(restarted.status("t1"), Some(false));
+        let later = Instant::now() + Duration::from_secs(2);
+        assert!(restarted.tick(later).is_empty());
+        assert_eq!(restarted.next_deadline(), None);
+    }
+}
@@ -1,22 +1,75 @@
-use gossip_glommers::{node::node::Node, transport::Transport};
-use std::io::BufRead;
+use gossip_glommers::{
+    config::config::Config, events::Event, node::node::Node, transport::Transport,
+};
+use tokio::io::AsyncBufReadExt;
 
 #[tokio::main]
 async fn main() {
-    let input_lines: std::io::StdinLock<'_> = std::io::stdin().lock();
+    let config = match Config::from_args(std::env::args().skip(1)) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("invalid arguments: \n err: {:?}", err);
+            std::process::exit(1);
+        }
+    };
+
+    let mut input_lines = tokio::io::BufReader::new(tokio::io::stdin()).lines();
 
     let transport = Transport {};
 
-    let mut node = Node::new().await;
+    // Created with the init message, so that every store is opened in the node's own directory
+    let mut node: Option<Node> = None;
+
+    loop {
+        // Wake up for deferred replies, e.g. long polls that time out
+        let deadline = node.as_ref().and_then(Node::next_deadline);
+        let deadline = async {
+            match deadline {
+                Some(deadline) => tokio::time::sleep_until(deadline).await,
+                None => std::future::pending().await,
+            }
+        };
 
-    for line in input_lines.lines() {
-        let line = line.unwrap();
+        let line = tokio::select! {
+            line = input_lines.next_line() => line,
+            _ = deadline => {
+                if let Some(node) = node.as_mut() {
+                    node.handle_deadlines().await;
+                }
+                continue;
+            }
+        };
+
+        let line = match line {
+            Ok(Some(line)) => line,
+            Ok(None) => break,
+            Err(err) => {
+                eprintln!("failed to read input: \n err: {:?}", err);
+                break;
+            }
+        };
 
         let message = match transport.handleinput(line) {
             Ok(message) => message,
             Err(_) => continue,
         };
 
+        if node.is_none() {
+            match &message.body.typ {
+                Event::Init { init, .. } => {
+                    node = Some(Node::new(config.for_node(&init.node_id)).await);
+                }
+                _ => {
+                    eprintln!("dropping a message received before init: {:?}", message);
+                    continue;
+                }
+            }
+        }
+        let node = match node.as_mut() {
+            Some(node) => node,
+            None => continue,
+        };
+
         // Run the message event
         let reply_message = node.runner(message.clone()).await;
 
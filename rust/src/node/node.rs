@@ -1,37 +1,411 @@
+use std::{collections::HashMap, fmt::Debug, future::Future, io, sync::Arc};
+
 use serde_json::Value;
+use tokio::time::{Duration, Instant};
 
 use crate::{
     broadcast::broadcast::{BroadCastMessage, Broadcast},
+    config::config::{Config, IdFormat, LogStrategy, PollLimits, StoreKind, Workload},
+    crdt::{counter::PNCounter, crdt::Crdt, replica::Replica, set::GSet},
     db::db::DB,
+    election::election::Election,
     events::*,
-    log::log::KLog,
-    uid::unique_id::UID,
+    kv::kv::{Kv, LIN_KV},
+    log::{
+        log::{key_owner, now, BatchMessage, KLog, LogError, DEFAULT_GROUP},
+        shared::SharedLog,
+        txn::Coordinator,
+    },
+    raft::raft::{KvOp, Raft, RaftError},
+    rpc::rpc::{error_message, relay, Rpc, RpcError},
+    transport::{MsgIds, Transport},
+    uid::{
+        generator::{generator, IdGenerator},
+        lease::BlockIds,
+        unique_id::{SystemClock, UidError, UID},
+    },
+    wal::wal::{Op, Recovered, State, Wal},
 };
 
+// Entries grouped by the node owning their key
+type ByOwner<V> = HashMap<String, Vec<(String, V)>>;
+
+// How long to wait for another node to answer a forwarded request
+const FORWARD_TIMEOUT: Duration = Duration::from_millis(1000);
+
 #[derive(Debug)]
 pub struct Node {
     pub node_id: String,
+    // Every node of the cluster, sorted
+    node_ids: Vec<String>,
     uid: UID,
+    msg_ids: Arc<MsgIds>,
+    // Format of the ids returned by `generate`. String ids when missing
+    id_generator: Option<Box<dyn IdGenerator>>,
+    // Replaces `id_generator` for dense ids
+    block_ids: Option<BlockIds>,
     broadcast: Broadcast,
+    // Replace the broadcast values for `read` in their workload
+    counter: Option<Replica<PNCounter>>,
+    set: Option<Replica<GSet<i64>>>,
+    // Serves `read`, `write` and `cas` on keys in the lin-kv workload
+    raft: Option<Raft>,
+    // Leader of the cluster, for the subsystems that need one
+    election: Option<Election>,
     db: DB<String, Value>,
     klog: KLog,
+    // Replaces `klog` when the log lives in the key value stores
+    shared_log: Option<SharedLog>,
+    // Commits the batches spanning several owners that this node received
+    coordinator: Coordinator,
+    // When to ask the coordinators about the transactions still prepared here
+    txn_queries: Instant,
+    // Polls held until new messages arrive or their timeout elapses
+    waiting_polls: Vec<WaitingPoll>,
+    poll_limits: PollLimits,
+    // Neighbours from the last topology message, kept for snapshots
+    neighbours: Vec<String>,
+    // Records the state changes when a data directory is configured
+    wal: Option<Wal>,
+    // Replies that are not sent as the return value of the runner go through here
+    transport: Transport,
+    rpc: Rpc,
+}
+
+#[derive(Debug)]
+struct WaitingPoll {
+    client: String,
+    poll: PollEvent,
+    shared: SharedEvent,
+    deadline: Instant,
 }
 
 impl Node {
-    pub async fn new() -> Node {
-        Node {
-            broadcast: Broadcast::new().await,
-            db: DB::new(),
+    // `config` must already be the one of this node, see `Config::for_node`
+    pub async fn new(config: Config) -> Node {
+        if config.workload == Workload::LinKv && !matches!(config.raft.store, StoreKind::File(..)) {
+            eprintln!(
+                "the raft log, term and vote are kept in memory and lost on restart. \
+                 Use --store raft=file:<dir> to keep them"
+            );
+        }
+
+        let msg_ids = Arc::new(MsgIds::new());
+        let rpc = Rpc::new(msg_ids.clone());
+        let data = config.data.clone();
+        // Commit decisions must survive restarts whenever anything does
+        let txn_store = match (config.log.txn_store(), &data) {
+            (StoreKind::Memory, Some(data)) => StoreKind::File(data.dir.clone(), data.fsync),
+            (txn_store, _) => txn_store,
+        };
+
+        let election = match config.election {
+            Some(election) => match Election::new(election, msg_ids.clone()) {
+                Ok(election) => Some(election),
+                Err(err) => {
+                    eprintln!("failed to open the election store: {:?}", err);
+                    std::process::exit(1);
+                }
+            },
+            None => None,
+        };
+
+        let mut node = Node {
+            broadcast: match Broadcast::new(config.broadcast, msg_ids.clone()).await {
+                Ok(broadcast) => broadcast,
+                Err(err) => {
+                    eprintln!("failed to open the broadcast store: {:?}", err);
+                    std::process::exit(1);
+                }
+            },
+            counter: match config.workload {
+                Workload::PnCounter => Some(Replica::new(config.replication, msg_ids.clone())),
+                _ => None,
+            },
+            set: match config.workload {
+                Workload::GSet => Some(Replica::new(config.replication, msg_ids.clone())),
+                _ => None,
+            },
+            raft: match config.workload {
+                Workload::LinKv => match Raft::open(config.raft, msg_ids.clone()) {
+                    Ok(raft) => Some(raft),
+                    Err(err) => {
+                        eprintln!("failed to open the raft log: {:?}", err);
+                        std::process::exit(1);
+                    }
+                },
+                _ => None,
+            },
+            election: election.clone(),
+            db: match DB::open(&config.store, "values") {
+                Ok(db) => db,
+                Err(err) => {
+                    eprintln!("failed to open the value store: {:?}", err);
+                    std::process::exit(1);
+                }
+            },
             node_id: String::new(),
-            uid: UID::new(),
-            klog: KLog::new(),
+            node_ids: vec![],
+            shared_log: match config.log.strategy {
+                LogStrategy::Leader => None,
+                LogStrategy::LinKv => Some(SharedLog::new(
+                    rpc.clone(),
+                    config.log.poll_limits,
+                    election.clone(),
+                )),
+            },
+            coordinator: match Coordinator::open(&txn_store, FORWARD_TIMEOUT, msg_ids.clone()) {
+                Ok(coordinator) => coordinator,
+                Err(err) => {
+                    eprintln!("failed to open the transaction store: {:?}", err);
+                    std::process::exit(1);
+                }
+            },
+            txn_queries: Instant::now() + FORWARD_TIMEOUT,
+            uid: UID::with_clock(Arc::new(SystemClock), config.ids.clock_regression),
+            msg_ids,
+            id_generator: generator(config.ids.format),
+            block_ids: match config.ids.format {
+                IdFormat::Dense => Some(BlockIds::new(
+                    Kv::new(LIN_KV, rpc.clone()),
+                    config.ids.lease,
+                )),
+                _ => None,
+            },
+            poll_limits: config.log.poll_limits,
+            klog: match KLog::new(config.log) {
+                Ok(klog) => klog,
+                Err(err) => {
+                    eprintln!("failed to open the kafka log: {}", err.text());
+                    std::process::exit(1);
+                }
+            },
+            rpc,
+            waiting_polls: vec![],
+            neighbours: vec![],
+            wal: None,
+            transport: Transport {},
+        };
+
+        if let Some(data) = data {
+            let (wal, recovered) = match Wal::open(data) {
+                Ok(opened) => opened,
+                Err(err) => {
+                    eprintln!("failed to open the data directory: {:?}", err);
+                    std::process::exit(1);
+                }
+            };
+            node.recover(recovered).await;
+            node.wal = Some(wal);
+        }
+        node
+    }
+
+    // Applies the snapshot and the operations that followed it
+    async fn recover(&mut self, recovered: Recovered) {
+        if let Some(state) = recovered.state {
+            if let Some(klog) = state.klog {
+                if let Err(err) = self.klog.restore(klog) {
+                    eprintln!("failed to restore the kafka log: {}", err.text());
+                    std::process::exit(1);
+                }
+            }
+            self.apply(Op::Values {
+                values: state.values,
+            })
+            .await;
+            self.apply(Op::Topology {
+                neighbours: state.neighbours,
+            })
+            .await;
+            for (group, members) in state.groups {
+                for member in members {
+                    self.klog.handle_join_group(group.clone(), member);
+                }
+            }
+            if let Some(replicated) = state.replicated {
+                self.apply(Op::Replicated { state: replicated }).await;
+            }
+        }
+
+        for op in recovered.ops {
+            self.apply(op).await;
+        }
+    }
+
+    async fn apply(&mut self, op: Op) {
+        let result = match op {
+            Op::Values { values } => {
+                let mut result = values
+                    .iter()
+                    .try_for_each(|(id, value)| self.db.add_message(id.clone(), value.clone()));
+                if result.is_ok() {
+                    // Seeds the values gossiped in epidemic mode
+                    result = self.broadcast.seed(values).await;
+                }
+                if let Err(err) = result {
+                    eprintln!("failed to replay broadcast values: \n err: {:?}", err);
+                }
+                Ok(())
+            }
+            Op::Topology { neighbours } => {
+                self.neighbours.clone_from(&neighbours);
+                self.broadcast.set_topology(neighbours).await;
+                Ok(())
+            }
+            Op::Replicated { state } => {
+                let seeded = match (self.counter.as_ref(), self.set.as_ref()) {
+                    (Some(counter), _) => {
+                        serde_json::from_value(state).map(|state| counter.seed(&state))
+                    }
+                    (_, Some(set)) => serde_json::from_value(state).map(|state| set.seed(&state)),
+                    (None, None) => Ok(()),
+                };
+                if let Err(err) = seeded {
+                    eprintln!("failed to replay the replicated state: \n err: {:?}", err);
+                }
+                Ok(())
+            }
+            Op::Append {
+                key,
+                msg,
+                msg_key,
+                producer_id,
+                seq,
+                timestamp,
+            } => self
+                .klog
+                .handle_append(key, msg, msg_key, producer_id, seq, timestamp)
+                .map(|_| ()),
+            Op::SendBatch {
+                msgs,
+                offsets,
+                group,
+                timestamp,
+            } => self
+                .klog
+                .handle_send_batch(msgs, offsets, group, timestamp)
+                .map(|_| ()),
+            Op::CommitOffsets { offsets, group } => self.klog.handle_commit_offsets(offsets, group),
+            Op::TxnPrepare {
+                txn,
+                coordinator,
+                msgs,
+                offsets,
+                group,
+                timestamp,
+            } => self
+                .klog
+                .handle_txn_prepare(txn, coordinator, msgs, offsets, group, timestamp)
+                .map(|_| ()),
+            Op::TxnDecide { txn, commit } => self.klog.handle_txn_decide(&txn, commit).map(|_| ()),
+            Op::JoinGroup { group, member } => {
+                self.klog.handle_join_group(group, member);
+                Ok(())
+            }
+            Op::LeaveGroup { group, member } => {
+                self.klog.handle_leave_group(&group, &member);
+                Ok(())
+            }
+        };
+
+        if let Err(err) = result {
+            eprintln!("failed to replay an operation: {}", err.text());
+        }
+    }
+
+    // Appends the operation to the write-ahead log, and takes a snapshot when one is due.
+    // The operation is already applied, so on failure the client is told it may or may not be
+    fn record(&mut self, op: Op) -> io::Result<()> {
+        let needs_snapshot = match self.wal.as_mut() {
+            Some(wal) => {
+                wal.append(op)?;
+                wal.needs_snapshot()
+            }
+            None => false,
+        };
+        if !needs_snapshot {
+            return Ok(());
+        }
+
+        let state = State {
+            values: self.db.get_entries().into_iter().collect(),
+            neighbours: self.neighbours.clone(),
+            klog: (!self.klog.is_durable()).then(|| self.klog.snapshot()),
+            groups: self.klog.groups(),
+            replicated: self.replicated_state(),
+        };
+        match self.wal.as_mut() {
+            Some(wal) => wal.snapshot(state),
+            None => Ok(()),
+        }
+    }
+
+    // Changes to the kafka log are only recorded when it does not persist itself
+    fn record_log(&mut self, op: Op) -> io::Result<()> {
+        if self.klog.is_durable() {
+            return Ok(());
+        }
+        self.record(op)
+    }
+
+    // The earliest time at which `handle_deadlines` has work to do
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.waiting_polls
+            .iter()
+            .map(|poll| poll.deadline)
+            .chain(self.raft.as_ref().and_then(Raft::next_deadline))
+            .chain(self.coordinator.next_deadline())
+            .chain(self.klog.has_prepared().then_some(self.txn_queries))
+            .min()
+    }
+
+    pub async fn handle_deadlines(&mut self) {
+        let now = Instant::now();
+        if let Some(raft) = self.raft.as_mut() {
+            for message in raft.tick(now) {
+                self.transport.handleoutput(message);
+            }
         }
+
+        let messages = self.coordinator.tick(now);
+        self.send_txn(messages);
+        if self.txn_queries <= now {
+            self.txn_queries = now + FORWARD_TIMEOUT;
+            self.query_prepared();
+        }
+
+        let (expired, waiting) = std::mem::take(&mut self.waiting_polls)
+            .into_iter()
+            .partition::<Vec<_>, _>(|poll| poll.deadline <= now);
+        self.waiting_polls = waiting;
+
+        for poll in expired {
+            // Nothing arrived in time. Reply with whatever the log holds now
+            let reply = self.handle_poll_reply(&poll.poll, &poll.shared);
+            self.send_deferred(&poll.client, reply);
+        }
+    }
+
+    // Sends a reply outside of the runner's return value
+    fn send_deferred(&self, dest: &str, reply: Option<Message>) {
+        let mut reply = match reply {
+            Some(reply) => reply,
+            None => return,
+        };
+
+        reply.src.clone_from(&self.node_id);
+        dest.clone_into(&mut reply.dest);
+        self.transport.handleoutput(reply);
     }
 
     pub async fn runner(&mut self, message: Message) -> Option<Message> {
+        // Replies to our own requests go to whoever is waiting for them
+        let message = self.rpc.deliver(message)?;
+
         // Match the event type
         match message.body.clone().typ {
-            Event::Init { init, shared } => self.handle_init(init, shared),
+            Event::Init { init, shared } => self.handle_init(init, shared).await,
             Event::InitOk { .. } => None,
             Event::Echo { echo, shared } => self.handle_echo(echo, shared),
             Event::EchoOk { .. } => None,
@@ -43,39 +417,150 @@ impl Node {
                 self.handle_broadcast(broadcast, shared, &message).await
             }
             Event::BroadcastOk { event_response } => self.handle_broadcast_ok(event_response).await,
-            Event::Read { read } => self.handle_read(read),
+            Event::Gossip { gossip, shared } => self.handle_gossip(gossip, shared, &message).await,
+            Event::GossipOk {
+                event_response,
+                gossip_ok,
+            } => {
+                self.handle_gossip_ok(event_response, gossip_ok, &message)
+                    .await
+            }
+            Event::Read { read } => self.handle_read(read, &message),
             Event::ReadOk {
                 event_response,
                 read_ok,
             } => self.handle_read_ok(event_response, read_ok),
-            Event::Generate { shared } => self.handle_generate(shared).await,
+            Event::Generate { shared } => self.handle_generate(shared, &message).await,
             Event::GenerateOk { .. } => None,
 
-            Event::Send { send, shared } => self.handle_send(shared, send),
+            Event::Send { send, shared } => self.handle_send(shared, send, &message),
             Event::SendOk { .. } => None,
-            Event::Poll { poll, shared } => self.handle_poll(poll, shared),
+            Event::SendBatch { send_batch, shared } => {
+                self.handle_send_batch(send_batch, shared, &message).await
+            }
+            Event::SendBatchOk { .. } => None,
+            Event::Poll { poll, shared } => self.handle_poll(poll, shared, &message),
             Event::PollOk { .. } => None,
             Event::CommitOffsets {
                 commit_offsets,
                 shared,
-            } => self.handle_commit_offsets(commit_offsets, shared),
+            } => self.handle_commit_offsets(commit_offsets, shared, &message),
             Event::CommitOffsetsOk { .. } => None,
             Event::ListCommittedOffsets {
                 list_committed_offsets,
                 shared,
-            } => self.handle_list_committed_offsets(list_committed_offsets, shared),
+            } => self.handle_list_committed_offsets(list_committed_offsets, shared, &message),
             Event::ListCommittedOffsetsOk { .. } => None,
+            Event::JoinGroup { join_group, shared } => {
+                self.handle_join_group(join_group, shared, &message)
+            }
+            Event::JoinGroupOk { .. } => None,
+            Event::LeaveGroup {
+                leave_group,
+                shared,
+            } => self.handle_leave_group(leave_group, shared, &message),
+            Event::LeaveGroupOk { .. } => None,
+            Event::GroupLag { group_lag, shared } => {
+                self.handle_group_lag(group_lag, shared, &message)
+            }
+            Event::GroupLagOk { .. } => None,
+            Event::ListKeys { shared } => self.handle_list_keys(shared, &message),
+            Event::ListKeysOk { .. } => None,
+            Event::TxnPrepare { .. }
+            | Event::TxnPrepareOk { .. }
+            | Event::TxnDecide { .. }
+            | Event::TxnDecideOk { .. }
+            | Event::TxnStatus { .. }
+            | Event::TxnStatusOk { .. } => self.handle_txn(message),
+
+            Event::Write { write, shared } => {
+                let op = KvOp::Write {
+                    key: write.key,
+                    value: write.value,
+                };
+                self.handle_kv(op, shared, &message)
+            }
+            Event::Cas { cas, shared } => {
+                let op = KvOp::Cas {
+                    key: cas.key,
+                    from: cas.from,
+                    to: cas.to,
+                    create_if_not_exists: cas.create_if_not_exists,
+                };
+                self.handle_kv(op, shared, &message)
+            }
+            Event::WriteOk { .. } | Event::CasOk { .. } => None,
+            Event::RequestVote {
+                request_vote,
+                shared,
+            } => self.handle_request_vote(request_vote, shared, &message),
+            Event::RequestVoteOk {
+                request_vote_ok, ..
+            } => self.handle_request_vote_ok(request_vote_ok, &message),
+            Event::AppendEntries {
+                append_entries,
+                shared,
+            } => self.handle_append_entries(append_entries, shared, &message),
+            Event::AppendEntriesOk {
+                append_entries_ok, ..
+            } => self.handle_append_entries_ok(append_entries_ok, &message),
+
+            Event::Add { add, shared } => self.handle_add(add, shared, &message),
+            Event::AddOk { .. } => None,
+            Event::Replicate { replicate, shared } => {
+                self.handle_replicate(replicate, shared, &message)
+            }
+            Event::ReplicateOk { event_response } => self.handle_replicate_ok(event_response),
+
+            Event::Campaign { campaign, shared } => {
+                self.handle_campaign(campaign, shared, &message)
+            }
+            Event::CampaignOk { campaign_ok, .. } => self.handle_campaign_ok(campaign_ok, &message),
+            Event::Heartbeat { heartbeat, shared } => {
+                self.handle_heartbeat(heartbeat, shared, &message)
+            }
+            Event::HeartbeatOk { heartbeat_ok, .. } => {
+                self.handle_heartbeat_ok(heartbeat_ok, &message)
+            }
+
+            Event::Metrics { shared } => self.handle_metrics(shared).await,
+            Event::MetricsOk { .. } => None,
         }
     }
 
-    fn handle_read(&mut self, read: ReadEvent) -> Option<Message> {
-        let mut messages = self.db.get_messages_as_value();
-        messages.sort_by(|a, b| {
-            a.as_u64()
-                .unwrap()
-                .partial_cmp(&b.as_u64().unwrap())
-                .unwrap()
-        });
+    fn handle_read(&mut self, read: ReadEvent, message: &Message) -> Option<Message> {
+        if let (Some(key), Some(_)) = (read.key.clone(), self.raft.as_ref()) {
+            let shared = SharedEvent {
+                msg_id: read.msg_id,
+            };
+            return self.handle_kv(KvOp::Read { key }, shared, message);
+        }
+
+        let read_ok = match (self.counter.as_ref(), self.set.as_ref()) {
+            (Some(counter), _) => ReadOkEvent {
+                messages: None,
+                value: Some(Value::from(counter.read(|counter| counter.value()))),
+            },
+            (_, Some(set)) => ReadOkEvent {
+                messages: None,
+                value: Some(Value::from(
+                    set.read(|set| set.iter().copied().collect::<Vec<_>>()),
+                )),
+            },
+            (None, None) => {
+                let mut messages = self.db.get_messages_as_value();
+                messages.sort_by(|a, b| {
+                    a.as_u64()
+                        .unwrap()
+                        .partial_cmp(&b.as_u64().unwrap())
+                        .unwrap()
+                });
+                ReadOkEvent {
+                    messages: Some(messages),
+                    value: None,
+                }
+            }
+        };
 
         Some(Message {
             dest: String::new(),
@@ -85,7 +570,7 @@ impl Node {
                     event_response: EventResponse {
                         in_reply_to: read.msg_id,
                     },
-                    read_ok: ReadOkEvent { messages },
+                    read_ok,
                 },
             },
         })
@@ -134,7 +619,7 @@ impl Node {
     //     }
     // }
 
-    fn handle_error(&self, shared: SharedEvent) -> Option<Message> {
+    fn handle_uid_error(&self, shared: SharedEvent, err: UidError) -> Option<Message> {
         let err = Message {
             dest: String::new(),
             src: String::new(),
@@ -144,8 +629,8 @@ impl Node {
                         in_reply_to: shared.msg_id,
                     },
                     error: ErrorEvent {
-                        code: 1003,
-                        text: "failed to generate id".to_string(),
+                        code: err.code(),
+                        text: err.text(),
                     },
                 },
             },
@@ -153,10 +638,87 @@ impl Node {
         Some(err)
     }
 
-    async fn handle_generate(&mut self, shared: SharedEvent) -> Option<Message> {
-        let snowflake = match self.uid.generate_unique_id(&self.node_id).await {
+    fn handle_log_error(&self, shared: SharedEvent, err: LogError) -> Option<Message> {
+        Some(Message {
+            dest: String::new(),
+            src: String::new(),
+            body: Body {
+                typ: Event::Error {
+                    event_response: EventResponse {
+                        in_reply_to: shared.msg_id,
+                    },
+                    error: ErrorEvent {
+                        code: err.code(),
+                        text: err.text(),
+                    },
+                },
+            },
+        })
+    }
+
+    async fn handle_metrics(&self, shared: SharedEvent) -> Option<Message> {
+        Some(Message {
+            src: String::new(),
+            dest: String::new(),
+            body: Body {
+                typ: Event::MetricsOk {
+                    event_response: EventResponse {
+                        in_reply_to: shared.msg_id,
+                    },
+                    metrics_ok: MetricsOkEvent {
+                        msg_ids: self.msg_ids.metrics(),
+                        ids: self.uid.metrics(),
+                        values: self.db.metrics(),
+                        broadcast_retries: self.broadcast.retry_metrics().await,
+                        broadcast_seen: self.broadcast.seen_metrics().await,
+                        leadership: self.election.as_ref().map(Election::leadership),
+                    },
+                },
+            },
+        })
+    }
+
+    async fn handle_generate(&mut self, shared: SharedEvent, message: &Message) -> Option<Message> {
+        if let Some(block_ids) = self.block_ids.clone() {
+            // A block may have to be leased from lin-kv first
+            self.spawn_reply(&message.src, shared.msg_id, async move {
+                let id = block_ids.next().await.inspect_err(|err| {
+                    eprintln!("failed to generate id: \n err: {:?}", err);
+                })?;
+                Ok(Event::GenerateOk {
+                    event_response: EventResponse { in_reply_to: 0 },
+                    generate_ok: GenerateOk {
+                        id: Value::from(id),
+                    },
+                })
+            });
+            return None;
+        }
+
+        let snowflake = match self.id_generator.as_deref() {
+            None => self
+                .uid
+                .generate_unique_id(&self.node_id)
+                .await
+                .map(Value::from),
+            Some(generator) => {
+                // The index of the node in the cluster keeps ids unique across nodes
+                match self
+                    .node_ids
+                    .iter()
+                    .position(|node_id| *node_id == self.node_id)
+                {
+                    Some(node_index) => self.uid.generate(node_index as u64, generator).await,
+                    None => Err(UidError::UnknownNode(self.node_id.clone())),
+                }
+            }
+        };
+        let snowflake = match snowflake {
             Ok(id) => id,
-            Err(_) => return self.handle_error(shared),
+            Err(err) => {
+                eprintln!("failed to generate id: \n err: {:?}", err);
+                return self.handle_uid_error(shared, err);
+            }
         };
         let message = Message {
             src: String::new(),
@@ -184,7 +746,11 @@ impl Node {
                 continue;
             }
 
-            self.broadcast.set_topology(nodes).await;
+            self.neighbours.clone_from(&nodes);
+            self.broadcast.set_topology(nodes.clone()).await;
+            if let Err(err) = self.record(Op::Topology { neighbours: nodes }) {
+                return self.handle_storage_error(shared, err);
+            }
             break;
         }
 
@@ -229,14 +795,19 @@ impl Node {
             payload.data = payload_value.data;
             payload.dist_message_id = payload_value.dist_message_id;
 
+            // The values are kept for `read`, so they also tell which broadcasts were seen
             let value = self.db.get_message(&payload.dist_message_id);
 
             if value.is_some() {
                 // No need to continue
                 return None;
             }
-            self.db
-                .add_message(payload.dist_message_id.clone(), payload.data.to_owned());
+            if let Err(err) = self
+                .db
+                .add_message(payload.dist_message_id.clone(), payload.data.to_owned())
+            {
+                return self.handle_storage_error(shared, err);
+            }
         } else {
             let id = match self.uid.generate_unique_id(self.node_id.as_str()).await {
                 Ok(id) => id,
@@ -248,12 +819,23 @@ impl Node {
 
             payload.data = data.message.clone();
             payload.dist_message_id.clone_from(&id);
-            self.db.add_message(id, data.message);
+            if let Err(err) = self.db.add_message(id, data.message) {
+                return self.handle_storage_error(shared, err);
+            }
         };
+        if let Err(err) = self.record(Op::Values {
+            values: HashMap::from([(payload.dist_message_id.clone(), payload.data.clone())]),
+        }) {
+            return self.handle_storage_error(shared, err);
+        }
 
-        self.broadcast
-            .handle_broadcast(&self.node_id, &message.src, &mut self.uid, payload)
-            .await;
+        if let Err(err) = self
+            .broadcast
+            .handle_broadcast(&self.node_id, &message.src, payload)
+            .await
+        {
+            return self.handle_storage_error(shared, err);
+        }
 
         Some(Message {
             dest: String::new(),
@@ -269,103 +851,1357 @@ impl Node {
     }
 
     async fn handle_broadcast_ok(&mut self, data: EventResponse) -> Option<Message> {
-        self.broadcast.handle_broadcast_ok(data.in_reply_to).await;
+        // The broadcast is sent again until it is forgotten
+        if let Err(err) = self.broadcast.handle_broadcast_ok(data.in_reply_to).await {
+            eprintln!(
+                "failed to forget an acknowledged broadcast: \n err: {:?}",
+                err
+            );
+        }
         None
     }
 
-    fn handle_unsupported_error(&mut self, shared: SharedEvent) -> Option<Message> {
+    async fn handle_gossip(
+        &mut self,
+        data: GossipEvent,
+        shared: SharedEvent,
+        message: &Message,
+    ) -> Option<Message> {
+        // Without an acknowledgement the peer sends the values again
+        let (fresh, missing) = match self
+            .broadcast
+            .handle_gossip(&message.src, data.values)
+            .await
+        {
+            Ok(merged) => merged,
+            Err(err) => return self.handle_storage_error(shared, err),
+        };
+        if let Err(err) = self.add_values(fresh) {
+            return self.handle_storage_error(shared, err);
+        }
+
         Some(Message {
             dest: String::new(),
             src: String::new(),
             body: Body {
-                typ: Event::Error {
+                typ: Event::GossipOk {
                     event_response: EventResponse {
                         in_reply_to: shared.msg_id,
                     },
-                    error: ErrorEvent {
-                        code: 10,
-                        text: "Message not supported on this version.".to_string(),
-                    },
+                    gossip_ok: GossipEvent { values: missing },
                 },
             },
         })
     }
 
-    fn handle_init(&mut self, data: InitEvent, shared: SharedEvent) -> Option<Message> {
-        self.node_id = data.node_id;
+    async fn handle_gossip_ok(
+        &mut self,
+        data: EventResponse,
+        gossip_ok: GossipEvent,
+        message: &Message,
+    ) -> Option<Message> {
+        let result = match self
+            .broadcast
+            .handle_gossip_ok(&message.src, data.in_reply_to, gossip_ok.values)
+            .await
+        {
+            Ok(fresh) => self.add_values(fresh),
+            Err(err) => Err(err),
+        };
+        if let Err(err) = result {
+            eprintln!("failed to store gossiped values: \n err: {:?}", err);
+        }
 
-        Some(Message {
-            dest: String::new(),
-            src: String::new(),
+        None
+    }
+
+    fn handle_add(
+        &mut self,
+        data: AddEvent,
+        shared: SharedEvent,
+        message: &Message,
+    ) -> Option<Message> {
+        // The reply may be sent later, so it is addressed now
+        let reply = Message {
+            src: self.node_id.clone(),
+            dest: message.src.clone(),
             body: Body {
-                typ: Event::InitOk {
+                typ: Event::AddOk {
                     event_response: EventResponse {
                         in_reply_to: shared.msg_id,
                     },
                 },
             },
-        })
+        };
+
+        let replied = match (self.counter.clone(), self.set.clone()) {
+            (Some(counter), _) => match data.delta {
+                Some(delta) => {
+                    let update = counter.update(|counter, node_id| {
+                        let before = counter.clone();
+                        counter.add(node_id, delta);
+                        counter.delta(&before)
+                    });
+                    self.record_update(&counter, update, reply)
+                }
+                None => return self.handle_malformed_request(shared, "add without a delta"),
+            },
+            (_, Some(set)) => match data.element.as_ref().and_then(Value::as_i64) {
+                Some(element) => {
+                    set.update(|set, _| set.insert(element));
+                    let mut update = GSet::new();
+                    update.insert(element);
+                    self.record_update(&set, update, reply)
+                }
+                None => return self.handle_malformed_request(shared, "elements must be integers"),
+            },
+            (None, None) => return self.handle_unsupported_error(shared),
+        };
+        match replied {
+            Ok(reply) => reply,
+            Err(err) => self.handle_storage_error(shared, err),
+        }
     }
 
-    fn handle_echo(&mut self, data: EchoEvent, shared: SharedEvent) -> Option<Message> {
+    // Records a local update of the counter or set. Without a WAL, the update is lost when this
+    // node restarts before a peer has it, so the reply waits until then
+    fn record_update<C: Crdt + PartialEq + Debug + Send + 'static>(
+        &mut self,
+        replica: &Replica<C>,
+        update: C,
+        reply: Message,
+    ) -> io::Result<Option<Message>> {
+        let state = serde_json::to_value(&update).map_err(io::Error::other)?;
+        self.record(Op::Replicated { state })?;
+        if self.wal.is_some() {
+            return Ok(Some(reply));
+        }
+        Ok(replica.hold(update, reply))
+    }
+
+    // Sends the replies to the adds peers now have
+    fn release_adds(&self) {
+        let released = match (self.counter.as_ref(), self.set.as_ref()) {
+            (Some(counter), _) => counter.released(),
+            (_, Some(set)) => set.released(),
+            (None, None) => return,
+        };
+        for reply in released {
+            self.transport.handleoutput(reply);
+        }
+    }
+
+    fn handle_replicate(
+        &mut self,
+        data: ReplicateEvent,
+        shared: SharedEvent,
+        message: &Message,
+    ) -> Option<Message> {
+        // The part of the state that was new to this node, to be recorded
+        let fresh = match (self.counter.as_ref(), self.set.as_ref()) {
+            (Some(counter), _) => serde_json::from_value(data.state).and_then(|state| {
+                serde_json::to_value(counter.merge(&message.src, state, data.full))
+            }),
+            (_, Some(set)) => serde_json::from_value(data.state)
+                .and_then(|state| serde_json::to_value(set.merge(&message.src, state, data.full))),
+            (None, None) => return self.handle_unsupported_error(shared),
+        };
+        match fresh {
+            Ok(Value::Null) => {}
+            Ok(state) => {
+                if let Err(err) = self.record(Op::Replicated { state }) {
+                    return self.handle_storage_error(shared, err);
+                }
+            }
+            Err(err) => {
+                eprintln!("failed to decode the replicated state: \n err: {:?}", err);
+                return None;
+            }
+        }
+        // The peer's state may hold adds of this node
+        self.release_adds();
+
         Some(Message {
             src: String::new(),
             dest: String::new(),
             body: Body {
-                typ: Event::EchoOk {
+                typ: Event::ReplicateOk {
                     event_response: EventResponse {
                         in_reply_to: shared.msg_id,
                     },
-                    echo: EchoEvent { echo: data.echo },
                 },
             },
         })
     }
 
-    // Log
+    // Whole state of the replicated counter or set, for snapshots
+    fn replicated_state(&self) -> Option<Value> {
+        let state = match (self.counter.as_ref(), self.set.as_ref()) {
+            (Some(counter), _) => counter.read(|counter| serde_json::to_value(counter)),
+            (_, Some(set)) => set.read(|set| serde_json::to_value(set)),
+            (None, None) => return None,
+        };
+        state
+            .inspect_err(|err| eprintln!("failed to snapshot the replicated state: {:?}", err))
+            .ok()
+    }
 
-    fn handle_send(&mut self, shared: SharedEvent, send: SendEvent) -> Option<Message> {
-        let offset = self.klog.handle_append(send.key, send.msg);
+    fn handle_replicate_ok(&mut self, data: EventResponse) -> Option<Message> {
+        if let Some(counter) = self.counter.as_ref() {
+            counter.acknowledge(data.in_reply_to);
+        }
+        if let Some(set) = self.set.as_ref() {
+            set.acknowledge(data.in_reply_to);
+        }
+        self.release_adds();
+        None
+    }
 
-        let message = Message {
+    fn handle_campaign(
+        &mut self,
+        data: CampaignEvent,
+        shared: SharedEvent,
+        message: &Message,
+    ) -> Option<Message> {
+        let campaign_ok = self.election.as_ref()?.handle_campaign(&message.src, data);
+
+        Some(Message {
             src: String::new(),
             dest: String::new(),
             body: Body {
-                typ: Event::SendOk {
+                typ: Event::CampaignOk {
                     event_response: EventResponse {
                         in_reply_to: shared.msg_id,
                     },
-                    send_ok: SendOkEvent { offset },
+                    campaign_ok,
                 },
             },
-        };
+        })
+    }
 
-        Some(message)
+    fn handle_campaign_ok(&mut self, data: CampaignOkEvent, message: &Message) -> Option<Message> {
+        self.election
+            .as_ref()?
+            .handle_campaign_ok(&message.src, data);
+        None
     }
-    fn handle_poll(&mut self, data: PollEvent, shared: SharedEvent) -> Option<Message> {
-        let messages = self.klog.handle_poll(data.offsets);
 
-        let message = Message {
+    fn handle_heartbeat(
+        &mut self,
+        data: HeartbeatEvent,
+        shared: SharedEvent,
+        message: &Message,
+    ) -> Option<Message> {
+        let heartbeat_ok = self.election.as_ref()?.handle_heartbeat(&message.src, data);
+
+        Some(Message {
             src: String::new(),
             dest: String::new(),
             body: Body {
-                typ: Event::PollOk {
+                typ: Event::HeartbeatOk {
                     event_response: EventResponse {
                         in_reply_to: shared.msg_id,
                     },
-                    poll_ok: PollOkEvent { msgs: messages },
+                    heartbeat_ok,
                 },
             },
-        };
-
-        Some(message)
+        })
     }
+
+    fn handle_heartbeat_ok(
+        &mut self,
+        data: HeartbeatOkEvent,
+        message: &Message,
+    ) -> Option<Message> {
+        self.election
+            .as_ref()?
+            .handle_heartbeat_ok(&message.src, data);
+        None
+    }
+
+    // Proposes the operation on the leader, or forwards the request to it
+    fn handle_kv(&mut self, op: KvOp, shared: SharedEvent, message: &Message) -> Option<Message> {
+        let raft = match self.raft.as_mut() {
+            Some(raft) => raft,
+            None => return self.handle_unsupported_error(shared),
+        };
+
+        let err = match raft.propose(op, &message.src, shared.msg_id) {
+            Ok(messages) => {
+                for message in messages {
+                    self.transport.handleoutput(message);
+                }
+                return None;
+            }
+            Err(err) => err,
+        };
+
+        if let RaftError::NotLeader {
+            leader: Some(leader),
+        } = err
+        {
+            let request = message.body.typ.clone();
+            self.forward(
+                leader,
+                &message.src,
+                shared.msg_id,
+                FORWARD_TIMEOUT,
+                move |shared| match request {
+                    Event::Read { read } => Event::Read {
+                        read: ReadEvent {
+                            msg_id: shared.msg_id,
+                            ..read
+                        },
+                    },
+                    Event::Write { write, .. } => Event::Write { write, shared },
+                    Event::Cas { cas, .. } => Event::Cas { cas, shared },
+                    request => request,
+                },
+            );
+            return None;
+        }
+
+        Some(Message {
+            src: String::new(),
+            dest: String::new(),
+            body: Body {
+                typ: Event::Error {
+                    event_response: EventResponse {
+                        in_reply_to: shared.msg_id,
+                    },
+                    error: ErrorEvent {
+                        code: err.code(),
+                        text: err.text(),
+                    },
+                },
+            },
+        })
+    }
+
+    fn handle_request_vote(
+        &mut self,
+        data: RequestVoteEvent,
+        shared: SharedEvent,
+        message: &Message,
+    ) -> Option<Message> {
+        let request_vote_ok = self.raft.as_mut()?.handle_request_vote(&message.src, data);
+
+        Some(Message {
+            src: String::new(),
+            dest: String::new(),
+            body: Body {
+                typ: Event::RequestVoteOk {
+                    event_response: EventResponse {
+                        in_reply_to: shared.msg_id,
+                    },
+                    request_vote_ok,
+                },
+            },
+        })
+    }
+
+    fn handle_request_vote_ok(
+        &mut self,
+        data: RequestVoteOkEvent,
+        message: &Message,
+    ) -> Option<Message> {
+        for message in self
+            .raft
+            .as_mut()?
+            .handle_request_vote_ok(&message.src, data)
+        {
+            self.transport.handleoutput(message);
+        }
+        None
+    }
+
+    fn handle_append_entries(
+        &mut self,
+        data: AppendEntriesEvent,
+        shared: SharedEvent,
+        message: &Message,
+    ) -> Option<Message> {
+        let (append_entries_ok, replies) = self
+            .raft
+            .as_mut()?
+            .handle_append_entries(&message.src, data);
+        for reply in replies {
+            self.transport.handleoutput(reply);
+        }
+
+        Some(Message {
+            src: String::new(),
+            dest: String::new(),
+            body: Body {
+                typ: Event::AppendEntriesOk {
+                    event_response: EventResponse {
+                        in_reply_to: shared.msg_id,
+                    },
+                    append_entries_ok,
+                },
+            },
+        })
+    }
+
+    fn handle_append_entries_ok(
+        &mut self,
+        data: AppendEntriesOkEvent,
+        message: &Message,
+    ) -> Option<Message> {
+        let raft = self.raft.as_mut()?;
+        for message in raft.handle_append_entries_ok(&message.src, data) {
+            self.transport.handleoutput(message);
+        }
+        None
+    }
+
+    fn handle_malformed_request(&self, shared: SharedEvent, text: &str) -> Option<Message> {
+        Some(Message {
+            src: String::new(),
+            dest: String::new(),
+            body: Body {
+                typ: Event::Error {
+                    event_response: EventResponse {
+                        in_reply_to: shared.msg_id,
+                    },
+                    error: ErrorEvent {
+                        // malformed-request
+                        code: 12,
+                        text: text.to_owned(),
+                    },
+                },
+            },
+        })
+    }
+
+    // Values new to this node, received from a peer
+    fn add_values(&mut self, values: HashMap<String, Value>) -> io::Result<()> {
+        // Values gossiped again after the seen ttl are stored already
+        let values = values
+            .into_iter()
+            .filter(|(id, _)| self.db.get_message(id).is_none())
+            .collect::<HashMap<_, _>>();
+        for (id, value) in values.iter() {
+            self.db.add_message(id.clone(), value.clone())?;
+        }
+        if !values.is_empty() {
+            self.record(Op::Values { values })?;
+        }
+        Ok(())
+    }
+
+    fn handle_storage_error(&self, shared: SharedEvent, err: io::Error) -> Option<Message> {
+        eprintln!("failed to write to a store: \n err: {:?}", err);
+        Some(Message {
+            dest: String::new(),
+            src: String::new(),
+            body: Body {
+                typ: Event::Error {
+                    event_response: EventResponse {
+                        in_reply_to: shared.msg_id,
+                    },
+                    error: ErrorEvent {
+                        // crash. The write may or may not have been persisted
+                        code: 13,
+                        text: format!("storage error: {}", err),
+                    },
+                },
+            },
+        })
+    }
+
+    fn handle_unsupported_error(&mut self, shared: SharedEvent) -> Option<Message> {
+        Some(Message {
+            dest: String::new(),
+            src: String::new(),
+            body: Body {
+                typ: Event::Error {
+                    event_response: EventResponse {
+                        in_reply_to: shared.msg_id,
+                    },
+                    error: ErrorEvent {
+                        code: 10,
+                        text: "Message not supported on this version.".to_string(),
+                    },
+                },
+            },
+        })
+    }
+
+    async fn handle_init(&mut self, data: InitEvent, shared: SharedEvent) -> Option<Message> {
+        self.node_id = data.node_id;
+        self.node_ids.clone_from(&data.node_ids);
+        self.node_ids.sort();
+        if let Some(shared_log) = self.shared_log.as_mut() {
+            shared_log.set_node_id(&self.node_id);
+        }
+        self.coordinator.set_node_id(&self.node_id);
+        if let Some(block_ids) = self.block_ids.as_mut() {
+            block_ids.set_node_id(&self.node_id);
+        }
+        if let Some(counter) = self.counter.as_ref() {
+            counter.set_membership(&self.node_id, &data.node_ids);
+        }
+        if let Some(set) = self.set.as_ref() {
+            set.set_membership(&self.node_id, &data.node_ids);
+        }
+        if let Some(raft) = self.raft.as_mut() {
+            raft.set_membership(&self.node_id, &data.node_ids);
+        }
+        if let Some(election) = self.election.as_ref() {
+            election.set_membership(&self.node_id, &data.node_ids);
+        }
+        self.broadcast
+            .set_membership(&self.node_id, data.node_ids)
+            .await;
+
+        Some(Message {
+            dest: String::new(),
+            src: String::new(),
+            body: Body {
+                typ: Event::InitOk {
+                    event_response: EventResponse {
+                        in_reply_to: shared.msg_id,
+                    },
+                },
+            },
+        })
+    }
+
+    fn handle_echo(&mut self, data: EchoEvent, shared: SharedEvent) -> Option<Message> {
+        Some(Message {
+            src: String::new(),
+            dest: String::new(),
+            body: Body {
+                typ: Event::EchoOk {
+                    event_response: EventResponse {
+                        in_reply_to: shared.msg_id,
+                    },
+                    echo: EchoEvent { echo: data.echo },
+                },
+            },
+        })
+    }
+
+    // Log
+    // Every key is owned by one node. The other nodes forward requests for the key to its owner,
+    // so that offsets are assigned in one place.
+
+    fn remote_owner(&self, key: &str) -> Option<String> {
+        key_owner(key, &self.node_ids)
+            .filter(|owner| **owner != self.node_id)
+            .cloned()
+    }
+
+    // Every other node, for a client request that every node answers for its own keys.
+    // Empty for the requests of other nodes, which only answer locally
+    fn fan_out_peers(&self, message: &Message) -> Vec<String> {
+        if self.node_ids.contains(&message.src) {
+            return vec![];
+        }
+        self.node_ids
+            .iter()
+            .filter(|node_id| **node_id != self.node_id)
+            .cloned()
+            .collect()
+    }
+
+    // Splits the entries into the local ones and the ones of every other owner
+    fn split_by_owner<V>(
+        &self,
+        entries: impl IntoIterator<Item = (String, V)>,
+    ) -> (Vec<(String, V)>, ByOwner<V>) {
+        let mut local = vec![];
+        let mut remote: ByOwner<V> = HashMap::new();
+
+        for (key, value) in entries {
+            match self.remote_owner(&key) {
+                Some(owner) => remote.entry(owner).or_default().push((key, value)),
+                None => local.push((key, value)),
+            }
+        }
+        (local, remote)
+    }
+
+    // Sends the request to the owner and relays its reply to the client
+    fn forward<F>(&self, owner: String, client: &str, in_reply_to: u64, timeout: Duration, event: F)
+    where
+        F: FnOnce(SharedEvent) -> Event + Send + 'static,
+    {
+        let rpc = self.rpc.clone();
+        let node_id = self.node_id.clone();
+        let client = client.to_owned();
+        let transport = self.transport.clone();
+
+        tokio::spawn(async move {
+            let reply = rpc.call(&node_id, &owner, event, timeout).await;
+            transport.handleoutput(relay(reply, &node_id, &client, in_reply_to));
+        });
+    }
+
+    // Replies to a request whose parts are answered by other owners.
+    // `merge` builds the reply from the replies of every owner.
+    fn scatter<F, M>(&self, client: &str, in_reply_to: u64, requests: Vec<(String, F)>, merge: M)
+    where
+        F: FnOnce(SharedEvent) -> Event + Send + 'static,
+        M: FnOnce(Vec<Event>) -> Event + Send + 'static,
+    {
+        let rpc = self.rpc.clone();
+        let node_id = self.node_id.clone();
+        let client = client.to_owned();
+        let transport = self.transport.clone();
+
+        tokio::spawn(async move {
+            let reply = match call_owners(&rpc, &node_id, requests).await {
+                Ok(replies) => Ok(Message {
+                    src: String::new(),
+                    dest: String::new(),
+                    body: Body {
+                        typ: merge(replies),
+                    },
+                }),
+                Err(reply) => reply,
+            };
+            transport.handleoutput(relay(reply, &node_id, &client, in_reply_to));
+        });
+    }
+
+    // Replies once the request completes in the background
+    fn spawn_reply<F>(&self, client: &str, in_reply_to: u64, reply: F)
+    where
+        F: Future<Output = Result<Event, ErrorEvent>> + Send + 'static,
+    {
+        let node_id = self.node_id.clone();
+        let client = client.to_owned();
+        let transport = self.transport.clone();
+
+        tokio::spawn(async move {
+            let reply = match reply.await {
+                Ok(event) => Message {
+                    src: String::new(),
+                    dest: String::new(),
+                    body: Body { typ: event },
+                },
+                Err(error) => error_message(error.code, &error.text),
+            };
+            transport.handleoutput(relay(Ok(reply), &node_id, &client, in_reply_to));
+        });
+    }
+
+    fn handle_send(
+        &mut self,
+        shared: SharedEvent,
+        send: SendEvent,
+        message: &Message,
+    ) -> Option<Message> {
+        if let Some(shared_log) = self.shared_log.clone() {
+            self.spawn_reply(&message.src, shared.msg_id, async move {
+                let offset = match (send.producer_id, send.seq) {
+                    (Some(producer_id), Some(seq)) => {
+                        shared_log
+                            .append_idempotent(&send.key, send.msg, &producer_id, seq)
+                            .await?
+                    }
+                    _ => shared_log.append(&send.key, send.msg).await?,
+                };
+                Ok(Event::SendOk {
+                    event_response: EventResponse { in_reply_to: 0 },
+                    send_ok: SendOkEvent { offset },
+                })
+            });
+            return None;
+        }
+
+        if let Some(owner) = self.remote_owner(&send.key) {
+            self.forward(
+                owner,
+                &message.src,
+                shared.msg_id,
+                FORWARD_TIMEOUT,
+                |shared| Event::Send { send, shared },
+            );
+            return None;
+        }
+
+        let key = send.key.clone();
+        let timestamp = now();
+        let op = Op::Append {
+            key: send.key.clone(),
+            msg: send.msg.clone(),
+            msg_key: send.msg_key.clone(),
+            producer_id: send.producer_id.clone(),
+            seq: send.seq,
+            timestamp,
+        };
+        let offset = match self.klog.handle_append(
+            send.key,
+            send.msg,
+            send.msg_key,
+            send.producer_id,
+            send.seq,
+            timestamp,
+        ) {
+            Ok(offset) => offset,
+            Err(err) => return self.handle_log_error(shared, err),
+        };
+        if let Err(err) = self.record_log(op) {
+            return self.handle_storage_error(shared, err);
+        }
+
+        self.wake_polls(&key);
+
+        let message = Message {
+            src: String::new(),
+            dest: String::new(),
+            body: Body {
+                typ: Event::SendOk {
+                    event_response: EventResponse {
+                        in_reply_to: shared.msg_id,
+                    },
+                    send_ok: SendOkEvent { offset },
+                },
+            },
+        };
+
+        Some(message)
+    }
+    async fn handle_send_batch(
+        &mut self,
+        data: SendBatchEvent,
+        shared: SharedEvent,
+        message: &Message,
+    ) -> Option<Message> {
+        if let Some(shared_log) = self.shared_log.clone() {
+            let txn = match self.uid.generate_unique_id(&self.node_id).await {
+                Ok(txn) => txn,
+                Err(err) => return self.handle_uid_error(shared, err),
+            };
+            let messages = data
+                .msgs
+                .into_iter()
+                .map(|message| BatchMessage {
+                    key: message.key,
+                    msg: message.msg,
+                    msg_key: message.msg_key,
+                })
+                .collect();
+            self.spawn_reply(&message.src, shared.msg_id, async move {
+                let offsets = shared_log
+                    .send_batch(&txn, messages, data.offsets, data.group)
+                    .await?;
+                Ok(Event::SendBatchOk {
+                    event_response: EventResponse { in_reply_to: 0 },
+                    send_batch_ok: SendBatchOk { offsets },
+                })
+            });
+            return None;
+        }
+
+        let mut owners = data
+            .msgs
+            .iter()
+            .map(|message| &message.key)
+            .chain(data.offsets.keys())
+            .map(|key| self.remote_owner(key))
+            .collect::<Vec<_>>();
+        owners.sort();
+        owners.dedup();
+
+        // Applying the parts on their owners independently would let polls see half a batch,
+        // so batches spanning several owners go through a two-phase commit
+        match owners.as_slice() {
+            // Every key is local
+            [] | [None] => {}
+            [Some(owner)] => {
+                let owner = owner.clone();
+                self.forward(
+                    owner,
+                    &message.src,
+                    shared.msg_id,
+                    FORWARD_TIMEOUT,
+                    |shared| Event::SendBatch {
+                        send_batch: data,
+                        shared,
+                    },
+                );
+                return None;
+            }
+            _ => {
+                let txn = match self.uid.generate_unique_id(&self.node_id).await {
+                    Ok(txn) => txn,
+                    Err(err) => return self.handle_uid_error(shared, err),
+                };
+                let node_ids = self.node_ids.clone();
+                let owner = |key: &str| {
+                    key_owner(key, &node_ids)
+                        .cloned()
+                        .unwrap_or_else(|| self.node_id.clone())
+                };
+                let messages = self.coordinator.begin(
+                    txn,
+                    &message.src,
+                    shared.msg_id,
+                    data,
+                    owner,
+                    Instant::now(),
+                );
+                self.send_txn(messages);
+                return None;
+            }
+        }
+
+        let offsets = match self.apply_send_batch(data.msgs, data.offsets, data.group) {
+            Ok(offsets) => offsets,
+            Err(err) => return self.handle_log_error(shared, err),
+        };
+
+        Some(Message {
+            src: String::new(),
+            dest: String::new(),
+            body: Body {
+                typ: Event::SendBatchOk {
+                    event_response: EventResponse {
+                        in_reply_to: shared.msg_id,
+                    },
+                    send_batch_ok: SendBatchOk { offsets },
+                },
+            },
+        })
+    }
+
+    fn apply_send_batch(
+        &mut self,
+        msgs: Vec<BatchSendMessage>,
+        offsets: HashMap<String, u64>,
+        group: Option<String>,
+    ) -> Result<Vec<u64>, LogError> {
+        let mut keys = msgs
+            .iter()
+            .map(|message| message.key.clone())
+            .collect::<Vec<_>>();
+        keys.sort();
+        keys.dedup();
+
+        let messages = msgs
+            .into_iter()
+            .map(|message| BatchMessage {
+                key: message.key,
+                msg: message.msg,
+                msg_key: message.msg_key,
+            })
+            .collect::<Vec<_>>();
+
+        let timestamp = now();
+        let op = Op::SendBatch {
+            msgs: messages.clone(),
+            offsets: offsets.clone(),
+            group: group.clone(),
+            timestamp,
+        };
+        let offsets = self
+            .klog
+            .handle_send_batch(messages, offsets, group, timestamp)?;
+        self.record_log(op).map_err(|err| LogError::Storage {
+            key: keys.join(", "),
+            err,
+        })?;
+
+        for key in keys {
+            self.wake_polls(&key);
+        }
+        Ok(offsets)
+    }
+
+    // Batches spanning several owners.
+    // The coordinator is the node that received the batch. It is often an owner too, and handles
+    // the messages addressed to itself right away
+    fn send_txn(&mut self, messages: Vec<Message>) {
+        for message in messages {
+            if message.dest != self.node_id {
+                self.transport.handleoutput(message);
+                continue;
+            }
+            if let Some(mut reply) = self.handle_txn(message) {
+                reply.src.clone_from(&self.node_id);
+                reply.dest.clone_from(&self.node_id);
+                self.send_txn(vec![reply]);
+            }
+        }
+    }
+
+    fn handle_txn(&mut self, message: Message) -> Option<Message> {
+        match message.body.typ {
+            Event::TxnPrepare {
+                txn_prepare,
+                shared,
+            } => self.handle_txn_prepare(txn_prepare, shared, &message.src),
+            Event::TxnPrepareOk { txn_prepare_ok, .. } => {
+                let messages = self
+                    .coordinator
+                    .handle_prepare_ok(&message.src, txn_prepare_ok);
+                self.send_txn(messages);
+                None
+            }
+            Event::TxnDecide { txn_decide, shared } => {
+                if !self.apply_txn_decision(&txn_decide.txn, txn_decide.commit) {
+                    // The coordinator sends the decision again
+                    return None;
+                }
+                Some(Message {
+                    src: String::new(),
+                    dest: String::new(),
+                    body: Body {
+                        typ: Event::TxnDecideOk {
+                            event_response: EventResponse {
+                                in_reply_to: shared.msg_id,
+                            },
+                            txn_decide_ok: TxnEvent {
+                                txn: txn_decide.txn,
+                            },
+                        },
+                    },
+                })
+            }
+            Event::TxnDecideOk { txn_decide_ok, .. } => {
+                let messages = self
+                    .coordinator
+                    .handle_decide_ok(&message.src, txn_decide_ok);
+                self.send_txn(messages);
+                None
+            }
+            Event::TxnStatus { txn_status, shared } => Some(Message {
+                src: String::new(),
+                dest: String::new(),
+                body: Body {
+                    typ: Event::TxnStatusOk {
+                        event_response: EventResponse {
+                            in_reply_to: shared.msg_id,
+                        },
+                        txn_status_ok: TxnStatusOkEvent {
+                            commit: self.coordinator.status(&txn_status.txn),
+                            txn: txn_status.txn,
+                        },
+                    },
+                },
+            }),
+            Event::TxnStatusOk {
+                event_response,
+                txn_status_ok,
+            } => {
+                let commit = txn_status_ok.commit?;
+                if !self.apply_txn_decision(&txn_status_ok.txn, commit) || !commit {
+                    return None;
+                }
+                // Like an answer to the decision, which may have been lost. It answers the status
+                // query, so that no pending call claims it
+                let applied = Message {
+                    src: self.node_id.clone(),
+                    dest: message.src,
+                    body: Body {
+                        typ: Event::TxnDecideOk {
+                            event_response,
+                            txn_decide_ok: TxnEvent {
+                                txn: txn_status_ok.txn,
+                            },
+                        },
+                    },
+                };
+                self.send_txn(vec![applied]);
+                None
+            }
+            _ => None,
+        }
+    }
+
+    // Prepares the part of a batch owned by this node, and votes with the result
+    fn handle_txn_prepare(
+        &mut self,
+        data: TxnPrepareEvent,
+        shared: SharedEvent,
+        coordinator: &str,
+    ) -> Option<Message> {
+        let messages = data
+            .msgs
+            .into_iter()
+            .map(|message| BatchMessage {
+                key: message.key,
+                msg: message.msg,
+                msg_key: message.msg_key,
+            })
+            .collect::<Vec<_>>();
+
+        let timestamp = now();
+        let op = Op::TxnPrepare {
+            txn: data.txn.clone(),
+            coordinator: coordinator.to_owned(),
+            msgs: messages.clone(),
+            offsets: data.offsets.clone(),
+            group: data.group.clone(),
+            timestamp,
+        };
+        let result = self
+            .klog
+            .handle_txn_prepare(
+                data.txn.clone(),
+                coordinator.to_owned(),
+                messages,
+                data.offsets,
+                data.group,
+                timestamp,
+            )
+            .and_then(|offsets| {
+                self.record_log(op).map_err(|err| LogError::Storage {
+                    key: String::new(),
+                    err,
+                })?;
+                Ok(offsets)
+            });
+
+        // The coordinator is asked about the transaction if its decision does not arrive
+        let now = Instant::now();
+        if self.txn_queries < now {
+            self.txn_queries = now + FORWARD_TIMEOUT;
+        }
+
+        let (offsets, error) = match result {
+            Ok(offsets) => (offsets, None),
+            Err(err) => (vec![], Some(err.into())),
+        };
+        Some(Message {
+            src: String::new(),
+            dest: String::new(),
+            body: Body {
+                typ: Event::TxnPrepareOk {
+                    event_response: EventResponse {
+                        in_reply_to: shared.msg_id,
+                    },
+                    txn_prepare_ok: TxnPrepareOkEvent {
+                        txn: data.txn,
+                        offsets,
+                        error,
+                    },
+                },
+            },
+        })
+    }
+
+    // Returns whether the decision is applied, or was already
+    fn apply_txn_decision(&mut self, txn: &str, commit: bool) -> bool {
+        if !self.klog.is_prepared(txn) {
+            return true;
+        }
+        let keys = match self.klog.handle_txn_decide(txn, commit) {
+            Ok(keys) => keys,
+            Err(err) => {
+                eprintln!("failed to apply a transaction: {}", err.text());
+                return false;
+            }
+        };
+        // Recorded once applied, so that a snapshot taken now includes it. Until it is recorded,
+        // a restart asks the coordinator again
+        let op = Op::TxnDecide {
+            txn: txn.to_owned(),
+            commit,
+        };
+        if let Err(err) = self.record_log(op) {
+            eprintln!("failed to record a transaction: \n err: {:?}", err);
+            return false;
+        }
+
+        for key in keys {
+            self.wake_polls(&key);
+        }
+        true
+    }
+
+    // Asks the coordinators about the transactions still prepared here
+    fn query_prepared(&mut self) {
+        for (txn, coordinator) in self.klog.prepared_txns() {
+            let status = TxnEvent { txn };
+            if coordinator == self.node_id {
+                let commit = self.coordinator.status(&status.txn);
+                if let Some(commit) = commit {
+                    if self.apply_txn_decision(&status.txn, commit) && commit {
+                        let node_id = self.node_id.clone();
+                        let messages = self.coordinator.handle_decide_ok(&node_id, status);
+                        self.send_txn(messages);
+                    }
+                }
+                continue;
+            }
+            self.transport.handleoutput(Message {
+                src: self.node_id.clone(),
+                dest: coordinator,
+                body: Body {
+                    typ: Event::TxnStatus {
+                        txn_status: status,
+                        shared: SharedEvent {
+                            msg_id: self.msg_ids.next(),
+                        },
+                    },
+                },
+            });
+        }
+    }
+
+    fn handle_poll(
+        &mut self,
+        data: PollEvent,
+        shared: SharedEvent,
+        message: &Message,
+    ) -> Option<Message> {
+        if let Some(shared_log) = self.shared_log.clone() {
+            self.spawn_reply(&message.src, shared.msg_id, async move {
+                let messages = shared_log
+                    .poll(
+                        data.offsets,
+                        data.max_messages_per_key,
+                        data.max_messages,
+                        data.max_bytes,
+                        data.timeout_ms,
+                    )
+                    .await;
+                Ok(Event::PollOk {
+                    event_response: EventResponse { in_reply_to: 0 },
+                    poll_ok: PollOkEvent { msgs: messages },
+                })
+            });
+            return None;
+        }
+
+        let (local, remote) = self.split_by_owner(data.offsets.clone());
+
+        if !remote.is_empty() {
+            if local.is_empty() && remote.len() == 1 {
+                // Only one owner. It handles the timeout too
+                let owner = remote.into_keys().next().unwrap();
+                let wait = self.poll_limits.wait(data.timeout_ms.unwrap_or(0));
+                let timeout = FORWARD_TIMEOUT.saturating_add(wait);
+                self.forward(owner, &message.src, shared.msg_id, timeout, |shared| {
+                    Event::Poll { poll: data, shared }
+                });
+                return None;
+            }
+
+            // Several owners. Nothing would tell this node when any of them gets messages
+            if data
+                .timeout_ms
+                .is_some_and(|timeout_ms| !self.poll_limits.wait(timeout_ms).is_zero())
+            {
+                let mut owners = remote.into_keys().collect::<Vec<_>>();
+                if !local.is_empty() {
+                    owners.push(self.node_id.clone());
+                }
+                owners.sort();
+                return self.handle_log_error(shared, LogError::WaitSpansOwners { owners });
+            }
+            let local = PollEvent {
+                offsets: local.into_iter().collect(),
+                timeout_ms: None,
+                ..data.clone()
+            };
+            let mut messages = match self.poll_messages(&local) {
+                Ok(messages) => messages,
+                Err(err) => return self.handle_log_error(shared, err),
+            };
+
+            let requests = remote
+                .into_iter()
+                .map(|(owner, offsets)| {
+                    let poll = PollEvent {
+                        offsets: offsets.into_iter().collect(),
+                        timeout_ms: None,
+                        ..data.clone()
+                    };
+                    (owner, move |shared| Event::Poll { poll, shared })
+                })
+                .collect::<Vec<_>>();
+
+            self.scatter(&message.src, shared.msg_id, requests, move |replies| {
+                for reply in replies {
+                    if let Event::PollOk { poll_ok, .. } = reply {
+                        messages.extend(poll_ok.msgs);
+                    }
+                }
+                Event::PollOk {
+                    event_response: EventResponse { in_reply_to: 0 },
+                    poll_ok: PollOkEvent { msgs: messages },
+                }
+            });
+            return None;
+        }
+
+        let messages = match self.poll_messages(&data) {
+            Ok(messages) => messages,
+            Err(err) => return self.handle_log_error(shared, err),
+        };
+
+        // With a timeout, an empty poll is held until messages arrive
+        let deadline = data
+            .timeout_ms
+            .and_then(|timeout_ms| Instant::now().checked_add(self.poll_limits.wait(timeout_ms)));
+        if let Some(deadline) = deadline {
+            if messages.values().all(|messages| messages.is_empty()) {
+                self.waiting_polls.push(WaitingPoll {
+                    client: message.src.clone(),
+                    poll: data,
+                    shared,
+                    deadline,
+                });
+                return None;
+            }
+        }
+
+        self.poll_ok(messages, &shared)
+    }
+
+    // Answers the polls waiting on the key
+    fn wake_polls(&mut self, key: &str) {
+        let (woken, waiting) = std::mem::take(&mut self.waiting_polls)
+            .into_iter()
+            .partition::<Vec<_>, _>(|poll| poll.poll.offsets.contains_key(key));
+        self.waiting_polls = waiting;
+
+        for poll in woken {
+            let reply = match self.poll_messages(&poll.poll) {
+                // The new message may be below the requested offset. Keep waiting
+                Ok(messages) if messages.values().all(|messages| messages.is_empty()) => {
+                    self.waiting_polls.push(poll);
+                    continue;
+                }
+                Ok(messages) => self.poll_ok(messages, &poll.shared),
+                Err(err) => self.handle_log_error(poll.shared.clone(), err),
+            };
+            self.send_deferred(&poll.client, reply);
+        }
+    }
+
+    fn handle_poll_reply(&mut self, data: &PollEvent, shared: &SharedEvent) -> Option<Message> {
+        match self.poll_messages(data) {
+            Ok(messages) => self.poll_ok(messages, shared),
+            Err(err) => self.handle_log_error(shared.clone(), err),
+        }
+    }
+
+    fn poll_messages(
+        &mut self,
+        data: &PollEvent,
+    ) -> Result<HashMap<String, Vec<Vec<Value>>>, LogError> {
+        // Age based retention is only applied when the log is touched
+        self.klog.handle_retention();
+
+        self.klog.handle_poll(
+            data.offsets.clone(),
+            data.max_messages_per_key,
+            data.max_messages,
+            data.max_bytes,
+        )
+    }
+
+    fn poll_ok(
+        &self,
+        messages: HashMap<String, Vec<Vec<Value>>>,
+        shared: &SharedEvent,
+    ) -> Option<Message> {
+        let message = Message {
+            src: String::new(),
+            dest: String::new(),
+            body: Body {
+                typ: Event::PollOk {
+                    event_response: EventResponse {
+                        in_reply_to: shared.msg_id,
+                    },
+                    poll_ok: PollOkEvent { msgs: messages },
+                },
+            },
+        };
+
+        Some(message)
+    }
+    // Offsets are committed by the owner of every key.
+    // When an owner fails, the offsets of the other owners stay committed. Commits are
+    // monotonic, so retrying the whole request is safe.
     fn handle_commit_offsets(
         &mut self,
         data: CommitOffsetsEvent,
         shared: SharedEvent,
+        message: &Message,
     ) -> Option<Message> {
-        self.klog.handle_commit_offsets(data.offsets);
+        if let Some(shared_log) = self.shared_log.clone() {
+            self.spawn_reply(&message.src, shared.msg_id, async move {
+                shared_log.commit_offsets(data.offsets, data.group).await?;
+                Ok(Event::CommitOffsetsOk {
+                    event_response: EventResponse { in_reply_to: 0 },
+                })
+            });
+            return None;
+        }
+
+        // Membership is checked by the coordinator of the group, which then commits every key
+        // with its owner. Its parts are not checked again
+        let group = data.group.as_deref().unwrap_or(DEFAULT_GROUP);
+        let coordinator = key_owner(group, &self.node_ids).cloned();
+        if coordinator.as_ref() != Some(&message.src) {
+            if let Some(coordinator) = self.remote_owner(group) {
+                let commit_offsets = data;
+                self.forward(
+                    coordinator,
+                    &message.src,
+                    shared.msg_id,
+                    FORWARD_TIMEOUT,
+                    move |shared| Event::CommitOffsets {
+                        commit_offsets,
+                        shared,
+                    },
+                );
+                return None;
+            }
+            if let Err(err) = self.klog.check_member(group, data.member.as_deref()) {
+                return self.handle_log_error(shared, err);
+            }
+        }
+
+        let (local, remote) = self.split_by_owner(data.offsets);
+
+        let local = local.into_iter().collect::<HashMap<_, _>>();
+        if let Err(err) = self
+            .klog
+            .handle_commit_offsets(local.clone(), data.group.clone())
+        {
+            return self.handle_log_error(shared, err);
+        }
+        if !local.is_empty() {
+            if let Err(err) = self.record_log(Op::CommitOffsets {
+                offsets: local,
+                group: data.group.clone(),
+            }) {
+                return self.handle_storage_error(shared, err);
+            }
+        }
+
+        if !remote.is_empty() {
+            let requests = remote
+                .into_iter()
+                .map(|(owner, offsets)| {
+                    let commit_offsets = CommitOffsetsEvent {
+                        offsets: offsets.into_iter().collect(),
+                        group: data.group.clone(),
+                        member: data.member.clone(),
+                    };
+                    (owner, move |shared| Event::CommitOffsets {
+                        commit_offsets,
+                        shared,
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            self.scatter(&message.src, shared.msg_id, requests, |_| {
+                Event::CommitOffsetsOk {
+                    event_response: EventResponse { in_reply_to: 0 },
+                }
+            });
+            return None;
+        }
 
         let message = Message {
             src: String::new(),
@@ -386,8 +2222,60 @@ impl Node {
         &mut self,
         data: ListCommittedOffsets,
         shared: SharedEvent,
+        message: &Message,
     ) -> Option<Message> {
-        let offsets = self.klog.handle_list_committed_offsets(data.keys);
+        if let Some(shared_log) = self.shared_log.clone() {
+            self.spawn_reply(&message.src, shared.msg_id, async move {
+                let offsets = shared_log
+                    .list_committed_offsets(data.keys, data.group)
+                    .await?;
+                Ok(Event::ListCommittedOffsetsOk {
+                    event_response: EventResponse { in_reply_to: 0 },
+                    list_committed_offsets_ok: ListCommittedOffsetsOk { offsets },
+                })
+            });
+            return None;
+        }
+
+        let (local, remote) = self.split_by_owner(data.keys.into_iter().map(|key| (key, ())));
+
+        let mut offsets = self.klog.handle_list_committed_offsets(
+            local.into_iter().map(|(key, _)| key).collect(),
+            data.group.clone(),
+        );
+
+        if !remote.is_empty() {
+            let requests = remote
+                .into_iter()
+                .map(|(owner, keys)| {
+                    let list_committed_offsets = ListCommittedOffsets {
+                        keys: keys.into_iter().map(|(key, _)| key).collect(),
+                        group: data.group.clone(),
+                    };
+                    (owner, move |shared| Event::ListCommittedOffsets {
+                        list_committed_offsets,
+                        shared,
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            self.scatter(&message.src, shared.msg_id, requests, move |replies| {
+                for reply in replies {
+                    if let Event::ListCommittedOffsetsOk {
+                        list_committed_offsets_ok,
+                        ..
+                    } = reply
+                    {
+                        offsets.extend(list_committed_offsets_ok.offsets);
+                    }
+                }
+                Event::ListCommittedOffsetsOk {
+                    event_response: EventResponse { in_reply_to: 0 },
+                    list_committed_offsets_ok: ListCommittedOffsetsOk { offsets },
+                }
+            });
+            return None;
+        }
 
         let message = Message {
             src: String::new(),
@@ -404,4 +2292,230 @@ impl Node {
 
         Some(message)
     }
+
+    fn handle_join_group(
+        &mut self,
+        data: GroupMemberEvent,
+        shared: SharedEvent,
+        message: &Message,
+    ) -> Option<Message> {
+        // Consumer groups and key listings are only kept by the leader strategy
+        if self.shared_log.is_some() {
+            return self.handle_unsupported_error(shared);
+        }
+        // Members are kept by the coordinator of the group
+        if let Some(coordinator) = self.remote_owner(&data.group) {
+            self.forward(
+                coordinator,
+                &message.src,
+                shared.msg_id,
+                FORWARD_TIMEOUT,
+                move |shared| Event::JoinGroup {
+                    join_group: data,
+                    shared,
+                },
+            );
+            return None;
+        }
+
+        self.klog
+            .handle_join_group(data.group.clone(), data.member.clone());
+        if let Err(err) = self.record(Op::JoinGroup {
+            group: data.group,
+            member: data.member,
+        }) {
+            return self.handle_storage_error(shared, err);
+        }
+
+        Some(Message {
+            src: String::new(),
+            dest: String::new(),
+            body: Body {
+                typ: Event::JoinGroupOk {
+                    event_response: EventResponse {
+                        in_reply_to: shared.msg_id,
+                    },
+                },
+            },
+        })
+    }
+
+    fn handle_leave_group(
+        &mut self,
+        data: GroupMemberEvent,
+        shared: SharedEvent,
+        message: &Message,
+    ) -> Option<Message> {
+        if self.shared_log.is_some() {
+            return self.handle_unsupported_error(shared);
+        }
+        if let Some(coordinator) = self.remote_owner(&data.group) {
+            self.forward(
+                coordinator,
+                &message.src,
+                shared.msg_id,
+                FORWARD_TIMEOUT,
+                move |shared| Event::LeaveGroup {
+                    leave_group: data,
+                    shared,
+                },
+            );
+            return None;
+        }
+
+        self.klog.handle_leave_group(&data.group, &data.member);
+        if let Err(err) = self.record(Op::LeaveGroup {
+            group: data.group,
+            member: data.member,
+        }) {
+            return self.handle_storage_error(shared, err);
+        }
+
+        Some(Message {
+            src: String::new(),
+            dest: String::new(),
+            body: Body {
+                typ: Event::LeaveGroupOk {
+                    event_response: EventResponse {
+                        in_reply_to: shared.msg_id,
+                    },
+                },
+            },
+        })
+    }
+
+    fn handle_group_lag(
+        &mut self,
+        data: GroupLagEvent,
+        shared: SharedEvent,
+        message: &Message,
+    ) -> Option<Message> {
+        if self.shared_log.is_some() {
+            return self.handle_unsupported_error(shared);
+        }
+
+        let (mut lag, mut members) = self
+            .klog
+            .handle_group_lag(data.group.clone(), data.keys.clone());
+
+        // Every node reports the keys it owns, and the coordinator the members
+        let peers = self.fan_out_peers(message);
+        if !peers.is_empty() {
+            let requests = peers
+                .into_iter()
+                .map(|peer| {
+                    let group_lag = data.clone();
+                    (peer, move |shared| Event::GroupLag { group_lag, shared })
+                })
+                .collect::<Vec<_>>();
+
+            self.scatter(&message.src, shared.msg_id, requests, move |replies| {
+                for reply in replies {
+                    if let Event::GroupLagOk { group_lag_ok, .. } = reply {
+                        lag.extend(group_lag_ok.lag);
+                        members.extend(group_lag_ok.members);
+                    }
+                }
+                members.sort();
+                members.dedup();
+                Event::GroupLagOk {
+                    event_response: EventResponse { in_reply_to: 0 },
+                    group_lag_ok: GroupLagOk { lag, members },
+                }
+            });
+            return None;
+        }
+
+        Some(Message {
+            src: String::new(),
+            dest: String::new(),
+            body: Body {
+                typ: Event::GroupLagOk {
+                    event_response: EventResponse {
+                        in_reply_to: shared.msg_id,
+                    },
+                    group_lag_ok: GroupLagOk { lag, members },
+                },
+            },
+        })
+    }
+
+    fn handle_list_keys(&mut self, shared: SharedEvent, message: &Message) -> Option<Message> {
+        if self.shared_log.is_some() {
+            return self.handle_unsupported_error(shared);
+        }
+
+        let mut keys = self.klog.handle_list_keys();
+
+        let peers = self.fan_out_peers(message);
+        if !peers.is_empty() {
+            let requests = peers
+                .into_iter()
+                .map(|peer| (peer, |shared| Event::ListKeys { shared }))
+                .collect::<Vec<_>>();
+
+            self.scatter(&message.src, shared.msg_id, requests, move |replies| {
+                for reply in replies {
+                    if let Event::ListKeysOk { list_keys_ok, .. } = reply {
+                        keys.extend(list_keys_ok.keys);
+                    }
+                }
+                Event::ListKeysOk {
+                    event_response: EventResponse { in_reply_to: 0 },
+                    list_keys_ok: ListKeysOk { keys },
+                }
+            });
+            return None;
+        }
+
+        let message = Message {
+            src: String::new(),
+            dest: String::new(),
+            body: Body {
+                typ: Event::ListKeysOk {
+                    event_response: EventResponse {
+                        in_reply_to: shared.msg_id,
+                    },
+                    list_keys_ok: ListKeysOk { keys },
+                },
+            },
+        };
+
+        Some(message)
+    }
+}
+
+// Calls every owner concurrently.
+// Returns the reply of every owner, in order, or the first failure to relay to the client.
+async fn call_owners<F>(
+    rpc: &Rpc,
+    node_id: &str,
+    requests: Vec<(String, F)>,
+) -> Result<Vec<Event>, Result<Message, RpcError>>
+where
+    F: FnOnce(SharedEvent) -> Event + Send + 'static,
+{
+    let calls = requests
+        .into_iter()
+        .map(|(owner, event)| {
+            let rpc = rpc.clone();
+            let node_id = node_id.to_owned();
+            tokio::spawn(async move { rpc.call(&node_id, &owner, event, FORWARD_TIMEOUT).await })
+        })
+        .collect::<Vec<_>>();
+
+    let mut replies = Vec::with_capacity(calls.len());
+    for call in calls {
+        let reply = match call.await {
+            Ok(Ok(reply)) => reply,
+            Ok(Err(err)) => return Err(Err(err)),
+            Err(_) => return Err(Err(RpcError::Timeout)),
+        };
+
+        match reply.body.typ {
+            Event::Error { .. } => return Err(Ok(reply)),
+            event => replies.push(event),
+        }
+    }
+    Ok(replies)
 }
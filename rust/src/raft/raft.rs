@@ -0,0 +1,811 @@
+use std::{
+    collections::HashMap,
+    io,
+    ops::Bound::{Included, Unbounded},
+    sync::Arc,
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::time::Instant;
+
+use crate::{
+    config::config::RaftConfig,
+    db::{
+        db::DB,
+        store::{open_store, Store},
+    },
+    election::ballot::{Ballot, Role},
+    events::*,
+    transport::MsgIds,
+};
+
+// Entries sent to a follower in a single append_entries
+const MAX_ENTRIES: usize = 100;
+
+// An operation on the replicated key value store.
+// Reads go through the log too, so they are linearized with the writes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum KvOp {
+    // Appended by every new leader, to commit the entries of earlier terms
+    Noop,
+    Read {
+        key: Value,
+    },
+    Write {
+        key: Value,
+        value: Value,
+    },
+    Cas {
+        key: Value,
+        from: Value,
+        to: Value,
+        create_if_not_exists: bool,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogEntry {
+    pub term: u64,
+    pub op: KvOp,
+}
+
+#[derive(Debug)]
+pub enum RaftError {
+    // Only the leader appends to the log. `leader` is the last one heard of
+    NotLeader { leader: Option<String> },
+    // The entry could not be stored
+    Storage(io::Error),
+}
+
+impl RaftError {
+    pub fn code(&self) -> u64 {
+        match self {
+            RaftError::NotLeader { .. } => 11,
+            RaftError::Storage(_) => 13,
+        }
+    }
+
+    pub fn text(&self) -> String {
+        match self {
+            RaftError::NotLeader { leader: None } => "no leader is known".to_string(),
+            RaftError::NotLeader {
+                leader: Some(leader),
+            } => format!("{} is the leader", leader),
+            RaftError::Storage(err) => format!("storage error: {}", err),
+        }
+    }
+}
+
+// A client waiting for its entry to be applied
+#[derive(Debug)]
+struct Pending {
+    client: String,
+    msg_id: u64,
+    // Another entry may replace it at the same index after a change of leader
+    term: u64,
+}
+
+// A key value store replicated with Raft.
+// Does no io itself: every call returns the messages to send to the other nodes and the clients,
+// and the node calls `tick` when `next_deadline` passes.
+#[derive(Debug)]
+pub struct Raft {
+    config: RaftConfig,
+    // The term, the vote and the role. The term and the vote must survive restarts with the log
+    ballot: Ballot,
+    // Entries by index, starting at 1
+    log: Box<dyn Store<u64, LogEntry>>,
+    last_index: u64,
+    commit_index: u64,
+    last_applied: u64,
+    leader: Option<String>,
+    // Leader only
+    next_index: HashMap<String, u64>,
+    match_index: HashMap<String, u64>,
+    heartbeat_deadline: Option<Instant>,
+    // The state machine, keyed by the JSON text of the keys. Rebuilt from the log on restart
+    kv: DB<String, Value>,
+    pending: HashMap<u64, Pending>,
+    msg_ids: Arc<MsgIds>,
+}
+
+impl Raft {
+    pub fn open(config: RaftConfig, msg_ids: Arc<MsgIds>) -> io::Result<Raft> {
+        let log: Box<dyn Store<u64, LogEntry>> = open_store(&config.store, "raft-log")?;
+        let ballot = Ballot::open(&config.store, "raft-meta", config.election_timeout)?;
+
+        let last_index = log
+            .range(Unbounded, Unbounded)
+            .last()
+            .map(|(index, _)| *index)
+            .unwrap_or_default();
+
+        Ok(Raft {
+            config,
+            ballot,
+            log,
+            last_index,
+            commit_index: 0,
+            last_applied: 0,
+            leader: None,
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            heartbeat_deadline: None,
+            kv: DB::new(),
+            pending: HashMap::new(),
+            msg_ids,
+        })
+    }
+
+    pub fn set_membership(&mut self, node_id: &str, node_ids: &[String]) {
+        self.ballot.set_membership(node_id, node_ids);
+    }
+
+    pub fn leader(&self) -> Option<&str> {
+        self.leader.as_deref()
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        match self.ballot.role() {
+            Role::Leader => self.heartbeat_deadline,
+            _ => self.ballot.election_deadline(),
+        }
+    }
+
+    pub fn tick(&mut self, now: Instant) -> Vec<Message> {
+        match self.ballot.role() {
+            Role::Leader if self.heartbeat_deadline.is_some_and(|at| at <= now) => {
+                self.heartbeat_deadline = Some(now + self.config.heartbeat_interval);
+                self.replicate()
+            }
+            Role::Follower | Role::Candidate
+                if self.ballot.election_deadline().is_some_and(|at| at <= now) =>
+            {
+                self.start_election()
+            }
+            _ => vec![],
+        }
+    }
+
+    // Appends the operation to the log. The client is answered once it is applied
+    pub fn propose(
+        &mut self,
+        op: KvOp,
+        client: &str,
+        msg_id: u64,
+    ) -> Result<Vec<Message>, RaftError> {
+        if self.ballot.role() != Role::Leader {
+            return Err(RaftError::NotLeader {
+                leader: self.leader.clone(),
+            });
+        }
+
+        let index = self.append(op).map_err(RaftError::Storage)?;
+        self.pending.insert(
+            index,
+            Pending {
+                client: client.to_owned(),
+                msg_id,
+                term: self.ballot.term(),
+            },
+        );
+
+        // Replicate right away rather than on the next heartbeat
+        let mut messages = self.replicate();
+        messages.extend(self.advance_commit());
+        Ok(messages)
+    }
+
+    pub fn handle_request_vote(&mut self, src: &str, data: RequestVoteEvent) -> RequestVoteOkEvent {
+        if data.term > self.ballot.term() {
+            self.step_down(data.term, None);
+        }
+
+        let (last_index, last_term) = (self.last_index, self.term_at(self.last_index));
+        let up_to_date = (data.last_log_term, data.last_log_index) >= (last_term, last_index);
+        let vote_granted = self.ballot.grant_vote(src, data.term, up_to_date);
+
+        RequestVoteOkEvent {
+            term: self.ballot.term(),
+            vote_granted,
+        }
+    }
+
+    pub fn handle_request_vote_ok(&mut self, src: &str, data: RequestVoteOkEvent) -> Vec<Message> {
+        if data.term > self.ballot.term() {
+            self.step_down(data.term, None);
+            return vec![];
+        }
+        if self.ballot.add_vote(src, data.term, data.vote_granted) {
+            return self.become_leader();
+        }
+        vec![]
+    }
+
+    // Also returns the replies to the clients of entries applied by this call
+    pub fn handle_append_entries(
+        &mut self,
+        src: &str,
+        data: AppendEntriesEvent,
+    ) -> (AppendEntriesOkEvent, Vec<Message>) {
+        let failure = |term, match_index| {
+            let append_entries_ok = AppendEntriesOkEvent {
+                term,
+                success: false,
+                match_index,
+            };
+            (append_entries_ok, vec![])
+        };
+
+        if data.term < self.ballot.term() {
+            return failure(self.ballot.term(), 0);
+        }
+        self.step_down(data.term, Some(src.to_owned()));
+
+        if data.prev_log_index > self.last_index
+            || self.term_at(data.prev_log_index) != data.prev_log_term
+        {
+            // Retry from before the mismatch. The leader walks back until the logs agree
+            let match_index = self.last_index.min(data.prev_log_index.saturating_sub(1));
+            return failure(self.ballot.term(), match_index);
+        }
+
+        let mut index = data.prev_log_index;
+        for entry in data.entries {
+            index += 1;
+            if index <= self.last_index && self.term_at(index) == entry.term {
+                continue;
+            }
+            if let Err(err) = self.store_entry(index, entry) {
+                eprintln!("failed to store raft entry {}: \n err: {:?}", index, err);
+                // Only acknowledge what was stored. The leader sends the rest again
+                return failure(self.ballot.term(), self.last_index.min(index - 1));
+            }
+        }
+        // The leader counts the entries as replicated once acknowledged
+        if let Err(err) = self.sync() {
+            eprintln!("failed to sync the raft log: \n err: {:?}", err);
+            return failure(self.ballot.term(), data.prev_log_index);
+        }
+
+        // A stale or reordered request may carry an older commit index
+        self.commit_index = self.commit_index.max(data.leader_commit.min(index));
+        let replies = self.apply();
+
+        let append_entries_ok = AppendEntriesOkEvent {
+            term: self.ballot.term(),
+            success: true,
+            match_index: index,
+        };
+        (append_entries_ok, replies)
+    }
+
+    pub fn handle_append_entries_ok(
+        &mut self,
+        src: &str,
+        data: AppendEntriesOkEvent,
+    ) -> Vec<Message> {
+        if data.term > self.ballot.term() {
+            self.step_down(data.term, None);
+            return vec![];
+        }
+        if self.ballot.role() != Role::Leader || data.term != self.ballot.term() {
+            return vec![];
+        }
+
+        // Replies may arrive out of order, so the follower never goes back before what it matched
+        let matched = self.match_index.entry(src.to_owned()).or_default();
+        if data.success {
+            *matched = (*matched).max(data.match_index);
+        }
+        let next_index = (*matched).max(data.match_index) + 1;
+        self.next_index.insert(src.to_owned(), next_index);
+
+        let mut messages = self.advance_commit();
+        if self.next_index[src] <= self.last_index {
+            // The follower is behind. Catch it up without waiting for the heartbeat
+            messages.push(self.append_entries(src));
+        }
+        messages
+    }
+
+    fn start_election(&mut self) -> Vec<Message> {
+        if let Err(err) = self.ballot.start_election() {
+            eprintln!("failed to store a raft vote: \n err: {:?}", err);
+            return vec![];
+        }
+        self.leader = None;
+
+        if self.ballot.has_majority() {
+            return self.become_leader();
+        }
+
+        let request_vote = RequestVoteEvent {
+            term: self.ballot.term(),
+            last_log_index: self.last_index,
+            last_log_term: self.term_at(self.last_index),
+        };
+        self.ballot
+            .peers()
+            .iter()
+            .map(|peer| Message {
+                src: self.ballot.node_id().to_owned(),
+                dest: peer.clone(),
+                body: Body {
+                    typ: Event::RequestVote {
+                        request_vote: request_vote.clone(),
+                        shared: SharedEvent {
+                            msg_id: self.msg_ids.next(),
+                        },
+                    },
+                },
+            })
+            .collect()
+    }
+
+    fn become_leader(&mut self) -> Vec<Message> {
+        self.ballot.become_leader();
+        self.leader = Some(self.ballot.node_id().to_owned());
+        self.heartbeat_deadline = Some(Instant::now() + self.config.heartbeat_interval);
+
+        for peer in self.ballot.peers() {
+            self.next_index.insert(peer.clone(), self.last_index + 1);
+            self.match_index.insert(peer.clone(), 0);
+        }
+
+        if let Err(err) = self.append(KvOp::Noop) {
+            eprintln!("failed to store a raft entry: \n err: {:?}", err);
+            let term = self.ballot.term();
+            self.step_down(term, None);
+            return vec![];
+        }
+        let mut messages = self.replicate();
+        messages.extend(self.advance_commit());
+        messages
+    }
+
+    // Follows the leader of `term`. Pending clients stay pending: their entries may still commit
+    fn step_down(&mut self, term: u64, leader: Option<String>) {
+        if leader.is_some() || self.ballot.role() == Role::Leader {
+            self.leader = leader;
+        }
+        self.ballot.step_down(term);
+        self.heartbeat_deadline = None;
+    }
+
+    // Makes the log, the term and the vote durable before they are acknowledged or counted
+    fn sync(&mut self) -> io::Result<()> {
+        self.log.sync()?;
+        self.ballot.sync()
+    }
+
+    fn term_at(&self, index: u64) -> u64 {
+        self.log
+            .get(&index)
+            .map(|entry| entry.term)
+            .unwrap_or_default()
+    }
+
+    fn append(&mut self, op: KvOp) -> io::Result<u64> {
+        let index = self.last_index + 1;
+        let entry = LogEntry {
+            term: self.ballot.term(),
+            op,
+        };
+        self.store_entry(index, entry)?;
+        Ok(index)
+    }
+
+    // Stores the entry at `index`, which is at most one past the end of the log
+    fn store_entry(&mut self, index: u64, entry: LogEntry) -> io::Result<()> {
+        if index <= self.last_index {
+            // A conflicting entry was never committed. Drop it and everything after it
+            self.truncate(index)?;
+        }
+        self.log.put(index, entry)?;
+        self.last_index = index;
+        Ok(())
+    }
+
+    // Drops the entries from `index` on. From the end, so the log stays contiguous on failure
+    fn truncate(&mut self, index: u64) -> io::Result<()> {
+        for last in (index..=self.last_index).rev() {
+            self.log.delete(&last)?;
+            self.last_index = last - 1;
+        }
+        Ok(())
+    }
+
+    fn replicate(&self) -> Vec<Message> {
+        self.ballot
+            .peers()
+            .iter()
+            .map(|peer| self.append_entries(peer))
+            .collect()
+    }
+
+    fn append_entries(&self, peer: &str) -> Message {
+        let next_index = self
+            .next_index
+            .get(peer)
+            .copied()
+            .unwrap_or(self.last_index + 1);
+        let entries = self
+            .log
+            .range(Included(&next_index), Unbounded)
+            .take(MAX_ENTRIES)
+            .map(|(_, entry)| entry.clone())
+            .collect();
+
+        Message {
+            src: self.ballot.node_id().to_owned(),
+            dest: peer.to_owned(),
+            body: Body {
+                typ: Event::AppendEntries {
+                    append_entries: AppendEntriesEvent {
+                        term: self.ballot.term(),
+                        prev_log_index: next_index - 1,
+                        prev_log_term: self.term_at(next_index - 1),
+                        entries,
+                        leader_commit: self.commit_index,
+                    },
+                    shared: SharedEvent {
+                        msg_id: self.msg_ids.next(),
+                    },
+                },
+            },
+        }
+    }
+
+    // Commits the latest entry of the current term stored on a majority
+    fn advance_commit(&mut self) -> Vec<Message> {
+        // The leader only counts itself once its entries are durable
+        if let Err(err) = self.sync() {
+            eprintln!("failed to sync the raft log: \n err: {:?}", err);
+            return vec![];
+        }
+        for index in (self.commit_index + 1..=self.last_index).rev() {
+            // Entries of earlier terms are only committed along with one of this term
+            if self.term_at(index) != self.ballot.term() {
+                break;
+            }
+            let replicas = 1 + self
+                .match_index
+                .values()
+                .filter(|matched| **matched >= index)
+                .count();
+            if replicas >= self.ballot.quorum() {
+                self.commit_index = index;
+                break;
+            }
+        }
+        self.apply()
+    }
+
+    // Applies the committed entries and answers the clients waiting for them
+    fn apply(&mut self) -> Vec<Message> {
+        let mut replies = vec![];
+        while self.last_applied < self.commit_index {
+            self.last_applied += 1;
+            let entry = match self.log.get(&self.last_applied) {
+                Some(entry) => entry.clone(),
+                None => continue,
+            };
+            let pending = self.pending.remove(&self.last_applied);
+            let in_reply_to = pending.as_ref().map(|p| p.msg_id).unwrap_or_default();
+            let result = self.execute(entry.op, in_reply_to);
+
+            let pending = match pending {
+                Some(pending) => pending,
+                None => continue,
+            };
+            let typ = match result {
+                // Another leader replaced the entry, so the operation never happened
+                _ if pending.term != entry.term => Event::Error {
+                    event_response: EventResponse { in_reply_to },
+                    error: ErrorEvent {
+                        code: 11,
+                        text: "the leader changed before the operation committed".to_string(),
+                    },
+                },
+                Ok(typ) => typ,
+                Err(error) => Event::Error {
+                    event_response: EventResponse { in_reply_to },
+                    error,
+                },
+            };
+            replies.push(Message {
+                src: self.ballot.node_id().to_owned(),
+                dest: pending.client,
+                body: Body { typ },
+            });
+        }
+        replies
+    }
+
+    fn execute(&mut self, op: KvOp, in_reply_to: u64) -> Result<Event, ErrorEvent> {
+        let response = EventResponse { in_reply_to };
+        match op {
+            KvOp::Noop => Ok(Event::WriteOk {
+                event_response: response,
+            }),
+            KvOp::Read { key } => match self.kv.get_message(&key.to_string()) {
+                Some(value) => Ok(Event::ReadOk {
+                    event_response: response,
+                    read_ok: ReadOkEvent {
+                        messages: None,
+                        value: Some(value),
+                    },
+                }),
+                None => Err(key_does_not_exist(&key)),
+            },
+            KvOp::Write { key, value } => {
+                self.kv
+                    .add_message(key.to_string(), value)
+                    .map_err(storage_error)?;
+                Ok(Event::WriteOk {
+                    event_response: response,
+                })
+            }
+            KvOp::Cas {
+                key,
+                from,
+                to,
+                create_if_not_exists,
+            } => match self.kv.get_message(&key.to_string()) {
+                Some(value) if value != from => Err(ErrorEvent {
+                    code: 22,
+                    text: format!("expected {} but found {}", from, value),
+                }),
+                None if !create_if_not_exists => Err(key_does_not_exist(&key)),
+                _ => {
+                    self.kv
+                        .add_message(key.to_string(), to)
+                        .map_err(storage_error)?;
+                    Ok(Event::CasOk {
+                        event_response: response,
+                    })
+                }
+            },
+        }
+    }
+}
+
+fn key_does_not_exist(key: &Value) -> ErrorEvent {
+    ErrorEvent {
+        code: 20,
+        text: format!("key {} does not exist", key),
+    }
+}
+
+fn storage_error(err: io::Error) -> ErrorEvent {
+    ErrorEvent {
+        code: 13,
+        text: format!("storage error: {}", err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::Duration;
+
+    fn raft(node_id: &str) -> Raft {
+        let mut raft = Raft::open(RaftConfig::default(), Arc::new(MsgIds::new())).unwrap();
+        raft.set_membership(node_id, &["n1", "n2", "n3"].map(str::to_owned));
+        raft
+    }
+
+    // Past every election deadline
+    fn later() -> Instant {
+        Instant::now() + Duration::from_secs(60)
+    }
+
+    // Makes the node the leader of its next term with the vote of n2
+    fn elect(raft: &mut Raft) {
+        raft.tick(later());
+        let granted = RequestVoteOkEvent {
+            term: raft.ballot.term(),
+            vote_granted: true,
+        };
+        raft.handle_request_vote_ok("n2", granted);
+        assert_eq!(raft.ballot.role(), Role::Leader);
+    }
+
+    fn vote(term: u64, last_log_index: u64, last_log_term: u64) -> RequestVoteEvent {
+        RequestVoteEvent {
+            term,
+            last_log_index,
+            last_log_term,
+        }
+    }
+
+    fn write(key: &str) -> KvOp {
+        KvOp::Write {
+            key: Value::from(key),
+            value: Value::from(1),
+        }
+    }
+
+    fn entry(term: u64, key: &str) -> LogEntry {
+        LogEntry {
+            term,
+            op: write(key),
+        }
+    }
+
+    fn append(
+        term: u64,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<LogEntry>,
+        leader_commit: u64,
+    ) -> AppendEntriesEvent {
+        AppendEntriesEvent {
+            term,
+            prev_log_index,
+            prev_log_term,
+            entries,
+            leader_commit,
+        }
+    }
+
+    fn ok(term: u64, success: bool, match_index: u64) -> AppendEntriesOkEvent {
+        AppendEntriesOkEvent {
+            term,
+            success,
+            match_index,
+        }
+    }
+
+    #[test]
+    fn grants_one_vote_per_term() {
+        let mut raft = raft("n2");
+        assert!(raft.handle_request_vote("n1", vote(1, 0, 0)).vote_granted);
+        assert!(!raft.handle_request_vote("n3", vote(1, 0, 0)).vote_granted);
+        // A retried request
+        assert!(raft.handle_request_vote("n1", vote(1, 0, 0)).vote_granted);
+
+        let granted = raft.handle_request_vote("n3", vote(2, 0, 0));
+        assert!(granted.vote_granted);
+        assert_eq!(granted.term, 2);
+
+        let stale = raft.handle_request_vote("n1", vote(1, 0, 0));
+        assert!(!stale.vote_granted);
+        assert_eq!(stale.term, 2);
+    }
+
+    #[test]
+    fn denies_votes_to_candidates_with_older_logs() {
+        let mut raft = raft("n2");
+        raft.handle_append_entries("n1", append(2, 0, 0, vec![entry(1, "a"), entry(2, "b")], 0));
+
+        // An older last term loses even with a longer log
+        let denied = raft.handle_request_vote("n3", vote(3, 5, 1));
+        assert!(!denied.vote_granted);
+        assert_eq!(denied.term, 3);
+        // The same last term with a shorter log
+        assert!(!raft.handle_request_vote("n3", vote(3, 1, 2)).vote_granted);
+        assert!(raft.handle_request_vote("n3", vote(3, 2, 2)).vote_granted);
+    }
+
+    #[test]
+    fn truncates_conflicting_entries() {
+        let mut raft = raft("n2");
+        let entries = vec![entry(1, "a"), entry(1, "b"), entry(1, "c")];
+        let (appended, _) = raft.handle_append_entries("n1", append(1, 0, 0, entries, 0));
+        assert!(appended.success);
+        assert_eq!(appended.match_index, 3);
+
+        // The leader of term 2 replaces the entries from index 2 on
+        let (appended, _) =
+            raft.handle_append_entries("n3", append(2, 1, 1, vec![entry(2, "d")], 0));
+        assert!(appended.success);
+        assert_eq!(appended.match_index, 2);
+        assert_eq!(raft.last_index, 2);
+        assert_eq!(raft.term_at(2), 2);
+        assert!(raft.log.get(&3).is_none());
+
+        // A request of the old leader arriving late
+        let entries = vec![entry(1, "a"), entry(1, "b")];
+        let (stale, _) = raft.handle_append_entries("n1", append(1, 0, 0, entries, 0));
+        assert!(!stale.success);
+        assert_eq!(stale.term, 2);
+
+        // A reordered request of the current leader keeps the entries after the ones it carries
+        let (appended, _) =
+            raft.handle_append_entries("n3", append(2, 0, 0, vec![entry(1, "a")], 0));
+        assert!(appended.success);
+        assert_eq!(appended.match_index, 1);
+        assert_eq!(raft.last_index, 2);
+        assert_eq!(raft.term_at(2), 2);
+    }
+
+    #[test]
+    fn commit_index_never_goes_back() {
+        let mut raft = raft("n2");
+        let entries = vec![entry(1, "a"), entry(1, "b"), entry(1, "c")];
+        raft.handle_append_entries("n1", append(1, 0, 0, entries, 2));
+        assert_eq!(raft.commit_index, 2);
+
+        // A request carrying fewer entries than the leader has committed, e.g. sent again
+        // from the start after a reordered failure
+        raft.handle_append_entries("n1", append(1, 0, 0, vec![entry(1, "a")], 3));
+        assert_eq!(raft.commit_index, 2);
+        raft.handle_append_entries("n1", append(1, 3, 1, vec![], 1));
+        assert_eq!(raft.commit_index, 2);
+    }
+
+    #[test]
+    fn commits_earlier_terms_only_with_an_entry_of_the_current_term() {
+        let mut raft = raft("n1");
+        elect(&mut raft);
+        raft.propose(write("a"), "c1", 7).unwrap();
+
+        // The leader steps down before the write is replicated, then is elected again
+        raft.handle_request_vote("n3", vote(2, 0, 0));
+        assert_eq!(raft.ballot.role(), Role::Follower);
+        elect(&mut raft);
+        assert_eq!(raft.ballot.term(), 3);
+
+        // The write of term 1 is on a majority, but is only committed with the entry of term 3
+        let replies = raft.handle_append_entries_ok("n2", ok(3, true, 2));
+        assert_eq!(raft.commit_index, 0);
+        assert!(replies.iter().all(|reply| reply.dest != "c1"));
+
+        let replies = raft.handle_append_entries_ok("n2", ok(3, true, 3));
+        assert_eq!(raft.commit_index, 3);
+        let reply = replies.iter().find(|reply| reply.dest == "c1").unwrap();
+        assert!(matches!(reply.body.typ, Event::WriteOk { .. }));
+    }
+
+    #[test]
+    fn ignores_stale_and_reordered_replies() {
+        let mut raft = raft("n1");
+        elect(&mut raft);
+        raft.propose(write("a"), "c1", 1).unwrap();
+        raft.propose(write("b"), "c1", 2).unwrap();
+
+        raft.handle_append_entries_ok("n2", ok(1, true, 3));
+        assert_eq!(raft.commit_index, 3);
+
+        // Replies of earlier rounds
+        raft.handle_append_entries_ok("n2", ok(1, true, 1));
+        raft.handle_append_entries_ok("n2", ok(1, false, 0));
+        assert_eq!(raft.match_index["n2"], 3);
+        assert_eq!(raft.next_index["n2"], 4);
+
+        // A reply of an earlier term
+        raft.handle_append_entries_ok("n3", ok(0, true, 3));
+        assert_eq!(raft.match_index["n3"], 0);
+        assert_eq!(raft.ballot.role(), Role::Leader);
+    }
+
+    #[test]
+    fn replaced_entries_fail_their_clients() {
+        let mut raft = raft("n1");
+        elect(&mut raft);
+        raft.handle_append_entries_ok("n2", ok(1, true, 1));
+        raft.propose(write("a"), "c1", 7).unwrap();
+
+        // n2 was elected without the write and replaced it
+        let request = append(2, 1, 1, vec![entry(2, "b")], 2);
+        let (appended, replies) = raft.handle_append_entries("n2", request);
+        assert!(appended.success);
+        assert_eq!(raft.ballot.role(), Role::Follower);
+
+        let reply = replies.iter().find(|reply| reply.dest == "c1").unwrap();
+        match &reply.body.typ {
+            Event::Error {
+                event_response,
+                error,
+            } => {
+                assert_eq!(event_response.in_reply_to, 7);
+                assert_eq!(error.code, 11);
+            }
+            typ => panic!("unexpected reply {:?}", typ),
+        }
+    }
+}
@@ -0,0 +1,126 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::sync::oneshot;
+
+use crate::{
+    events::{Body, ErrorEvent, Event, EventResponse, Message, SharedEvent},
+    transport::{MsgIds, Transport},
+};
+
+// Request/response calls to other nodes and services.
+// Replies arrive through the node's input like any other message and are handed back
+// to the caller with `deliver`.
+#[derive(Debug, Clone)]
+pub struct Rpc {
+    pending: Arc<Mutex<HashMap<u64, oneshot::Sender<Message>>>>,
+    // Shared with every other sender of msg_ids so that replies cannot be confused
+    msg_ids: Arc<MsgIds>,
+    transport: Transport,
+}
+
+#[derive(Debug)]
+pub enum RpcError {
+    // No reply within the timeout. The request may or may not have been applied
+    Timeout,
+}
+
+impl Rpc {
+    pub fn new(msg_ids: Arc<MsgIds>) -> Rpc {
+        Rpc {
+            pending: Arc::new(Mutex::new(HashMap::new())),
+            msg_ids,
+            transport: Transport {},
+        }
+    }
+
+    // `event` builds the request from its msg_id
+    pub async fn call<F>(
+        &self,
+        src: &str,
+        dest: &str,
+        event: F,
+        timeout: Duration,
+    ) -> Result<Message, RpcError>
+    where
+        F: FnOnce(SharedEvent) -> Event,
+    {
+        let msg_id = self.msg_ids.next();
+
+        let (sender, receiver) = oneshot::channel();
+        self.pending.lock().unwrap().insert(msg_id, sender);
+
+        self.transport.handleoutput(Message {
+            src: src.to_owned(),
+            dest: dest.to_owned(),
+            body: Body {
+                typ: event(SharedEvent { msg_id }),
+            },
+        });
+
+        match tokio::time::timeout(timeout, receiver).await {
+            Ok(Ok(reply)) => Ok(reply),
+            _ => {
+                self.pending.lock().unwrap().remove(&msg_id);
+                Err(RpcError::Timeout)
+            }
+        }
+    }
+
+    // Hands a reply to the call waiting for it.
+    // Returns the message back when nobody is waiting for it.
+    pub fn deliver(&self, message: Message) -> Option<Message> {
+        let in_reply_to = match message.body.in_reply_to() {
+            Some(in_reply_to) => in_reply_to,
+            None => return Some(message),
+        };
+
+        let sender = self.pending.lock().unwrap().remove(&in_reply_to);
+        match sender {
+            Some(sender) => {
+                // The caller may have timed out in the meantime
+                let _ = sender.send(message);
+                None
+            }
+            None => Some(message),
+        }
+    }
+}
+
+// Turns the reply of a forwarded request into the reply to the original request
+pub fn relay(reply: Result<Message, RpcError>, src: &str, dest: &str, in_reply_to: u64) -> Message {
+    let mut reply = match reply {
+        Ok(reply) => reply,
+        Err(err) => {
+            eprintln!("forwarded request failed: \n err: {:?}", err);
+            error_message(0, "timed out waiting for the forwarded request")
+        }
+    };
+
+    if let Some(event_response) = reply.body.event_response_mut() {
+        event_response.in_reply_to = in_reply_to;
+    }
+    src.clone_into(&mut reply.src);
+    dest.clone_into(&mut reply.dest);
+    reply
+}
+
+// Error reply. `in_reply_to` and the origins still have to be set
+pub fn error_message(code: u64, text: &str) -> Message {
+    Message {
+        src: String::new(),
+        dest: String::new(),
+        body: Body {
+            typ: Event::Error {
+                event_response: EventResponse { in_reply_to: 0 },
+                error: ErrorEvent {
+                    code,
+                    text: text.to_owned(),
+                },
+            },
+        },
+    }
+}
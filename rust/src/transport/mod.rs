@@ -1,15 +1,48 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde::{Deserialize, Serialize};
+
 use crate::events::Message;
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub struct Transport;
 
+// Allocates the msg_id of every request this node sends.
+// Replies are matched on their in_reply_to, so every sender of the node shares one allocator.
+// Independent from the ids handed out to clients.
+#[derive(Debug, Default)]
+pub struct MsgIds {
+    next: AtomicU64,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MsgIdMetrics {
+    pub allocated: u64,
+}
+
+impl MsgIds {
+    pub fn new() -> MsgIds {
+        MsgIds::default()
+    }
+
+    pub fn next(&self) -> u64 {
+        self.next.fetch_add(1, Ordering::Relaxed)
+    }
+
+    pub fn metrics(&self) -> MsgIdMetrics {
+        MsgIdMetrics {
+            allocated: self.next.load(Ordering::Relaxed),
+        }
+    }
+}
+
 impl Transport {
-    pub fn handleinput(&self, input: String) -> Result<Message, ()> {
+    pub fn handleinput(&self, input: String) -> Result<Message, serde_json::Error> {
         let message = match serde_json::from_str::<Message>(&input) {
             Ok(message) => message,
             Err(err) => {
-                handle_deserialization_error(err, input);
-                return Err(());
+                handle_deserialization_error(&err, input);
+                return Err(err);
             }
         };
         Ok(message)
@@ -29,7 +62,7 @@ fn handle_serialization_error(error: serde_json::Error, message: Message) {
     )
 }
 
-fn handle_deserialization_error(error: serde_json::Error, input: String) {
+fn handle_deserialization_error(error: &serde_json::Error, input: String) {
     eprintln!(
         "failed to deserialize input: \n Input {} \n err {:?}",
         input, error
@@ -0,0 +1,336 @@
+use std::fmt::Debug;
+
+use serde_json::Value;
+
+use crate::{
+    config::config::IdFormat,
+    uid::unique_id::{UidError, NODE_BITS, SEQUENCE_BITS, SNOWFLAKE_EPOCH, TIMESTAMP_BITS},
+};
+
+// The parts every id is built from.
+// A node never hands out the same timestamp and sequence twice, and the node index is unique
+// in the cluster, so ids keeping all three are unique.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdParts {
+    // Milliseconds since the unix epoch
+    pub timestamp: u64,
+    // Index of the node in the sorted node ids
+    pub node_index: u64,
+    // Position of the id within its millisecond
+    pub sequence: u64,
+}
+
+// Turns the parts into an id of a given format.
+// Ids of one node sort in generation order, numerically or lexicographically.
+pub trait IdGenerator: Debug + Send + Sync {
+    fn encode(&self, parts: &IdParts) -> Result<Value, UidError>;
+}
+
+// Returns the generator of the format.
+// String ids are generated by `UID::generate_unique_id` and dense ids by `BlockIds`.
+pub fn generator(format: IdFormat) -> Option<Box<dyn IdGenerator>> {
+    match format {
+        IdFormat::String | IdFormat::Dense => None,
+        IdFormat::Snowflake => Some(Box::new(Snowflake)),
+        IdFormat::UuidV7 => Some(Box::new(UuidV7)),
+        IdFormat::Ulid => Some(Box::new(Ulid)),
+        IdFormat::Ksuid => Some(Box::new(Ksuid)),
+    }
+}
+
+// 64 bit number: 41 bits of milliseconds since SNOWFLAKE_EPOCH, 10 node bits, 12 sequence bits
+#[derive(Debug, Default)]
+pub struct Snowflake;
+
+impl Snowflake {
+    pub fn to_u64(parts: &IdParts) -> Result<u64, UidError> {
+        let elapsed = match parts.timestamp.checked_sub(SNOWFLAKE_EPOCH) {
+            Some(elapsed) if elapsed < 1 << TIMESTAMP_BITS => elapsed,
+            _ => return Err(UidError::GenerateError),
+        };
+
+        Ok(elapsed << (NODE_BITS + SEQUENCE_BITS)
+            | parts.node_index << SEQUENCE_BITS
+            | parts.sequence)
+    }
+}
+
+impl IdGenerator for Snowflake {
+    fn encode(&self, parts: &IdParts) -> Result<Value, UidError> {
+        Snowflake::to_u64(parts).map(Value::from)
+    }
+}
+
+// RFC 9562 version 7: 48 bits of milliseconds, the version, 12 bits of sequence in `rand_a`,
+// the variant, then the node and random bits in `rand_b`
+#[derive(Debug, Default)]
+pub struct UuidV7;
+
+impl IdGenerator for UuidV7 {
+    fn encode(&self, parts: &IdParts) -> Result<Value, UidError> {
+        let timestamp = fit(parts.timestamp, 48)?;
+
+        let high = timestamp << 16 | 0x7 << 12 | parts.sequence;
+        let low = 0b10 << 62 | parts.node_index << 52 | random_bits(52) as u64;
+
+        Ok(Value::from(format!(
+            "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+            high >> 32,
+            (high >> 16) & 0xffff,
+            high & 0xffff,
+            low >> 48,
+            low & 0xffff_ffff_ffff
+        )))
+    }
+}
+
+// 48 bits of milliseconds, then 80 bits holding the node, the sequence and random bits.
+// Crockford's base32, 26 characters.
+#[derive(Debug, Default)]
+pub struct Ulid;
+
+const CROCKFORD: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+impl IdGenerator for Ulid {
+    fn encode(&self, parts: &IdParts) -> Result<Value, UidError> {
+        let timestamp = fit(parts.timestamp, 48)? as u128;
+
+        let value = timestamp << 80
+            | (parts.node_index as u128) << 70
+            | (parts.sequence as u128) << 58
+            | random_bits(58);
+
+        // 130 bits of characters for 128 bits of value. The first character holds 3 bits
+        let id = (0..26)
+            .map(|i| CROCKFORD[((value >> (5 * (25 - i))) & 0x1f) as usize] as char)
+            .collect::<String>();
+        Ok(Value::from(id))
+    }
+}
+
+// 32 bits of seconds since KSUID_EPOCH, then a 128 bit payload holding the node, the millisecond
+// within the second, the sequence and random bits. Base62, 27 characters.
+#[derive(Debug, Default)]
+pub struct Ksuid;
+
+const KSUID_EPOCH: u64 = 1_400_000_000;
+const BASE62: &[u8; 62] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+impl IdGenerator for Ksuid {
+    fn encode(&self, parts: &IdParts) -> Result<Value, UidError> {
+        let seconds = match (parts.timestamp / 1000).checked_sub(KSUID_EPOCH) {
+            Some(seconds) => fit(seconds, 32)?,
+            None => return Err(UidError::GenerateError),
+        };
+
+        let payload = (parts.node_index as u128) << 118
+            | ((parts.timestamp % 1000) as u128) << 108
+            | (parts.sequence as u128) << 96
+            | random_bits(96);
+
+        let mut bytes = [0u8; 20];
+        bytes[..4].copy_from_slice(&(seconds as u32).to_be_bytes());
+        bytes[4..].copy_from_slice(&payload.to_be_bytes());
+
+        Ok(Value::from(base62(&bytes, 27)))
+    }
+}
+
+fn fit(value: u64, bits: u32) -> Result<u64, UidError> {
+    if value >> bits == 0 {
+        Ok(value)
+    } else {
+        Err(UidError::GenerateError)
+    }
+}
+
+fn random_bits(bits: u32) -> u128 {
+    rand::random::<u128>() & ((1 << bits) - 1)
+}
+
+// Big-endian bytes to base62, left padded with zeros to `width` characters
+fn base62(bytes: &[u8], width: usize) -> String {
+    let mut number = bytes.to_vec();
+    let mut digits = vec![];
+
+    // Long division by 62, one digit per pass
+    while number.iter().any(|byte| *byte != 0) {
+        let mut remainder = 0u32;
+        for byte in number.iter_mut() {
+            let value = remainder << 8 | *byte as u32;
+            *byte = (value / 62) as u8;
+            remainder = value % 62;
+        }
+        digits.push(BASE62[remainder as usize]);
+    }
+
+    while digits.len() < width {
+        digits.push(b'0');
+    }
+    digits.iter().rev().map(|digit| *digit as char).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2016-07-30, the timestamp of the example in the ULID spec
+    const START: u64 = 1_469_918_176_385;
+
+    fn parts(timestamp: u64, node_index: u64, sequence: u64) -> IdParts {
+        IdParts {
+            timestamp,
+            node_index,
+            sequence,
+        }
+    }
+
+    fn encode(generator: &dyn IdGenerator, parts: &IdParts) -> String {
+        generator
+            .encode(parts)
+            .unwrap()
+            .as_str()
+            .unwrap()
+            .to_owned()
+    }
+
+    // Ids of one node in generation order, across sequences, milliseconds and seconds
+    fn in_generation_order(node_index: u64) -> Vec<IdParts> {
+        [START, START + 1, START + 614, START + 615, START + 60_000]
+            .into_iter()
+            .flat_map(|timestamp| {
+                [0, 1, 2, 4095]
+                    .into_iter()
+                    .map(move |sequence| parts(timestamp, node_index, sequence))
+            })
+            .collect()
+    }
+
+    fn assert_sorted(generator: &dyn IdGenerator) {
+        let ids = in_generation_order(7)
+            .iter()
+            .map(|parts| encode(generator, parts))
+            .collect::<Vec<_>>();
+        for pair in ids.windows(2) {
+            assert!(pair[0] < pair[1], "{} sorts after {}", pair[0], pair[1]);
+        }
+    }
+
+    fn crockford_decode(id: &str) -> u128 {
+        id.bytes().fold(0, |value, c| {
+            let digit = CROCKFORD.iter().position(|d| *d == c).unwrap();
+            value << 5 | digit as u128
+        })
+    }
+
+    fn base62_decode(id: &str) -> [u8; 20] {
+        let mut bytes = [0u8; 20];
+        for c in id.bytes() {
+            let mut carry = BASE62.iter().position(|d| *d == c).unwrap() as u32;
+            for byte in bytes.iter_mut().rev() {
+                let value = *byte as u32 * 62 + carry;
+                *byte = value as u8;
+                carry = value >> 8;
+            }
+            assert_eq!(carry, 0, "{} overflows 20 bytes", id);
+        }
+        bytes
+    }
+
+    #[test]
+    fn uuidv7_sets_the_version_and_the_variant() {
+        let id = encode(&UuidV7, &parts(START, 5, 42));
+
+        assert_eq!(id.len(), 36);
+        let dashes = id
+            .char_indices()
+            .filter(|(_, c)| *c == '-')
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+        assert_eq!(dashes, vec![8, 13, 18, 23]);
+        assert!(id.chars().all(|c| c == '-' || c.is_ascii_hexdigit()));
+        assert!(!id.chars().any(|c| c.is_ascii_uppercase()));
+
+        let value = u128::from_str_radix(&id.replace('-', ""), 16).unwrap();
+        assert_eq!((value >> 76) & 0xf, 7);
+        assert_eq!((value >> 62) & 0b11, 0b10);
+        assert_eq!(value >> 80, START as u128);
+        assert_eq!((value >> 64) & 0xfff, 42);
+        assert_eq!((value >> 52) & 0x3ff, 5);
+    }
+
+    #[test]
+    fn uuidv7_rejects_timestamps_past_48_bits() {
+        assert!(matches!(
+            UuidV7.encode(&parts(1 << 48, 0, 0)),
+            Err(UidError::GenerateError)
+        ));
+    }
+
+    #[test]
+    fn ulid_uses_crockford_base32() {
+        let id = encode(&Ulid, &parts(START, 5, 42));
+
+        assert_eq!(id.len(), 26);
+        assert!(id.bytes().all(|c| CROCKFORD.contains(&c)));
+        // The letters that read like digits are left out
+        assert!(!id.contains(['I', 'L', 'O', 'U']));
+        // 130 bits of characters for 128 bits
+        assert!(id.as_bytes()[0] <= b'7');
+        // The timestamp of the example in the spec
+        assert!(id.starts_with("01ARYZ6S41"), "{}", id);
+
+        let value = crockford_decode(&id);
+        assert_eq!(value >> 80, START as u128);
+        assert_eq!((value >> 70) & 0x3ff, 5);
+        assert_eq!((value >> 58) & 0xfff, 42);
+    }
+
+    #[test]
+    fn base62_pads_and_divides_every_byte() {
+        assert_eq!(base62(&[0; 20], 27), "0".repeat(27));
+        assert_eq!(base62(&[61], 1), "z");
+        assert_eq!(base62(&[62], 1), "10");
+        // 256 = 4 * 62 + 8
+        assert_eq!(base62(&[1, 0], 3), "048");
+        // The largest KSUID
+        assert_eq!(base62(&[0xff; 20], 27), "aWgEPTl1tmebfsQzFP4bxwgy80V");
+
+        // The example of the KSUID reference implementation
+        let mut bytes = [0u8; 20];
+        bytes[..4].copy_from_slice(&107_608_047u32.to_be_bytes());
+        bytes[4..].copy_from_slice(&0xB5A1CD34B5F99D1154FB6853345C9735u128.to_be_bytes());
+        assert_eq!(base62(&bytes, 27), "0ujtsYcgvSTl8PAuAdqWYSMnLOv");
+    }
+
+    #[test]
+    fn ksuid_embeds_seconds_since_its_epoch() {
+        let id = encode(&Ksuid, &parts(START, 5, 42));
+
+        assert_eq!(id.len(), 27);
+        assert!(id.bytes().all(|c| c.is_ascii_alphanumeric()));
+
+        let bytes = base62_decode(&id);
+        let seconds = u32::from_be_bytes(bytes[..4].try_into().unwrap()) as u64;
+        let payload = u128::from_be_bytes(bytes[4..].try_into().unwrap());
+        assert_eq!(seconds, START / 1000 - KSUID_EPOCH);
+        assert_eq!(payload >> 118, 5);
+        assert_eq!((payload >> 108) & 0x3ff, (START % 1000) as u128);
+        assert_eq!((payload >> 96) & 0xfff, 42);
+    }
+
+    #[test]
+    fn ksuid_rejects_timestamps_before_its_epoch() {
+        assert!(matches!(
+            Ksuid.encode(&parts(KSUID_EPOCH * 1000 - 1, 0, 0)),
+            Err(UidError::GenerateError)
+        ));
+    }
+
+    #[test]
+    fn ids_of_a_node_sort_in_generation_order() {
+        assert_sorted(&UuidV7);
+        assert_sorted(&Ulid);
+        assert_sorted(&Ksuid);
+    }
+}
@@ -0,0 +1,158 @@
+use std::{
+    collections::VecDeque,
+    ops::Range,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serde_json::json;
+use tokio::sync::Notify;
+
+use crate::{
+    config::config::LeaseConfig,
+    kv::kv::{Kv, KvError},
+    uid::unique_id::UidError,
+};
+
+// Next free id of the cluster, in lin-kv
+const COUNTER_KEY: &str = "id-blocks";
+
+// Give up leasing a block after this many conflicts or timeouts
+const MAX_ATTEMPTS: usize = 50;
+
+// How long `next` waits for a block before failing
+const LEASE_TIMEOUT: Duration = Duration::from_millis(2000);
+
+// Dense integer ids, unique across the cluster and across restarts.
+// Every node leases blocks of ids from a counter in lin-kv with a compare-and-set and hands
+// them out locally. A new block is leased in the background once the remaining ids fall below
+// the low-water mark.
+// A lease that times out may or may not have been applied, so its block is never used.
+// Ids are dense within a block, not across blocks.
+#[derive(Debug, Clone)]
+pub struct BlockIds {
+    kv: Kv,
+    config: LeaseConfig,
+    state: Arc<Mutex<LeaseState>>,
+    refilled: Arc<Notify>,
+}
+
+#[derive(Debug, Default)]
+struct LeaseState {
+    blocks: VecDeque<Range<u64>>,
+    refilling: bool,
+}
+
+impl LeaseState {
+    fn take(&mut self) -> Option<u64> {
+        while let Some(block) = self.blocks.front_mut() {
+            match block.next() {
+                Some(id) => return Some(id),
+                None => {
+                    self.blocks.pop_front();
+                }
+            }
+        }
+        None
+    }
+
+    fn remaining(&self) -> u64 {
+        self.blocks
+            .iter()
+            .map(|block| block.end - block.start)
+            .sum()
+    }
+}
+
+impl BlockIds {
+    pub fn new(kv: Kv, config: LeaseConfig) -> BlockIds {
+        BlockIds {
+            kv,
+            config,
+            state: Arc::new(Mutex::new(LeaseState::default())),
+            refilled: Arc::new(Notify::new()),
+        }
+    }
+
+    // Must be called once the node knows its id, before any id is generated
+    pub fn set_node_id(&mut self, node_id: &str) {
+        self.kv.set_node_id(node_id);
+    }
+
+    pub async fn next(&self) -> Result<u64, UidError> {
+        let deadline = tokio::time::Instant::now() + LEASE_TIMEOUT;
+
+        loop {
+            // Created before the state is checked so that a refill in between is not missed
+            let refilled = self.refilled.notified();
+
+            {
+                let mut state = self.state.lock().unwrap();
+                let id = state.take();
+                if state.remaining() < self.config.low_water {
+                    self.refill(&mut state);
+                }
+                if let Some(id) = id {
+                    return Ok(id);
+                }
+            }
+
+            if tokio::time::timeout_at(deadline, refilled).await.is_err() {
+                return Err(UidError::LeaseUnavailable);
+            }
+        }
+    }
+
+    // Leases a block in the background, unless a lease is already in flight
+    fn refill(&self, state: &mut LeaseState) {
+        if state.refilling {
+            return;
+        }
+        state.refilling = true;
+
+        let block_ids = self.clone();
+        tokio::spawn(async move {
+            let block = block_ids.lease().await;
+
+            let mut state = block_ids.state.lock().unwrap();
+            state.refilling = false;
+            match block {
+                Ok(block) => state.blocks.push_back(block),
+                Err(err) => eprintln!("failed to lease a block of ids: \n err: {:?}", err),
+            }
+            drop(state);
+
+            // Waiters retry, and lease again after a failure
+            block_ids.refilled.notify_waiters();
+        });
+    }
+
+    async fn lease(&self) -> Result<Range<u64>, KvError> {
+        let mut last_err = KvError::Timeout;
+
+        for _ in 0..MAX_ATTEMPTS {
+            let start = match self.kv.read(COUNTER_KEY).await {
+                Ok(start) => start.as_u64().unwrap_or_default(),
+                Err(KvError::KeyDoesNotExist) => 0,
+                Err(err) => {
+                    last_err = err;
+                    continue;
+                }
+            };
+            let end = start + self.config.block_size;
+
+            match self
+                .kv
+                .cas(COUNTER_KEY, json!(start), json!(end), true)
+                .await
+            {
+                Ok(()) => return Ok(start..end),
+                // Another node leased the block first, or the lease timed out and
+                // the block may belong to nobody. Either way, lease the next one
+                Err(err) => last_err = err,
+            }
+        }
+
+        Err(last_err)
+    }
+}
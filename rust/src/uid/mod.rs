@@ -1 +1,3 @@
+pub mod generator;
+pub mod lease;
 pub mod unique_id;
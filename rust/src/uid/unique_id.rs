@@ -1,12 +1,128 @@
-use tokio::sync::Mutex;
+use std::{
+    fmt::Debug,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::time::Instant;
+
+use crate::{
+    config::config::ClockRegression,
+    events::ErrorEvent,
+    uid::generator::{IdGenerator, IdParts, Snowflake},
+};
+
+// Layout of a snowflake, from the most significant bit:
+// 1 unused bit, 41 bits of milliseconds since SNOWFLAKE_EPOCH, 10 node bits, 12 sequence bits.
+// The timestamp lasts about 69 years past the epoch.
+pub const SNOWFLAKE_EPOCH: u64 = 1_704_067_200_000; // 2024-01-01T00:00:00Z
+pub const TIMESTAMP_BITS: u32 = 41;
+pub const NODE_BITS: u32 = 10;
+pub const SEQUENCE_BITS: u32 = 12;
+pub const MAX_NODES: u64 = 1 << NODE_BITS;
+const MAX_SEQUENCE: u64 = (1 << SEQUENCE_BITS) - 1;
+
+// Source of the timestamps embedded in ids
+pub trait Clock: Debug + Send + Sync {
+    // Milliseconds since the unix epoch
+    fn now_ms(&self) -> u64;
+}
 
 #[derive(Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_ms(&self) -> u64 {
+        // A clock before 1970 reads as 0 and fails the generation
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_millis() as u64)
+    }
+}
+
+// Id service behind `generate`. Lock free, and independent from the msg_ids of the transport.
+#[derive(Debug)]
 pub struct UID {
-    message_counter: Mutex<u64>,
+    // Counter of the string ids
+    string_counter: AtomicU64,
+    // Timestamp of the last string id
+    string_timestamp: AtomicU64,
+    // Timestamp of the last snowflake, shifted left by SEQUENCE_BITS, and its sequence
+    snowflake: AtomicU64,
+    clock: Arc<dyn Clock>,
+    regression: ClockRegression,
+    metrics: Metrics,
+}
+
+impl Default for UID {
+    fn default() -> Self {
+        UID::with_clock(Arc::new(SystemClock), ClockRegression::default())
+    }
 }
+
+#[derive(Debug, Default)]
+struct Metrics {
+    generated: AtomicU64,
+    failed: AtomicU64,
+    clock_regressions: AtomicU64,
+    sequence_rollovers: AtomicU64,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct IdMetrics {
+    pub generated: u64,
+    pub failed: u64,
+    // Times the clock read earlier than the last id
+    pub clock_regressions: u64,
+    // Times every sequence number of a millisecond was taken
+    pub sequence_rollovers: u64,
+}
+
 #[derive(Debug)]
 pub enum UidError {
     GenerateError,
+    // The node index does not fit in the node bits
+    NodeOutOfRange(u64),
+    // The clock is behind the last id and the policy does not allow to go on
+    ClockMovedBackwards { last: u64, now: u64 },
+    // No block of ids could be leased in time
+    LeaseUnavailable,
+    // The node is not in the membership, e.g. before the init message
+    UnknownNode(String),
+}
+
+impl From<UidError> for ErrorEvent {
+    fn from(err: UidError) -> Self {
+        ErrorEvent {
+            code: err.code(),
+            text: err.text(),
+        }
+    }
+}
+
+impl UidError {
+    // Maelstrom error code
+    pub fn code(&self) -> u64 {
+        match self {
+            // temporarily-unavailable
+            UidError::UnknownNode(_) => 11,
+            _ => 1003,
+        }
+    }
+
+    pub fn text(&self) -> String {
+        match self {
+            UidError::UnknownNode(node_id) => {
+                format!("node {:?} is not in the cluster membership", node_id)
+            }
+            _ => "failed to generate id".to_string(),
+        }
+    }
 }
 
 impl UID {
@@ -14,32 +130,331 @@ impl UID {
         UID::default()
     }
 
-    pub async fn generate_unique_id(&mut self, node_id: &str) -> Result<String, UidError> {
+    pub fn with_clock(clock: Arc<dyn Clock>, regression: ClockRegression) -> UID {
+        UID {
+            string_counter: AtomicU64::new(0),
+            string_timestamp: AtomicU64::new(0),
+            snowflake: AtomicU64::new(0),
+            clock,
+            regression,
+            metrics: Metrics::default(),
+        }
+    }
+
+    pub fn metrics(&self) -> IdMetrics {
+        IdMetrics {
+            generated: self.metrics.generated.load(Ordering::Relaxed),
+            failed: self.metrics.failed.load(Ordering::Relaxed),
+            clock_regressions: self.metrics.clock_regressions.load(Ordering::Relaxed),
+            sequence_rollovers: self.metrics.sequence_rollovers.load(Ordering::Relaxed),
+        }
+    }
+
+    pub async fn generate_unique_id(&self, node_id: &str) -> Result<String, UidError> {
         // Generate a snowflake with the following parts
         // Timestamp in milliseconds.
         // The node identifier. A node can generate 1000 ids per second without any breaking uniqueness with other nodes
         // A node's sequence. Ensure uniqueness within a node when it generates more than 1 id within the same millisecond.
 
-        let mut message_counter = self.message_counter.lock().await;
+        let last_timestamp = self.string_timestamp.load(Ordering::Acquire);
+        let timestamp = match self.next_timestamp(last_timestamp, false).await {
+            Ok(timestamp) => timestamp,
+            Err(err) => return self.record(Err(err)),
+        };
+        self.string_timestamp.fetch_max(timestamp, Ordering::AcqRel);
+
+        // The counter alone keeps the ids unique
+        let counter = self.string_counter.fetch_add(1, Ordering::Relaxed);
+
+        let snowflake = format!("{}-{}-{}", timestamp, node_id, counter);
+        self.record(Ok(snowflake))
+    }
+
+    // Ids of the same node are increasing. Ids of different nodes are ordered by time,
+    // down to the millisecond.
+    pub async fn generate_snowflake(&self, node_index: u64) -> Result<u64, UidError> {
+        let id = match self.next_parts(node_index).await {
+            Ok(parts) => Snowflake::to_u64(&parts),
+            Err(err) => Err(err),
+        };
+        self.record(id)
+    }
 
-        let timestamp = match std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
-            Ok(duration) => duration.as_millis(),
-            Err(_) => {
-                eprintln!("failed to generate timestamp");
-                return Err(UidError::GenerateError);
+    // An id in the format of the generator
+    pub async fn generate(
+        &self,
+        node_index: u64,
+        generator: &dyn IdGenerator,
+    ) -> Result<Value, UidError> {
+        let id = match self.next_parts(node_index).await {
+            Ok(parts) => generator.encode(&parts),
+            Err(err) => Err(err),
+        };
+        self.record(id)
+    }
+
+    fn record<T>(&self, id: Result<T, UidError>) -> Result<T, UidError> {
+        let counter = match id {
+            Ok(_) => &self.metrics.generated,
+            Err(_) => &self.metrics.failed,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        id
+    }
+
+    // The timestamp and sequence of the next id. Shared by every numeric and sortable format
+    async fn next_parts(&self, node_index: u64) -> Result<IdParts, UidError> {
+        if node_index >= MAX_NODES {
+            return Err(UidError::NodeOutOfRange(node_index));
+        }
+
+        loop {
+            let state = self.snowflake.load(Ordering::Acquire);
+            let last_timestamp = state >> SEQUENCE_BITS;
+            let last_sequence = state & MAX_SEQUENCE;
+
+            let mut timestamp = self.next_timestamp(last_timestamp, false).await?;
+            let sequence = if timestamp > last_timestamp {
+                0
+            } else if last_sequence < MAX_SEQUENCE {
+                last_sequence + 1
+            } else {
+                // Every id of this millisecond is taken. Move to the next one
+                self.metrics
+                    .sequence_rollovers
+                    .fetch_add(1, Ordering::Relaxed);
+                timestamp = self.next_timestamp(last_timestamp, true).await?;
+                0
+            };
+
+            // Somebody else took an id in the meantime. Start over from their id
+            let next = timestamp << SEQUENCE_BITS | sequence;
+            if self
+                .snowflake
+                .compare_exchange(state, next, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+            {
+                return Ok(IdParts {
+                    timestamp,
+                    node_index,
+                    sequence,
+                });
             }
+        }
+    }
+
+    // Returns a timestamp no earlier than `last`, or later than `last` when `after` is set.
+    // A clock behind `last` is handled according to the regression policy.
+    async fn next_timestamp(&self, last: u64, after: bool) -> Result<u64, UidError> {
+        let min = if after { last + 1 } else { last };
+        let started = Instant::now();
+        let mut regressed = false;
+
+        loop {
+            let now = self.clock.now_ms();
+            if now >= min {
+                return Ok(now);
+            }
+
+            // Waiting for the next millisecond is expected. Anything before `last` is a regression
+            if now < last {
+                if !regressed {
+                    regressed = true;
+                    self.metrics
+                        .clock_regressions
+                        .fetch_add(1, Ordering::Relaxed);
+                }
+
+                match self.regression {
+                    ClockRegression::Fail => {
+                        return Err(UidError::ClockMovedBackwards { last, now })
+                    }
+                    // Keep counting from the last timestamp until the clock catches up
+                    ClockRegression::Borrow => return Ok(min),
+                    ClockRegression::Wait(max_wait) if started.elapsed() >= max_wait => {
+                        return Err(UidError::ClockMovedBackwards { last, now })
+                    }
+                    ClockRegression::Wait(_) => {}
+                }
+            }
+
+            tokio::time::sleep(Duration::from_micros(100)).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const START: u64 = SNOWFLAKE_EPOCH + 1_000_000;
+
+    #[derive(Debug)]
+    struct FakeClock(AtomicU64);
+
+    impl FakeClock {
+        fn new(now: u64) -> Arc<FakeClock> {
+            Arc::new(FakeClock(AtomicU64::new(now)))
+        }
+
+        fn set(&self, now: u64) {
+            self.0.store(now, Ordering::SeqCst);
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now_ms(&self) -> u64 {
+            self.0.load(Ordering::SeqCst)
+        }
+    }
+
+    fn timestamp(id: u64) -> u64 {
+        (id >> (NODE_BITS + SEQUENCE_BITS)) + SNOWFLAKE_EPOCH
+    }
+
+    #[tokio::test]
+    async fn snowflakes_embed_the_node_and_the_sequence() {
+        let clock = FakeClock::new(START);
+        let uid = UID::with_clock(clock.clone(), ClockRegression::Fail);
+
+        let first = uid.generate_snowflake(5).await.unwrap();
+        let second = uid.generate_snowflake(5).await.unwrap();
+
+        assert_eq!(timestamp(first), START);
+        assert_eq!((first >> SEQUENCE_BITS) & (MAX_NODES - 1), 5);
+        assert_eq!(first & MAX_SEQUENCE, 0);
+        assert_eq!(second, first + 1);
+
+        clock.set(START + 1);
+        let third = uid.generate_snowflake(5).await.unwrap();
+        assert_eq!(timestamp(third), START + 1);
+        assert_eq!(third & MAX_SEQUENCE, 0);
+
+        assert!(matches!(
+            uid.generate_snowflake(MAX_NODES).await,
+            Err(UidError::NodeOutOfRange(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn fail_policy_rejects_a_regression() {
+        let clock = FakeClock::new(START);
+        let uid = UID::with_clock(clock.clone(), ClockRegression::Fail);
+
+        uid.generate_snowflake(0).await.unwrap();
+        clock.set(START - 10);
+
+        match uid.generate_snowflake(0).await {
+            Err(UidError::ClockMovedBackwards { last, now }) => {
+                assert_eq!(last, START);
+                assert_eq!(now, START - 10);
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        // Ids resume once the clock catches up
+        clock.set(START);
+        let id = uid.generate_snowflake(0).await.unwrap();
+        assert_eq!(timestamp(id), START);
+        assert_eq!(id & MAX_SEQUENCE, 1);
+    }
+
+    #[tokio::test]
+    async fn fail_policy_applies_to_string_ids() {
+        let clock = FakeClock::new(START);
+        let uid = UID::with_clock(clock.clone(), ClockRegression::Fail);
+
+        let id = uid.generate_unique_id("n0").await.unwrap();
+        assert_eq!(id, format!("{}-n0-0", START));
+
+        clock.set(START - 1);
+        assert!(matches!(
+            uid.generate_unique_id("n0").await,
+            Err(UidError::ClockMovedBackwards { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn borrow_policy_keeps_ids_increasing() {
+        let clock = FakeClock::new(START);
+        let uid = UID::with_clock(clock.clone(), ClockRegression::Borrow);
+
+        let mut last = uid.generate_snowflake(1).await.unwrap();
+        clock.set(START - 1000);
+
+        // More ids than a millisecond holds, all while the clock is behind
+        for _ in 0..2 * (MAX_SEQUENCE + 1) {
+            let id = uid.generate_snowflake(1).await.unwrap();
+            assert!(id > last);
+            last = id;
+        }
+        assert_eq!(timestamp(last), START + 2);
+
+        // The clock catching up does not go back either
+        clock.set(START + 1);
+        assert!(uid.generate_snowflake(1).await.unwrap() > last);
+    }
+
+    #[tokio::test]
+    async fn wait_policy_waits_for_the_clock_to_catch_up() {
+        let clock = FakeClock::new(START);
+        let uid = UID::with_clock(clock.clone(), ClockRegression::Wait(Duration::from_secs(5)));
+
+        let first = uid.generate_snowflake(0).await.unwrap();
+        clock.set(START - 5);
+
+        let catch_up = {
+            let clock = clock.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                clock.set(START + 3);
+            })
         };
 
-        let snowflake = format!("{}-{}-{}", timestamp, node_id, message_counter);
-        *message_counter += 1;
-        Ok(snowflake)
+        let second = uid.generate_snowflake(0).await.unwrap();
+        catch_up.await.unwrap();
+
+        assert!(second > first);
+        assert_eq!(timestamp(second), START + 3);
+    }
+
+    #[tokio::test]
+    async fn wait_policy_gives_up_after_the_maximum_wait() {
+        let clock = FakeClock::new(START);
+        let uid = UID::with_clock(
+            clock.clone(),
+            ClockRegression::Wait(Duration::from_millis(10)),
+        );
+
+        uid.generate_snowflake(0).await.unwrap();
+        clock.set(START - 5);
+
+        assert!(matches!(
+            uid.generate_snowflake(0).await,
+            Err(UidError::ClockMovedBackwards { .. })
+        ));
     }
-    pub async fn generate_int_unique_id(&mut self) -> Result<u64, UidError> {
-        // Generates an integer id that doesn't clash with the above snowflake implementation
 
-        let mut message_counter = self.message_counter.lock().await;
-        let id = *message_counter;
-        *message_counter += 1;
-        Ok(id)
+    #[tokio::test]
+    async fn exhausted_sequence_waits_for_the_next_millisecond() {
+        let clock = FakeClock::new(START);
+        let uid = UID::with_clock(clock.clone(), ClockRegression::Fail);
+
+        for _ in 0..=MAX_SEQUENCE {
+            uid.generate_snowflake(0).await.unwrap();
+        }
+
+        let next = {
+            let clock = clock.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                clock.set(START + 1);
+            })
+        };
+
+        let id = uid.generate_snowflake(0).await.unwrap();
+        next.await.unwrap();
+
+        assert_eq!(timestamp(id), START + 1);
+        assert_eq!(id & MAX_SEQUENCE, 0);
     }
 }
@@ -0,0 +1,339 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    config::config::{DataConfig, FsyncPolicy},
+    log::log::{now, BatchMessage, KLogSnapshot},
+};
+
+const WAL_FILE: &str = "wal.log";
+const SNAPSHOT_FILE: &str = "snapshot.json";
+
+// A change to the node state, recorded once it has been applied and before it is acknowledged
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Op {
+    // Broadcast values by distributed message id
+    Values {
+        values: HashMap<String, Value>,
+    },
+    Topology {
+        neighbours: Vec<String>,
+    },
+    // State or delta of the replicated counter or set, merged on replay
+    Replicated {
+        state: Value,
+    },
+    Append {
+        key: String,
+        msg: Value,
+        msg_key: Option<String>,
+        producer_id: Option<String>,
+        seq: Option<u64>,
+        // Replayed with the original time, so retention by age still applies
+        #[serde(default = "now")]
+        timestamp: u64,
+    },
+    SendBatch {
+        msgs: Vec<BatchMessage>,
+        offsets: HashMap<String, u64>,
+        group: Option<String>,
+        #[serde(default = "now")]
+        timestamp: u64,
+    },
+    CommitOffsets {
+        offsets: HashMap<String, u64>,
+        group: Option<String>,
+    },
+    // The part of a batch spanning several owners, and the decision of its coordinator
+    TxnPrepare {
+        txn: String,
+        coordinator: String,
+        msgs: Vec<BatchMessage>,
+        offsets: HashMap<String, u64>,
+        group: Option<String>,
+        #[serde(default = "now")]
+        timestamp: u64,
+    },
+    TxnDecide {
+        txn: String,
+        commit: bool,
+    },
+    JoinGroup {
+        group: String,
+        member: String,
+    },
+    LeaveGroup {
+        group: String,
+        member: String,
+    },
+}
+
+// Everything the operations have built up so far
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct State {
+    pub values: HashMap<String, Value>,
+    pub neighbours: Vec<String>,
+    // Missing when the kafka log persists itself
+    pub klog: Option<KLogSnapshot>,
+    // Members of every consumer group, which the kafka log does not persist itself
+    #[serde(default)]
+    pub groups: HashMap<String, Vec<String>>,
+    // Whole state of the replicated counter or set
+    #[serde(default)]
+    pub replicated: Option<Value>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Record {
+    seq: u64,
+    op: Op,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    // Sequence of the last operation included in the state
+    seq: u64,
+    state: State,
+}
+
+// What is found in the directory on startup. The operations come after the state.
+#[derive(Debug, Default)]
+pub struct Recovered {
+    pub state: Option<State>,
+    pub ops: Vec<Op>,
+}
+
+// Write-ahead log of the node state.
+// Every operation is appended to `wal.log` with a sequence number. Every `snapshot_every`
+// operations the whole state is written to `snapshot.json` and the log starts over.
+// A crash between the two leaves operations already in the snapshot at the start of the log,
+// and they are skipped by their sequence number.
+#[derive(Debug)]
+pub struct Wal {
+    config: DataConfig,
+    file: File,
+    // Sequence of the last appended operation
+    seq: u64,
+    unsynced: u64,
+    since_snapshot: u64,
+}
+
+impl Wal {
+    pub fn open(config: DataConfig) -> io::Result<(Wal, Recovered)> {
+        fs::create_dir_all(&config.dir)?;
+
+        let snapshot = match fs::read(config.dir.join(SNAPSHOT_FILE)) {
+            Ok(bytes) => {
+                Some(serde_json::from_slice::<Snapshot>(&bytes).map_err(io::Error::other)?)
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+            Err(err) => return Err(err),
+        };
+        let mut seq = snapshot.as_ref().map_or(0, |snapshot| snapshot.seq);
+
+        let path = config.dir.join(WAL_FILE);
+        let mut ops = vec![];
+        let mut valid_bytes = 0;
+        if path.exists() {
+            let reader = BufReader::new(File::open(&path)?);
+            for line in reader.split(b'\n') {
+                let line = line?;
+                // A torn last line is dropped
+                let record = match serde_json::from_slice::<Record>(&line) {
+                    Ok(record) => record,
+                    Err(_) => break,
+                };
+                valid_bytes += line.len() as u64 + 1;
+
+                if record.seq > seq {
+                    seq = record.seq;
+                    ops.push(record.op);
+                }
+            }
+        }
+
+        let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+        if valid_bytes > file.metadata()?.len() {
+            // The last record is complete but misses its newline
+            file.write_all(b"\n")?;
+        } else {
+            file.set_len(valid_bytes)?;
+        }
+
+        let since_snapshot = ops.len() as u64;
+        let wal = Wal {
+            config,
+            file,
+            seq,
+            unsynced: 0,
+            since_snapshot,
+        };
+        let recovered = Recovered {
+            state: snapshot.map(|snapshot| snapshot.state),
+            ops,
+        };
+        Ok((wal, recovered))
+    }
+
+    pub fn append(&mut self, op: Op) -> io::Result<()> {
+        let record = Record {
+            seq: self.seq + 1,
+            op,
+        };
+        let mut line = serde_json::to_vec(&record).map_err(io::Error::other)?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+
+        self.seq = record.seq;
+        self.since_snapshot += 1;
+        self.unsynced += 1;
+        let sync = match self.config.fsync {
+            FsyncPolicy::Always => true,
+            FsyncPolicy::Every(n) => self.unsynced >= n,
+            FsyncPolicy::Never => false,
+        };
+        if sync {
+            self.file.sync_data()?;
+            self.unsynced = 0;
+        }
+        Ok(())
+    }
+
+    pub fn needs_snapshot(&self) -> bool {
+        self.since_snapshot >= self.config.snapshot_every
+    }
+
+    // `state` must include every appended operation
+    pub fn snapshot(&mut self, state: State) -> io::Result<()> {
+        let snapshot = Snapshot {
+            seq: self.seq,
+            state,
+        };
+        let tmp = self.config.dir.join("snapshot.tmp");
+
+        let mut file = File::create(&tmp)?;
+        file.write_all(&serde_json::to_vec(&snapshot).map_err(io::Error::other)?)?;
+        if self.config.fsync != FsyncPolicy::Never {
+            file.sync_data()?;
+        }
+        fs::rename(tmp, self.config.dir.join(SNAPSHOT_FILE))?;
+
+        self.file.set_len(0)?;
+        self.unsynced = 0;
+        self.since_snapshot = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open(name: &str) -> (Wal, Recovered) {
+        Wal::open(DataConfig::new(dir(name))).unwrap()
+    }
+
+    fn dir(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("wal-{}-{}", name, std::process::id()))
+    }
+
+    fn fresh(name: &str) -> (Wal, Recovered) {
+        let _ = fs::remove_dir_all(dir(name));
+        open(name)
+    }
+
+    fn topology(node: &str) -> Op {
+        Op::Topology {
+            neighbours: vec![node.to_owned()],
+        }
+    }
+
+    fn neighbours(ops: &[Op]) -> Vec<String> {
+        ops.iter()
+            .map(|op| match op {
+                Op::Topology { neighbours } => neighbours[0].clone(),
+                op => panic!("unexpected op {:?}", op),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn torn_tail_is_truncated() {
+        let (mut wal, _) = fresh("torn");
+        wal.append(topology("n1")).unwrap();
+        wal.append(topology("n2")).unwrap();
+        drop(wal);
+
+        let path = dir("torn").join(WAL_FILE);
+        let valid = fs::metadata(&path).unwrap().len();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(br#"{"seq":3,"op":{"op":"topo"#).unwrap();
+        drop(file);
+
+        let (mut wal, recovered) = open("torn");
+        assert_eq!(neighbours(&recovered.ops), ["n1", "n2"]);
+        assert_eq!(fs::metadata(&path).unwrap().len(), valid);
+
+        // Records appended after the truncation are read back
+        wal.append(topology("n3")).unwrap();
+        drop(wal);
+        let (_, recovered) = open("torn");
+        assert_eq!(neighbours(&recovered.ops), ["n1", "n2", "n3"]);
+    }
+
+    #[test]
+    fn ops_in_the_snapshot_are_skipped() {
+        let (mut wal, _) = fresh("snapshot");
+        wal.append(topology("n1")).unwrap();
+        wal.append(topology("n2")).unwrap();
+
+        // A crash after the snapshot is renamed and before the log is truncated
+        let path = dir("snapshot").join(WAL_FILE);
+        let log = fs::read(&path).unwrap();
+        let state = State {
+            neighbours: vec!["n1".to_owned(), "n2".to_owned()],
+            ..State::default()
+        };
+        wal.snapshot(state).unwrap();
+        drop(wal);
+        fs::write(&path, log).unwrap();
+
+        let (mut wal, recovered) = open("snapshot");
+        assert_eq!(recovered.state.unwrap().neighbours, ["n1", "n2"]);
+        assert!(recovered.ops.is_empty());
+
+        // Sequence numbers go on from the snapshot
+        wal.append(topology("n3")).unwrap();
+        drop(wal);
+        let (_, recovered) = open("snapshot");
+        assert_eq!(neighbours(&recovered.ops), ["n3"]);
+    }
+
+    #[test]
+    fn appends_keep_their_timestamp() {
+        let (mut wal, _) = fresh("timestamp");
+        wal.append(Op::Append {
+            key: "k1".to_owned(),
+            msg: Value::from(1),
+            msg_key: None,
+            producer_id: None,
+            seq: None,
+            timestamp: 42,
+        })
+        .unwrap();
+        drop(wal);
+
+        let (_, recovered) = open("timestamp");
+        match &recovered.ops[..] {
+            [Op::Append { timestamp, .. }] => assert_eq!(*timestamp, 42),
+            ops => panic!("unexpected ops {:?}", ops),
+        }
+    }
+}